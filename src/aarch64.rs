@@ -0,0 +1,511 @@
+use backend::{self, block_label, Backend};
+use ir::{BinaryOp, BlockId, Function, Instruction, Terminator, UnaryOp, Value};
+use regalloc::{Allocation, Location};
+use std::fmt;
+use types::{DataModel, Type};
+
+#[derive(Debug)]
+pub enum Line {
+    Directive(Directive, String),
+    Label(String),
+    Inst(Opcode, Vec<Operand>),
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Line::Directive(dir, other) => write!(f, ".{} {}", format!("{:?}", dir).to_lowercase(), other),
+            Line::Label(label) => write!(f, "{}:", label),
+            Line::Inst(opcode, operands) => {
+                write!(f, "\t{}", format!("{:?}", opcode).to_lowercase())?;
+                for (i, operand) in operands.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { "\t" } else { ", " }, operand)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Directive {
+    Globl,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Opcode {
+    Mov,
+    Movz,
+    Movk,
+    Sxtw,
+    Add,
+    Sub,
+    Mul,
+    Sdiv,
+    Udiv,
+    Neg,
+    Mvn,
+    Lsl,
+    Asr,
+    Lsr,
+    Cmp,
+    Cset,
+    Ldr,
+    Str,
+    Stp,
+    Ldp,
+    B,
+    Bl,
+    Cbnz,
+    Ret,
+    Svc,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Register {
+    X(u8),
+    SP,
+    /// The zero register, `xzr` or `wzr`
+    ZR,
+}
+
+/// Frame pointer and link register, saved as a pair by every function
+const FP: Register = Register::X(29);
+const LR: Register = Register::X(30);
+
+/// Holds the system call number, the arguments and result going in x0-x5 (Linux AArch64)
+const SYSCALL_NUMBER: Register = Register::X(8);
+
+/// Intra-procedure-call scratch registers, never allocated, used to reach spilled values
+const SCRATCH: [Register; 2] = [Register::X(16), Register::X(17)];
+
+/// The temporaries first, then the argument registers x0-x7 (rcc makes no calls yet so they
+/// are free), then the callee-saved x19-x28. x18 is the platform register and stays untouched.
+const ALLOCATABLE: [Register; 25] = [
+    Register::X(9),
+    Register::X(10),
+    Register::X(11),
+    Register::X(12),
+    Register::X(13),
+    Register::X(14),
+    Register::X(15),
+    Register::X(0),
+    Register::X(1),
+    Register::X(2),
+    Register::X(3),
+    Register::X(4),
+    Register::X(5),
+    Register::X(6),
+    Register::X(7),
+    Register::X(19),
+    Register::X(20),
+    Register::X(21),
+    Register::X(22),
+    Register::X(23),
+    Register::X(24),
+    Register::X(25),
+    Register::X(26),
+    Register::X(27),
+    Register::X(28),
+];
+
+impl Register {
+    fn is_callee_saved(self) -> bool {
+        match self {
+            Register::X(n) => (19..=28).contains(&n),
+            _ => false,
+        }
+    }
+
+    fn name(self, width: Width) -> String {
+        match (self, width) {
+            (Register::X(n), Width::W) => format!("w{}", n),
+            (Register::X(n), Width::X) => format!("x{}", n),
+            (Register::SP, Width::W) => String::from("wsp"),
+            (Register::SP, Width::X) => String::from("sp"),
+            (Register::ZR, Width::W) => String::from("wzr"),
+            (Register::ZR, Width::X) => String::from("xzr"),
+        }
+    }
+}
+
+/// Register views: the 32-bit `w` and the 64-bit `x` names
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Width {
+    W,
+    X,
+}
+
+impl Width {
+    fn of(ctype: Type) -> Width {
+        match ctype.size(DataModel::LP64) {
+            4 => Width::W,
+            _ => Width::X,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Operand {
+    Register(Register, Width),
+    Immediate(u64),
+    /// `[base, #offset]`
+    Memory(Register, i64),
+    /// `[base, #offset]!`, adjusting the base before the access
+    PreIndex(Register, i64),
+    /// `[base], #offset`, adjusting the base after the access
+    PostIndex(Register, i64),
+    /// `#value, lsl #shift`
+    Shifted(u64, u32),
+    Condition(&'static str),
+    Label(String),
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Register(reg, width) => write!(f, "{}", reg.name(*width)),
+            Operand::Immediate(int) => write!(f, "#{}", int),
+            Operand::Memory(base, 0) => write!(f, "[{}]", base.name(Width::X)),
+            Operand::Memory(base, offset) => write!(f, "[{}, #{}]", base.name(Width::X), offset),
+            Operand::PreIndex(base, offset) => write!(f, "[{}, #{}]!", base.name(Width::X), offset),
+            Operand::PostIndex(base, offset) => write!(f, "[{}], #{}", base.name(Width::X), offset),
+            Operand::Shifted(int, shift) => write!(f, "#{}, lsl #{}", int, shift),
+            Operand::Condition(cond) => write!(f, "{}", cond),
+            Operand::Label(label) => write!(f, "{}", label),
+        }
+    }
+}
+
+/// The AArch64 backend, following AAPCS64
+pub struct AArch64;
+
+impl Backend for AArch64 {
+    type Register = Register;
+    type Line = Line;
+
+    fn registers(&self) -> &'static [Register] {
+        &ALLOCATABLE
+    }
+
+    fn clobbers(&self, inst: &Instruction) -> Vec<Register> {
+        match inst {
+            // x0 carries the result even when there are no arguments
+            Instruction::Syscall { args, .. } => (0..args.len().max(1) as u8).map(Register::X).collect(),
+            _ => vec![],
+        }
+    }
+
+    fn select(&self, func: &Function, allocation: &Allocation<Register>) -> Vec<Line> {
+        select_fn(func, allocation)
+    }
+
+    fn start(&self) -> Vec<Line> {
+        vec![
+            Line::Directive(Directive::Globl, String::from("_start")),
+            Line::Label(String::from("_start")),
+            // A zero frame pointer marks the outermost frame
+            Line::Inst(Opcode::Mov, vec![reg(FP, Width::X), reg(Register::ZR, Width::X)]),
+            Line::Inst(Opcode::Bl, vec![Operand::Label(String::from("main"))]),
+            Line::Inst(Opcode::Mov, vec![reg(SYSCALL_NUMBER, Width::X), Operand::Immediate(93)]),
+            Line::Inst(Opcode::Svc, vec![Operand::Immediate(0)]),
+        ]
+    }
+
+    fn render(&self, lines: &[Line]) -> Vec<String> {
+        backend::prologue('%')
+            .into_iter()
+            .chain(lines.iter().map(|line| line.to_string()))
+            .collect()
+    }
+}
+
+fn reg(reg: Register, width: Width) -> Operand {
+    Operand::Register(reg, width)
+}
+
+fn select_fn(func: &Function, allocation: &Allocation<Register>) -> Vec<Line> {
+    let saved: Vec<Register> = allocation
+        .used
+        .iter()
+        .cloned()
+        .filter(|reg| reg.is_callee_saved())
+        .collect();
+    // Callee-saved registers and spill slots sit above the stack pointer, below the frame record
+    let locals = ((saved.len() + allocation.spill_slots) as u64 * 8).div_ceil(16) * 16;
+
+    let mut selector = Selector {
+        func,
+        allocation,
+        saved: &saved,
+        locals,
+        lines: vec![
+            Line::Directive(Directive::Globl, func.name.clone()),
+            Line::Label(func.name.clone()),
+            Line::Inst(
+                Opcode::Stp,
+                vec![
+                    reg(FP, Width::X),
+                    reg(LR, Width::X),
+                    Operand::PreIndex(Register::SP, -16),
+                ],
+            ),
+            Line::Inst(Opcode::Mov, vec![reg(FP, Width::X), reg(Register::SP, Width::X)]),
+        ],
+    };
+    if locals > 0 {
+        selector.reserve_locals();
+    }
+    for (i, saved) in saved.iter().enumerate() {
+        selector.emit(
+            Opcode::Str,
+            vec![reg(*saved, Width::X), Operand::Memory(Register::SP, 8 * i as i64)],
+        );
+    }
+
+    for (i, block) in func.blocks.iter().enumerate() {
+        selector.lines.push(Line::Label(block_label(func, BlockId(i))));
+        for inst in &block.instructions {
+            selector.instruction(inst);
+        }
+        selector.terminator(&block.terminator);
+    }
+    selector.lines
+}
+
+struct Selector<'a> {
+    func: &'a Function,
+    allocation: &'a Allocation<Register>,
+    /// Callee-saved registers the function uses, stored at the bottom of the frame
+    saved: &'a [Register],
+    /// Bytes reserved below the frame record
+    locals: u64,
+    lines: Vec<Line>,
+}
+
+impl<'a> Selector<'a> {
+    fn emit(&mut self, opcode: Opcode, operands: Vec<Operand>) {
+        self.lines.push(Line::Inst(opcode, operands));
+    }
+
+    /// Moves the stack pointer down past the locals area; the epilogue restores it from the frame pointer
+    fn reserve_locals(&mut self) {
+        let sp = reg(Register::SP, Width::X);
+        if self.locals < 4096 {
+            self.emit(Opcode::Sub, vec![sp.clone(), sp, Operand::Immediate(self.locals)]);
+        } else {
+            self.load_constant(SCRATCH[0], self.locals, Width::X);
+            self.emit(Opcode::Sub, vec![sp.clone(), sp, reg(SCRATCH[0], Width::X)]);
+        }
+    }
+
+    /// Builds a constant out of 16-bit pieces, skipping the pieces that are zero
+    fn load_constant(&mut self, target: Register, bits: u64, width: Width) {
+        let pieces = if width == Width::W { 2 } else { 4 };
+        let mut opcode = Opcode::Movz;
+        for i in 0..pieces {
+            let piece = (bits >> (16 * i)) & 0xffff;
+            if piece != 0 || (opcode == Opcode::Movz && i == pieces - 1) {
+                self.emit(opcode, vec![reg(target, width), Operand::Shifted(piece, 16 * i)]);
+                opcode = Opcode::Movk;
+            }
+        }
+    }
+
+    fn slot(&self, slot: usize) -> Operand {
+        Operand::Memory(Register::SP, 8 * (self.saved.len() + slot) as i64)
+    }
+
+    fn width(&self, value: Value) -> Width {
+        Width::of(self.func.type_of(value))
+    }
+
+    /// A register holding `value`, loaded into the given scratch register when it is
+    /// a constant or spilled
+    fn read(&mut self, value: Value, width: Width, scratch: Register) -> Operand {
+        match value {
+            Value::Const(int) if int.is_zero() => reg(Register::ZR, width),
+            Value::Const(int) => {
+                self.load_constant(scratch, int.value, width);
+                reg(scratch, width)
+            }
+            Value::Register(vreg) => match self.allocation.location(vreg) {
+                Location::Register(allocated) => reg(allocated, width),
+                Location::Stack(slot) => {
+                    let slot = self.slot(slot);
+                    self.emit(Opcode::Ldr, vec![reg(scratch, Width::X), slot]);
+                    reg(scratch, width)
+                }
+            },
+        }
+    }
+
+    /// Puts `value` in the register `target`
+    fn load(&mut self, value: Value, target: Register) {
+        match self.read(value, Width::X, target) {
+            Operand::Register(source, _) if source == target => {}
+            source => self.emit(Opcode::Mov, vec![reg(target, Width::X), source]),
+        }
+    }
+
+    /// The register an instruction should write `dst` to, and the slot to store it to afterwards
+    fn write(&self, dst: Value) -> (Operand, Option<Operand>) {
+        let width = self.width(dst);
+        match dst {
+            Value::Register(vreg) => match self.allocation.location(vreg) {
+                Location::Register(allocated) => (reg(allocated, width), None),
+                Location::Stack(slot) => (reg(SCRATCH[0], width), Some(self.slot(slot))),
+            },
+            Value::Const(_) => unreachable!("instructions always write a register"),
+        }
+    }
+
+    fn store(&mut self, slot: Option<Operand>) {
+        if let Some(slot) = slot {
+            self.emit(Opcode::Str, vec![reg(SCRATCH[0], Width::X), slot]);
+        }
+    }
+
+    fn instruction(&mut self, inst: &Instruction) {
+        let dst_value = Value::Register(inst.dst());
+        let width = self.width(dst_value);
+        let (dst, slot) = self.write(dst_value);
+
+        match inst {
+            Instruction::Copy {
+                src: Value::Const(int), ..
+            } if !int.is_zero() => {
+                let target = match dst {
+                    Operand::Register(target, _) => target,
+                    _ => unreachable!(),
+                };
+                self.load_constant(target, int.value, width);
+            }
+            Instruction::Copy { src, .. } => {
+                let src = self.read(*src, width, SCRATCH[1]);
+                self.emit(Opcode::Mov, vec![dst, src]);
+            }
+            Instruction::Phi { .. } => panic!("phi instructions must be removed before code generation"),
+            Instruction::Convert { src, .. } => {
+                let src_type = self.func.type_of(*src);
+                let src_width = Width::of(src_type);
+                let src = self.read(*src, src_width, SCRATCH[1]);
+                if src_type.is_signed() && src_width == Width::W && width == Width::X {
+                    self.emit(Opcode::Sxtw, vec![dst, src]);
+                } else {
+                    // Writing a w register zero extends, and reading one truncates
+                    let (dst, src) = match (dst, src) {
+                        (Operand::Register(dst, _), Operand::Register(src, _)) if src_width != width => {
+                            (reg(dst, Width::W), reg(src, Width::W))
+                        }
+                        other => other,
+                    };
+                    self.emit(Opcode::Mov, vec![dst, src]);
+                }
+            }
+            Instruction::Unary { op, src, .. } => {
+                let src = self.read(*src, width, SCRATCH[1]);
+                let opcode = match op {
+                    UnaryOp::Neg => Opcode::Neg,
+                    UnaryOp::Not => Opcode::Mvn,
+                };
+                self.emit(opcode, vec![dst, src]);
+            }
+            Instruction::Binary { op, left, right, .. } => {
+                let op_width = self.width(*left);
+                let left_reg = self.read(*left, op_width, SCRATCH[0]);
+                // add, sub and cmp take a 12-bit unsigned immediate
+                let right_reg = match (op, right) {
+                    (BinaryOp::Add, Value::Const(int))
+                    | (BinaryOp::Sub, Value::Const(int))
+                    | (BinaryOp::Equal, Value::Const(int))
+                        if int.value < 4096 =>
+                    {
+                        Operand::Immediate(int.value)
+                    }
+                    // Shifts by a register take the count modulo the width, and so does the immediate form
+                    (BinaryOp::Shl, Value::Const(int)) | (BinaryOp::Shr, Value::Const(int)) => {
+                        let bits = if op_width == Width::W { 32 } else { 64 };
+                        Operand::Immediate(int.value % bits)
+                    }
+                    _ => self.read(*right, op_width, SCRATCH[1]),
+                };
+                match op {
+                    BinaryOp::Equal => {
+                        self.emit(Opcode::Cmp, vec![left_reg, right_reg]);
+                        self.emit(Opcode::Cset, vec![dst, Operand::Condition("eq")]);
+                    }
+                    _ => {
+                        let opcode = match op {
+                            BinaryOp::Add => Opcode::Add,
+                            BinaryOp::Sub => Opcode::Sub,
+                            BinaryOp::Mul => Opcode::Mul,
+                            BinaryOp::Shl => Opcode::Lsl,
+                            BinaryOp::Shr if self.func.type_of(*left).is_signed() => Opcode::Asr,
+                            BinaryOp::Shr => Opcode::Lsr,
+                            _ if self.func.type_of(*left).is_signed() => Opcode::Sdiv,
+                            _ => Opcode::Udiv,
+                        };
+                        self.emit(opcode, vec![dst, left_reg, right_reg]);
+                    }
+                }
+            }
+            Instruction::Syscall { number, args, .. } => {
+                // No operand lives in a register the call clobbers, so each loads straight into place
+                for (i, arg) in args.iter().enumerate() {
+                    self.load(*arg, Register::X(i as u8));
+                }
+                self.load(*number, SYSCALL_NUMBER);
+                self.emit(Opcode::Svc, vec![Operand::Immediate(0)]);
+                self.emit(Opcode::Mov, vec![dst, reg(Register::X(0), width)]);
+            }
+        }
+        self.store(slot);
+    }
+
+    fn terminator(&mut self, terminator: &Terminator) {
+        match terminator {
+            Terminator::Return(value) => {
+                let width = Width::of(self.func.return_type);
+                match *value {
+                    Value::Const(int) => self.load_constant(Register::X(0), int.value, width),
+                    value => {
+                        let value = self.read(value, width, SCRATCH[0]);
+                        self.emit(Opcode::Mov, vec![reg(Register::X(0), width), value]);
+                    }
+                }
+                for (i, saved) in self.saved.iter().enumerate() {
+                    self.emit(
+                        Opcode::Ldr,
+                        vec![reg(*saved, Width::X), Operand::Memory(Register::SP, 8 * i as i64)],
+                    );
+                }
+                self.emit(Opcode::Mov, vec![reg(Register::SP, Width::X), reg(FP, Width::X)]);
+                self.emit(
+                    Opcode::Ldp,
+                    vec![
+                        reg(FP, Width::X),
+                        reg(LR, Width::X),
+                        Operand::PostIndex(Register::SP, 16),
+                    ],
+                );
+                self.emit(Opcode::Ret, vec![]);
+            }
+            Terminator::Jump(target) => {
+                let label = block_label(self.func, *target);
+                self.emit(Opcode::B, vec![Operand::Label(label)]);
+            }
+            Terminator::Branch(value, then, otherwise) => {
+                let (then, otherwise) = (block_label(self.func, *then), block_label(self.func, *otherwise));
+                if let Value::Const(int) = value {
+                    let target = if int.is_zero() { otherwise } else { then };
+                    self.emit(Opcode::B, vec![Operand::Label(target)]);
+                    return;
+                }
+                let value = self.read(*value, self.width(*value), SCRATCH[0]);
+                self.emit(Opcode::Cbnz, vec![value, Operand::Label(then)]);
+                self.emit(Opcode::B, vec![Operand::Label(otherwise)]);
+            }
+        }
+    }
+}
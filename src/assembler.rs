@@ -1,22 +1,130 @@
-use std::path::Path;
+use exporter;
+use rcc::Target;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
-pub fn assemble(asm_path: &Path, exe_path: &Path) {
-    let output = if cfg!(target_os = "windows") {
-        panic!("Cannot compile the assembly on windos")
-    } else {
-        Command::new("gcc")
-            .arg(asm_path.to_str().unwrap())
-            .arg("-o")
-            .arg(exe_path.to_str().unwrap())
-            .output()
-            .expect("failed to compile")
-    };
-
-    let stderr = String::from_utf8_lossy(&output.stderr);
-    if !stderr.is_empty() {
-        println!("Error:");
-        println!("{}", stderr);
-        panic!();
+/// Writes an executable the built-in linker produced
+pub fn write_executable(exe_path: &Path, executable: &[u8]) -> Result<(), String> {
+    exporter::to_binary_file(exe_path, executable)?;
+    make_executable(exe_path)
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Path) -> Result<(), String> {
+    use std::os::unix::fs::PermissionsExt;
+    if path == Path::new("-") {
+        return Ok(());
+    }
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+        .map_err(|err| format!("{}: cannot make executable: {}", path.display(), err))
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &Path) -> Result<(), String> {
+    Ok(())
+}
+
+/// What rcc says about the external commands it runs
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Echo {
+    Quiet,
+    /// `-v`: print each command before running it
+    Verbose,
+    /// `-###`: print each command, quoted, instead of running it
+    DryRun,
+}
+
+/// The external assembler and linker, for what rcc does not do in-process
+pub struct Toolchain {
+    pub target: Target,
+    /// A command line from `--as` or `RCC_AS`, run as `<assembler> <input> -o <output>`.
+    /// The target's gcc with `-c` otherwise.
+    pub assembler: Option<String>,
+    /// A command line from `--ld` or `RCC_LD`, run as `<linker> <inputs> <flags> -o <output>`.
+    /// The target's gcc otherwise.
+    pub linker: Option<String>,
+    pub echo: Echo,
+}
+
+impl Toolchain {
+    /// Assembles into a relocatable object, for targets the built-in encoder does not cover
+    pub fn assemble(&self, asm_path: &Path, obj_path: &Path) -> Result<(), String> {
+        let mut command = match &self.assembler {
+            Some(assembler) => split(assembler),
+            None => {
+                let mut command = self.gcc();
+                command.push(String::from("-c"));
+                command
+            }
+        };
+        command.push(path(asm_path));
+        command.push(String::from("-o"));
+        command.push(path(obj_path));
+        self.run(&command)
+    }
+
+    /// Links objects and `-l`/`-L` options into an executable; the default linker, the
+    /// target's gcc, also takes assembly files. Under `-nostdlib` the inputs bring their own
+    /// `_start`, and only libgcc is linked in, for helpers like `__divdi3`.
+    pub fn link(
+        &self,
+        inputs: &[PathBuf],
+        exe_path: &Path,
+        nostdlib: bool,
+        linker_args: &[String],
+    ) -> Result<(), String> {
+        let mut command = match &self.linker {
+            Some(linker) => split(linker),
+            None => self.gcc(),
+        };
+        command.extend(inputs.iter().map(|input| path(input)));
+        // After the inputs, so that libraries resolve what they refer to
+        command.extend(linker_args.iter().cloned());
+        if nostdlib {
+            command.extend(["-nostdlib", "-static", "-lgcc"].iter().map(|flag| String::from(*flag)));
+        }
+        command.push(String::from("-o"));
+        command.push(path(exe_path));
+        self.run(&command)
+    }
+
+    fn gcc(&self) -> Vec<String> {
+        let mut command = vec![String::from(self.target.gcc())];
+        command.extend(self.target.gcc_flags().iter().map(|flag| String::from(*flag)));
+        command
     }
-}
\ No newline at end of file
+
+    /// Runs a command with rcc's own standard streams, so that its warnings come through, and
+    /// fails only on its exit status
+    fn run(&self, command: &[String]) -> Result<(), String> {
+        match self.echo {
+            Echo::Quiet => {}
+            Echo::Verbose => eprintln!("{}", command.join(" ")),
+            Echo::DryRun => {
+                let quoted: Vec<String> = command.iter().map(|arg| format!("\"{}\"", arg)).collect();
+                eprintln!(" {}", quoted.join(" "));
+                return Ok(());
+            }
+        }
+        let status = Command::new(&command[0])
+            .args(&command[1..])
+            .status()
+            .map_err(|err| format!("cannot run '{}': {}", command[0], err))?;
+        match status.code() {
+            _ if status.success() => Ok(()),
+            Some(code) => Err(format!("{} returned {} exit status", command[0], code)),
+            None => Err(format!("{} was terminated by a signal", command[0])),
+        }
+    }
+}
+
+/// Splits a configured command line, which the driver checks is not empty, into the program
+/// and its arguments
+fn split(command: &str) -> Vec<String> {
+    command.split_whitespace().map(String::from).collect()
+}
+
+fn path(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
@@ -0,0 +1,81 @@
+use ir::{BlockId, Function, Instruction, Module};
+use regalloc::{self, Allocation};
+use std::fmt;
+use types::Type;
+
+/// What a target architecture supplies to turn the IR into assembly: its registers, its
+/// instructions, and how to select and print them. Allocation is shared by every target.
+pub trait Backend {
+    type Register: Copy + Eq + 'static;
+    type Line: fmt::Debug;
+
+    /// Registers handed to the allocator, in order of preference
+    fn registers(&self) -> &'static [Self::Register];
+
+    /// Registers an instruction destroys besides its result
+    fn clobbers(&self, _inst: &Instruction) -> Vec<Self::Register> {
+        Vec::new()
+    }
+
+    /// Whether a value of the type fits in one register; the others live in spill slots
+    fn fits(&self, _ctype: Type) -> bool {
+        true
+    }
+
+    /// Rewrites the operands the target cannot encode before registers are allocated
+    fn legalize(&self, func: &Function) -> Function {
+        func.clone()
+    }
+
+    fn select(&self, func: &Function, allocation: &Allocation<Self::Register>) -> Vec<Self::Line>;
+
+    /// `_start` for programs linked without the C library: calls `main` and passes its result
+    /// to the `exit` system call
+    fn start(&self) -> Vec<Self::Line>;
+
+    /// Machine-level rewrites once every function is selected
+    fn finish(&self, lines: Vec<Self::Line>) -> Vec<Self::Line> {
+        lines
+    }
+
+    fn render(&self, lines: &[Self::Line]) -> Vec<String>;
+}
+
+/// Opens an assembly file, as gcc does, with an empty `.note.GNU-stack` section, which tells
+/// the linker the code needs no executable stack, then switches back to `.text`. `progbits`
+/// is the section type, written after `@` or, where `@` starts a comment, `%`.
+pub fn prologue(type_prefix: char) -> Vec<String> {
+    vec![
+        format!(".section .note.GNU-stack,\"\",{}progbits", type_prefix),
+        String::from(".text"),
+    ]
+}
+
+/// The module's assembly, preceded by `_start` when it is the entry of a program linked
+/// without the C library
+pub fn generate<B: Backend>(backend: &B, module: &Module, start: bool) -> Vec<String> {
+    let mut lines = if start { backend.start() } else { Vec::new() };
+    lines.extend(generate_module(backend, module));
+    backend.render(&lines)
+}
+
+/// Every function selected into the target's instructions, before they are printed
+pub fn generate_module<B: Backend>(backend: &B, module: &Module) -> Vec<B::Line> {
+    let mut lines = Vec::new();
+    for func in &module.functions {
+        let func = backend.legalize(func);
+        let allocation = regalloc::allocate(
+            &func,
+            backend.registers(),
+            |inst| backend.clobbers(inst),
+            |ctype| backend.fits(ctype),
+        );
+        lines.extend(backend.select(&func, &allocation));
+    }
+    backend.finish(lines)
+}
+
+/// Local label of a basic block, unique across the module
+pub fn block_label(func: &Function, block: BlockId) -> String {
+    format!(".L{}_{}", func.name, block)
+}
@@ -0,0 +1,266 @@
+use lexer::Span;
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Level {
+    Warning,
+    /// Stops the compilation
+    Error,
+}
+
+/// The warnings rcc checks for, each controlled by the `-W` flag gcc has for it
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Warning {
+    /// A constant expression or conversion overflows its signed type
+    Overflow,
+    /// A constant division by zero
+    DivByZero,
+    /// A constant shift count below zero
+    ShiftCountNegative,
+    /// A constant shift count not less than the width of the value shifted
+    ShiftCountOverflow,
+    /// An implicit conversion to a narrower type, which may change the value
+    Conversion,
+    /// An implicit conversion between signed and unsigned types, which may change the value.
+    /// `-Wconversion` turns it on unless it is toggled itself.
+    SignConversion,
+    /// `~` on the result of `!`, which is never all ones, where `!` was likely meant
+    BoolOperation,
+    /// A product tested with `!`, where `&&` on the factors was likely meant
+    IntInBoolContext,
+}
+
+/// Which flag turns a warning on when no `-W<name>` or `-Wno-<name>` names it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Group {
+    Default,
+    All,
+    Extra,
+}
+
+const WARNINGS: [(Warning, &str, Group); 8] = [
+    (Warning::Overflow, "overflow", Group::Default),
+    (Warning::DivByZero, "div-by-zero", Group::Default),
+    (Warning::ShiftCountNegative, "shift-count-negative", Group::Default),
+    (Warning::ShiftCountOverflow, "shift-count-overflow", Group::Default),
+    (Warning::Conversion, "conversion", Group::Extra),
+    (Warning::SignConversion, "sign-conversion", Group::Extra),
+    (Warning::BoolOperation, "bool-operation", Group::All),
+    (Warning::IntInBoolContext, "int-in-bool-context", Group::All),
+];
+
+impl Warning {
+    /// The name of the warning's flag, after `-W`
+    pub fn name(self) -> &'static str {
+        match WARNINGS.iter().find(|(warning, _, _)| *warning == self) {
+            Some((_, name, _)) => name,
+            None => panic!("Warning '{:?}' has no flag", self),
+        }
+    }
+
+    fn group(self) -> Group {
+        match WARNINGS.iter().find(|(warning, _, _)| *warning == self) {
+            Some((_, _, group)) => *group,
+            None => panic!("Warning '{:?}' has no flag", self),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub level: Level,
+    /// The warning that raised the diagnostic, so that its flag can control it
+    pub warning: Option<Warning>,
+    pub message: String,
+    /// The token the diagnostic points at, if it is about one
+    pub span: Option<Span>,
+}
+
+impl Diagnostic {
+    pub fn warning(warning: Warning, message: String) -> Diagnostic {
+        Diagnostic {
+            level: Level::Warning,
+            warning: Some(warning),
+            message,
+            span: None,
+        }
+    }
+
+    pub fn error(message: String) -> Diagnostic {
+        Diagnostic {
+            level: Level::Error,
+            warning: None,
+            message,
+            span: None,
+        }
+    }
+
+    pub fn at(mut self, span: Span) -> Diagnostic {
+        self.span = Some(span);
+        self
+    }
+
+    /// The diagnostic after the file it is about, and the line and column it points at in
+    /// the file's source, as gcc prints them
+    pub fn located(&self, path: &str, source: &str) -> String {
+        match self.span {
+            Some(span) => {
+                let (line, column) = span.line_column(source);
+                format!("{}:{}:{}: {}", path, line, column, self)
+            }
+            None => format!("{}: {}", path, self),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let level = match self.level {
+            Level::Warning => "warning",
+            Level::Error => "error",
+        };
+        write!(f, "{}: {}", level, self.message)?;
+        // The flag controlling the warning, as gcc shows it
+        match (self.level, self.warning) {
+            (Level::Warning, Some(warning)) => write!(f, " [-W{}]", warning.name()),
+            (Level::Error, Some(warning)) => write!(f, " [-Werror={}]", warning.name()),
+            (_, None) => Ok(()),
+        }
+    }
+}
+
+/// Which warnings are reported: the default ones, those `-Wall` and `-Wextra` add, refined
+/// by `-W<name>` and `-Wno-<name>` toggles. `-Werror` makes the reported ones errors.
+#[derive(Debug, Default, Clone)]
+pub struct Warnings {
+    all: bool,
+    extra: bool,
+    errors: bool,
+    toggles: HashMap<Warning, bool>,
+}
+
+impl Warnings {
+    /// `-Wall`
+    pub fn set_all(&mut self, all: bool) {
+        self.all = all;
+    }
+
+    /// `-Wextra`
+    pub fn set_extra(&mut self, extra: bool) {
+        self.extra = extra;
+    }
+
+    /// `-Werror`
+    pub fn set_errors(&mut self, errors: bool) {
+        self.errors = errors;
+    }
+
+    /// Turns a warning on or off by name. Turning off one rcc does not have is not an error,
+    /// as in gcc, so that flags meant for other compilers can be passed.
+    pub fn toggle(&mut self, name: &str, enabled: bool) -> Result<(), String> {
+        match WARNINGS.iter().find(|(_, candidate, _)| *candidate == name) {
+            Some((warning, _, _)) => {
+                self.toggles.insert(*warning, enabled);
+                Ok(())
+            }
+            None if !enabled => Ok(()),
+            None => Err(format!("unknown warning option '-W{}'", name)),
+        }
+    }
+
+    pub fn enabled(&self, warning: Warning) -> bool {
+        match self.toggles.get(&warning) {
+            Some(enabled) => *enabled,
+            None if warning == Warning::SignConversion => self.enabled(Warning::Conversion),
+            None => match warning.group() {
+                Group::Default => true,
+                Group::All => self.all,
+                Group::Extra => self.extra,
+            },
+        }
+    }
+
+    /// Drops the warnings that are off, and makes the rest errors under `-Werror`
+    pub fn apply(&self, diagnostics: Vec<Diagnostic>) -> Vec<Diagnostic> {
+        diagnostics
+            .into_iter()
+            .filter(|diagnostic| match diagnostic.warning {
+                Some(warning) if diagnostic.level == Level::Warning => self.enabled(warning),
+                _ => true,
+            })
+            .map(|mut diagnostic| {
+                if self.errors {
+                    diagnostic.level = Level::Error;
+                }
+                diagnostic
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reported(warnings: &Warnings) -> Vec<String> {
+        let diagnostics = vec![
+            Diagnostic::warning(Warning::Overflow, String::from("overflow")),
+            Diagnostic::warning(Warning::Conversion, String::from("conversion")),
+            Diagnostic::error(String::from("failure")),
+        ];
+        warnings.apply(diagnostics).iter().map(Diagnostic::to_string).collect()
+    }
+
+    #[test]
+    fn groups_and_toggles_select_warnings() {
+        let mut warnings = Warnings::default();
+        assert_eq!(
+            reported(&warnings),
+            ["warning: overflow [-Woverflow]", "error: failure"]
+        );
+        warnings.set_extra(true);
+        warnings.toggle("overflow", false).unwrap();
+        assert_eq!(
+            reported(&warnings),
+            ["warning: conversion [-Wconversion]", "error: failure"]
+        );
+        assert!(warnings.toggle("bogus", true).is_err());
+        assert!(warnings.toggle("bogus", false).is_ok());
+    }
+
+    #[test]
+    fn wall_adds_its_warnings() {
+        let mut warnings = Warnings::default();
+        assert!(!warnings.enabled(Warning::BoolOperation));
+        warnings.set_all(true);
+        assert!(warnings.enabled(Warning::BoolOperation));
+        assert!(warnings.enabled(Warning::IntInBoolContext));
+        assert!(!warnings.enabled(Warning::Conversion));
+    }
+
+    #[test]
+    fn diagnostics_are_located_at_their_span() {
+        let source = "int main() {\n    return 1 / 0;\n}";
+        let diagnostic =
+            Diagnostic::warning(Warning::DivByZero, String::from("division by zero")).at(Span { start: 26, end: 27 });
+        assert_eq!(
+            diagnostic.located("a.c", source),
+            "a.c:2:14: warning: division by zero [-Wdiv-by-zero]"
+        );
+        assert_eq!(
+            Diagnostic::error(String::from("failure")).located("a.c", source),
+            "a.c: error: failure"
+        );
+    }
+
+    #[test]
+    fn werror_makes_warnings_errors() {
+        let mut warnings = Warnings::default();
+        warnings.set_errors(true);
+        assert_eq!(
+            reported(&warnings),
+            ["error: overflow [-Werror=overflow]", "error: failure"]
+        );
+    }
+}
@@ -0,0 +1,526 @@
+use assembler::Echo;
+use rcc::{Emit, Format, Optimizations, Syntax, Target, Warnings};
+use std::env;
+use std::io::{self, Read};
+use std::path::Path;
+
+pub const USAGE: &str = "\
+Usage: rcc [options] file...
+
+Options:
+  --help                 Display this information
+  --version              Display the compiler version
+  -E                     Preprocess only; do not compile, assemble or link
+  -S                     Compile only; do not assemble or link
+  -c                     Compile and assemble, but do not link
+  -o <file>              Place the output into <file>, '-' for standard output
+  -l<library>            Link with <library>
+  -L<dir>                Search <dir> for libraries
+  -nostdlib              Link without the C library, with rcc's own _start
+  -ffreestanding         Compile for a freestanding environment
+  -O<level>              Optimization level, 0 to 3, s, z, g or fast
+  -f<pass>, -fno-<pass>  Turn an optimization pass on or off
+  -fPIC, -fno-common, ...
+                         Code generation options that change nothing in rcc's
+                         output, accepted and ignored
+  -fsanitize=undefined-lite
+                         Abort on signed overflow, division by zero and shift
+                         counts out of range at run time (x86-64 only)
+  -Wall, -Wextra         Turn on more warnings
+  -Wl,<options>          Pass comma-separated <options> on to the linker
+  -W<name>, -Wno-<name>  Turn a warning on or off: overflow, div-by-zero,
+                         shift-count-negative, shift-count-overflow,
+                         conversion, sign-conversion, bool-operation or
+                         int-in-bool-context
+  -Werror                Make every warning an error
+  -m32, -m64             Generate code for i386 or x86-64
+  --target <triple>      Generate code for the target triple
+  -masm=<dialect>        x86 assembler dialect, att or intel
+  --emit=<kind>[=<file>],...
+                         Write only these outputs, of tokens, ast, ir, asm or
+                         llvm, to <file> or '-' for standard output; llvm
+                         uses opaque pointers, which need LLVM 15 or later
+  --emit-format=<format> Write tokens and the ast as text or json
+  --as=<command>         Assemble with <command> instead of gcc -c (or set RCC_AS)
+  --ld=<command>         Link with <command> instead of gcc (or set RCC_LD)
+  -g, -std=<standard>, -pipe, -D<macro>, -U<macro>, -I<dir>
+                         Accepted for compatibility with gcc, and ignored
+  -v                     Print the commands rcc runs
+  -###                   Print the commands rcc would run, without running them
+
+Files ending in .c are compiled, .s and .S are assembled, and any other file is
+passed to the linker. '-' reads C from standard input.
+";
+
+/// How far the driver takes its inputs, as `-E`, `-S` and `-c` choose
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    /// `-E`: the preprocessed source
+    Source,
+    /// `-S`: an assembly file per C input
+    Assembly,
+    /// `-c`: an object per C or assembly input
+    Object,
+    /// Every input linked into one executable
+    Executable,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Language {
+    C,
+    Assembly,
+    /// Objects, archives and anything else the linker takes
+    Object,
+}
+
+#[derive(Debug)]
+pub struct Input {
+    /// `-` stands for standard input
+    pub path: String,
+    pub language: Language,
+}
+
+impl Input {
+    /// Takes the language from the extension, as gcc does
+    fn new(path: String) -> Input {
+        let language = if path == "-" {
+            Language::C
+        } else {
+            match Path::new(&path).extension().and_then(|extension| extension.to_str()) {
+                Some("c") => Language::C,
+                Some("s") | Some("S") => Language::Assembly,
+                _ => Language::Object,
+            }
+        };
+        Input { path, language }
+    }
+
+    /// The name outputs are derived from: the file name without its directory or extension.
+    /// Standard input has none, so its outputs go to standard output instead.
+    pub fn stem(&self) -> Option<String> {
+        if self.path == "-" {
+            return None;
+        }
+        match Path::new(&self.path).file_stem() {
+            Some(stem) => Some(stem.to_string_lossy().into_owned()),
+            None => Some(self.path.clone()),
+        }
+    }
+
+    pub fn read(&self) -> Result<String, String> {
+        let mut source = String::new();
+        if self.path == "-" {
+            io::stdin()
+                .read_to_string(&mut source)
+                .map_err(|err| format!("cannot read standard input: {}", err))?;
+        } else {
+            source = std::fs::read_to_string(&self.path).map_err(|err| format!("{}: {}", self.path, err))?;
+        }
+        Ok(source)
+    }
+}
+
+pub struct Options {
+    pub inputs: Vec<Input>,
+    pub output: Option<String>,
+    pub stage: Stage,
+    pub target: Target,
+    pub syntax: Syntax,
+    pub optimizations: Optimizations,
+    pub warnings: Warnings,
+    /// `--emit` outputs, each with the file it goes to if one is given. Asking for any of
+    /// them replaces the usual outputs and skips linking.
+    pub emits: Vec<(Emit, Option<String>)>,
+    pub format: Format,
+    pub nostdlib: bool,
+    pub sanitize: bool,
+    /// `-l` and `-L` options, handed to the linker in order
+    pub linker_args: Vec<String>,
+    pub assembler: Option<String>,
+    pub linker: Option<String>,
+    pub echo: Echo,
+}
+
+impl Options {
+    /// Whether rcc encodes objects itself rather than through an assembler, which is only
+    /// needed for other architectures or when one is configured
+    pub fn assembles_in_process(&self) -> bool {
+        self.target == Target::X86_64 && self.assembler.is_none()
+    }
+
+    /// Whether rcc links the executable itself rather than through gcc, which is only needed
+    /// for other architectures, for libraries, or when a linker is configured
+    pub fn links_in_process(&self) -> bool {
+        self.target == Target::X86_64 && self.linker_args.is_empty() && self.linker.is_none()
+    }
+}
+
+pub enum Command {
+    Help,
+    Version,
+    Build(Box<Options>),
+}
+
+/// `-f` options Makefiles commonly pass for code generation rcc does not vary, such as position
+/// independence, sections and unwind tables
+const CODEGEN_FLAGS: &[&str] = &[
+    "pic",
+    "PIC",
+    "pie",
+    "PIE",
+    "plt",
+    "common",
+    "strict-aliasing",
+    "omit-frame-pointer",
+    "stack-protector",
+    "stack-protector-strong",
+    "stack-protector-all",
+    "stack-clash-protection",
+    "builtin",
+    "function-sections",
+    "data-sections",
+    "asynchronous-unwind-tables",
+    "unwind-tables",
+    "ident",
+];
+
+/// Whether `-f<flag>` or `-fno-<flag>` is a code generation option rcc ignores
+fn codegen_flag(flag: &str) -> bool {
+    let flag = flag.strip_prefix("no-").unwrap_or(flag);
+    CODEGEN_FLAGS.contains(&flag) || flag.starts_with("visibility=") || flag.starts_with("diagnostics-color")
+}
+
+/// Reads gcc-style command-line arguments
+pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let mut options = Options {
+        inputs: Vec::new(),
+        output: None,
+        stage: Stage::Executable,
+        target: Target::X86_64,
+        syntax: Syntax::ATT,
+        optimizations: Optimizations::default(),
+        warnings: Warnings::default(),
+        emits: Vec::new(),
+        format: Format::Text,
+        nostdlib: false,
+        sanitize: false,
+        linker_args: Vec::new(),
+        assembler: None,
+        linker: None,
+        echo: Echo::Quiet,
+    };
+    while let Some(arg) = args.next() {
+        if arg == "--help" {
+            return Ok(Command::Help);
+        } else if arg == "--version" {
+            return Ok(Command::Version);
+        } else if arg == "--target" || arg.starts_with("--target=") {
+            let triple = match arg.strip_prefix("--target=") {
+                Some(triple) => String::from(triple),
+                None => value(&mut args, &arg)?,
+            };
+            options.target = Target::from_triple(&triple)?;
+        } else if arg == "-o" {
+            options.output = Some(value(&mut args, &arg)?);
+        } else if arg == "-E" || arg == "-S" || arg == "-c" {
+            // The earliest stage asked for wins, as in gcc
+            let stage = match arg.as_str() {
+                "-E" => Stage::Source,
+                "-S" => Stage::Assembly,
+                _ => Stage::Object,
+            };
+            options.stage = options.stage.min(stage);
+        } else if arg == "-l" || arg == "-L" {
+            let operand = value(&mut args, &arg)?;
+            options.linker_args.push(format!("{}{}", arg, operand));
+        } else if arg.starts_with("-l") || arg.starts_with("-L") {
+            options.linker_args.push(arg);
+        } else if let Some(command) = arg.strip_prefix("--as=") {
+            options.assembler = Some(tool(command)?);
+        } else if let Some(command) = arg.strip_prefix("--ld=") {
+            options.linker = Some(tool(command)?);
+        } else if arg == "-v" {
+            options.echo = options.echo.max(Echo::Verbose);
+        } else if arg == "-###" {
+            options.echo = Echo::DryRun;
+        } else if arg == "-nostdlib" {
+            options.nostdlib = true;
+        } else if arg == "-ffreestanding" {
+            // rcc makes no assumptions about the C library, so there is nothing to turn off
+        } else if arg == "-D" || arg == "-U" || arg == "-I" {
+            value(&mut args, &arg)?;
+        } else if arg.starts_with("-D") || arg.starts_with("-U") || arg.starts_with("-I") {
+            // Makefiles pass these for the preprocessor; C without directives needs none of them
+        } else if arg.starts_with("-g") || arg.starts_with("-std=") || arg == "-pipe" {
+            // rcc emits no debug information, accepts one dialect and uses no temporary files
+            // it could pipe instead
+        } else if arg == "-m32" {
+            options.target = Target::I386;
+        } else if arg == "-m64" {
+            options.target = Target::X86_64;
+        } else if let Some(kinds) = arg.strip_prefix("--emit=") {
+            for kind in kinds.split(',') {
+                let (kind, path) = match kind.find('=') {
+                    Some(equals) => (&kind[..equals], Some(String::from(&kind[equals + 1..]))),
+                    None => (kind, None),
+                };
+                let emit = match kind {
+                    "tokens" => Emit::Tokens,
+                    "ast" => Emit::Ast,
+                    "ir" => Emit::Ir,
+                    "asm" => Emit::Assembly,
+                    "llvm" => Emit::Llvm,
+                    _ => return Err(format!("unknown output kind '{}'", kind)),
+                };
+                options.emits.push((emit, path));
+            }
+        } else if let Some(format) = arg.strip_prefix("--emit-format=") {
+            options.format = match format {
+                "text" => Format::Text,
+                "json" => Format::Json,
+                _ => return Err(format!("unknown output format '{}'", format)),
+            };
+        } else if let Some(dialect) = arg.strip_prefix("-masm=") {
+            options.syntax = match dialect {
+                "att" => Syntax::ATT,
+                "intel" => Syntax::Intel,
+                _ => return Err(format!("unknown assembler dialect '{}'", dialect)),
+            };
+        } else if let Some(level) = arg.strip_prefix("-O") {
+            let level = match level {
+                "" | "g" => 1,
+                // rcc has no size-specific passes, so these optimize as for speed
+                "s" | "z" => 2,
+                "fast" => 3,
+                _ => level
+                    .parse()
+                    .map_err(|_| format!("invalid optimization level '{}'", arg))?,
+            };
+            options.optimizations.set_level(level);
+        } else if let Some(checks) = arg
+            .strip_prefix("-fsanitize=")
+            .or_else(|| arg.strip_prefix("-fno-sanitize="))
+        {
+            if checks != "undefined-lite" {
+                return Err(format!("unrecognized argument to '-fsanitize=' option: '{}'", checks));
+            }
+            options.sanitize = arg.starts_with("-fsanitize=");
+        } else if arg.strip_prefix("-f").is_some_and(codegen_flag) {
+            // Accepted so that rcc can stand in as `CC`, like the preprocessor options above
+        } else if let Some(pass) = arg.strip_prefix("-fno-") {
+            options.optimizations.toggle(pass, false)?;
+        } else if let Some(pass) = arg.strip_prefix("-f") {
+            options.optimizations.toggle(pass, true)?;
+        } else if let Some(linker_options) = arg.strip_prefix("-Wl,") {
+            // Passed on as written, since gcc takes them the same way when it links
+            options.linker_args.push(format!("-Wl,{}", linker_options));
+        } else if arg == "-Wall" {
+            options.warnings.set_all(true);
+        } else if arg == "-Wextra" {
+            options.warnings.set_extra(true);
+        } else if arg == "-Werror" || arg == "-Wno-error" {
+            options.warnings.set_errors(arg == "-Werror");
+        } else if let Some(warning) = arg.strip_prefix("-Wno-") {
+            options.warnings.toggle(warning, false)?;
+        } else if let Some(warning) = arg.strip_prefix("-W") {
+            options.warnings.toggle(warning, true)?;
+        } else if arg.starts_with('-') && arg != "-" {
+            return Err(format!("unrecognized command-line option '{}'", arg));
+        } else {
+            options.inputs.push(Input::new(arg));
+        }
+    }
+
+    if options.inputs.is_empty() {
+        // `-v` on its own asks for the version, as in gcc
+        if options.echo == Echo::Verbose {
+            return Ok(Command::Version);
+        }
+        return Err(String::from("no input files"));
+    }
+    if options.output.is_some() && options.stage != Stage::Executable && options.inputs.len() > 1 {
+        return Err(String::from(
+            "cannot specify '-o' with '-c', '-S' or '-E' with multiple files",
+        ));
+    }
+    if options.output.is_some() && !options.emits.is_empty() {
+        let unnamed = options.emits.iter().filter(|(_, path)| path.is_none()).count();
+        if options.inputs.len() > 1 || unnamed > 1 {
+            return Err(String::from(
+                "cannot specify '-o' with '--emit' with multiple files or outputs",
+            ));
+        }
+    }
+    // The environment only fills in what the command line leaves out
+    if options.assembler.is_none() {
+        options.assembler = env::var("RCC_AS").ok().filter(|command| !command.trim().is_empty());
+    }
+    if options.linker.is_none() {
+        options.linker = env::var("RCC_LD").ok().filter(|command| !command.trim().is_empty());
+    }
+    Ok(Command::Build(Box::new(options)))
+}
+
+/// A command line given for the assembler or the linker
+fn tool(command: &str) -> Result<String, String> {
+    if command.trim().is_empty() {
+        return Err(String::from("empty assembler or linker command"));
+    }
+    Ok(String::from(command))
+}
+
+/// The operand of an option given as a separate argument
+fn value<I: Iterator<Item = String>>(args: &mut I, option: &str) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("missing argument to '{}'", option))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcc::diagnostic::Warning;
+
+    fn build(args: &[&str]) -> Result<Options, String> {
+        match parse(args.iter().map(|arg| String::from(*arg)))? {
+            Command::Build(options) => Ok(*options),
+            _ => Err(String::from("not a build")),
+        }
+    }
+
+    #[test]
+    fn inputs_take_their_language_from_the_extension() {
+        let options = build(&["main.c", "start.s", "lib.o", "libm.a", "-"]).unwrap();
+        let languages: Vec<Language> = options.inputs.iter().map(|input| input.language).collect();
+        assert_eq!(
+            languages,
+            [
+                Language::C,
+                Language::Assembly,
+                Language::Object,
+                Language::Object,
+                Language::C
+            ]
+        );
+        assert_eq!(options.inputs[0].stem().as_deref(), Some("main"));
+        assert_eq!(options.inputs[4].stem(), None);
+    }
+
+    #[test]
+    fn the_earliest_stage_wins() {
+        assert_eq!(build(&["-c", "-S", "a.c"]).unwrap().stage, Stage::Assembly);
+        assert_eq!(build(&["-S", "-E", "a.c"]).unwrap().stage, Stage::Source);
+        assert_eq!(build(&["a.c"]).unwrap().stage, Stage::Executable);
+    }
+
+    #[test]
+    fn linker_options_keep_their_order() {
+        let options = build(&["-L", "lib", "a.c", "-lm", "-Lother", "-l", "c"]).unwrap();
+        assert_eq!(options.linker_args, ["-Llib", "-lm", "-Lother", "-lc"]);
+        assert!(!options.links_in_process());
+        assert!(build(&["a.c"]).unwrap().links_in_process());
+    }
+
+    #[test]
+    fn tools_can_be_configured() {
+        let options = build(&["--as=as --64", "--ld=ld", "-v", "a.c"]).unwrap();
+        assert_eq!(options.assembler.as_deref(), Some("as --64"));
+        assert_eq!(options.linker.as_deref(), Some("ld"));
+        assert_eq!(options.echo, Echo::Verbose);
+        assert!(!options.assembles_in_process());
+        assert!(!options.links_in_process());
+        assert_eq!(build(&["-###", "-v", "a.c"]).unwrap().echo, Echo::DryRun);
+        assert!(build(&["--as=", "a.c"]).is_err());
+    }
+
+    #[test]
+    fn emits_can_name_their_files() {
+        let options = build(&["--emit=tokens=-,ast,asm=out.s", "--emit-format=json", "a.c"]).unwrap();
+        assert_eq!(
+            options.emits,
+            [
+                (Emit::Tokens, Some(String::from("-"))),
+                (Emit::Ast, None),
+                (Emit::Assembly, Some(String::from("out.s")))
+            ]
+        );
+        assert_eq!(options.format, Format::Json);
+        assert!(build(&["--emit=ast", "-o", "a.ast", "a.c"]).is_ok());
+        assert!(build(&["--emit=ast,ir", "-o", "a.ast", "a.c"]).is_err());
+        assert!(build(&["--emit=bytecode", "a.c"]).is_err());
+    }
+
+    #[test]
+    fn warnings_can_be_controlled() {
+        let options = build(&["-Wextra", "-Wno-sign-conversion", "-Wno-overflow", "a.c"]).unwrap();
+        assert!(options.warnings.enabled(Warning::Conversion));
+        assert!(!options.warnings.enabled(Warning::SignConversion));
+        assert!(!options.warnings.enabled(Warning::Overflow));
+        assert!(options.warnings.enabled(Warning::DivByZero));
+        let options = build(&["-Wsign-conversion", "a.c"]).unwrap();
+        assert!(options.warnings.enabled(Warning::SignConversion));
+        assert!(!options.warnings.enabled(Warning::Conversion));
+        assert!(build(&["-Wall", "-Werror", "-Wno-error", "a.c"]).is_ok());
+        assert!(build(&["-Wno-bogus", "a.c"]).is_ok());
+    }
+
+    #[test]
+    fn common_gcc_options_are_ignored() {
+        for args in &[
+            &["-g", "-std=c11", "-pipe", "a.c"][..],
+            &["-ggdb3", "-DNDEBUG", "-DVERSION=2", "-UDEBUG", "-Iinclude", "a.c"],
+            &["-D", "NDEBUG", "-I", "include", "a.c"],
+        ] {
+            let options = build(args).unwrap();
+            assert_eq!(options.inputs.len(), 1);
+        }
+        assert!(build(&["a.c", "-I"]).is_err());
+    }
+
+    #[test]
+    fn makefile_codegen_options_are_ignored() {
+        let options = build(&[
+            "-fPIC",
+            "-fno-common",
+            "-fvisibility=hidden",
+            "-fno-stack-protector",
+            "a.c",
+        ])
+        .unwrap();
+        assert_eq!(options.inputs.len(), 1);
+        assert!(build(&["-fbogus", "a.c"]).is_err());
+    }
+
+    #[test]
+    fn linker_options_are_passed_through() {
+        let options = build(&["-Wl,--gc-sections,-z,now", "a.c", "-lm"]).unwrap();
+        assert_eq!(options.linker_args, ["-Wl,--gc-sections,-z,now", "-lm"]);
+        assert!(!options.links_in_process());
+    }
+
+    #[test]
+    fn named_optimization_levels_are_accepted() {
+        for level in &["-Os", "-Oz", "-Og", "-Ofast"] {
+            assert!(build(&[level, "a.c"]).is_ok());
+        }
+        assert!(build(&["-Ox", "a.c"]).is_err());
+    }
+
+    #[test]
+    fn only_the_lite_sanitizer_is_known() {
+        assert!(build(&["-fsanitize=undefined-lite", "a.c"]).unwrap().sanitize);
+        assert!(
+            !build(&["-fsanitize=undefined-lite", "-fno-sanitize=undefined-lite", "a.c"])
+                .unwrap()
+                .sanitize
+        );
+        assert!(build(&["-fsanitize=address", "a.c"]).is_err());
+    }
+
+    #[test]
+    fn bad_command_lines_are_rejected() {
+        assert!(build(&[]).is_err());
+        assert!(build(&["-o"]).is_err());
+        assert!(build(&["-Wbogus", "a.c"]).is_err());
+        assert!(build(&["-c", "-o", "x.o", "a.c", "b.c"]).is_err());
+        assert!(build(&["-o", "prog", "a.c", "b.c"]).is_ok());
+    }
+}
@@ -0,0 +1,227 @@
+/// A relocatable object holding one section of code, as the encoder produces it
+#[derive(Debug)]
+pub struct Object {
+    pub text: Vec<u8>,
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>,
+}
+
+#[derive(Debug)]
+pub struct Symbol {
+    pub name: String,
+    /// Offset in the text section, or `None` for a symbol another object defines
+    pub value: Option<u64>,
+    pub global: bool,
+}
+
+/// A 32-bit field of the text section the linker fills in
+#[derive(Debug)]
+pub struct Relocation {
+    pub offset: u64,
+    /// Index into the object's symbols
+    pub symbol: usize,
+    pub kind: RelocationKind,
+    pub addend: i64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum RelocationKind {
+    /// `S + A - P`, for data the code refers to
+    PC32,
+    /// `L + A - P`, going through the procedure linkage table when the function is in a
+    /// shared object
+    PLT32,
+}
+
+impl RelocationKind {
+    fn number(self) -> u64 {
+        match self {
+            RelocationKind::PC32 => 2,
+            RelocationKind::PLT32 => 4,
+        }
+    }
+}
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_SECTION: u8 = 3;
+
+/// Section indices, in the order the sections are written
+const TEXT: u16 = 1;
+const RELA_TEXT: usize = 2;
+const SYMTAB: usize = 3;
+const STRTAB: usize = 4;
+const SHSTRTAB: usize = 6;
+const SECTIONS: usize = 7;
+
+/// A string table: names joined by NUL bytes, starting with the empty name
+struct StringTable {
+    bytes: Vec<u8>,
+}
+
+impl StringTable {
+    fn new() -> StringTable {
+        StringTable { bytes: vec![0] }
+    }
+
+    fn add(&mut self, name: &str) -> u32 {
+        let offset = self.bytes.len() as u32;
+        self.bytes.extend_from_slice(name.as_bytes());
+        self.bytes.push(0);
+        offset
+    }
+}
+
+struct Section {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entry_size: u64,
+}
+
+/// Lays out the object as an x86-64 ELF64 relocatable file: the text, its relocations, the
+/// symbol and string tables, and an empty `.note.GNU-stack` so the stack stays non-executable
+pub fn write(object: &Object) -> Vec<u8> {
+    // ELF wants the local symbols first, after the null symbol and the one for the text section
+    let mut order: Vec<usize> = (0..object.symbols.len()).collect();
+    order.sort_by_key(|i| object.symbols[*i].global);
+    let mut index = vec![0; object.symbols.len()];
+    for (position, i) in order.iter().enumerate() {
+        index[*i] = position + 2;
+    }
+    let first_global = 2 + object.symbols.iter().filter(|symbol| !symbol.global).count();
+
+    let mut strtab = StringTable::new();
+    let mut symtab = Vec::new();
+    symtab.extend(symbol_entry(0, STB_LOCAL, STT_NOTYPE, 0, 0));
+    symtab.extend(symbol_entry(0, STB_LOCAL, STT_SECTION, TEXT, 0));
+    for i in &order {
+        let symbol = &object.symbols[*i];
+        let name = strtab.add(&symbol.name);
+        let binding = if symbol.global { STB_GLOBAL } else { STB_LOCAL };
+        let (section, value) = match symbol.value {
+            Some(value) => (TEXT, value),
+            None => (0, 0),
+        };
+        symtab.extend(symbol_entry(name, binding, STT_NOTYPE, section, value));
+    }
+
+    let mut rela = Vec::new();
+    for relocation in &object.relocations {
+        rela.extend(&relocation.offset.to_le_bytes());
+        let info = (index[relocation.symbol] as u64) << 32 | relocation.kind.number();
+        rela.extend(&info.to_le_bytes());
+        rela.extend(&relocation.addend.to_le_bytes());
+    }
+
+    let mut shstrtab = StringTable::new();
+    let names = [
+        shstrtab.add(".text"),
+        shstrtab.add(".rela.text"),
+        shstrtab.add(".symtab"),
+        shstrtab.add(".strtab"),
+        shstrtab.add(".note.GNU-stack"),
+        shstrtab.add(".shstrtab"),
+    ];
+
+    let mut file = vec![0; 64];
+    let mut sections = Vec::new();
+    let mut add_section = |file: &mut Vec<u8>, name, kind, flags, contents: &[u8], align: u64, entry_size| {
+        while !(file.len() as u64).is_multiple_of(align) {
+            file.push(0);
+        }
+        sections.push(Section {
+            name,
+            kind,
+            flags,
+            offset: file.len() as u64,
+            size: contents.len() as u64,
+            link: 0,
+            info: 0,
+            align,
+            entry_size,
+        });
+        file.extend_from_slice(contents);
+    };
+    add_section(
+        &mut file,
+        names[0],
+        SHT_PROGBITS,
+        SHF_ALLOC | SHF_EXECINSTR,
+        &object.text,
+        16,
+        0,
+    );
+    add_section(&mut file, names[1], SHT_RELA, SHF_INFO_LINK, &rela, 8, 24);
+    add_section(&mut file, names[2], SHT_SYMTAB, 0, &symtab, 8, 24);
+    add_section(&mut file, names[3], SHT_STRTAB, 0, &strtab.bytes, 1, 0);
+    add_section(&mut file, names[4], SHT_PROGBITS, 0, &[], 1, 0);
+    add_section(&mut file, names[5], SHT_STRTAB, 0, &shstrtab.bytes, 1, 0);
+    sections[RELA_TEXT - 1].link = SYMTAB as u32;
+    sections[RELA_TEXT - 1].info = u32::from(TEXT);
+    sections[SYMTAB - 1].link = STRTAB as u32;
+    sections[SYMTAB - 1].info = first_global as u32;
+
+    while !file.len().is_multiple_of(8) {
+        file.push(0);
+    }
+    let section_headers = file.len() as u64;
+    // The null section header comes first
+    file.extend_from_slice(&[0; 64]);
+    for section in &sections {
+        file.extend(&section.name.to_le_bytes());
+        file.extend(&section.kind.to_le_bytes());
+        file.extend(&section.flags.to_le_bytes());
+        file.extend(&0u64.to_le_bytes());
+        file.extend(&section.offset.to_le_bytes());
+        file.extend(&section.size.to_le_bytes());
+        file.extend(&section.link.to_le_bytes());
+        file.extend(&section.info.to_le_bytes());
+        file.extend(&section.align.to_le_bytes());
+        file.extend(&section.entry_size.to_le_bytes());
+    }
+
+    let mut header = Vec::new();
+    // Magic, 64-bit, little endian, version 1, System V ABI
+    header.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    header.extend(&1u16.to_le_bytes()); // ET_REL
+    header.extend(&62u16.to_le_bytes()); // EM_X86_64
+    header.extend(&1u32.to_le_bytes());
+    header.extend(&0u64.to_le_bytes()); // No entry point
+    header.extend(&0u64.to_le_bytes()); // No program headers
+    header.extend(&section_headers.to_le_bytes());
+    header.extend(&0u32.to_le_bytes());
+    header.extend(&64u16.to_le_bytes());
+    header.extend(&0u16.to_le_bytes());
+    header.extend(&0u16.to_le_bytes());
+    header.extend(&64u16.to_le_bytes());
+    header.extend(&(SECTIONS as u16).to_le_bytes());
+    header.extend(&(SHSTRTAB as u16).to_le_bytes());
+    file[..64].copy_from_slice(&header);
+    file
+}
+
+fn symbol_entry(name: u32, binding: u8, kind: u8, section: u16, value: u64) -> Vec<u8> {
+    let mut entry = Vec::new();
+    entry.extend(&name.to_le_bytes());
+    entry.push(binding << 4 | kind);
+    entry.push(0);
+    entry.extend(&section.to_le_bytes());
+    entry.extend(&value.to_le_bytes());
+    entry.extend(&0u64.to_le_bytes());
+    entry
+}
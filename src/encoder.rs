@@ -0,0 +1,511 @@
+use elf::{Object, Relocation, RelocationKind, Symbol};
+use generator::{Directive, DoubleArgInst, Line, NoArgInst, Param, Register, SingleArgInst, Width};
+use std::collections::HashMap;
+
+impl Register {
+    /// Number of the register in ModRM, SIB and opcode fields; the fourth bit goes in a REX prefix
+    fn number(self) -> u8 {
+        match self {
+            Register::RAX => 0,
+            Register::RCX => 1,
+            Register::RDX => 2,
+            Register::RBX => 3,
+            Register::RSP => 4,
+            Register::RBP => 5,
+            Register::RSI => 6,
+            Register::RDI => 7,
+            Register::R8 => 8,
+            Register::R9 => 9,
+            Register::R10 => 10,
+            Register::R11 => 11,
+            Register::R12 => 12,
+            Register::R13 => 13,
+            Register::R14 => 14,
+            Register::R15 => 15,
+        }
+    }
+}
+
+/// The `/digit` opcode extension of the immediate forms of the arithmetic instructions
+fn alu_extension(inst: &DoubleArgInst) -> u8 {
+    match inst {
+        DoubleArgInst::Add => 0,
+        DoubleArgInst::Sub => 5,
+        DoubleArgInst::Xor => 6,
+        DoubleArgInst::Cmp => 7,
+        _ => unreachable!(),
+    }
+}
+
+/// The `/digit` opcode extension of the shifts
+fn shift_extension(inst: &DoubleArgInst) -> u8 {
+    match inst {
+        DoubleArgInst::Shl => 4,
+        DoubleArgInst::Shr => 5,
+        DoubleArgInst::Sar => 7,
+        _ => unreachable!(),
+    }
+}
+
+fn fits_i8(int: i64) -> bool {
+    int as i8 as i64 == int
+}
+
+fn fits_i32(int: i64) -> bool {
+    int as i32 as i64 == int
+}
+
+/// Encodes x86-64 assembly into machine code for a relocatable object. Every `.globl` label
+/// becomes a global symbol, other labels outside `.L` local ones become local symbols, and
+/// jumps to labels that are not defined are left to the linker as relocations.
+pub fn assemble(lines: &[Line]) -> Object {
+    let mut encoder = Encoder {
+        text: Vec::new(),
+        labels: HashMap::new(),
+        fixups: Vec::new(),
+    };
+    let mut globals = Vec::new();
+    for line in lines {
+        match line {
+            Line::Directive(Directive::Globl, name) => globals.push(name.clone()),
+            Line::Directive(Directive::Ascii, string) => encoder.emit(&unquoted(string)),
+            Line::Label(label) => {
+                encoder.labels.insert(label.clone(), encoder.text.len());
+            }
+            Line::NoArgInst(inst) => encoder.no_arg(inst),
+            Line::SingleArgInst(inst, param) => encoder.single_arg(inst, param),
+            Line::DoubleArgInst(inst, src, dst) => encoder.double_arg(inst, src, dst),
+        }
+    }
+
+    // Every branch takes a 32-bit displacement, so offsets are known once the text is encoded
+    let mut symbols: Vec<Symbol> = Vec::new();
+    let mut labels: Vec<(&String, &usize)> = encoder.labels.iter().collect();
+    labels.sort_by_key(|(label, offset)| (**offset, *label));
+    for (label, offset) in labels {
+        if !label.starts_with(".L") {
+            symbols.push(Symbol {
+                name: label.clone(),
+                value: Some(*offset as u64),
+                global: globals.contains(label),
+            });
+        }
+    }
+
+    let mut relocations = Vec::new();
+    for (offset, target, kind) in encoder.fixups {
+        match encoder.labels.get(&target) {
+            Some(address) => {
+                let displacement = *address as i64 - (offset as i64 + 4);
+                encoder.text[offset..offset + 4].copy_from_slice(&(displacement as i32).to_le_bytes());
+            }
+            None => {
+                let symbol = match symbols.iter().position(|symbol| symbol.name == target) {
+                    Some(symbol) => symbol,
+                    None => {
+                        symbols.push(Symbol {
+                            name: target.clone(),
+                            value: None,
+                            global: true,
+                        });
+                        symbols.len() - 1
+                    }
+                };
+                relocations.push(Relocation {
+                    offset: offset as u64,
+                    symbol,
+                    kind,
+                    // The displacement is relative to the end of the instruction, 4 bytes on
+                    addend: -4,
+                });
+            }
+        }
+    }
+
+    Object {
+        text: encoder.text,
+        symbols,
+        relocations,
+    }
+}
+
+struct Encoder {
+    text: Vec<u8>,
+    labels: HashMap<String, usize>,
+    /// 32-bit displacements still to be filled in: their offset, target label and the
+    /// relocation asked of the linker if the label is not in this object
+    fixups: Vec<(usize, String, RelocationKind)>,
+}
+
+impl Encoder {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.text.extend_from_slice(bytes);
+    }
+
+    /// Emits the REX prefix, opcode and ModRM byte (plus SIB and displacement) of an instruction
+    /// with register or opcode extension `reg` and register or memory operand `rm`. A
+    /// rip-relative displacement is relative to its own end, so no immediate may follow it.
+    fn modrm(&mut self, opcode: &[u8], reg: u8, rm: &Param, width: Width) {
+        let mut rex = if width == Width::Quad { 0x48 } else { 0 };
+        if reg >= 8 {
+            rex |= 0x44;
+        }
+        let (modrm, tail) = match rm {
+            Param::Register(base, rm_width) => {
+                let number = base.number();
+                if number >= 8 {
+                    rex |= 0x41;
+                }
+                // spl, bpl, sil and dil only exist with a REX prefix, without one they are ah-bh
+                if *rm_width == Width::Byte && (4..8).contains(&number) {
+                    rex |= 0x40;
+                }
+                (0xc0 | (reg & 7) << 3 | number & 7, Vec::new())
+            }
+            Param::Memory(base, offset, _) => {
+                if base.number() >= 8 {
+                    rex |= 0x41;
+                }
+                self.address(reg, *base, None, *offset)
+            }
+            Param::Indexed(base, index, scale, offset) => {
+                if base.number() >= 8 {
+                    rex |= 0x41;
+                }
+                if index.number() >= 8 {
+                    rex |= 0x42;
+                }
+                self.address(reg, *base, Some((*index, *scale)), *offset)
+            }
+            // rbp in the base field without a displacement
+            Param::RipRelative(_) => (0x05 | (reg & 7) << 3, Vec::new()),
+            other => panic!("'{}' is not a register or memory operand", other),
+        };
+        if rex != 0 {
+            self.emit(&[rex]);
+        }
+        self.emit(opcode);
+        self.emit(&[modrm]);
+        self.emit(&tail);
+        if let Param::RipRelative(label) = rm {
+            self.fixups.push((self.text.len(), label.clone(), RelocationKind::PC32));
+            self.emit(&[0; 4]);
+        }
+    }
+
+    /// The ModRM byte addressing `offset(base, index, scale)`, and the SIB byte and displacement
+    /// following it
+    fn address(&self, reg: u8, base: Register, index: Option<(Register, u8)>, offset: i64) -> (u8, Vec<u8>) {
+        let base_number = base.number() & 7;
+        // rbp and r13 in the base field without a displacement mean rip-relative or no base
+        let (mode, displacement) = if offset == 0 && base_number != 5 {
+            (0x00, Vec::new())
+        } else if fits_i8(offset) {
+            (0x40, vec![offset as u8])
+        } else {
+            (0x80, (offset as i32).to_le_bytes().to_vec())
+        };
+        let mut tail = Vec::new();
+        let rm = match index {
+            Some((index, scale)) => {
+                let scale_bits = match scale {
+                    1 => 0,
+                    2 => 1,
+                    4 => 2,
+                    8 => 3,
+                    _ => panic!("invalid scale {}", scale),
+                };
+                tail.push(scale_bits << 6 | (index.number() & 7) << 3 | base_number);
+                4
+            }
+            // rsp and r12 in the base field announce a SIB byte, so they need one without an index
+            None if base_number == 4 => {
+                tail.push(0x24);
+                4
+            }
+            None => base_number,
+        };
+        tail.extend(displacement);
+        (mode | (reg & 7) << 3 | rm, tail)
+    }
+
+    /// Emits a 32-bit displacement to `label`, resolved once every label is known
+    fn branch(&mut self, opcode: &[u8], label: &str) {
+        self.emit(opcode);
+        self.fixups
+            .push((self.text.len(), String::from(label), RelocationKind::PLT32));
+        self.emit(&[0; 4]);
+    }
+
+    fn no_arg(&mut self, inst: &NoArgInst) {
+        match inst {
+            NoArgInst::Ret => self.emit(&[0xc3]),
+            NoArgInst::Cltd => self.emit(&[0x99]),
+            NoArgInst::Cqto => self.emit(&[0x48, 0x99]),
+            NoArgInst::Syscall => self.emit(&[0x0f, 0x05]),
+        }
+    }
+
+    fn single_arg(&mut self, inst: &SingleArgInst, param: &Param) {
+        let width = width(&[param]);
+        match (inst, param) {
+            (SingleArgInst::Push, Param::Register(reg, _)) | (SingleArgInst::Pop, Param::Register(reg, _)) => {
+                if reg.number() >= 8 {
+                    self.emit(&[0x41]);
+                }
+                let base = if *inst == SingleArgInst::Push { 0x50 } else { 0x58 };
+                self.emit(&[base + (reg.number() & 7)]);
+            }
+            (SingleArgInst::Not, _) => self.modrm(&[0xf7], 2, param, width),
+            (SingleArgInst::Neg, _) => self.modrm(&[0xf7], 3, param, width),
+            (SingleArgInst::Div, _) => self.modrm(&[0xf7], 6, param, width),
+            (SingleArgInst::Idiv, _) => self.modrm(&[0xf7], 7, param, width),
+            (SingleArgInst::Sete, _) => self.modrm(&[0x0f, 0x94], 0, param, Width::Byte),
+            (SingleArgInst::Jmp, Param::Label(label)) => self.branch(&[0xe9], label),
+            (SingleArgInst::Jne, Param::Label(label)) => self.branch(&[0x0f, 0x85], label),
+            (SingleArgInst::Je, Param::Label(label)) => self.branch(&[0x0f, 0x84], label),
+            (SingleArgInst::Jo, Param::Label(label)) => self.branch(&[0x0f, 0x80], label),
+            (SingleArgInst::Ja, Param::Label(label)) => self.branch(&[0x0f, 0x87], label),
+            (SingleArgInst::Call, Param::Label(label)) => self.branch(&[0xe8], label),
+            _ => panic!("cannot encode '{:?} {}'", inst, param),
+        }
+    }
+
+    fn double_arg(&mut self, inst: &DoubleArgInst, src: &Param, dst: &Param) {
+        let width = width(&[src, dst]);
+        match (inst, src, dst) {
+            // The count is an immediate or cl, whose width says nothing about the operand size
+            (DoubleArgInst::Shl, ..) | (DoubleArgInst::Sar, ..) | (DoubleArgInst::Shr, ..) => {
+                let extension = shift_extension(inst);
+                let width = self::width(&[dst]);
+                match src {
+                    Param::Const(int) => {
+                        self.modrm(&[0xc1], extension, dst, width);
+                        self.emit(&[*int as u8]);
+                    }
+                    Param::Register(Register::RCX, Width::Byte) => self.modrm(&[0xd3], extension, dst, width),
+                    _ => panic!("cannot encode '{:?} {}, {}'", inst, src, dst),
+                }
+            }
+            (DoubleArgInst::Mov, Param::Const(int), Param::Register(reg, _))
+                if !fits_i32(*int) || width == Width::Long =>
+            {
+                // `mov $imm, %reg` with an immediate of the register's full width
+                let number = reg.number();
+                let mut rex = if width == Width::Quad { 0x48 } else { 0 };
+                if number >= 8 {
+                    rex |= 0x41;
+                }
+                if rex != 0 {
+                    self.emit(&[rex]);
+                }
+                self.emit(&[0xb8 + (number & 7)]);
+                match width {
+                    Width::Quad => self.emit(&int.to_le_bytes()),
+                    _ => self.emit(&(*int as i32).to_le_bytes()),
+                }
+            }
+            (DoubleArgInst::Mov, Param::Const(int), _) => {
+                self.modrm(&[0xc7], 0, dst, width);
+                self.emit(&(*int as i32).to_le_bytes());
+            }
+            (DoubleArgInst::Mov, Param::Register(reg, _), _) => self.modrm(&[0x89], reg.number(), dst, width),
+            (DoubleArgInst::Mov, _, Param::Register(reg, _)) => self.modrm(&[0x8b], reg.number(), src, width),
+            (DoubleArgInst::Movslq, _, Param::Register(reg, _)) => self.modrm(&[0x63], reg.number(), src, Width::Quad),
+            (DoubleArgInst::Lea, _, Param::Register(reg, dst_width)) => {
+                self.modrm(&[0x8d], reg.number(), src, *dst_width)
+            }
+            (DoubleArgInst::Imul, Param::Const(int), Param::Register(reg, _)) => {
+                if fits_i8(*int) {
+                    self.modrm(&[0x6b], reg.number(), dst, width);
+                    self.emit(&[*int as u8]);
+                } else {
+                    self.modrm(&[0x69], reg.number(), dst, width);
+                    self.emit(&(*int as i32).to_le_bytes());
+                }
+            }
+            (DoubleArgInst::Imul, _, Param::Register(reg, _)) => self.modrm(&[0x0f, 0xaf], reg.number(), src, width),
+            (DoubleArgInst::Add, ..)
+            | (DoubleArgInst::Sub, ..)
+            | (DoubleArgInst::Xor, ..)
+            | (DoubleArgInst::Cmp, ..) => {
+                let extension = alu_extension(inst);
+                // The register-to-memory form of each operation is at 8 times its extension
+                let base = extension << 3;
+                match (src, dst) {
+                    (Param::Const(int), _) if fits_i8(*int) => {
+                        self.modrm(&[0x83], extension, dst, width);
+                        self.emit(&[*int as u8]);
+                    }
+                    (Param::Const(int), _) => {
+                        self.modrm(&[0x81], extension, dst, width);
+                        self.emit(&(*int as i32).to_le_bytes());
+                    }
+                    (Param::Register(reg, _), _) => self.modrm(&[base + 1], reg.number(), dst, width),
+                    (_, Param::Register(reg, _)) => self.modrm(&[base + 3], reg.number(), src, width),
+                    _ => panic!("cannot encode '{:?} {}, {}'", inst, src, dst),
+                }
+            }
+            _ => panic!("cannot encode '{:?} {}, {}'", inst, src, dst),
+        }
+    }
+}
+
+/// The bytes of an `.ascii` operand, undoing the escapes the generator writes
+fn unquoted(string: &str) -> Vec<u8> {
+    let mut unescaped = String::new();
+    let mut chars = string[1..string.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        unescaped.push(match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some(escaped) => escaped,
+                None => panic!("'{}' ends in an escape", string),
+            },
+            c => c,
+        });
+    }
+    unescaped.into_bytes()
+}
+
+/// The operand size of an instruction, given by its register or memory operands
+fn width(params: &[&Param]) -> Width {
+    params
+        .iter()
+        .find_map(|param| match param {
+            Param::Register(_, width) | Param::Memory(_, _, width) => Some(*width),
+            _ => None,
+        })
+        .unwrap_or(Width::Quad)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(lines: Vec<Line>) -> Vec<u8> {
+        assemble(&lines).text
+    }
+
+    #[test]
+    fn matches_gnu_as() {
+        let lines = vec![
+            Line::SingleArgInst(SingleArgInst::Push, Param::Register(Register::RBP, Width::Quad)),
+            Line::DoubleArgInst(
+                DoubleArgInst::Mov,
+                Param::Register(Register::RSP, Width::Quad),
+                Param::Register(Register::RBP, Width::Quad),
+            ),
+            Line::DoubleArgInst(
+                DoubleArgInst::Mov,
+                Param::Register(Register::R12, Width::Long),
+                Param::Memory(Register::RBP, -8, Width::Long),
+            ),
+            Line::DoubleArgInst(
+                DoubleArgInst::Lea,
+                Param::Indexed(Register::R13, Register::RAX, 8, 0),
+                Param::Register(Register::RCX, Width::Quad),
+            ),
+            Line::SingleArgInst(SingleArgInst::Sete, Param::Register(Register::RSI, Width::Byte)),
+            Line::DoubleArgInst(
+                DoubleArgInst::Mov,
+                Param::Const(1 << 40),
+                Param::Register(Register::RAX, Width::Quad),
+            ),
+            Line::NoArgInst(NoArgInst::Ret),
+        ];
+        assert_eq!(
+            encode(lines),
+            vec![
+                0x55, // push %rbp
+                0x48, 0x89, 0xe5, // mov %rsp, %rbp
+                0x44, 0x89, 0x65, 0xf8, // mov %r12d, -8(%rbp)
+                0x49, 0x8d, 0x4c, 0xc5, 0x00, // lea 0(%r13,%rax,8), %rcx
+                0x40, 0x0f, 0x94, 0xc6, // sete %sil
+                0x48, 0xb8, 0, 0, 0, 0, 0, 1, 0, 0,    // movabs $1<<40, %rax
+                0xc3, // ret
+            ]
+        );
+    }
+
+    #[test]
+    fn local_jumps_are_resolved() {
+        let object = assemble(&[
+            Line::Label(String::from(".Lmain_bb0")),
+            Line::NoArgInst(NoArgInst::Cltd),
+            Line::SingleArgInst(SingleArgInst::Jmp, Param::Label(String::from(".Lmain_bb0"))),
+        ]);
+        assert_eq!(object.text, vec![0x99, 0xe9, 0xfa, 0xff, 0xff, 0xff]);
+        assert!(object.symbols.is_empty());
+        assert!(object.relocations.is_empty());
+    }
+
+    #[test]
+    fn shifts_match_gnu_as() {
+        let object = assemble(&[
+            Line::Label(String::from(".Lmain_bb0")),
+            Line::DoubleArgInst(
+                DoubleArgInst::Shl,
+                Param::Const(3),
+                Param::Register(Register::RDX, Width::Long),
+            ),
+            Line::DoubleArgInst(
+                DoubleArgInst::Sar,
+                Param::Register(Register::RCX, Width::Byte),
+                Param::Register(Register::RAX, Width::Quad),
+            ),
+            Line::DoubleArgInst(
+                DoubleArgInst::Shr,
+                Param::Register(Register::RCX, Width::Byte),
+                Param::Register(Register::R9, Width::Long),
+            ),
+            Line::SingleArgInst(SingleArgInst::Ja, Param::Label(String::from(".Lmain_bb0"))),
+        ]);
+        assert_eq!(
+            object.text,
+            vec![
+                0xc1, 0xe2, 0x03, // shl $3, %edx
+                0x48, 0xd3, 0xf8, // sar %cl, %rax
+                0x41, 0xd3, 0xe9, // shr %cl, %r9d
+                0x0f, 0x87, 0xf1, 0xff, 0xff, 0xff, // ja .Lmain_bb0
+            ]
+        );
+    }
+
+    #[test]
+    fn undefined_targets_are_relocated() {
+        let object = assemble(&[
+            Line::Directive(Directive::Globl, String::from("main")),
+            Line::Label(String::from("main")),
+            Line::SingleArgInst(SingleArgInst::Jne, Param::Label(String::from("exit"))),
+        ]);
+        assert_eq!(object.text, vec![0x0f, 0x85, 0, 0, 0, 0]);
+        assert_eq!(object.symbols[1].name, "exit");
+        assert_eq!(object.symbols[1].value, None);
+        let relocation = &object.relocations[0];
+        assert_eq!((relocation.offset, relocation.symbol), (2, 1));
+        assert_eq!((relocation.kind, relocation.addend), (RelocationKind::PLT32, -4));
+    }
+
+    #[test]
+    fn strings_are_addressed_relative_to_rip() {
+        let object = assemble(&[
+            Line::SingleArgInst(SingleArgInst::Jo, Param::Label(String::from(".Lmessage"))),
+            Line::DoubleArgInst(
+                DoubleArgInst::Lea,
+                Param::RipRelative(String::from(".Lmessage")),
+                Param::Register(Register::R9, Width::Quad),
+            ),
+            Line::Label(String::from(".Lmessage")),
+            Line::Directive(Directive::Ascii, String::from("\"a\\\\\\\"\\n\"")),
+        ]);
+        assert_eq!(
+            object.text,
+            vec![
+                0x0f, 0x80, 0x07, 0, 0, 0, // jo .Lmessage
+                0x4c, 0x8d, 0x0d, 0, 0, 0, 0, // lea .Lmessage(%rip), %r9
+                b'a', b'\\', b'"', b'\n',
+            ]
+        );
+    }
+}
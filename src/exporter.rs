@@ -1,14 +1,31 @@
 use std::fs::File;
-use std::io::Write;
+use std::io::{self, Write};
 use std::path::Path;
 
-pub fn to_file(name: &Path, assembly: &[String]) {
-    if let Ok(mut file) = File::create(name) {
-        for line in assembly {
-            let _res = writeln!(file, "{}", line);
-        }
-        let _res = file.flush();
+/// Opens the output, `-` being standard output
+fn create(name: &Path) -> io::Result<Box<dyn Write>> {
+    if name == Path::new("-") {
+        Ok(Box::new(io::stdout()))
     } else {
-        panic!("unable to create assembly file");
+        Ok(Box::new(File::create(name)?))
     }
 }
+
+/// An I/O error, prefixed with the file it happened on
+fn failure(name: &Path) -> impl Fn(io::Error) -> String + '_ {
+    move |err| format!("{}: {}", name.display(), err)
+}
+
+pub fn to_file(name: &Path, lines: &[String]) -> Result<(), String> {
+    let mut file = create(name).map_err(failure(name))?;
+    for line in lines {
+        writeln!(file, "{}", line).map_err(failure(name))?;
+    }
+    file.flush().map_err(failure(name))
+}
+
+pub fn to_binary_file(name: &Path, contents: &[u8]) -> Result<(), String> {
+    let mut file = create(name).map_err(failure(name))?;
+    file.write_all(contents).map_err(failure(name))?;
+    file.flush().map_err(failure(name))
+}
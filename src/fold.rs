@@ -0,0 +1,291 @@
+use diagnostic::{Diagnostic, Warning};
+use lexer::Operator;
+use parser::{Expression, ExpressionKind, Program};
+use types::{Constant, DataModel, Type};
+use visit::{self, VisitorMut};
+
+/// Replaces every constant subexpression of the program with its value.
+/// Operations whose result is undefined, like division by zero, are left for run time.
+pub fn fold(mut prog: Program, diagnostics: &mut Vec<Diagnostic>) -> Program {
+    let model = prog.model;
+    Folder { diagnostics, model }.visit_program_mut(&mut prog);
+    prog
+}
+
+/// Evaluates an integer constant expression, as needed for array sizes, case labels and `#if`
+#[allow(dead_code)] // rcc does not parse any of those yet
+pub fn evaluate(exp: &Expression, model: DataModel, diagnostics: &mut Vec<Diagnostic>) -> Option<Constant> {
+    match &exp.kind {
+        ExpressionKind::Const(int) => Some(*int),
+        ExpressionKind::Parenthesized(exp) => evaluate(exp, model, diagnostics),
+        ExpressionKind::Binary { left, operator, right } => {
+            let l = evaluate(left, model, diagnostics)?;
+            let r = evaluate(right, model, diagnostics)?;
+            binary(*operator, l, r, model, diagnostics)
+        }
+        ExpressionKind::Unary { operator, operand } => {
+            let operand = evaluate(operand, model, diagnostics)?;
+            unary(*operator, operand, model, diagnostics)
+        }
+        ExpressionKind::Call { .. } | ExpressionKind::Syscall { .. } => None,
+    }
+}
+
+/// Applies a unary operator with C semantics
+pub fn unary(
+    operator: Operator,
+    operand: Constant,
+    model: DataModel,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Option<Constant> {
+    let ctype = operand.ctype.promote();
+    let operand = operand.cast(ctype, model);
+    match operator {
+        Operator::Negate => Some(checked(-operand.mathematical(), ctype, model, diagnostics)),
+        Operator::Not => Some(Constant::wrap(!operand.mathematical(), ctype, model)),
+        Operator::Bang => Some(Constant::wrap(operand.is_zero() as i128, Type::Int, model)),
+        _ => panic!("Unexpected operator '{:?}'", operator),
+    }
+}
+
+/// Applies a binary operator with C semantics, after the usual arithmetic conversions
+pub fn binary(
+    operator: Operator,
+    left: Constant,
+    right: Constant,
+    model: DataModel,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Option<Constant> {
+    if operator.is_shift() {
+        return shift(operator, left, right, model, diagnostics);
+    }
+    let ctype = left.ctype.common(right.ctype, model);
+    let (l, r) = (
+        left.cast(ctype, model).mathematical(),
+        right.cast(ctype, model).mathematical(),
+    );
+    match operator {
+        Operator::Plus => Some(checked(l + r, ctype, model, diagnostics)),
+        Operator::Negate => Some(checked(l - r, ctype, model, diagnostics)),
+        // Only unsigned 64-bit products overflow i128, and they wrap anyway, so the low bits suffice
+        Operator::Star => Some(checked(l.wrapping_mul(r), ctype, model, diagnostics)),
+        Operator::Divide => {
+            if r == 0 {
+                diagnostics.push(Diagnostic::warning(
+                    Warning::DivByZero,
+                    String::from("division by zero"),
+                ));
+                None
+            } else {
+                // Rust's integer division truncates toward zero, as C does
+                Some(checked(l / r, ctype, model, diagnostics))
+            }
+        }
+        _ => panic!("Unexpected operator '{:?}'", operator),
+    }
+}
+
+/// Shifts in the promoted type of the left operand. A count that is negative or not less than
+/// its width is undefined, so the shift is left for run time.
+fn shift(
+    operator: Operator,
+    left: Constant,
+    right: Constant,
+    model: DataModel,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Option<Constant> {
+    let ctype = left.ctype.promote();
+    let value = left.cast(ctype, model).mathematical();
+    let count = right.cast(right.ctype.promote(), model).mathematical();
+    let direction = if operator == Operator::ShiftLeft {
+        "left"
+    } else {
+        "right"
+    };
+    if count < 0 {
+        diagnostics.push(Diagnostic::warning(
+            Warning::ShiftCountNegative,
+            format!("{} shift count is negative", direction),
+        ));
+        return None;
+    }
+    if count >= i128::from(ctype.bits(model)) {
+        diagnostics.push(Diagnostic::warning(
+            Warning::ShiftCountOverflow,
+            format!("{} shift count >= width of type", direction),
+        ));
+        return None;
+    }
+    // Bits shifted out of the type are dropped as it wraps; negative values shift arithmetically
+    let shifted = match operator {
+        Operator::ShiftLeft => value << count,
+        _ => value >> count,
+    };
+    Some(Constant::wrap(shifted, ctype, model))
+}
+
+/// Wraps an exact result into its type, warning when a signed type overflows
+fn checked(value: i128, ctype: Type, model: DataModel, diagnostics: &mut Vec<Diagnostic>) -> Constant {
+    let result = Constant::wrap(value, ctype, model);
+    if ctype.is_signed() && (value < ctype.min_value(model) || value > ctype.max_value(model) as i128) {
+        diagnostics.push(Diagnostic::warning(
+            Warning::Overflow,
+            format!(
+                "integer overflow in expression of type '{}' results in '{}'",
+                ctype,
+                result.mathematical()
+            ),
+        ));
+    }
+    result
+}
+
+/// Folds bottom-up, so that each node sees its operands already folded
+struct Folder<'a> {
+    diagnostics: &'a mut Vec<Diagnostic>,
+    model: DataModel,
+}
+
+impl<'a> VisitorMut for Folder<'a> {
+    fn visit_expression_mut(&mut self, exp: &mut Expression) {
+        visit::walk_expression_mut(self, exp);
+        let raised = self.diagnostics.len();
+        let folded = match &exp.kind {
+            ExpressionKind::Binary { left, operator, right } => match (&left.kind, &right.kind) {
+                (ExpressionKind::Const(l), ExpressionKind::Const(r)) => {
+                    binary(*operator, *l, *r, self.model, self.diagnostics)
+                }
+                _ => None,
+            },
+            ExpressionKind::Unary { operator, operand } => match operand.kind {
+                ExpressionKind::Const(int) => unary(*operator, int, self.model, self.diagnostics),
+                _ => None,
+            },
+            ExpressionKind::Parenthesized(inner) => match inner.kind {
+                ExpressionKind::Const(int) => Some(int),
+                _ => None,
+            },
+            ExpressionKind::Const(_) | ExpressionKind::Call { .. } | ExpressionKind::Syscall { .. } => None,
+        };
+        // The operations report at their operator, which the folded constant keeps
+        for diagnostic in &mut self.diagnostics[raised..] {
+            diagnostic.span = Some(exp.span);
+        }
+        if let Some(int) = folded {
+            exp.kind = ExpressionKind::Const(int);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lexer;
+    use parser::{self, Statement};
+
+    fn fold_return(exp: &str) -> (Expression, Vec<String>) {
+        let source = format!("int main() {{ return {}; }}", exp);
+        let mut diagnostics = Vec::new();
+        let prog = fold(
+            parser::parse(lexer::lex(&source).unwrap(), DataModel::LP64).unwrap(),
+            &mut diagnostics,
+        );
+        let messages = diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.message.clone())
+            .collect();
+        match prog.function.statement {
+            Statement::Return(exp) => (exp, messages),
+        }
+    }
+
+    fn folded(exp: &str) -> String {
+        match fold_return(exp) {
+            (
+                Expression {
+                    kind: ExpressionKind::Const(int),
+                    ..
+                },
+                _,
+            ) => int.to_string(),
+            (exp, _) => panic!("'{:?}' was not folded", exp),
+        }
+    }
+
+    #[test]
+    fn operations_follow_c_semantics() {
+        assert_eq!(folded("1 + 2 * 3"), "7");
+        assert_eq!(folded("-7 / 2"), "-3");
+        assert_eq!(folded("~0"), "-1");
+        assert_eq!(folded("!5"), "0");
+        assert_eq!(folded("0u - 1"), "4294967295u");
+        assert_eq!(folded("1 + 2l"), "3l");
+    }
+
+    #[test]
+    fn unsigned_products_wrap_without_overflowing_the_compiler() {
+        assert_eq!(folded("18446744073709551615ull * 18446744073709551615ull"), "1ull");
+        assert_eq!(folded("0xFFFFFFFFFFFFFFFF * 0xFFFFFFFFFFFFFFFF"), "1ul");
+        assert_eq!(
+            fold_return("0xFFFFFFFFFFFFFFFF * 0xFFFFFFFFFFFFFFFF").1,
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn signed_overflow_wraps_with_a_warning() {
+        let (exp, messages) = fold_return("2147483647 * 2");
+        assert_eq!(
+            exp.kind,
+            ExpressionKind::Const(Constant::wrap(-2, Type::Int, DataModel::LP64))
+        );
+        assert_eq!(
+            messages,
+            ["integer overflow in expression of type 'int' results in '-2'"]
+        );
+        assert_eq!(
+            fold_return("-9223372036854775807l * -9223372036854775807l").1,
+            ["integer overflow in expression of type 'long' results in '1'"]
+        );
+    }
+
+    #[test]
+    fn division_by_zero_is_left_for_run_time() {
+        let (exp, messages) = fold_return("1 / (2 - 2)");
+        assert!(matches!(exp.kind, ExpressionKind::Binary { .. }));
+        assert_eq!(messages, ["division by zero"]);
+    }
+
+    #[test]
+    fn shifts_take_the_type_of_their_left_operand() {
+        assert_eq!(folded("1 << 4"), "16");
+        assert_eq!(folded("1 << 31"), "-2147483648");
+        assert_eq!(folded("-16 >> 2"), "-4");
+        assert_eq!(folded("0xFFFFFFFFu >> 28ul"), "15u");
+        assert_eq!(folded("3ul << 62"), "13835058055282163712ul");
+    }
+
+    #[test]
+    fn shift_counts_out_of_range_are_left_for_run_time() {
+        let (exp, messages) = fold_return("1 << 32");
+        assert!(matches!(exp.kind, ExpressionKind::Binary { .. }));
+        assert_eq!(messages, ["left shift count >= width of type"]);
+        assert_eq!(fold_return("1l >> -1").1, ["right shift count is negative"]);
+        assert!(fold_return("1l << 32").1.is_empty());
+    }
+
+    #[test]
+    fn warnings_point_at_the_operator() {
+        let source = "int main() {\n    return 1 + 2147483647 * 2;\n}";
+        let mut diagnostics = Vec::new();
+        fold(
+            parser::parse(lexer::lex(source).unwrap(), DataModel::LP64).unwrap(),
+            &mut diagnostics,
+        );
+        let spans: Vec<_> = diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.span.unwrap().line_column(source))
+            .collect();
+        assert_eq!(spans, [(2, 27)]);
+    }
+}
@@ -1,9 +1,13 @@
-use lexer::Operator;
-use parser::{Expression, Factor, Function, Program, Statement, Term};
+use backend::{self, block_label, Backend};
+use ir::{BinaryOp, BlockId, Function, Instruction, Terminator, UnaryOp, Value};
+use lexer::Span;
+use peephole;
+use regalloc::{Allocation, Location};
 use std::fmt;
+use types::{Constant, DataModel, Type};
 
-#[derive(Debug)]
-enum Line {
+#[derive(Debug, Clone, PartialEq)]
+pub enum Line {
     Directive(Directive, String),
     Label(String),
     NoArgInst(NoArgInst),
@@ -11,9 +15,21 @@ enum Line {
     DoubleArgInst(DoubleArgInst, Param, Param),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Syntax {
     ATT,
+    Intel,
+}
+
+impl Syntax {
+    /// Directive telling the assembler which syntax follows, AT&T being its default
+    pub fn header(&self) -> Option<&'static str> {
+        match self {
+            Syntax::ATT => None,
+            Syntax::Intel => Some(".intel_syntax noprefix"),
+        }
+    }
 }
 
 impl Line {
@@ -23,18 +39,47 @@ impl Line {
                 Line::Directive(dir, other) => format!(".{} {}", dir.to_asm(), other),
                 Line::Label(label) => format!("{}:", label),
                 Line::NoArgInst(inst) => format!("\t{}", inst.to_asm()),
-                Line::SingleArgInst(inst, arg) => format!("\t{}\t{}", inst.to_asm(), arg.to_string()),
+                Line::SingleArgInst(inst, arg) => format!("\t{}{}\t{}", inst.to_asm(), suffix(&[arg]), arg),
                 Line::DoubleArgInst(inst, src, dst) => {
-                    format!("\t{}\t{}, {}", inst.to_asm(), src.to_string(), dst.to_string())
+                    format!("\t{}{}\t{}, {}", inst.to_asm(), suffix(&[src, dst]), src, dst)
+                }
+            },
+            Syntax::Intel => match self {
+                Line::Directive(dir, other) => format!(".{} {}", dir.to_asm(), other),
+                Line::Label(label) => format!("{}:", label),
+                Line::NoArgInst(inst) => format!("\t{}", inst.to_intel()),
+                Line::SingleArgInst(inst, arg) => format!("\t{}\t{}", inst.to_asm(), arg.to_intel()),
+                Line::DoubleArgInst(DoubleArgInst::Lea, address, dst) => {
+                    format!("\tlea\t{}, {}", dst.to_intel(), address.address_to_intel())
+                }
+                Line::DoubleArgInst(inst, src, dst) => {
+                    format!("\t{}\t{}, {}", inst.to_intel(), dst.to_intel(), src.to_intel())
                 }
             },
         }
     }
 }
 
+/// AT&T size suffix, only needed when no register operand gives away the operand size
+fn suffix(params: &[&Param]) -> &'static str {
+    if params.iter().any(|param| matches!(param, Param::Register(..))) {
+        return "";
+    }
+    match params.iter().find_map(|param| match param {
+        Param::Memory(_, _, width) => Some(width),
+        _ => None,
+    }) {
+        Some(Width::Byte) => "b",
+        Some(Width::Long) => "l",
+        Some(Width::Quad) => "q",
+        None => "",
+    }
+}
+
 macro_rules! asm_from_name {
     ($enumname: ident { $($enumval: ident),*}) => {
-        #[derive(Debug)]
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        #[allow(clippy::upper_case_acronyms)]
         pub enum $enumname {
             $(
                 $enumval,
@@ -52,217 +97,838 @@ macro_rules! asm_from_name {
     };
 }
 
-asm_from_name!(Directive { Globl });
-asm_from_name!(NoArgInst { Ret });
+asm_from_name!(Directive { Globl, Ascii });
+asm_from_name!(NoArgInst {
+    Ret,
+    Cltd,
+    Cqto,
+    Syscall
+});
+
+impl NoArgInst {
+    fn to_intel(&self) -> String {
+        match self {
+            NoArgInst::Cltd => String::from("cdq"),
+            NoArgInst::Cqto => String::from("cqo"),
+            _ => self.to_asm(),
+        }
+    }
+}
+
 asm_from_name!(SingleArgInst {
     Sete,
     Not,
     Neg,
     Push,
     Idiv,
-    Pop
+    Div,
+    Pop,
+    Jmp,
+    Jne,
+    Je,
+    Jo,
+    Ja,
+    Call
 });
 asm_from_name!(DoubleArgInst {
     Mov,
+    Movslq,
+    Lea,
     Cmp,
     Add,
     Sub,
-    Imul
+    Imul,
+    Xor,
+    Shl,
+    Sar,
+    Shr
 });
 
-asm_from_name!(Register { RAX, RBX, RCX, RDX, AL });
+impl DoubleArgInst {
+    fn to_intel(&self) -> String {
+        match self {
+            DoubleArgInst::Movslq => String::from("movsxd"),
+            _ => self.to_asm(),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Register {
+    RAX,
+    RBX,
+    RCX,
+    RDX,
+    RSI,
+    RDI,
+    RSP,
+    RBP,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+/// Every register but the stack and frame pointers, caller-saved ones first
+const ALLOCATABLE: [Register; 14] = [
+    Register::RAX,
+    Register::RCX,
+    Register::RDX,
+    Register::RSI,
+    Register::RDI,
+    Register::R8,
+    Register::R9,
+    Register::R10,
+    Register::R11,
+    Register::RBX,
+    Register::R12,
+    Register::R13,
+    Register::R14,
+    Register::R15,
+];
+
+/// Registers a function must preserve for its caller (System V AMD64 ABI)
+const CALLEE_SAVED: [Register; 5] = [
+    Register::RBX,
+    Register::R12,
+    Register::R13,
+    Register::R14,
+    Register::R15,
+];
+
+/// Registers carrying system call arguments, in order (Linux x86-64 syscall convention)
+const SYSCALL_ARGS: [Register; 6] = [
+    Register::RDI,
+    Register::RSI,
+    Register::RDX,
+    Register::R10,
+    Register::R8,
+    Register::R9,
+];
+
+impl Register {
+    fn name(self, width: Width) -> String {
+        let (byte, long, quad) = match self {
+            Register::RAX => ("al", "eax", "rax"),
+            Register::RBX => ("bl", "ebx", "rbx"),
+            Register::RCX => ("cl", "ecx", "rcx"),
+            Register::RDX => ("dl", "edx", "rdx"),
+            Register::RSI => ("sil", "esi", "rsi"),
+            Register::RDI => ("dil", "edi", "rdi"),
+            Register::RSP => ("spl", "esp", "rsp"),
+            Register::RBP => ("bpl", "ebp", "rbp"),
+            _ => {
+                let quad = format!("{:?}", self).to_lowercase();
+                return match width {
+                    Width::Byte => format!("{}b", quad),
+                    Width::Long => format!("{}d", quad),
+                    Width::Quad => quad,
+                };
+            }
+        };
+        String::from(match width {
+            Width::Byte => byte,
+            Width::Long => long,
+            Width::Quad => quad,
+        })
+    }
+}
+
+/// Operand sizes
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Width {
+    Byte,
+    Long,
+    Quad,
+}
 
-#[derive(Debug)]
+impl Width {
+    fn of(ctype: Type) -> Width {
+        match ctype.size(DataModel::LP64) {
+            4 => Width::Long,
+            _ => Width::Quad,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Param {
-    Const(u32),
+    Const(i64),
     // Str(String),
-    Register(Register),
+    Register(Register, Width),
+    /// `offset(base)`
+    Memory(Register, i64, Width),
+    /// `offset(base, index, scale)`, only as the address `lea` computes
+    Indexed(Register, Register, u8, i64),
+    /// `label(%rip)`, only as the address `lea` computes
+    RipRelative(String),
+    Label(String),
 }
 
-impl ToString for Param {
-    fn to_string(&self) -> String {
+impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Param::Const(int) => format!("${}", int),
-            // Param::Str(string) => string.clone(),
-            Param::Register(reg) => format!("%{}", reg.to_asm()),
+            Param::Const(int) => write!(f, "${}", int),
+            // Param::Str(string) => write!(f, "{}", string),
+            Param::Register(reg, width) => write!(f, "%{}", reg.name(*width)),
+            Param::Memory(base, offset, _) => write!(f, "{}(%{})", offset, base.name(Width::Quad)),
+            Param::Indexed(base, index, scale, offset) => {
+                if *offset != 0 {
+                    write!(f, "{}", offset)?;
+                }
+                write!(
+                    f,
+                    "(%{},%{},{})",
+                    base.name(Width::Quad),
+                    index.name(Width::Quad),
+                    scale
+                )
+            }
+            Param::RipRelative(label) => write!(f, "{}(%rip)", label),
+            Param::Label(label) => write!(f, "{}", label),
         }
     }
 }
 
-impl fmt::Display for Register {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
+impl Param {
+    fn to_intel(&self) -> String {
+        match self {
+            Param::Const(int) => format!("{}", int),
+            Param::Register(reg, width) => reg.name(*width),
+            Param::Memory(_, _, width) => {
+                let size = match width {
+                    Width::Byte => "BYTE",
+                    Width::Long => "DWORD",
+                    Width::Quad => "QWORD",
+                };
+                format!("{} PTR {}", size, self.address_to_intel())
+            }
+            Param::Indexed(..) => self.address_to_intel(),
+            Param::RipRelative(label) => format!("[rip+{}]", label),
+            Param::Label(label) => label.clone(),
+        }
+    }
+
+    /// `[base+index*scale+offset]`, the part of an Intel memory operand without its size
+    fn address_to_intel(&self) -> String {
+        let (mut address, offset) = match self {
+            Param::Memory(base, offset, _) => (base.name(Width::Quad), *offset),
+            Param::Indexed(base, index, scale, offset) => (
+                format!("{}+{}*{}", base.name(Width::Quad), index.name(Width::Quad), scale),
+                *offset,
+            ),
+            other => return other.to_intel(),
+        };
+        if offset != 0 {
+            address += &format!("{:+}", offset);
+        }
+        format!("[{}]", address)
+    }
+
+    fn is_memory(&self) -> bool {
+        matches!(self, Param::Memory(..))
+    }
+
+    fn register(&self) -> Option<Register> {
+        match self {
+            Param::Register(reg, _) => Some(*reg),
+            _ => None,
+        }
     }
 }
 
-pub fn generate(prog: &Program, syntax: &Syntax) -> Vec<String> {
-    generate_prog(&prog)
-        .iter()
-        .map(|inst| inst.apply_syntax(&syntax))
-        .collect()
+/// The file a program was compiled from, which runtime checks report their locations in
+#[derive(Debug, Copy, Clone)]
+pub struct SourceFile<'a> {
+    pub path: &'a str,
+    pub text: &'a str,
 }
 
-pub fn debug(prog: &Program) {
-    println!("ASSEMBLY OUTPUT:");
-    for asm in generate_prog(&prog) {
-        println!("{:?}", asm);
+/// The x86-64 backend, printing in either assembler syntax
+#[allow(non_camel_case_types)]
+pub struct X86_64<'a> {
+    pub syntax: Syntax,
+    pub peephole: bool,
+    /// Whether signed overflow and division by zero are checked at run time
+    pub sanitize: bool,
+    /// Where the checked operations are, if the program was compiled from source
+    pub source: Option<SourceFile<'a>>,
+}
+
+impl<'a> Backend for X86_64<'a> {
+    type Register = Register;
+    type Line = Line;
+
+    fn registers(&self) -> &'static [Register] {
+        &ALLOCATABLE
+    }
+
+    fn clobbers(&self, inst: &Instruction) -> Vec<Register> {
+        match inst {
+            Instruction::Binary { op: BinaryOp::Div, .. } => vec![Register::RAX, Register::RDX],
+            // A count only known at run time goes in cl
+            Instruction::Binary {
+                op: BinaryOp::Shl | BinaryOp::Shr,
+                right: Value::Register(_),
+                ..
+            } => vec![Register::RCX],
+            // The kernel returns in rax and destroys rcx and r11
+            Instruction::Syscall { args, .. } => {
+                let mut clobbered = vec![Register::RAX, Register::RCX, Register::R11];
+                clobbered.extend(&SYSCALL_ARGS[..args.len()]);
+                clobbered
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Gives the operands x86 cannot encode, an immediate divisor or a 64-bit immediate
+    /// arithmetic operand, registers of their own
+    fn legalize(&self, func: &Function) -> Function {
+        let mut func = func.clone();
+        for i in 0..func.blocks.len() {
+            let instructions = std::mem::take(&mut func.blocks[i].instructions);
+            for mut inst in instructions {
+                let divisor = matches!(inst, Instruction::Binary { op: BinaryOp::Div, .. });
+                if let Instruction::Binary { left, right, .. } = &mut inst {
+                    for (operand, is_divisor) in [(left, false), (right, divisor)] {
+                        if let Value::Const(int) = *operand {
+                            if is_divisor || !fits_imm32(int) {
+                                let dst = func.new_register(int.ctype);
+                                func.blocks[i]
+                                    .instructions
+                                    .push(Instruction::Copy { dst, src: *operand });
+                                *operand = Value::Register(dst);
+                            }
+                        }
+                    }
+                }
+                func.blocks[i].instructions.push(inst);
+            }
+        }
+        func
+    }
+
+    fn select(&self, func: &Function, allocation: &Allocation<Register>) -> Vec<Line> {
+        select_fn(func, allocation, self.sanitize, self.source)
     }
-    println!();
+
+    fn start(&self) -> Vec<Line> {
+        start()
+    }
+
+    fn finish(&self, lines: Vec<Line>) -> Vec<Line> {
+        let mut lines = if self.peephole {
+            peephole::optimize(lines)
+        } else {
+            lines
+        };
+        if self.sanitize {
+            lines.extend(ubsan_abort());
+        }
+        lines
+    }
+
+    fn render(&self, lines: &[Line]) -> Vec<String> {
+        self.syntax
+            .header()
+            .map(String::from)
+            .into_iter()
+            .chain(backend::prologue('@'))
+            .chain(lines.iter().map(|inst| inst.apply_syntax(&self.syntax)))
+            .collect()
+    }
+}
+
+/// `_start` for executables without a C library: calls `main` and hands its result to the
+/// `exit` system call
+pub fn start() -> Vec<Line> {
+    let eax = reg(Register::RAX, Width::Long);
+    let ebp = reg(Register::RBP, Width::Long);
+    vec![
+        Line::Directive(Directive::Globl, String::from("_start")),
+        Line::Label(String::from("_start")),
+        // A zero frame pointer marks the outermost frame
+        Line::DoubleArgInst(DoubleArgInst::Xor, ebp.clone(), ebp),
+        Line::SingleArgInst(SingleArgInst::Call, Param::Label(String::from("main"))),
+        Line::DoubleArgInst(DoubleArgInst::Mov, eax.clone(), reg(Register::RDI, Width::Long)),
+        Line::DoubleArgInst(DoubleArgInst::Mov, Param::Const(60), eax),
+        Line::NoArgInst(NoArgInst::Syscall),
+    ]
 }
 
-fn generate_prog(prog: &Program) -> Vec<Line> {
-    generate_fn(&prog.function)
+/// The handler failed runtime checks call, with their message in rsi and its length in rdx.
+/// It writes the message to standard error and raises SIGABRT as `abort` would, using system
+/// calls alone so that programs linked without the C library can be checked too. Each
+/// translation unit carries its own copy as a local symbol.
+fn ubsan_abort() -> Vec<Line> {
+    let eax = reg(Register::RAX, Width::Long);
+    let edi = reg(Register::RDI, Width::Long);
+    let mov = |int, param: &Param| Line::DoubleArgInst(DoubleArgInst::Mov, Param::Const(int), param.clone());
+    vec![
+        Line::Label(String::from(UBSAN_ABORT)),
+        // write(2, message, length)
+        mov(2, &edi),
+        mov(1, &eax),
+        Line::NoArgInst(NoArgInst::Syscall),
+        // kill(getpid(), SIGABRT)
+        mov(39, &eax),
+        Line::NoArgInst(NoArgInst::Syscall),
+        Line::DoubleArgInst(DoubleArgInst::Mov, eax.clone(), edi.clone()),
+        mov(6, &reg(Register::RSI, Width::Long)),
+        mov(62, &eax),
+        Line::NoArgInst(NoArgInst::Syscall),
+        // exit_group(128 + SIGABRT), should the signal be caught or ignored
+        mov(134, &edi),
+        mov(231, &eax),
+        Line::NoArgInst(NoArgInst::Syscall),
+    ]
 }
 
-fn generate_fn(func: &Function) -> Vec<Line> {
-    combine(
-        vec![
+const UBSAN_ABORT: &str = "__rcc_ubsan_abort";
+
+/// A string as an `.ascii` operand, escaping only what the assembler requires
+fn quoted(text: &str) -> String {
+    let escaped = text.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+fn reg(reg: Register, width: Width) -> Param {
+    Param::Register(reg, width)
+}
+
+fn fits_imm32(int: Constant) -> bool {
+    int.as_i64() >= i64::from(i32::MIN) && int.as_i64() <= i64::from(i32::MAX) || int.ctype.size(DataModel::LP64) == 4
+}
+
+fn select_fn(
+    func: &Function,
+    allocation: &Allocation<Register>,
+    sanitize: bool,
+    source: Option<SourceFile>,
+) -> Vec<Line> {
+    let saved: Vec<Register> = CALLEE_SAVED
+        .iter()
+        .filter(|reg| allocation.used.contains(reg))
+        .cloned()
+        .collect();
+    let frame_size = ((saved.len() + allocation.spill_slots) as i64 * 8 + 15) / 16 * 16;
+
+    let mut selector = Selector {
+        func,
+        allocation,
+        saved: &saved,
+        lines: vec![
             Line::Directive(Directive::Globl, func.name.clone()),
             Line::Label(func.name.clone()),
+            Line::SingleArgInst(SingleArgInst::Push, reg(Register::RBP, Width::Quad)),
+            Line::DoubleArgInst(
+                DoubleArgInst::Mov,
+                reg(Register::RSP, Width::Quad),
+                reg(Register::RBP, Width::Quad),
+            ),
         ],
-        generate_stmt(&func.statement),
-    )
-}
+        block: BlockId(0),
+        index: 0,
+        sanitize,
+        source,
+        checks: Vec::new(),
+    };
+    if frame_size > 0 {
+        selector.emit2(
+            DoubleArgInst::Sub,
+            Param::Const(frame_size),
+            reg(Register::RSP, Width::Quad),
+        );
+    }
+    for (i, saved) in saved.iter().enumerate() {
+        selector.emit2(DoubleArgInst::Mov, reg(*saved, Width::Quad), selector.saved_slot(i));
+    }
 
-fn generate_stmt(stmt: &Statement) -> Vec<Line> {
-    match stmt {
-        Statement::Return(exp) => append(generate_exp(exp), Line::NoArgInst(NoArgInst::Ret)),
+    for (i, block) in func.blocks.iter().enumerate() {
+        selector.block = BlockId(i);
+        selector.lines.push(Line::Label(block_label(func, BlockId(i))));
+        for (index, inst) in block.instructions.iter().enumerate() {
+            selector.index = index;
+            selector.instruction(inst);
+        }
+        selector.index = block.instructions.len();
+        selector.terminator(&block.terminator);
     }
+    selector.failed_checks();
+    selector.lines
 }
 
-fn generate_exp(exp: &Expression) -> Vec<Line> {
-    match exp {
-        Expression::Term(term) => generate_term(term),
-        Expression::BinaryOperation { left, operator, right } => {
-            let mut l = generate_exp(left);
-            let mut r = generate_term(right);
-            let mut op = match operator {
-                Operator::Plus => vec![Line::DoubleArgInst(
-                    DoubleArgInst::Add,
-                    Param::Register(Register::RCX),
-                    Param::Register(Register::RAX),
-                )],
-                Operator::Negate => vec![
-                    Line::DoubleArgInst(
-                        DoubleArgInst::Sub,
-                        Param::Register(Register::RAX),
-                        Param::Register(Register::RCX),
-                    ),
-                    Line::DoubleArgInst(
-                        DoubleArgInst::Mov,
-                        Param::Register(Register::RCX),
-                        Param::Register(Register::RAX),
-                    ),
-                ],
-                _ => panic!(format!("Unexpected operater '{:?}'", operator)),
-            };
-
-            /*
-               <code for left side>
-               pushl %eax
-               <code for right side>
-               popl %ecx
-               <code for operation>
-            */
-            l.push(Line::SingleArgInst(SingleArgInst::Push, Param::Register(Register::RAX)));
-            l.append(&mut r);
-            l.push(Line::SingleArgInst(SingleArgInst::Pop, Param::Register(Register::RCX)));
-            l.append(&mut op);
-            l
+struct Selector<'a> {
+    func: &'a Function,
+    allocation: &'a Allocation<Register>,
+    /// Callee-saved registers the function uses, stored at the top of the frame
+    saved: &'a [Register],
+    lines: Vec<Line>,
+    /// Position of the instruction being selected
+    block: BlockId,
+    index: usize,
+    sanitize: bool,
+    source: Option<SourceFile<'a>>,
+    /// The label each runtime check jumps to when it fails, and the message it prints
+    checks: Vec<(String, String)>,
+}
+
+impl<'a> Selector<'a> {
+    fn emit1(&mut self, inst: SingleArgInst, param: Param) {
+        self.lines.push(Line::SingleArgInst(inst, param));
+    }
+
+    fn emit2(&mut self, inst: DoubleArgInst, src: Param, dst: Param) {
+        self.lines.push(Line::DoubleArgInst(inst, src, dst));
+    }
+
+    fn mov(&mut self, src: Param, dst: Param) {
+        if src != dst {
+            self.emit2(DoubleArgInst::Mov, src, dst);
+        }
+    }
+
+    /// The label the next runtime check jumps to on failure
+    fn check_label(&self) -> String {
+        format!(".L{}_ubsan{}", self.func.name, self.checks.len())
+    }
+
+    /// Jumps to the handler with `error` when `jump` is taken. The message starts with the
+    /// file, line and column of the operator checked, or, for a program compiled without its
+    /// source, with the function.
+    fn check(&mut self, jump: SingleArgInst, error: String, span: Option<Span>) {
+        let label = self.check_label();
+        self.emit1(jump, Param::Label(label.clone()));
+        let location = match (self.source, span) {
+            (Some(source), Some(span)) => {
+                let (line, column) = span.line_column(source.text);
+                format!("{}:{}:{}", source.path, line, column)
+            }
+            _ => self.func.name.clone(),
+        };
+        let message = format!("{}: runtime error: {}\n", location, error);
+        self.checks.push((label, message));
+    }
+
+    /// The failed checks' calls to the handler, then their messages, after the function
+    fn failed_checks(&mut self) {
+        let checks = std::mem::take(&mut self.checks);
+        for (label, message) in &checks {
+            self.lines.push(Line::Label(label.clone()));
+            self.emit2(
+                DoubleArgInst::Lea,
+                Param::RipRelative(format!("{}_message", label)),
+                reg(Register::RSI, Width::Quad),
+            );
+            self.emit2(
+                DoubleArgInst::Mov,
+                Param::Const(message.len() as i64),
+                reg(Register::RDX, Width::Long),
+            );
+            self.emit1(SingleArgInst::Call, Param::Label(String::from(UBSAN_ABORT)));
+        }
+        for (label, message) in &checks {
+            self.lines.push(Line::Label(format!("{}_message", label)));
+            self.lines.push(Line::Directive(Directive::Ascii, quoted(message)));
         }
     }
-}
 
-fn generate_term(term: &Term) -> Vec<Line> {
-    match term {
-        Term::Factor(factor) => generate_factor(factor),
-        Term::BinaryOperation { left, operator, right } => {
-            let mut l = generate_term(left);
-            let mut r = generate_factor(right);
-            let mut op = match operator {
-                Operator::Star => vec![Line::DoubleArgInst(
-                    DoubleArgInst::Imul,
-                    Param::Register(Register::RCX),
-                    Param::Register(Register::RAX),
-                )],
-                Operator::Divide => vec![
-                    Line::DoubleArgInst(
-                        DoubleArgInst::Mov,
-                        Param::Register(Register::RAX),
-                        Param::Register(Register::RBX),
-                    ),
-                    Line::DoubleArgInst(
-                        DoubleArgInst::Mov,
-                        Param::Register(Register::RCX),
-                        Param::Register(Register::RAX),
-                    ),
-                    Line::DoubleArgInst(DoubleArgInst::Mov, Param::Const(0), Param::Register(Register::RDX)),
-                    Line::SingleArgInst(SingleArgInst::Idiv, Param::Register(Register::RBX)),
-                ],
-                _ => panic!(format!("Unexpected operater '{:?}'", operator)),
-            };
-
-            /*
-               <code for left side>
-               pushl %eax
-               <code for right side>
-               popl %ecx
-               <code for operation>
-            */
-            l.push(Line::SingleArgInst(SingleArgInst::Push, Param::Register(Register::RAX)));
-            l.append(&mut r);
-            l.push(Line::SingleArgInst(SingleArgInst::Pop, Param::Register(Register::RCX)));
-            l.append(&mut op);
-            l
+    /// The message of a signed overflow check, for operations whose overflow is undefined
+    fn overflow(&self, value: Value, operation: &str) -> Option<String> {
+        let ctype = self.func.type_of(value);
+        if self.sanitize && ctype.is_signed() {
+            Some(format!("signed integer overflow in '{}' {}", ctype, operation))
+        } else {
+            None
+        }
+    }
+
+    fn saved_slot(&self, index: usize) -> Param {
+        Param::Memory(Register::RBP, -8 * (index as i64 + 1), Width::Quad)
+    }
+
+    fn param(&self, value: Value, width: Width) -> Param {
+        match value {
+            Value::Const(int) => Param::Const(int.as_i64()),
+            Value::Register(vreg) => match self.allocation.location(vreg) {
+                Location::Register(reg) => Param::Register(reg, width),
+                Location::Stack(slot) => {
+                    Param::Memory(Register::RBP, -8 * ((self.saved.len() + slot) as i64 + 1), width)
+                }
+            },
         }
     }
-}
 
-fn generate_factor(factor: &Factor) -> Vec<Line> {
-    match factor {
-        Factor::Expression(exp) => generate_exp(exp),
-        Factor::Const(int) => vec![Line::DoubleArgInst(
-            DoubleArgInst::Mov,
-            Param::Const(*int),
-            Param::Register(Register::RAX),
-        )],
-        Factor::UnaryOperation { operator, factor } => {
-            let factor_code = generate_factor(factor);
-            match operator {
-                Operator::Negate => append(
-                    factor_code,
-                    Line::SingleArgInst(SingleArgInst::Neg, Param::Register(Register::RAX)),
-                ),
-                Operator::Not => append(
-                    factor_code,
-                    Line::SingleArgInst(SingleArgInst::Not, Param::Register(Register::RAX)),
-                ),
-                Operator::Bang => combine(
-                    factor_code,
-                    vec![
-                        Line::DoubleArgInst(DoubleArgInst::Cmp, Param::Const(0), Param::Register(Register::RAX)),
-                        Line::DoubleArgInst(DoubleArgInst::Mov, Param::Const(0), Param::Register(Register::RAX)),
-                        Line::SingleArgInst(SingleArgInst::Sete, Param::Register(Register::AL)),
-                    ],
-                ),
-                _ => panic!(format!("Unexpected operater '{:?}'", operator)),
+    fn width(&self, value: Value) -> Width {
+        Width::of(self.func.type_of(value))
+    }
+
+    /// Runs `body` with a register that holds nothing live. When pressure leaves none free,
+    /// one is borrowed by saving it on the stack around the instruction.
+    fn with_scratch<F: FnOnce(&mut Self, Register)>(&mut self, excluding: &[Register], body: F) {
+        match self.allocation.free_at(self.block, self.index, excluding) {
+            Some(scratch) => body(self, scratch),
+            None => {
+                let scratch = *ALLOCATABLE.iter().find(|reg| !excluding.contains(reg)).unwrap();
+                self.emit1(SingleArgInst::Push, reg(scratch, Width::Quad));
+                body(self, scratch);
+                self.emit1(SingleArgInst::Pop, reg(scratch, Width::Quad));
             }
         }
     }
-}
 
-fn combine<T>(mut left: Vec<T>, mut right: Vec<T>) -> Vec<T> {
-    left.append(&mut right);
-    left
-}
+    /// Computes into the destination register directly, or into a scratch register
+    /// when the destination is in memory or `conflict` occupies it
+    fn with_target<F: FnOnce(&mut Self, Register)>(&mut self, dst: &Param, conflict: &[&Param], body: F) {
+        match dst.register() {
+            Some(target) if !conflict.iter().any(|param| param.register() == Some(target)) => body(self, target),
+            _ => {
+                let mut excluding: Vec<Register> = conflict.iter().filter_map(|param| param.register()).collect();
+                excluding.extend(dst.register());
+                let width = match dst {
+                    Param::Register(_, width) | Param::Memory(_, _, width) => *width,
+                    _ => Width::Quad,
+                };
+                let dst = dst.clone();
+                self.with_scratch(&excluding, |selector, scratch| {
+                    body(selector, scratch);
+                    selector.mov(reg(scratch, width), dst);
+                });
+            }
+        }
+    }
 
-// fn prepend<T>(left: T, mut right: Vec<T>) -> Vec<T> {
-//     let mut pre = vec![left];
-//     pre.append(&mut right);
-//     pre
-// }
+    fn instruction(&mut self, inst: &Instruction) {
+        let dst_value = Value::Register(inst.dst());
+        let width = self.width(dst_value);
+        let dst = self.param(dst_value, width);
 
-fn append<T>(mut left: Vec<T>, right: T) -> Vec<T> {
-    left.push(right);
-    left
+        match inst {
+            Instruction::Copy { src, .. } => {
+                let src = self.param(*src, width);
+                let direct = match (&src, &dst) {
+                    (Param::Memory(..), Param::Memory(..)) => false,
+                    (Param::Const(int), Param::Memory(..)) => *int as i32 as i64 == *int || width == Width::Long,
+                    _ => true,
+                };
+                if direct {
+                    self.mov(src, dst);
+                } else {
+                    self.with_target(&dst, &[], |selector, target| selector.mov(src, reg(target, width)));
+                }
+            }
+            Instruction::Phi { .. } => panic!("phi instructions must be removed before code generation"),
+            Instruction::Convert { src, .. } => {
+                let src_type = self.func.type_of(*src);
+                let src_width = Width::of(src_type);
+                let src = self.param(*src, src_width);
+                self.with_target(&dst, &[], |selector, target| {
+                    if src_type.is_signed() && src_width == Width::Long && width == Width::Quad {
+                        selector.emit2(DoubleArgInst::Movslq, src, reg(target, Width::Quad));
+                    } else if src_width == width {
+                        selector.mov(src, reg(target, width));
+                    } else {
+                        // Narrowing reads the low half; a 32-bit move zero extends
+                        let src = match src {
+                            Param::Const(int) => Param::Const(int as i32 as i64),
+                            Param::Register(reg, _) => Param::Register(reg, Width::Long),
+                            Param::Memory(base, offset, _) => Param::Memory(base, offset, Width::Long),
+                            other => other,
+                        };
+                        selector.mov(src, reg(target, Width::Long));
+                    }
+                });
+            }
+            Instruction::Unary { op, src, span, .. } => {
+                let src = self.param(*src, width);
+                let (inst, overflow) = match op {
+                    UnaryOp::Neg => (SingleArgInst::Neg, self.overflow(dst_value, "negation")),
+                    UnaryOp::Not => (SingleArgInst::Not, None),
+                };
+                self.with_target(&dst, &[], |selector, target| {
+                    selector.mov(src, reg(target, width));
+                    selector.emit1(inst, reg(target, width));
+                    if let Some(error) = overflow {
+                        selector.check(SingleArgInst::Jo, error, *span);
+                    }
+                });
+            }
+            Instruction::Binary {
+                op, left, right, span, ..
+            } => {
+                let op_width = self.width(*left);
+                let mut l = self.param(*left, op_width);
+                let mut r = self.param(*right, op_width);
+                match op {
+                    BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul => {
+                        let (inst, operation) = match op {
+                            BinaryOp::Add => (DoubleArgInst::Add, "addition"),
+                            BinaryOp::Sub => (DoubleArgInst::Sub, "subtraction"),
+                            _ => (DoubleArgInst::Imul, "multiplication"),
+                        };
+                        let overflow = self.overflow(*left, operation);
+                        // Commutative operations can swap their operands to compute in place
+                        if *op != BinaryOp::Sub && r.register().is_some() && r.register() == dst.register() {
+                            std::mem::swap(&mut l, &mut r);
+                        }
+                        let conflict = r.clone();
+                        self.with_target(&dst, &[&conflict], |selector, target| {
+                            selector.mov(l, reg(target, width));
+                            selector.emit2(inst, r, reg(target, width));
+                            if let Some(error) = overflow {
+                                selector.check(SingleArgInst::Jo, error, *span);
+                            }
+                        });
+                    }
+                    BinaryOp::Div => {
+                        let rax = reg(Register::RAX, op_width);
+                        self.mov(l, rax.clone());
+                        if self.sanitize {
+                            self.emit2(DoubleArgInst::Cmp, Param::Const(0), r.clone());
+                            self.check(SingleArgInst::Je, String::from("division by zero"), *span);
+                        }
+                        if let Some(error) = self.overflow(*left, "division") {
+                            // Only the most negative dividend overflows dividing by -1, which
+                            // negating it detects; the negation is undone otherwise
+                            let divisor_checked = format!("{}_done", self.check_label());
+                            self.emit2(DoubleArgInst::Cmp, Param::Const(-1), r.clone());
+                            self.emit1(SingleArgInst::Jne, Param::Label(divisor_checked.clone()));
+                            self.emit1(SingleArgInst::Neg, rax.clone());
+                            self.check(SingleArgInst::Jo, error, *span);
+                            self.emit1(SingleArgInst::Neg, rax.clone());
+                            self.lines.push(Line::Label(divisor_checked));
+                        }
+                        if self.func.type_of(*left).is_signed() {
+                            self.lines.push(Line::NoArgInst(if op_width == Width::Quad {
+                                NoArgInst::Cqto
+                            } else {
+                                NoArgInst::Cltd
+                            }));
+                            self.emit1(SingleArgInst::Idiv, r);
+                        } else {
+                            self.emit2(
+                                DoubleArgInst::Xor,
+                                reg(Register::RDX, Width::Long),
+                                reg(Register::RDX, Width::Long),
+                            );
+                            self.emit1(SingleArgInst::Div, r);
+                        }
+                        self.mov(rax, dst);
+                    }
+                    BinaryOp::Shl | BinaryOp::Shr => {
+                        let count_width = self.width(*right);
+                        let count = self.param(*right, count_width);
+                        let bits: i64 = if op_width == Width::Long { 32 } else { 64 };
+                        if self.sanitize {
+                            let error = format!(
+                                "shift exponent is negative or too large for {}-bit type '{}'",
+                                bits,
+                                self.func.type_of(*left)
+                            );
+                            match count {
+                                Param::Const(int) if int < 0 || int >= bits => {
+                                    self.check(SingleArgInst::Jmp, error, *span)
+                                }
+                                Param::Const(_) => {}
+                                // Compared unsigned, so that a negative count is out of range too
+                                _ => {
+                                    self.emit2(DoubleArgInst::Cmp, Param::Const(bits - 1), count.clone());
+                                    self.check(SingleArgInst::Ja, error, *span);
+                                }
+                            }
+                        }
+                        let inst = match op {
+                            BinaryOp::Shl => DoubleArgInst::Shl,
+                            _ if self.func.type_of(*left).is_signed() => DoubleArgInst::Sar,
+                            _ => DoubleArgInst::Shr,
+                        };
+                        // The processor masks the count to the width, and so does this for constants
+                        let count = match count {
+                            Param::Const(int) => Param::Const(int & (bits - 1)),
+                            count => {
+                                self.mov(count, reg(Register::RCX, count_width));
+                                reg(Register::RCX, Width::Byte)
+                            }
+                        };
+                        let conflict = count.clone();
+                        self.with_target(&dst, &[&conflict], |selector, target| {
+                            selector.mov(l, reg(target, width));
+                            selector.emit2(inst, count, reg(target, width));
+                        });
+                    }
+                    BinaryOp::Equal => {
+                        if let Param::Const(_) = l {
+                            std::mem::swap(&mut l, &mut r);
+                        }
+                        if l.is_memory() && r.is_memory() || matches!(l, Param::Const(_)) {
+                            let (l_value, r_value) = (l, r);
+                            self.with_scratch(&[], |selector, scratch| {
+                                selector.mov(l_value, reg(scratch, op_width));
+                                selector.emit2(DoubleArgInst::Cmp, r_value, reg(scratch, op_width));
+                            });
+                        } else {
+                            self.emit2(DoubleArgInst::Cmp, r, l);
+                        }
+                        self.with_target(&dst, &[], |selector, target| {
+                            selector.emit2(DoubleArgInst::Mov, Param::Const(0), reg(target, Width::Long));
+                            selector.emit1(SingleArgInst::Sete, reg(target, Width::Byte));
+                        });
+                    }
+                }
+            }
+            Instruction::Syscall { number, args, .. } => {
+                // No operand lives in a register the call clobbers, so they load in any order
+                for (arg, target) in args.iter().zip(&SYSCALL_ARGS) {
+                    let arg = self.param(*arg, Width::Quad);
+                    self.mov(arg, reg(*target, Width::Quad));
+                }
+                let number = self.param(*number, Width::Quad);
+                self.mov(number, reg(Register::RAX, Width::Quad));
+                self.lines.push(Line::NoArgInst(NoArgInst::Syscall));
+                self.mov(reg(Register::RAX, width), dst);
+            }
+        }
+    }
+
+    fn terminator(&mut self, terminator: &Terminator) {
+        match terminator {
+            Terminator::Return(value) => {
+                let width = Width::of(self.func.return_type);
+                let value = self.param(*value, width);
+                self.mov(value, reg(Register::RAX, width));
+                for (i, saved) in self.saved.iter().enumerate() {
+                    self.emit2(DoubleArgInst::Mov, self.saved_slot(i), reg(*saved, Width::Quad));
+                }
+                self.emit2(
+                    DoubleArgInst::Mov,
+                    reg(Register::RBP, Width::Quad),
+                    reg(Register::RSP, Width::Quad),
+                );
+                self.emit1(SingleArgInst::Pop, reg(Register::RBP, Width::Quad));
+                self.lines.push(Line::NoArgInst(NoArgInst::Ret));
+            }
+            Terminator::Jump(target) => {
+                let label = block_label(self.func, *target);
+                self.emit1(SingleArgInst::Jmp, Param::Label(label));
+            }
+            Terminator::Branch(value, then, otherwise) => {
+                let (then, otherwise) = (block_label(self.func, *then), block_label(self.func, *otherwise));
+                if let Value::Const(int) = value {
+                    let target = if int.is_zero() { otherwise } else { then };
+                    self.emit1(SingleArgInst::Jmp, Param::Label(target));
+                    return;
+                }
+                let value = self.param(*value, self.width(*value));
+                self.emit2(DoubleArgInst::Cmp, Param::Const(0), value);
+                self.emit1(SingleArgInst::Jne, Param::Label(then));
+                self.emit1(SingleArgInst::Jmp, Param::Label(otherwise));
+            }
+        }
+    }
 }
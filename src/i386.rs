@@ -0,0 +1,612 @@
+use backend::{self, block_label, Backend};
+use generator::Syntax;
+use ir::{BinaryOp, BlockId, Function, Instruction, Terminator, UnaryOp, Value};
+use regalloc::{Allocation, Location};
+use types::{DataModel, Type};
+
+#[derive(Debug)]
+pub enum Line {
+    Directive(Directive, String),
+    Label(String),
+    /// Operands in Intel order, destination first
+    Inst(Opcode, Vec<Operand>),
+}
+
+#[derive(Debug)]
+pub enum Directive {
+    Globl,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Opcode {
+    Mov,
+    /// Zero extends a byte register
+    Movzb,
+    Add,
+    Adc,
+    Sub,
+    Sbb,
+    Imul,
+    /// Unsigned `edx:eax = eax * operand`
+    Mul,
+    Idiv,
+    Div,
+    Neg,
+    Not,
+    Xor,
+    Or,
+    Shl,
+    Sar,
+    Shr,
+    Cmp,
+    Cltd,
+    Sete,
+    Push,
+    Pop,
+    Call,
+    Jmp,
+    Jne,
+    Ret,
+    /// Software interrupt, `int $0x80` entering the kernel
+    Int,
+}
+
+impl Opcode {
+    fn name(self, syntax: &Syntax) -> &'static str {
+        match (self, syntax) {
+            (Opcode::Mov, _) => "mov",
+            (Opcode::Movzb, Syntax::ATT) => "movzb",
+            (Opcode::Movzb, Syntax::Intel) => "movzx",
+            (Opcode::Add, _) => "add",
+            (Opcode::Adc, _) => "adc",
+            (Opcode::Sub, _) => "sub",
+            (Opcode::Sbb, _) => "sbb",
+            (Opcode::Imul, _) => "imul",
+            (Opcode::Mul, _) => "mul",
+            (Opcode::Idiv, _) => "idiv",
+            (Opcode::Div, _) => "div",
+            (Opcode::Neg, _) => "neg",
+            (Opcode::Not, _) => "not",
+            (Opcode::Xor, _) => "xor",
+            (Opcode::Or, _) => "or",
+            (Opcode::Shl, _) => "shl",
+            (Opcode::Sar, _) => "sar",
+            (Opcode::Shr, _) => "shr",
+            (Opcode::Cmp, _) => "cmp",
+            (Opcode::Cltd, Syntax::ATT) => "cltd",
+            (Opcode::Cltd, Syntax::Intel) => "cdq",
+            (Opcode::Sete, _) => "sete",
+            (Opcode::Push, _) => "push",
+            (Opcode::Pop, _) => "pop",
+            (Opcode::Call, _) => "call",
+            (Opcode::Jmp, _) => "jmp",
+            (Opcode::Jne, _) => "jne",
+            (Opcode::Ret, _) => "ret",
+            (Opcode::Int, _) => "int",
+        }
+    }
+
+    /// Whether AT&T syntax spells out the 32-bit operand size, as in `pushl`
+    fn sized(self) -> bool {
+        !matches!(
+            self,
+            Opcode::Cltd | Opcode::Sete | Opcode::Call | Opcode::Jmp | Opcode::Jne | Opcode::Ret | Opcode::Int
+        )
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Register {
+    EAX,
+    EBX,
+    ECX,
+    EDX,
+    ESI,
+    EDI,
+    ESP,
+    EBP,
+}
+
+impl Register {
+    fn name(self) -> &'static str {
+        match self {
+            Register::EAX => "eax",
+            Register::EBX => "ebx",
+            Register::ECX => "ecx",
+            Register::EDX => "edx",
+            Register::ESI => "esi",
+            Register::EDI => "edi",
+            Register::ESP => "esp",
+            Register::EBP => "ebp",
+        }
+    }
+}
+
+/// Never allocated: every instruction computes in these, and `__divdi3` may destroy them
+const SCRATCH: [Register; 3] = [Register::EAX, Register::ECX, Register::EDX];
+
+/// The callee-saved registers (i386 System V ABI), the only ones left after the scratch ones
+const ALLOCATABLE: [Register; 3] = [Register::EBX, Register::ESI, Register::EDI];
+
+/// Registers carrying system call arguments, in order (Linux i386 `int $0x80` convention)
+const SYSCALL_ARGS: [Register; 6] = [
+    Register::EBX,
+    Register::ECX,
+    Register::EDX,
+    Register::ESI,
+    Register::EDI,
+    Register::EBP,
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Register(Register),
+    /// `al`, the low byte of `eax`
+    Low(Register),
+    Immediate(i32),
+    /// `offset(base)`, always 32 bits wide
+    Memory(Register, i64),
+    Label(String),
+}
+
+impl Operand {
+    fn to_asm(&self, syntax: &Syntax) -> String {
+        match (self, syntax) {
+            (Operand::Register(reg), Syntax::ATT) => format!("%{}", reg.name()),
+            (Operand::Register(reg), Syntax::Intel) => String::from(reg.name()),
+            (Operand::Low(reg), _) => {
+                let low = format!("{}l", &reg.name()[1..2]);
+                match syntax {
+                    Syntax::ATT => format!("%{}", low),
+                    Syntax::Intel => low,
+                }
+            }
+            (Operand::Immediate(int), Syntax::ATT) => format!("${}", int),
+            (Operand::Immediate(int), Syntax::Intel) => format!("{}", int),
+            (Operand::Memory(base, offset), Syntax::ATT) => format!("{}(%{})", offset, base.name()),
+            (Operand::Memory(base, offset), Syntax::Intel) => {
+                format!("DWORD PTR [{}{:+}]", base.name(), offset)
+            }
+            (Operand::Label(label), _) => label.clone(),
+        }
+    }
+
+    fn is_memory(&self) -> bool {
+        matches!(self, Operand::Memory(..))
+    }
+}
+
+impl Line {
+    fn to_asm(&self, syntax: &Syntax) -> String {
+        match self {
+            Line::Directive(dir, other) => format!(".{} {}", format!("{:?}", dir).to_lowercase(), other),
+            Line::Label(label) => format!("{}:", label),
+            Line::Inst(opcode, operands) => {
+                let mut operands: Vec<String> = operands.iter().map(|operand| operand.to_asm(syntax)).collect();
+                let mut name = String::from(opcode.name(syntax));
+                if let Syntax::ATT = syntax {
+                    operands.reverse();
+                    if opcode.sized() {
+                        name.push('l');
+                    }
+                }
+                if operands.is_empty() {
+                    format!("\t{}", name)
+                } else {
+                    format!("\t{}\t{}", name, operands.join(", "))
+                }
+            }
+        }
+    }
+}
+
+/// The 32-bit x86 backend, following the cdecl calling convention: arguments are pushed on the
+/// stack right to left, the caller pops them, and results come back in `eax`, or `edx:eax` for
+/// 64-bit ones. Values wider than a register live in pairs of 32-bit stack words.
+pub struct I386 {
+    pub syntax: Syntax,
+}
+
+impl Backend for I386 {
+    type Register = Register;
+    type Line = Line;
+
+    fn registers(&self) -> &'static [Register] {
+        &ALLOCATABLE
+    }
+
+    fn clobbers(&self, inst: &Instruction) -> Vec<Register> {
+        match inst {
+            Instruction::Syscall { args, .. } => SYSCALL_ARGS[..args.len()].to_vec(),
+            _ => vec![],
+        }
+    }
+
+    fn fits(&self, ctype: Type) -> bool {
+        !is_wide(ctype)
+    }
+
+    fn select(&self, func: &Function, allocation: &Allocation<Register>) -> Vec<Line> {
+        select_fn(func, allocation)
+    }
+
+    fn start(&self) -> Vec<Line> {
+        vec![
+            Line::Directive(Directive::Globl, String::from("_start")),
+            Line::Label(String::from("_start")),
+            // A zero frame pointer marks the outermost frame
+            Line::Inst(Opcode::Xor, vec![reg(Register::EBP), reg(Register::EBP)]),
+            Line::Inst(Opcode::Call, vec![Operand::Label(String::from("main"))]),
+            Line::Inst(Opcode::Mov, vec![reg(Register::EBX), reg(Register::EAX)]),
+            Line::Inst(Opcode::Mov, vec![reg(Register::EAX), Operand::Immediate(1)]),
+            Line::Inst(Opcode::Int, vec![Operand::Immediate(0x80)]),
+        ]
+    }
+
+    fn render(&self, lines: &[Line]) -> Vec<String> {
+        self.syntax
+            .header()
+            .map(String::from)
+            .into_iter()
+            .chain(backend::prologue('@'))
+            .chain(lines.iter().map(|line| line.to_asm(&self.syntax)))
+            .collect()
+    }
+}
+
+fn reg(reg: Register) -> Operand {
+    Operand::Register(reg)
+}
+
+/// Whether values of the type take a pair of words
+fn is_wide(ctype: Type) -> bool {
+    ctype.size(DataModel::ILP32) > 4
+}
+
+fn select_fn(func: &Function, allocation: &Allocation<Register>) -> Vec<Line> {
+    let saved: Vec<Register> = allocation.used.clone();
+    // The return address and ebp take 8 bytes, so a frame of 8 modulo 16 keeps the stack
+    // 16-byte aligned at calls
+    let locals = saved.len() as i64 * 4 + allocation.spill_slots as i64 * 8;
+    let frame_size = if locals > 0 { (locals + 8 + 15) / 16 * 16 - 8 } else { 0 };
+
+    let mut selector = Selector {
+        func,
+        allocation,
+        saved: &saved,
+        lines: vec![
+            Line::Directive(Directive::Globl, func.name.clone()),
+            Line::Label(func.name.clone()),
+            Line::Inst(Opcode::Push, vec![reg(Register::EBP)]),
+            Line::Inst(Opcode::Mov, vec![reg(Register::EBP), reg(Register::ESP)]),
+        ],
+    };
+    if frame_size > 0 {
+        selector.emit(
+            Opcode::Sub,
+            vec![reg(Register::ESP), Operand::Immediate(frame_size as i32)],
+        );
+    }
+    for (i, saved) in saved.iter().enumerate() {
+        selector.emit(
+            Opcode::Mov,
+            vec![Operand::Memory(Register::EBP, -4 * (i as i64 + 1)), reg(*saved)],
+        );
+    }
+
+    for (i, block) in func.blocks.iter().enumerate() {
+        selector.lines.push(Line::Label(block_label(func, BlockId(i))));
+        for inst in &block.instructions {
+            selector.instruction(inst);
+        }
+        selector.terminator(&block.terminator);
+    }
+    selector.lines
+}
+
+struct Selector<'a> {
+    func: &'a Function,
+    allocation: &'a Allocation<Register>,
+    /// Callee-saved registers the function uses, stored at the top of the frame
+    saved: &'a [Register],
+    lines: Vec<Line>,
+}
+
+impl<'a> Selector<'a> {
+    fn emit(&mut self, opcode: Opcode, operands: Vec<Operand>) {
+        self.lines.push(Line::Inst(opcode, operands));
+    }
+
+    /// Moves between any two operands, going through `eax` from memory to memory
+    fn mov(&mut self, dst: Operand, src: Operand) {
+        if dst == src {
+            return;
+        }
+        if dst.is_memory() && src.is_memory() {
+            self.emit(Opcode::Mov, vec![reg(SCRATCH[0]), src]);
+            self.emit(Opcode::Mov, vec![dst, reg(SCRATCH[0])]);
+        } else {
+            self.emit(Opcode::Mov, vec![dst, src]);
+        }
+    }
+
+    fn is_wide(&self, value: Value) -> bool {
+        is_wide(self.func.type_of(value))
+    }
+
+    /// The low or high 32 bits of a value. Values of a register's width only have a low half.
+    fn half(&self, value: Value, high: bool) -> Operand {
+        match value {
+            Value::Const(int) => {
+                let bits = if high { int.value >> 32 } else { int.value };
+                Operand::Immediate(bits as u32 as i32)
+            }
+            Value::Register(vreg) => match self.allocation.location(vreg) {
+                Location::Register(reg) => Operand::Register(reg),
+                Location::Stack(slot) => {
+                    let offset = -4 * self.saved.len() as i64 - 8 * (slot as i64 + 1);
+                    Operand::Memory(Register::EBP, if high { offset + 4 } else { offset })
+                }
+            },
+        }
+    }
+
+    fn low(&self, value: Value) -> Operand {
+        self.half(value, false)
+    }
+
+    fn high(&self, value: Value) -> Operand {
+        self.half(value, true)
+    }
+
+    /// Loads a 64-bit value into `edx:eax`
+    fn load_pair(&mut self, value: Value) {
+        let (low, high) = (self.low(value), self.high(value));
+        self.emit(Opcode::Mov, vec![reg(Register::EAX), low]);
+        self.emit(Opcode::Mov, vec![reg(Register::EDX), high]);
+    }
+
+    /// Stores `edx:eax` into a 64-bit value
+    fn store_pair(&mut self, value: Value) {
+        let (low, high) = (self.low(value), self.high(value));
+        self.emit(Opcode::Mov, vec![low, reg(Register::EAX)]);
+        self.emit(Opcode::Mov, vec![high, reg(Register::EDX)]);
+    }
+
+    /// Sets `eax` to 1 if the last comparison found its operands equal, 0 otherwise
+    fn set_equal(&mut self) {
+        self.emit(Opcode::Sete, vec![Operand::Low(Register::EAX)]);
+        self.emit(Opcode::Movzb, vec![reg(Register::EAX), Operand::Low(Register::EAX)]);
+    }
+
+    fn instruction(&mut self, inst: &Instruction) {
+        let dst = Value::Register(inst.dst());
+        match inst {
+            Instruction::Copy { src, .. } => {
+                self.mov(self.low(dst), self.low(*src));
+                if self.is_wide(dst) {
+                    self.mov(self.high(dst), self.high(*src));
+                }
+            }
+            Instruction::Phi { .. } => panic!("phi instructions must be removed before code generation"),
+            Instruction::Convert { src, .. } => match (self.is_wide(*src), self.is_wide(dst)) {
+                (false, true) => {
+                    self.emit(Opcode::Mov, vec![reg(Register::EAX), self.low(*src)]);
+                    if self.func.type_of(*src).is_signed() {
+                        self.emit(Opcode::Cltd, vec![]);
+                    } else {
+                        self.emit(Opcode::Xor, vec![reg(Register::EDX), reg(Register::EDX)]);
+                    }
+                    self.store_pair(dst);
+                }
+                (true, true) => {
+                    self.mov(self.low(dst), self.low(*src));
+                    self.mov(self.high(dst), self.high(*src));
+                }
+                // Narrowing keeps the low half
+                _ => self.mov(self.low(dst), self.low(*src)),
+            },
+            Instruction::Unary { op, src, .. } if self.is_wide(dst) => {
+                self.load_pair(*src);
+                match op {
+                    UnaryOp::Neg => {
+                        // -(hi:lo) is -lo with the borrow taken out of -hi
+                        self.emit(Opcode::Neg, vec![reg(Register::EAX)]);
+                        self.emit(Opcode::Adc, vec![reg(Register::EDX), Operand::Immediate(0)]);
+                        self.emit(Opcode::Neg, vec![reg(Register::EDX)]);
+                    }
+                    UnaryOp::Not => {
+                        self.emit(Opcode::Not, vec![reg(Register::EAX)]);
+                        self.emit(Opcode::Not, vec![reg(Register::EDX)]);
+                    }
+                }
+                self.store_pair(dst);
+            }
+            Instruction::Unary { op, src, .. } => {
+                let opcode = match op {
+                    UnaryOp::Neg => Opcode::Neg,
+                    UnaryOp::Not => Opcode::Not,
+                };
+                self.emit(Opcode::Mov, vec![reg(Register::EAX), self.low(*src)]);
+                self.emit(opcode, vec![reg(Register::EAX)]);
+                self.mov(self.low(dst), reg(Register::EAX));
+            }
+            Instruction::Syscall { number, args, .. } => {
+                // Every operand is pushed before any is popped into place, so ebp still
+                // addresses the frame when the sixth argument is read into it
+                let sixth = args.len() == SYSCALL_ARGS.len();
+                if sixth {
+                    self.emit(Opcode::Push, vec![reg(Register::EBP)]);
+                }
+                for operand in std::iter::once(number).chain(args) {
+                    self.emit(Opcode::Push, vec![self.low(*operand)]);
+                }
+                for target in SYSCALL_ARGS[..args.len()].iter().rev() {
+                    self.emit(Opcode::Pop, vec![reg(*target)]);
+                }
+                self.emit(Opcode::Pop, vec![reg(Register::EAX)]);
+                self.emit(Opcode::Int, vec![Operand::Immediate(0x80)]);
+                if sixth {
+                    self.emit(Opcode::Pop, vec![reg(Register::EBP)]);
+                }
+                self.mov(self.low(dst), reg(Register::EAX));
+            }
+            Instruction::Binary { op, left, right, .. } if self.is_wide(*left) => {
+                self.wide_binary(*op, dst, *left, *right)
+            }
+            Instruction::Binary { op, left, right, .. } => {
+                let (l, r) = (self.low(*left), self.low(*right));
+                self.emit(Opcode::Mov, vec![reg(Register::EAX), l]);
+                match op {
+                    BinaryOp::Add => self.emit(Opcode::Add, vec![reg(Register::EAX), r]),
+                    BinaryOp::Sub => self.emit(Opcode::Sub, vec![reg(Register::EAX), r]),
+                    BinaryOp::Mul => self.emit(Opcode::Imul, vec![reg(Register::EAX), r]),
+                    BinaryOp::Div => {
+                        // Division has no immediate form
+                        let divisor = match r {
+                            Operand::Immediate(_) => {
+                                self.emit(Opcode::Mov, vec![reg(Register::ECX), r]);
+                                reg(Register::ECX)
+                            }
+                            other => other,
+                        };
+                        if self.func.type_of(*left).is_signed() {
+                            self.emit(Opcode::Cltd, vec![]);
+                            self.emit(Opcode::Idiv, vec![divisor]);
+                        } else {
+                            self.emit(Opcode::Xor, vec![reg(Register::EDX), reg(Register::EDX)]);
+                            self.emit(Opcode::Div, vec![divisor]);
+                        }
+                    }
+                    BinaryOp::Equal => {
+                        self.emit(Opcode::Cmp, vec![reg(Register::EAX), r]);
+                        self.set_equal();
+                    }
+                    BinaryOp::Shl | BinaryOp::Shr => {
+                        let opcode = match op {
+                            BinaryOp::Shl => Opcode::Shl,
+                            _ if self.func.type_of(*left).is_signed() => Opcode::Sar,
+                            _ => Opcode::Shr,
+                        };
+                        // A variable count has to be in `cl`
+                        let count = match r {
+                            Operand::Immediate(count) => Operand::Immediate(count & 31),
+                            other => {
+                                self.emit(Opcode::Mov, vec![reg(Register::ECX), other]);
+                                Operand::Low(Register::ECX)
+                            }
+                        };
+                        self.emit(opcode, vec![reg(Register::EAX), count]);
+                    }
+                }
+                self.mov(self.low(dst), reg(Register::EAX));
+            }
+        }
+    }
+
+    /// 64-bit arithmetic on pairs of 32-bit words
+    fn wide_binary(&mut self, op: BinaryOp, dst: Value, left: Value, right: Value) {
+        match op {
+            BinaryOp::Add | BinaryOp::Sub => {
+                let (low, high) = match op {
+                    BinaryOp::Add => (Opcode::Add, Opcode::Adc),
+                    _ => (Opcode::Sub, Opcode::Sbb),
+                };
+                self.load_pair(left);
+                self.emit(low, vec![reg(Register::EAX), self.low(right)]);
+                self.emit(high, vec![reg(Register::EDX), self.high(right)]);
+                self.store_pair(dst);
+            }
+            BinaryOp::Mul => {
+                // The low 64 bits of the product: lo*lo in full, plus both cross products in
+                // the high word
+                self.emit(Opcode::Mov, vec![reg(Register::ECX), self.high(right)]);
+                self.emit(Opcode::Imul, vec![reg(Register::ECX), self.low(left)]);
+                self.emit(Opcode::Mov, vec![reg(Register::EDX), self.high(left)]);
+                self.emit(Opcode::Imul, vec![reg(Register::EDX), self.low(right)]);
+                self.emit(Opcode::Add, vec![reg(Register::ECX), reg(Register::EDX)]);
+                self.emit(Opcode::Mov, vec![reg(Register::EAX), self.low(left)]);
+                self.emit(Opcode::Mov, vec![reg(Register::EDX), self.low(right)]);
+                self.emit(Opcode::Mul, vec![reg(Register::EDX)]);
+                self.emit(Opcode::Add, vec![reg(Register::EDX), reg(Register::ECX)]);
+                self.store_pair(dst);
+            }
+            BinaryOp::Div => {
+                // i386 has no 64-bit division, so call libgcc's, pushing cdecl arguments
+                // right to left
+                let function = if self.func.type_of(left).is_signed() {
+                    "__divdi3"
+                } else {
+                    "__udivdi3"
+                };
+                for operand in [self.high(right), self.low(right), self.high(left), self.low(left)] {
+                    self.emit(Opcode::Push, vec![operand]);
+                }
+                self.emit(Opcode::Call, vec![Operand::Label(String::from(function))]);
+                self.emit(Opcode::Add, vec![reg(Register::ESP), Operand::Immediate(16)]);
+                self.store_pair(dst);
+            }
+            BinaryOp::Shl | BinaryOp::Shr => {
+                // libgcc's shifts take the count as an int, which is its low word
+                let function = match op {
+                    BinaryOp::Shl => "__ashldi3",
+                    _ if self.func.type_of(left).is_signed() => "__ashrdi3",
+                    _ => "__lshrdi3",
+                };
+                for operand in [self.low(right), self.high(left), self.low(left)] {
+                    self.emit(Opcode::Push, vec![operand]);
+                }
+                self.emit(Opcode::Call, vec![Operand::Label(String::from(function))]);
+                self.emit(Opcode::Add, vec![reg(Register::ESP), Operand::Immediate(12)]);
+                self.store_pair(dst);
+            }
+            BinaryOp::Equal => {
+                // The words are equal exactly when neither xor leaves a bit set
+                self.load_pair(left);
+                self.emit(Opcode::Xor, vec![reg(Register::EAX), self.low(right)]);
+                self.emit(Opcode::Xor, vec![reg(Register::EDX), self.high(right)]);
+                self.emit(Opcode::Or, vec![reg(Register::EAX), reg(Register::EDX)]);
+                self.set_equal();
+                self.mov(self.low(dst), reg(Register::EAX));
+            }
+        }
+    }
+
+    fn terminator(&mut self, terminator: &Terminator) {
+        match terminator {
+            Terminator::Return(value) => {
+                self.mov(reg(Register::EAX), self.low(*value));
+                for (i, saved) in self.saved.iter().enumerate() {
+                    self.emit(
+                        Opcode::Mov,
+                        vec![reg(*saved), Operand::Memory(Register::EBP, -4 * (i as i64 + 1))],
+                    );
+                }
+                self.emit(Opcode::Mov, vec![reg(Register::ESP), reg(Register::EBP)]);
+                self.emit(Opcode::Pop, vec![reg(Register::EBP)]);
+                self.emit(Opcode::Ret, vec![]);
+            }
+            Terminator::Jump(target) => {
+                let label = block_label(self.func, *target);
+                self.emit(Opcode::Jmp, vec![Operand::Label(label)]);
+            }
+            Terminator::Branch(value, then, otherwise) => {
+                let (then, otherwise) = (block_label(self.func, *then), block_label(self.func, *otherwise));
+                if let Value::Const(int) = value {
+                    let target = if int.is_zero() { otherwise } else { then };
+                    self.emit(Opcode::Jmp, vec![Operand::Label(target)]);
+                    return;
+                }
+                self.emit(Opcode::Mov, vec![reg(Register::EAX), self.low(*value)]);
+                if self.is_wide(*value) {
+                    self.emit(Opcode::Or, vec![reg(Register::EAX), self.high(*value)]);
+                } else {
+                    self.emit(Opcode::Cmp, vec![reg(Register::EAX), Operand::Immediate(0)]);
+                }
+                self.emit(Opcode::Jne, vec![Operand::Label(then)]);
+                self.emit(Opcode::Jmp, vec![Operand::Label(otherwise)]);
+            }
+        }
+    }
+}
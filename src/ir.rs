@@ -0,0 +1,611 @@
+use lexer::{Operator, Span};
+use parser::{Expression, ExpressionKind, Program, Statement};
+use std::fmt;
+use types::{Constant, DataModel, Type};
+
+/// A target-independent, three-address representation of a program
+#[derive(Debug)]
+pub struct Module {
+    pub functions: Vec<Function>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: String,
+    pub return_type: Type,
+    /// The type of every virtual register, indexed by its number
+    pub registers: Vec<Type>,
+    /// Basic blocks, the first one being the entry
+    pub blocks: Vec<Block>,
+    /// The data model the types of the registers have their sizes under
+    pub model: DataModel,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct VReg(pub usize);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockId(pub usize);
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub instructions: Vec<Instruction>,
+    pub terminator: Terminator,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Value {
+    Const(Constant),
+    Register(VReg),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    /// Signed or unsigned division, following the type of the operands
+    Div,
+    /// Shifts the left operand by the right one, which keeps a type of its own
+    Shl,
+    /// An arithmetic or logical shift, following the type of the left operand
+    Shr,
+    /// Yields an `int` 1 when the operands are equal, 0 otherwise
+    Equal,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instruction {
+    Copy {
+        dst: VReg,
+        src: Value,
+    },
+    /// Selects the value flowing in from the predecessor the block was entered from.
+    /// Only present while the function is in SSA form.
+    Phi {
+        dst: VReg,
+        sources: Vec<(BlockId, Value)>,
+    },
+    /// Converts `src` to the type of `dst`, sign or zero extending by the type of `src`
+    Convert {
+        dst: VReg,
+        src: Value,
+    },
+    /// `span` is the operator the operation was written with, for runtime checks to report
+    Unary {
+        op: UnaryOp,
+        dst: VReg,
+        src: Value,
+        span: Option<Span>,
+    },
+    Binary {
+        op: BinaryOp,
+        dst: VReg,
+        left: Value,
+        right: Value,
+        span: Option<Span>,
+    },
+    /// Traps into the kernel with system call `number`, in registers the target's ABI picks,
+    /// and yields its `long` result. The only instruction with side effects.
+    Syscall {
+        dst: VReg,
+        number: Value,
+        args: Vec<Value>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(dead_code)] // Lowering does not produce control flow until the language has some
+pub enum Terminator {
+    Return(Value),
+    Jump(BlockId),
+    /// Jumps to the first block when the value is non-zero, otherwise to the second
+    Branch(Value, BlockId, BlockId),
+}
+
+impl Function {
+    pub fn type_of(&self, value: Value) -> Type {
+        match value {
+            Value::Const(int) => int.ctype,
+            Value::Register(reg) => self.registers[reg.0],
+        }
+    }
+
+    pub fn new_register(&mut self, ctype: Type) -> VReg {
+        self.registers.push(ctype);
+        VReg(self.registers.len() - 1)
+    }
+
+    /// Predecessors of every block, indexed by block number
+    pub fn predecessors(&self) -> Vec<Vec<BlockId>> {
+        let mut preds = vec![Vec::new(); self.blocks.len()];
+        for (i, block) in self.blocks.iter().enumerate() {
+            for succ in block.terminator.successors() {
+                if !preds[succ.0].contains(&BlockId(i)) {
+                    preds[succ.0].push(BlockId(i));
+                }
+            }
+        }
+        preds
+    }
+}
+
+impl Instruction {
+    pub fn dst(&self) -> VReg {
+        match self {
+            Instruction::Copy { dst, .. }
+            | Instruction::Phi { dst, .. }
+            | Instruction::Convert { dst, .. }
+            | Instruction::Unary { dst, .. }
+            | Instruction::Binary { dst, .. }
+            | Instruction::Syscall { dst, .. } => *dst,
+        }
+    }
+
+    pub fn dst_mut(&mut self) -> &mut VReg {
+        match self {
+            Instruction::Copy { dst, .. }
+            | Instruction::Phi { dst, .. }
+            | Instruction::Convert { dst, .. }
+            | Instruction::Unary { dst, .. }
+            | Instruction::Binary { dst, .. }
+            | Instruction::Syscall { dst, .. } => dst,
+        }
+    }
+
+    pub fn operands(&self) -> Vec<Value> {
+        match self {
+            Instruction::Copy { src, .. } | Instruction::Convert { src, .. } | Instruction::Unary { src, .. } => {
+                vec![*src]
+            }
+            Instruction::Phi { sources, .. } => sources.iter().map(|(_, value)| *value).collect(),
+            Instruction::Binary { left, right, .. } => vec![*left, *right],
+            Instruction::Syscall { number, args, .. } => std::iter::once(number).chain(args).cloned().collect(),
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Value> {
+        match self {
+            Instruction::Copy { src, .. } | Instruction::Convert { src, .. } | Instruction::Unary { src, .. } => {
+                vec![src]
+            }
+            Instruction::Phi { sources, .. } => sources.iter_mut().map(|(_, value)| value).collect(),
+            Instruction::Binary { left, right, .. } => vec![left, right],
+            Instruction::Syscall { number, args, .. } => std::iter::once(number).chain(args).collect(),
+        }
+    }
+
+    pub fn is_phi(&self) -> bool {
+        matches!(self, Instruction::Phi { .. })
+    }
+
+    /// Whether the instruction does more than compute its result, so that it must run exactly
+    /// where and as often as the program says
+    pub fn has_side_effects(&self) -> bool {
+        matches!(self, Instruction::Syscall { .. })
+    }
+}
+
+impl Terminator {
+    pub fn operands(&self) -> Vec<Value> {
+        match self {
+            Terminator::Return(value) | Terminator::Branch(value, _, _) => vec![*value],
+            Terminator::Jump(_) => vec![],
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Value> {
+        match self {
+            Terminator::Return(value) | Terminator::Branch(value, _, _) => vec![value],
+            Terminator::Jump(_) => vec![],
+        }
+    }
+
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Return(_) => vec![],
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch(_, then, otherwise) => vec![*then, *otherwise],
+        }
+    }
+
+    pub fn successors_mut(&mut self) -> Vec<&mut BlockId> {
+        match self {
+            Terminator::Return(_) => vec![],
+            Terminator::Jump(target) => vec![target],
+            Terminator::Branch(_, then, otherwise) => vec![then, otherwise],
+        }
+    }
+}
+
+pub fn lower(prog: &Program) -> Module {
+    let func = &prog.function;
+    let mut builder = Builder {
+        func: Function {
+            name: func.name.clone(),
+            return_type: Type::Int,
+            registers: Vec::new(),
+            blocks: Vec::new(),
+            model: prog.model,
+        },
+        instructions: Vec::new(),
+    };
+
+    match &func.statement {
+        Statement::Return(exp) => {
+            let value = builder.exp(exp);
+            let value = builder.convert(value, Type::Int);
+            builder.finish_block(Terminator::Return(value));
+        }
+    }
+
+    Module {
+        functions: vec![builder.func],
+    }
+}
+
+struct Builder {
+    func: Function,
+    /// Instructions of the block under construction
+    instructions: Vec<Instruction>,
+}
+
+impl Builder {
+    fn new_register(&mut self, ctype: Type) -> VReg {
+        self.func.new_register(ctype)
+    }
+
+    fn emit(&mut self, inst: Instruction) -> Value {
+        let dst = inst.dst();
+        self.instructions.push(inst);
+        Value::Register(dst)
+    }
+
+    fn finish_block(&mut self, terminator: Terminator) -> BlockId {
+        let instructions = self.instructions.drain(..).collect();
+        self.func.blocks.push(Block {
+            instructions,
+            terminator,
+        });
+        BlockId(self.func.blocks.len() - 1)
+    }
+
+    fn convert(&mut self, value: Value, ctype: Type) -> Value {
+        if self.func.type_of(value) == ctype {
+            return value;
+        }
+        match value {
+            Value::Const(int) => Value::Const(int.cast(ctype, self.func.model)),
+            Value::Register(_) => {
+                let dst = self.new_register(ctype);
+                self.emit(Instruction::Convert { dst, src: value })
+            }
+        }
+    }
+
+    /// An operation in the type it has, converting its operands to that type first. A shift
+    /// count keeps its own type.
+    fn binary(&mut self, operator: Operator, ctype: Type, left: Value, right: Value, span: Span) -> Value {
+        let left = self.convert(left, ctype);
+        let right = if operator.is_shift() {
+            right
+        } else {
+            self.convert(right, ctype)
+        };
+        let op = match operator {
+            Operator::Plus => BinaryOp::Add,
+            Operator::Negate => BinaryOp::Sub,
+            Operator::Star => BinaryOp::Mul,
+            Operator::Divide => BinaryOp::Div,
+            Operator::ShiftLeft => BinaryOp::Shl,
+            Operator::ShiftRight => BinaryOp::Shr,
+            _ => panic!("Unexpected operator '{:?}'", operator),
+        };
+        let dst = self.new_register(ctype);
+        self.emit(Instruction::Binary {
+            op,
+            dst,
+            left,
+            right,
+            span: Some(span),
+        })
+    }
+
+    fn exp(&mut self, exp: &Expression) -> Value {
+        match &exp.kind {
+            ExpressionKind::Binary { left, operator, right } => {
+                let l = self.exp(left);
+                let r = self.exp(right);
+                self.binary(*operator, exp.ctype(), l, r, exp.span)
+            }
+            ExpressionKind::Parenthesized(exp) => self.exp(exp),
+            ExpressionKind::Const(int) => Value::Const(*int),
+            ExpressionKind::Unary { operator, operand } => {
+                let src = self.exp(operand);
+                match operator {
+                    Operator::Negate | Operator::Not => {
+                        let op = match operator {
+                            Operator::Negate => UnaryOp::Neg,
+                            _ => UnaryOp::Not,
+                        };
+                        let ctype = exp.ctype();
+                        let src = self.convert(src, ctype);
+                        let dst = self.new_register(ctype);
+                        self.emit(Instruction::Unary {
+                            op,
+                            dst,
+                            src,
+                            span: Some(exp.span),
+                        })
+                    }
+                    // Compared in the operand's own type, while the result is an `int`
+                    Operator::Bang => {
+                        let dst = self.new_register(exp.ctype());
+                        let zero = Value::Const(Constant {
+                            value: 0,
+                            ctype: operand.ctype(),
+                        });
+                        self.emit(Instruction::Binary {
+                            op: BinaryOp::Equal,
+                            dst,
+                            left: src,
+                            right: zero,
+                            span: Some(exp.span),
+                        })
+                    }
+                    _ => panic!("Unexpected operator '{:?}'", operator),
+                }
+            }
+            // Semantic analysis resolves every call, and rcc only calls builtins
+            ExpressionKind::Call { name, .. } => panic!("Unresolved call to '{}'", name),
+            ExpressionKind::Syscall { number, args } => {
+                let number = self.exp(number);
+                let number = self.convert(number, Type::Long);
+                let args = args
+                    .iter()
+                    .map(|arg| {
+                        let arg = self.exp(arg);
+                        self.convert(arg, Type::Long)
+                    })
+                    .collect();
+                let dst = self.new_register(exp.ctype());
+                self.emit(Instruction::Syscall { dst, number, args })
+            }
+        }
+    }
+}
+
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for func in &self.functions {
+            write!(f, "{}", func)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "function {}() -> {} {{", self.name, self.return_type)?;
+        for (i, block) in self.blocks.iter().enumerate() {
+            writeln!(f, "{}:", BlockId(i))?;
+            for inst in &block.instructions {
+                let dst = inst.dst();
+                write!(f, "\t{}: {} = ", dst, self.registers[dst.0])?;
+                match inst {
+                    Instruction::Copy { src, .. } => writeln!(f, "{}", src)?,
+                    Instruction::Phi { sources, .. } => {
+                        let sources: Vec<String> = sources
+                            .iter()
+                            .map(|(block, value)| format!("[{}, {}]", block, value))
+                            .collect();
+                        writeln!(f, "phi {}", sources.join(", "))?
+                    }
+                    Instruction::Convert { src, .. } => writeln!(f, "convert {}", src)?,
+                    Instruction::Unary { op, src, .. } => writeln!(f, "{} {}", op, src)?,
+                    Instruction::Binary { op, left, right, .. } => writeln!(f, "{} {}, {}", op, left, right)?,
+                    Instruction::Syscall { .. } => {
+                        let operands: Vec<String> = inst.operands().iter().map(Value::to_string).collect();
+                        writeln!(f, "syscall {}", operands.join(", "))?
+                    }
+                }
+            }
+            match &block.terminator {
+                Terminator::Return(value) => writeln!(f, "\tret {}", value)?,
+                Terminator::Jump(target) => writeln!(f, "\tjmp {}", target)?,
+                Terminator::Branch(value, then, otherwise) => writeln!(f, "\tbr {}, {}, {}", value, then, otherwise)?,
+            }
+        }
+        writeln!(f, "}}")
+    }
+}
+
+impl fmt::Display for UnaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            UnaryOp::Neg => "neg",
+            UnaryOp::Not => "not",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            BinaryOp::Add => "add",
+            BinaryOp::Sub => "sub",
+            BinaryOp::Mul => "mul",
+            BinaryOp::Div => "div",
+            BinaryOp::Shl => "shl",
+            BinaryOp::Shr => "shr",
+            BinaryOp::Equal => "eq",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for VReg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "%{}", self.0)
+    }
+}
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bb{}", self.0)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Const(int) => write!(f, "{}", int),
+            Value::Register(reg) => write!(f, "{}", reg),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use lexer;
+    use parser;
+    use sema;
+
+    // Builders for hand-written IR, which the tests of the passes over it share
+
+    pub fn int(value: i128) -> Value {
+        Value::Const(Constant::wrap(value, Type::Int, DataModel::LP64))
+    }
+
+    pub fn reg(number: usize) -> Value {
+        Value::Register(VReg(number))
+    }
+
+    pub fn copy(dst: usize, src: Value) -> Instruction {
+        Instruction::Copy { dst: VReg(dst), src }
+    }
+
+    pub fn binary(op: BinaryOp, dst: usize, left: Value, right: Value) -> Instruction {
+        Instruction::Binary {
+            op,
+            dst: VReg(dst),
+            left,
+            right,
+            span: None,
+        }
+    }
+
+    /// A system call whose result no pass can know
+    pub fn syscall(dst: usize) -> Instruction {
+        Instruction::Syscall {
+            dst: VReg(dst),
+            number: int(39),
+            args: vec![],
+        }
+    }
+
+    pub fn function(registers: usize, blocks: Vec<(Vec<Instruction>, Terminator)>) -> Function {
+        Function {
+            name: String::from("f"),
+            return_type: Type::Int,
+            registers: vec![Type::Int; registers],
+            model: DataModel::LP64,
+            blocks: blocks
+                .into_iter()
+                .map(|(instructions, terminator)| Block {
+                    instructions,
+                    terminator,
+                })
+                .collect(),
+        }
+    }
+
+    fn lower_return(exp: &str) -> Vec<String> {
+        let source = format!("int main() {{ return {}; }}", exp);
+        let mut prog = parser::parse(lexer::lex(&source).unwrap(), DataModel::LP64).unwrap();
+        sema::analyze(&mut prog, &mut Vec::new()).unwrap();
+        lower(&prog).to_string().lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn unary_operators_act_on_promoted_operands() {
+        assert_eq!(
+            lower_return("-(1 + 2)"),
+            [
+                "function main() -> int {",
+                "bb0:",
+                "\t%0: int = add 1, 2",
+                "\t%1: int = neg %0",
+                "\tret %1",
+                "}",
+            ]
+        );
+        // `!` compares with a zero of the operand's type and yields an `int`
+        assert_eq!(
+            lower_return("!(1 * 2u)")[2..4],
+            ["\t%0: unsigned int = mul 1u, 2u", "\t%1: int = eq %0, 0u"]
+        );
+        assert_eq!(lower_return("~(1 + 2)")[3], "\t%1: int = not %0");
+    }
+
+    #[test]
+    fn binary_operators_convert_to_the_common_type() {
+        assert_eq!(
+            lower_return("(1 + 2) * 3l"),
+            [
+                "function main() -> int {",
+                "bb0:",
+                "\t%0: int = add 1, 2",
+                "\t%1: long = convert %0",
+                "\t%2: long = mul %1, 3l",
+                "\t%3: int = convert %2",
+                "\tret %3",
+                "}",
+            ]
+        );
+        // Constants are converted in place rather than by an instruction
+        assert_eq!(
+            lower_return("(1u + 2) - 3")[2..4],
+            ["\t%0: unsigned int = add 1u, 2u", "\t%1: unsigned int = sub %0, 3u"]
+        );
+    }
+
+    #[test]
+    fn shift_counts_keep_their_own_type() {
+        assert_eq!(
+            lower_return("(1 + 2) << 3ul")[2..5],
+            ["\t%0: int = add 1, 2", "\t%1: int = shl %0, 3ul", "\tret %1"]
+        );
+        assert_eq!(lower_return("1l >> 2")[2], "\t%0: long = shr 1l, 2");
+    }
+
+    #[test]
+    fn the_return_value_is_converted_to_int() {
+        assert_eq!(
+            lower_return("1l - 2")[2..5],
+            ["\t%0: long = sub 1l, 2l", "\t%1: int = convert %0", "\tret %1"]
+        );
+        assert_eq!(lower_return("7")[2], "\tret 7");
+    }
+
+    #[test]
+    fn system_call_operands_are_longs() {
+        assert_eq!(
+            lower_return("__builtin_syscall(60, 1 + 2)")[2..6],
+            [
+                "\t%0: int = add 1, 2",
+                "\t%1: long = convert %0",
+                "\t%2: long = syscall 60l, %1",
+                "\t%3: int = convert %2",
+            ]
+        );
+    }
+}
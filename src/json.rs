@@ -0,0 +1,89 @@
+use std::fmt;
+
+/// How a stage is written out: readable text, or JSON for other tools
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+/// A JSON value, for the machine-readable forms of the compiler's stages. Objects keep their
+/// keys in the order they are written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Json {
+    Number(i128),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(&'static str, Json)>),
+}
+
+impl Json {
+    pub fn string(text: &str) -> Json {
+        Json::String(String::from(text))
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Number(number) => write!(f, "{}", number),
+            Json::String(text) => write_string(f, text),
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, text: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in text.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_written_compactly() {
+        let value = Json::Object(vec![
+            ("name", Json::string("main")),
+            ("values", Json::Array(vec![Json::Number(-1), Json::Number(2)])),
+            ("empty", Json::Array(Vec::new())),
+        ]);
+        assert_eq!(value.to_string(), r#"{"name":"main","values":[-1,2],"empty":[]}"#);
+    }
+
+    #[test]
+    fn strings_are_escaped() {
+        assert_eq!(Json::string("a\"b\\c\n\u{1}").to_string(), r#""a\"b\\c\n\u0001""#);
+    }
+}
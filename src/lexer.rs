@@ -1,21 +1,47 @@
 extern crate regex;
 
-#[derive(Debug)]
+use diagnostic::Diagnostic;
+use json::{Format, Json};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Keyword {
     Int,
     Return,
 }
 
-#[derive(Debug)]
+impl Keyword {
+    pub fn spelling(self) -> &'static str {
+        match self {
+            Keyword::Int => "int",
+            Keyword::Return => "return",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Symbol {
     LeftBrace,
     RightBrace,
     LeftParenthesis,
     RightParenthesis,
+    Comma,
     Semicolon,
 }
 
-#[derive(Debug, Copy, Clone)]
+impl Symbol {
+    pub fn spelling(self) -> &'static str {
+        match self {
+            Symbol::LeftBrace => "{",
+            Symbol::RightBrace => "}",
+            Symbol::LeftParenthesis => "(",
+            Symbol::RightParenthesis => ")",
+            Symbol::Comma => ",",
+            Symbol::Semicolon => ";",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Operator {
     Negate,
     Not,
@@ -23,10 +49,36 @@ pub enum Operator {
     Plus,
     Star,
     Divide,
+    ShiftLeft,
+    ShiftRight,
 }
 
-#[derive(Debug)]
+impl Operator {
+    /// How the operator is written in C
+    pub fn spelling(self) -> &'static str {
+        match self {
+            Operator::Negate => "-",
+            Operator::Not => "~",
+            Operator::Bang => "!",
+            Operator::Plus => "+",
+            Operator::Star => "*",
+            Operator::Divide => "/",
+            Operator::ShiftLeft => "<<",
+            Operator::ShiftRight => ">>",
+        }
+    }
+
+    /// Whether the operator shifts its left operand by its right one, which keeps its own
+    /// type instead of converting to the left one's
+    pub fn is_shift(self) -> bool {
+        matches!(self, Operator::ShiftLeft | Operator::ShiftRight)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
 pub enum Integer {
+    Binary = 2,
+    Octal = 8,
     Decimal = 10,
     Hexadecimal = 16,
 }
@@ -43,7 +95,7 @@ pub enum TokenType {
 impl TokenType {
     fn bound(&self) -> &'static str {
         match self {
-            TokenType::Keyword { .. } => r"\b",
+            TokenType::Keyword { .. } | TokenType::Integer { .. } => r"\b",
             _ => "",
         }
     }
@@ -61,6 +113,16 @@ impl TokenType {
     fn prepare(&self) -> String {
         format!("{}{}{}{}", r"^", self.regex(), self.bound(), r"\s*")
     }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            TokenType::Symbol { .. } => "symbol",
+            TokenType::Operator { .. } => "operator",
+            TokenType::Keyword { .. } => "keyword",
+            TokenType::Integer { .. } => "integer",
+            TokenType::Identifier { .. } => "identifier",
+        }
+    }
 }
 
 struct TokenDef {
@@ -77,12 +139,52 @@ impl TokenDef {
     }
 }
 
+/// Where a token is in the source, as byte offsets
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// The 1-based line and column of the start, counting columns in characters
+    pub fn line_column(self, source: &str) -> (usize, usize) {
+        let before = &source[..self.start];
+        let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+        (
+            before.matches('\n').count() + 1,
+            before[line_start..].chars().count() + 1,
+        )
+    }
+}
+
 pub struct Token {
     pub ttype: &'static TokenType,
     pub value: Option<String>,
+    pub span: Span,
+}
+
+impl Token {
+    /// The token as it is written in the source
+    pub fn text(&self) -> &str {
+        match (self.ttype, &self.value) {
+            (TokenType::Symbol { stype, .. }, _) => stype.spelling(),
+            (TokenType::Operator { otype, .. }, _) => otype.spelling(),
+            (TokenType::Keyword { ktype, .. }, _) => ktype.spelling(),
+            (_, Some(value)) => value,
+            (_, None) => panic!("Token {:?} has no text", self.ttype),
+        }
+    }
+}
+
+// Optional integer suffix: any ordering of `u` with `l`/`ll` (C11 6.4.4.1)
+macro_rules! int_suffix {
+    () => {
+        r"(?:[uU](?:ll|LL|[lL])?|(?:ll|LL|[lL])[uU]?)?"
+    };
 }
 
-const RAW_PATTERNS: [TokenType; 16] = [
+const RAW_PATTERNS: [TokenType; 21] = [
     TokenType::Symbol {
         regex: r"\{",
         stype: Symbol::LeftBrace,
@@ -99,6 +201,10 @@ const RAW_PATTERNS: [TokenType; 16] = [
         regex: r"\)",
         stype: Symbol::RightParenthesis,
     },
+    TokenType::Symbol {
+        regex: r",",
+        stype: Symbol::Comma,
+    },
     TokenType::Symbol {
         regex: r";",
         stype: Symbol::Semicolon,
@@ -127,6 +233,14 @@ const RAW_PATTERNS: [TokenType; 16] = [
         regex: r"/",
         otype: Operator::Divide,
     },
+    TokenType::Operator {
+        regex: r"<<",
+        otype: Operator::ShiftLeft,
+    },
+    TokenType::Operator {
+        regex: r">>",
+        otype: Operator::ShiftRight,
+    },
     TokenType::Keyword {
         regex: r"int",
         ktype: Keyword::Int,
@@ -136,36 +250,52 @@ const RAW_PATTERNS: [TokenType; 16] = [
         ktype: Keyword::Return,
     },
     TokenType::Integer {
-        regex: r"0x[0-9a-fA-F]+",
+        regex: concat!(r"0[xX][0-9a-fA-F]+", int_suffix!()),
         itype: Integer::Hexadecimal,
     },
-    // TokenType::Integer { regex: r"0[0-7]+", base: 8), // Might not be correct
     TokenType::Integer {
-        regex: r"[0-9]+",
+        regex: concat!(r"0[bB][01]+", int_suffix!()),
+        itype: Integer::Binary,
+    },
+    TokenType::Integer {
+        regex: concat!(r"0[0-7]*", int_suffix!()),
+        itype: Integer::Octal,
+    },
+    TokenType::Integer {
+        regex: concat!(r"[1-9][0-9]*", int_suffix!()),
         itype: Integer::Decimal,
     },
-    TokenType::Identifier { regex: r"[a-zA-Z]+" },
+    TokenType::Identifier {
+        regex: r"[a-zA-Z_][a-zA-Z0-9_]*",
+    },
 ];
 
-pub fn lex(code: &String) -> Vec<Token> {
-    let mut source = code.as_str();
+pub fn lex(code: &str) -> Result<Vec<Token>, Diagnostic> {
+    let mut source = code;
 
     let patterns: Vec<TokenDef> = RAW_PATTERNS.iter().map(TokenDef::create).collect();
 
     let mut tokens = Vec::new();
-    source = source.trim();
+    // Tokens take the whitespace after them, so only the leading whitespace is left to skip
+    source = source.trim_start();
     while !source.is_empty() {
         let mut found = false;
         for pattern in &patterns {
-            if let Some(tok) = pattern.regex.find(&source) {
+            if let Some(tok) = pattern.regex.find(source) {
+                let text = tok.as_str().trim_end();
                 let val = match pattern.ttype {
-                    TokenType::Integer { .. } | TokenType::Identifier { .. } => Some(String::from(tok.as_str().trim())),
+                    TokenType::Integer { .. } | TokenType::Identifier { .. } => Some(String::from(text)),
                     _ => None,
                 };
 
+                let start = code.len() - source.len();
                 tokens.push(Token {
                     value: val,
                     ttype: pattern.ttype,
+                    span: Span {
+                        start,
+                        end: start + text.len(),
+                    },
                 });
                 source = &source[tok.end()..];
                 found = true;
@@ -174,18 +304,165 @@ pub fn lex(code: &String) -> Vec<Token> {
         }
 
         if !found {
-            println!("{}", source);
-            panic!("Unenpected token while lexing");
+            let start = code.len() - source.len();
+            let unexpected = source.chars().next().unwrap_or_default();
+            if unexpected.is_ascii_digit() {
+                let end = source
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(source.len());
+                return Err(Diagnostic::error(invalid_integer(&source[..end])).at(Span {
+                    start,
+                    end: start + end,
+                }));
+            }
+            return Err(
+                Diagnostic::error(format!("unexpected character '{}'", unexpected)).at(Span {
+                    start,
+                    end: start + unexpected.len_utf8(),
+                }),
+            );
         }
     }
 
-    tokens
+    Ok(tokens)
+}
+
+/// Why a word starting with a digit is no integer constant: a digit its base does not have, or
+/// a suffix that is not one of C's, as gcc reports them
+fn invalid_integer(word: &str) -> String {
+    let (prefix, radix, base) = match word.get(..2) {
+        Some("0x") | Some("0X") => (2, 16, "hexadecimal"),
+        Some("0b") | Some("0B") => (2, 2, "binary"),
+        _ if word.starts_with('0') => (0, 8, "octal"),
+        _ => (0, 10, "decimal"),
+    };
+    // Any decimal digit continues the constant, so that a digit out of its base is reported as such
+    let digits = &word[prefix..];
+    let digits_end = digits
+        .find(|c: char| !c.is_digit(radix.max(10)))
+        .unwrap_or(digits.len());
+    if let Some(digit) = digits[..digits_end].chars().find(|c| !c.is_digit(radix)) {
+        return format!("invalid digit '{}' in {} constant", digit, base);
+    }
+    // A prefix without digits is a `0` with a suffix
+    let suffix = if digits_end == 0 {
+        &word[1..]
+    } else {
+        &digits[digits_end..]
+    };
+    format!("invalid suffix '{}' on integer constant", suffix)
 }
 
-pub fn debug(tokens: &[Token]) {
-    println!("LEXING OUTPUT:");
+/// The tokens with their kinds and positions, one per line as text or as a JSON array
+pub fn dump(source: &str, tokens: &[Token], format: Format) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut values = Vec::new();
     for tok in tokens {
-        println!("{:?}", tok.ttype);
+        let text = &source[tok.span.start..tok.span.end];
+        let (line, column) = tok.span.line_column(source);
+        match format {
+            Format::Text => lines.push(format!("{}:{}: {} '{}'", line, column, tok.ttype.kind(), text)),
+            Format::Json => values.push(Json::Object(vec![
+                ("kind", Json::string(tok.ttype.kind())),
+                ("text", Json::string(text)),
+                ("line", Json::Number(line as i128)),
+                ("column", Json::Number(column as i128)),
+                ("start", Json::Number(tok.span.start as i128)),
+                ("end", Json::Number(tok.span.end as i128)),
+            ])),
+        }
+    }
+    if format == Format::Json {
+        lines.push(Json::Array(values).to_string());
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(source: &str) -> Vec<(&'static str, &str)> {
+        lex(source)
+            .unwrap()
+            .iter()
+            .map(|tok| (tok.ttype.kind(), &source[tok.span.start..tok.span.end]))
+            .collect()
+    }
+
+    fn error(source: &str) -> String {
+        match lex(source) {
+            Ok(_) => panic!("'{}' lexed", source),
+            Err(diagnostic) => diagnostic.message,
+        }
+    }
+
+    #[test]
+    fn tokens_take_the_longest_spelling_of_their_kind() {
+        assert_eq!(
+            kinds("int main() { return -~!1; }"),
+            [
+                ("keyword", "int"),
+                ("identifier", "main"),
+                ("symbol", "("),
+                ("symbol", ")"),
+                ("symbol", "{"),
+                ("keyword", "return"),
+                ("operator", "-"),
+                ("operator", "~"),
+                ("operator", "!"),
+                ("integer", "1"),
+                ("symbol", ";"),
+                ("symbol", "}"),
+            ]
+        );
+        assert_eq!(
+            kinds("1<<2>>3"),
+            [
+                ("integer", "1"),
+                ("operator", "<<"),
+                ("integer", "2"),
+                ("operator", ">>"),
+                ("integer", "3"),
+            ]
+        );
+        assert_eq!(
+            kinds("integer returns"),
+            [("identifier", "integer"), ("identifier", "returns")]
+        );
+    }
+
+    #[test]
+    fn integers_keep_their_prefixes_and_suffixes() {
+        for source in &["0x1Fu", "0b101LL", "017", "0", "42ul", "7LLU"] {
+            assert_eq!(kinds(source), [("integer", *source)]);
+        }
+    }
+
+    #[test]
+    fn spans_locate_tokens_by_line_and_column() {
+        let source = "int\n  main";
+        let tokens = lex(source).unwrap();
+        assert_eq!(tokens[1].span, Span { start: 6, end: 10 });
+        assert_eq!(tokens[1].span.line_column(source), (2, 3));
+    }
+
+    #[test]
+    fn digits_outside_the_base_are_reported() {
+        assert_eq!(error("09"), "invalid digit '9' in octal constant");
+        assert_eq!(error("0b102"), "invalid digit '2' in binary constant");
+    }
+
+    #[test]
+    fn bad_suffixes_are_reported() {
+        assert_eq!(error("123abc"), "invalid suffix 'abc' on integer constant");
+        assert_eq!(error("0x"), "invalid suffix 'x' on integer constant");
+        assert_eq!(error("0xfg"), "invalid suffix 'g' on integer constant");
+        assert_eq!(error("1uu"), "invalid suffix 'uu' on integer constant");
+    }
+
+    #[test]
+    fn unknown_characters_are_reported() {
+        assert_eq!(error("int main() { return 1 @ 2; }"), "unexpected character '@'");
     }
-    println!();
 }
@@ -0,0 +1,48 @@
+//! rcc as a library: source text in, assembly, objects or diagnostics out. `Session` holds
+//! the settings and runs every stage; `lex`, `parse` and `generate` run them one at a time.
+
+pub mod diagnostic;
+pub mod lexer;
+pub mod parser;
+pub mod target;
+pub mod types;
+pub mod visit;
+
+mod aarch64;
+mod backend;
+mod elf;
+mod encoder;
+mod fold;
+mod generator;
+mod i386;
+mod ir;
+mod json;
+mod linker;
+mod llvm;
+mod opt;
+mod peephole;
+mod regalloc;
+mod riscv;
+mod sema;
+mod session;
+mod ssa;
+mod unparser;
+mod wasm;
+
+pub use diagnostic::{Diagnostic, Warnings};
+pub use generator::Syntax;
+pub use json::Format;
+pub use lexer::lex;
+pub use opt::Options as Optimizations;
+pub use parser::{parse, Expression, ExpressionKind, Function, Program, Statement};
+pub use session::{Compilation, Emit, Output, Session};
+pub use target::Target;
+pub use unparser::unparse;
+
+/// Compiles a parsed program to assembly for the target, with the default settings
+pub fn generate(prog: Program, target: Target) -> Result<Vec<String>, Vec<Diagnostic>> {
+    match Session::new(target).generate(prog, &[Emit::Assembly])?.outputs.pop() {
+        Some(Output::Text(assembly)) => Ok(assembly),
+        _ => unreachable!(),
+    }
+}
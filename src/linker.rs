@@ -0,0 +1,367 @@
+use elf::Object;
+use encoder;
+use generator;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+/// Where the executable is loaded, the usual base of non-PIE x86-64 executables
+const BASE: u64 = 0x40_0000;
+const PAGE: u64 = 0x1000;
+const HEADER_SIZE: u64 = 64;
+const PROGRAM_HEADER_SIZE: u64 = 56;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+const SHF_WRITE: u64 = 0x1;
+const SHF_ALLOC: u64 = 0x2;
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xfff1;
+const STB_LOCAL: u8 = 0;
+
+const R_X86_64_64: u32 = 1;
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+const R_X86_64_32: u32 = 10;
+const R_X86_64_32S: u32 = 11;
+
+/// The object holding `_start`, for objects that do not bring their own
+pub fn start_stub() -> Object {
+    encoder::assemble(&generator::start())
+}
+
+/// A section header of an input object
+struct Section {
+    kind: u32,
+    flags: u64,
+    offset: usize,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+}
+
+struct Symbol {
+    name: String,
+    info: u8,
+    section: u16,
+    value: u64,
+}
+
+/// A loadable segment of the executable, which maps the file at the same offset from `BASE`
+struct Segment {
+    writable: bool,
+    start: u64,
+    /// End of the part backed by the file, the rest being zeroed
+    file_end: u64,
+    end: u64,
+}
+
+/// A relocatable ELF64 object, read back for linking
+struct Input<'a> {
+    bytes: &'a [u8],
+    sections: Vec<Section>,
+    symbols: Vec<Symbol>,
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(&bytes[at..at + 4]);
+    u32::from_le_bytes(word)
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    let mut word = [0; 8];
+    word.copy_from_slice(&bytes[at..at + 8]);
+    u64::from_le_bytes(word)
+}
+
+fn read_name(bytes: &[u8], at: usize) -> String {
+    let end = bytes[at..]
+        .iter()
+        .position(|byte| *byte == 0)
+        .map_or(bytes.len(), |len| at + len);
+    String::from_utf8_lossy(&bytes[at..end]).into_owned()
+}
+
+fn parse(bytes: &[u8]) -> Result<Input<'_>, String> {
+    if bytes.len() < HEADER_SIZE as usize || bytes[..4] != [0x7f, b'E', b'L', b'F'] {
+        return Err(String::from("not an ELF file"));
+    }
+    if bytes[4] != 2 || bytes[5] != 1 || read_u16(bytes, 16) != 1 || read_u16(bytes, 18) != 62 {
+        return Err(String::from("not a relocatable x86-64 ELF64 object"));
+    }
+    let section_headers = read_u64(bytes, 0x28) as usize;
+    let sections: Vec<Section> = (0..read_u16(bytes, 0x3c) as usize)
+        .map(|i| {
+            let header = section_headers + i * 64;
+            Section {
+                kind: read_u32(bytes, header + 4),
+                flags: read_u64(bytes, header + 8),
+                offset: read_u64(bytes, header + 24) as usize,
+                size: read_u64(bytes, header + 32),
+                link: read_u32(bytes, header + 40),
+                info: read_u32(bytes, header + 44),
+                align: read_u64(bytes, header + 48).max(1),
+            }
+        })
+        .collect();
+
+    let mut symbols = Vec::new();
+    if let Some(symtab) = sections.iter().find(|section| section.kind == SHT_SYMTAB) {
+        let names = sections[symtab.link as usize].offset;
+        for i in 0..symtab.size as usize / 24 {
+            let entry = symtab.offset + i * 24;
+            symbols.push(Symbol {
+                name: read_name(bytes, names + read_u32(bytes, entry) as usize),
+                info: bytes[entry + 4],
+                section: read_u16(bytes, entry + 6),
+                value: read_u64(bytes, entry + 8),
+            });
+        }
+    }
+    Ok(Input {
+        bytes,
+        sections,
+        symbols,
+    })
+}
+
+/// Links relocatable objects into a static x86-64 executable entered at `_start`. Sections
+/// that are only allocated go in a read-only, executable segment with the headers, and
+/// writable ones in a second, read-write segment.
+pub fn link(objects: &[Vec<u8>]) -> Result<Vec<u8>, String> {
+    let inputs = objects
+        .iter()
+        .map(|bytes| parse(bytes))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Place every allocated section, keyed by object and section index: read-only ones right
+    // after the headers, writable ones from the next page on
+    let has_data = inputs.iter().any(|input| {
+        input
+            .sections
+            .iter()
+            .any(|section| is_loaded(section) && is_writable(section))
+    });
+    let program_headers = if has_data { 2 } else { 1 };
+    let mut addresses: HashMap<(usize, usize), u64> = HashMap::new();
+    let mut segments = Vec::new();
+    let mut address = BASE + HEADER_SIZE + program_headers * PROGRAM_HEADER_SIZE;
+    for writable in [false, true] {
+        if writable && !has_data {
+            break;
+        }
+        if writable {
+            address = address.div_ceil(PAGE) * PAGE;
+        }
+        let mut segment = Segment {
+            writable,
+            start: if writable { address } else { BASE },
+            file_end: address,
+            end: address,
+        };
+        for (i, input) in inputs.iter().enumerate() {
+            for (j, section) in input.sections.iter().enumerate() {
+                if !is_loaded(section) || is_writable(section) != writable {
+                    continue;
+                }
+                address = address.div_ceil(section.align) * section.align;
+                addresses.insert((i, j), address);
+                address += section.size;
+                if section.kind == SHT_PROGBITS {
+                    // Uninitialized sections take no room in the file, so they must come last
+                    if segment.end > segment.file_end {
+                        return Err(String::from("uninitialized sections must follow the initialized ones"));
+                    }
+                    segment.file_end = address;
+                }
+                segment.end = address;
+            }
+        }
+        segments.push(segment);
+    }
+
+    // Resolve global symbols across objects
+    let mut globals: HashMap<&str, u64> = HashMap::new();
+    for (i, input) in inputs.iter().enumerate() {
+        for symbol in &input.symbols {
+            if symbol.info >> 4 != STB_LOCAL && symbol.section != SHN_UNDEF {
+                let value = symbol_address(&addresses, i, symbol)?;
+                if globals.insert(&symbol.name, value).is_some() {
+                    return Err(format!("multiple definition of '{}'", symbol.name));
+                }
+            }
+        }
+    }
+    let entry = *globals.get("_start").ok_or("undefined reference to '_start'")?;
+
+    let file_size = segments
+        .iter()
+        .map(|segment| segment.file_end - BASE)
+        .max()
+        .unwrap_or(0);
+    let mut image = vec![0; file_size as usize];
+    for (i, input) in inputs.iter().enumerate() {
+        for (j, section) in input.sections.iter().enumerate() {
+            if let (Some(address), SHT_PROGBITS) = (addresses.get(&(i, j)), section.kind) {
+                let at = (address - BASE) as usize;
+                image[at..at + section.size as usize]
+                    .copy_from_slice(&input.bytes[section.offset..section.offset + section.size as usize]);
+            }
+        }
+    }
+
+    for (i, input) in inputs.iter().enumerate() {
+        for rela in input.sections.iter().filter(|section| section.kind == SHT_RELA) {
+            let target = match addresses.get(&(i, rela.info as usize)) {
+                Some(target) => *target,
+                None => continue,
+            };
+            for k in 0..rela.size as usize / 24 {
+                let entry = rela.offset + k * 24;
+                let offset = read_u64(input.bytes, entry);
+                let info = read_u64(input.bytes, entry + 8);
+                let addend = read_u64(input.bytes, entry + 16) as i64;
+                let symbol = &input.symbols[(info >> 32) as usize];
+                let value = if symbol.section == SHN_UNDEF {
+                    *globals
+                        .get(symbol.name.as_str())
+                        .ok_or_else(|| format!("undefined reference to '{}'", symbol.name))?
+                } else {
+                    symbol_address(&addresses, i, symbol)?
+                };
+
+                let place = target + offset;
+                let at = (place - BASE) as usize;
+                let result = value.wrapping_add(addend as u64);
+                // Without shared objects a PLT entry is the function itself
+                let bytes = match info as u32 {
+                    R_X86_64_64 => result.to_le_bytes().to_vec(),
+                    R_X86_64_PC32 | R_X86_64_PLT32 => {
+                        let displacement = result.wrapping_sub(place) as i64;
+                        let field = i32::try_from(displacement).map_err(|_| relocation_overflow(symbol))?;
+                        field.to_le_bytes().to_vec()
+                    }
+                    R_X86_64_32 => {
+                        let field = u32::try_from(result).map_err(|_| relocation_overflow(symbol))?;
+                        field.to_le_bytes().to_vec()
+                    }
+                    R_X86_64_32S => {
+                        let field = i32::try_from(result as i64).map_err(|_| relocation_overflow(symbol))?;
+                        field.to_le_bytes().to_vec()
+                    }
+                    kind => return Err(format!("unsupported relocation type {}", kind)),
+                };
+                image[at..at + bytes.len()].copy_from_slice(&bytes);
+            }
+        }
+    }
+
+    let mut header = Vec::new();
+    // Magic, 64-bit, little endian, version 1, System V ABI
+    header.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    header.extend(&2u16.to_le_bytes()); // ET_EXEC
+    header.extend(&62u16.to_le_bytes()); // EM_X86_64
+    header.extend(&1u32.to_le_bytes());
+    header.extend(&entry.to_le_bytes());
+    header.extend(&HEADER_SIZE.to_le_bytes());
+    header.extend(&0u64.to_le_bytes()); // No section headers
+    header.extend(&0u32.to_le_bytes());
+    header.extend(&(HEADER_SIZE as u16).to_le_bytes());
+    header.extend(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    header.extend(&(segments.len() as u16).to_le_bytes());
+    header.extend(&64u16.to_le_bytes());
+    header.extend(&0u16.to_le_bytes());
+    header.extend(&0u16.to_le_bytes());
+    for segment in &segments {
+        let flags: u32 = if segment.writable { 0x4 | 0x2 } else { 0x4 | 0x1 };
+        header.extend(&1u32.to_le_bytes()); // PT_LOAD
+        header.extend(&flags.to_le_bytes());
+        header.extend(&(segment.start - BASE).to_le_bytes());
+        header.extend(&segment.start.to_le_bytes());
+        header.extend(&segment.start.to_le_bytes());
+        header.extend(&(segment.file_end - segment.start).to_le_bytes());
+        header.extend(&(segment.end - segment.start).to_le_bytes());
+        header.extend(&PAGE.to_le_bytes());
+    }
+    image[..header.len()].copy_from_slice(&header);
+    Ok(image)
+}
+
+fn is_writable(section: &Section) -> bool {
+    section.flags & SHF_WRITE != 0
+}
+
+/// Sections that take up memory in the running program
+fn is_loaded(section: &Section) -> bool {
+    section.flags & SHF_ALLOC != 0 && (section.kind == SHT_PROGBITS || section.kind == SHT_NOBITS)
+}
+
+fn symbol_address(addresses: &HashMap<(usize, usize), u64>, object: usize, symbol: &Symbol) -> Result<u64, String> {
+    match symbol.section {
+        SHN_ABS => Ok(symbol.value),
+        section => match addresses.get(&(object, section as usize)) {
+            Some(address) => Ok(address + symbol.value),
+            None => Err(format!("'{}' is in a section that is not loaded", symbol.name)),
+        },
+    }
+}
+
+fn relocation_overflow(symbol: &Symbol) -> String {
+    format!("relocation against '{}' out of range", symbol.name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use elf;
+    use generator::{Directive, DoubleArgInst, Line, NoArgInst, Param, Register, Width};
+
+    fn main_returning(int: i64) -> Vec<u8> {
+        elf::write(&encoder::assemble(&[
+            Line::Directive(Directive::Globl, String::from("main")),
+            Line::Label(String::from("main")),
+            Line::DoubleArgInst(
+                DoubleArgInst::Mov,
+                Param::Const(int),
+                Param::Register(Register::RAX, Width::Long),
+            ),
+            Line::NoArgInst(NoArgInst::Ret),
+        ]))
+    }
+
+    #[test]
+    fn links_start_stub_to_main() {
+        let executable = link(&[elf::write(&start_stub()), main_returning(42)]).unwrap();
+        assert_eq!(&executable[..4], b"\x7fELF");
+        assert_eq!(read_u16(&executable, 16), 2);
+        let entry = read_u64(&executable, 24);
+        // The headers take 120 bytes, and the text is aligned to 16
+        assert_eq!(entry, BASE + 128);
+
+        // `call main` lands on the `mov $42, %eax` of the second object
+        let call = (entry - BASE) as usize + 2;
+        assert_eq!(executable[call], 0xe8);
+        let displacement = read_u32(&executable, call + 1) as i32 as i64;
+        let main = (call as i64 + 5 + displacement) as usize;
+        assert_eq!(executable[main..main + 6], [0xb8, 42, 0, 0, 0, 0xc3]);
+    }
+
+    #[test]
+    fn reports_undefined_and_duplicate_symbols() {
+        assert_eq!(
+            link(&[elf::write(&start_stub())]),
+            Err(String::from("undefined reference to 'main'"))
+        );
+        assert_eq!(
+            link(&[elf::write(&start_stub()), main_returning(1), main_returning(2)]),
+            Err(String::from("multiple definition of 'main'"))
+        );
+    }
+}
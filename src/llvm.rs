@@ -0,0 +1,294 @@
+use lexer::Operator;
+use parser::{Expression, ExpressionKind, Program, Statement};
+use std::cmp::Ordering;
+use std::fmt;
+use target::Target;
+use types::{Constant, DataModel, Type};
+
+/// An operand of an LLVM instruction: a constant written inline or a named value
+#[derive(Debug, Clone)]
+enum Operand {
+    Const(i64),
+    Local(String),
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Const(int) => write!(f, "{}", int),
+            Operand::Local(name) => write!(f, "%{}", name),
+        }
+    }
+}
+
+/// The LLVM integer type of a C type; signedness lives in the instructions instead
+fn llvm_type(ctype: Type, model: DataModel) -> String {
+    format!("i{}", ctype.bits(model))
+}
+
+/// A textual LLVM IR module for the program, which `llc` or `opt` can take over from. Its
+/// pointers have the opaque `ptr` type of LLVM 15 and later.
+pub fn generate(prog: &Program, target: Target) -> Vec<String> {
+    let func = &prog.function;
+    let return_type = llvm_type(Type::Int, prog.model);
+    let mut builder = Builder {
+        lines: Vec::new(),
+        next: 0,
+        target,
+        model: prog.model,
+    };
+
+    // Every return stores to one slot and branches to a single exit block, as clang does at -O0
+    builder.lines.push(String::from("entry:"));
+    builder.push(format!("%retval = alloca {}, align 4", return_type));
+    match &func.statement {
+        Statement::Return(exp) => {
+            let value = builder.exp(exp);
+            let value = builder.convert(value, exp.ctype(), Type::Int);
+            builder.push(format!("store {} {}, ptr %retval, align 4", return_type, value));
+            builder.push(String::from("br label %return"));
+        }
+    }
+    builder.lines.push(String::from("return:"));
+    let result = builder.temp();
+    builder.push(format!("{} = load {}, ptr %retval, align 4", result, return_type));
+    builder.push(format!("ret {} {}", return_type, result));
+
+    let mut lines = vec![
+        format!("; ModuleID = '{}'", func.name),
+        format!("target triple = \"{}\"", target.triple()),
+        String::new(),
+        format!("define dso_local {} @{}() {{", return_type, func.name),
+    ];
+    lines.append(&mut builder.lines);
+    lines.push(String::from("}"));
+    lines
+}
+
+struct Builder {
+    lines: Vec<String>,
+    /// Temporaries are named `%tN` so that instructions never have to be numbered in order
+    next: usize,
+    target: Target,
+    model: DataModel,
+}
+
+/// How a target traps into the kernel, as inline assembly constraints
+struct SyscallAbi {
+    instruction: &'static str,
+    result: &'static str,
+    number: &'static str,
+    args: [&'static str; 6],
+    clobbers: &'static str,
+}
+
+impl SyscallAbi {
+    fn of(target: Target) -> SyscallAbi {
+        match target {
+            Target::X86_64 => SyscallAbi {
+                instruction: "syscall",
+                result: "{rax}",
+                number: "{rax}",
+                args: ["{rdi}", "{rsi}", "{rdx}", "{r10}", "{r8}", "{r9}"],
+                clobbers: "~{rcx},~{r11},~{memory}",
+            },
+            Target::I386 => SyscallAbi {
+                instruction: "int $$0x80",
+                result: "{eax}",
+                number: "{eax}",
+                args: ["{ebx}", "{ecx}", "{edx}", "{esi}", "{edi}", "{ebp}"],
+                clobbers: "~{memory}",
+            },
+            Target::AArch64 => SyscallAbi {
+                instruction: "svc #0",
+                result: "{x0}",
+                number: "{x8}",
+                args: ["{x0}", "{x1}", "{x2}", "{x3}", "{x4}", "{x5}"],
+                clobbers: "~{memory}",
+            },
+            Target::RiscV64 => SyscallAbi {
+                instruction: "ecall",
+                result: "{x10}",
+                number: "{x17}",
+                args: ["{x10}", "{x11}", "{x12}", "{x13}", "{x14}", "{x15}"],
+                clobbers: "~{memory}",
+            },
+            Target::Wasm32 => panic!("__builtin_syscall is not available on wasm32"),
+        }
+    }
+}
+
+impl Builder {
+    fn push(&mut self, inst: String) {
+        self.lines.push(format!("  {}", inst));
+    }
+
+    fn temp(&mut self) -> Operand {
+        self.next += 1;
+        Operand::Local(format!("t{}", self.next - 1))
+    }
+
+    fn emit(&mut self, inst: String) -> Operand {
+        let dst = self.temp();
+        self.push(format!("{} = {}", dst, inst));
+        dst
+    }
+
+    /// Converts a value between C types, folding the conversion of constants
+    fn convert(&mut self, value: Operand, from: Type, to: Type) -> Operand {
+        if let Operand::Const(int) = value {
+            let int = Constant::wrap(i128::from(int), from, self.model).cast(to, self.model);
+            return Operand::Const(int.as_i64());
+        }
+        let (from_type, to_type) = (llvm_type(from, self.model), llvm_type(to, self.model));
+        let op = match from.bits(self.model).cmp(&to.bits(self.model)) {
+            Ordering::Equal => return value,
+            Ordering::Greater => "trunc",
+            Ordering::Less if from.is_signed() => "sext",
+            Ordering::Less => "zext",
+        };
+        self.emit(format!("{} {} {} to {}", op, from_type, value, to_type))
+    }
+
+    /// Emits an operand, converted to the type of the operation on it
+    fn operand(&mut self, operand: &Expression, ctype: Type) -> Operand {
+        let value = self.exp(operand);
+        self.convert(value, operand.ctype(), ctype)
+    }
+
+    fn exp(&mut self, exp: &Expression) -> Operand {
+        let ctype = exp.ctype();
+        let ty = llvm_type(ctype, self.model);
+        // Signed overflow is undefined in C, which `nsw` passes on to the optimizer
+        let nsw = if ctype.is_signed() { " nsw" } else { "" };
+        match &exp.kind {
+            ExpressionKind::Binary { left, operator, right } => {
+                // A shift count has a type of its own, while LLVM shifts take both operands in one
+                let left = self.operand(left, ctype);
+                let right = self.operand(right, ctype);
+                let op = match operator {
+                    Operator::Plus => format!("add{}", nsw),
+                    Operator::Negate => format!("sub{}", nsw),
+                    Operator::Star => format!("mul{}", nsw),
+                    Operator::Divide if ctype.is_signed() => String::from("sdiv"),
+                    Operator::Divide => String::from("udiv"),
+                    Operator::ShiftLeft => String::from("shl"),
+                    Operator::ShiftRight if ctype.is_signed() => String::from("ashr"),
+                    Operator::ShiftRight => String::from("lshr"),
+                    _ => panic!("Unexpected operator '{:?}'", operator),
+                };
+                self.emit(format!("{} {} {}, {}", op, ty, left, right))
+            }
+            ExpressionKind::Parenthesized(exp) => self.exp(exp),
+            ExpressionKind::Const(int) => Operand::Const(int.as_i64()),
+            ExpressionKind::Unary { operator, operand } => match operator {
+                Operator::Negate => {
+                    let src = self.operand(operand, ctype);
+                    self.emit(format!("sub{} {} 0, {}", nsw, ty, src))
+                }
+                Operator::Not => {
+                    let src = self.operand(operand, ctype);
+                    self.emit(format!("xor {} {}, -1", ty, src))
+                }
+                // Compared in the operand's own type, while the result is an `int`
+                Operator::Bang => {
+                    let src = self.exp(operand);
+                    let src_type = llvm_type(operand.ctype(), self.model);
+                    let is_zero = self.emit(format!("icmp eq {} {}, 0", src_type, src));
+                    self.emit(format!("zext i1 {} to {}", is_zero, ty))
+                }
+                _ => panic!("Unexpected operator '{:?}'", operator),
+            },
+            ExpressionKind::Call { name, .. } => panic!("Unresolved call to '{}'", name),
+            ExpressionKind::Syscall { number, args } => {
+                // The number and the arguments are all passed as `long`, like the kernel's registers
+                let mut operands = Vec::new();
+                for exp in std::iter::once(&**number).chain(args) {
+                    let value = self.operand(exp, Type::Long);
+                    operands.push(format!("{} {}", ty, value));
+                }
+                let abi = SyscallAbi::of(self.target);
+                let mut constraints = vec![format!("={}", abi.result), String::from(abi.number)];
+                constraints.extend(abi.args[..args.len()].iter().map(|arg| String::from(*arg)));
+                constraints.push(String::from(abi.clobbers));
+                self.emit(format!(
+                    "call {} asm sideeffect \"{}\", \"{}\"({})",
+                    ty,
+                    abi.instruction,
+                    constraints.join(","),
+                    operands.join(", ")
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lexer;
+    use parser;
+    use sema;
+
+    fn compile(source: &str) -> Vec<String> {
+        let mut prog = parser::parse(lexer::lex(source).unwrap(), DataModel::LP64).unwrap();
+        sema::analyze(&mut prog, &mut Vec::new()).unwrap();
+        generate(&prog, Target::X86_64)
+    }
+
+    #[test]
+    fn function_shape() {
+        assert_eq!(
+            compile("int main() { return !2u; }"),
+            vec![
+                "; ModuleID = 'main'",
+                "target triple = \"x86_64-pc-linux-gnu\"",
+                "",
+                "define dso_local i32 @main() {",
+                "entry:",
+                "  %retval = alloca i32, align 4",
+                "  %t0 = icmp eq i32 2, 0",
+                "  %t1 = zext i1 %t0 to i32",
+                "  store i32 %t1, ptr %retval, align 4",
+                "  br label %return",
+                "return:",
+                "  %t2 = load i32, ptr %retval, align 4",
+                "  ret i32 %t2",
+                "}",
+            ]
+        );
+    }
+
+    #[test]
+    fn pointers_are_opaque() {
+        let lines = compile("int main() { return 2; }");
+        assert!(lines.contains(&String::from("  store i32 2, ptr %retval, align 4")));
+        assert!(lines.contains(&String::from("  %t0 = load i32, ptr %retval, align 4")));
+        assert!(!lines.iter().any(|line| line.contains("i32*")));
+    }
+
+    #[test]
+    fn conversions_follow_signedness() {
+        let lines = compile("int main() { return 1 + 2l - 3u / 4ul; }");
+        assert!(lines.contains(&String::from("  %t0 = add nsw i64 1, 2")));
+        assert!(lines.contains(&String::from("  %t1 = udiv i64 3, 4")));
+        assert!(lines.contains(&String::from("  %t2 = sub i64 %t0, %t1")));
+        assert!(lines.contains(&String::from("  %t3 = trunc i64 %t2 to i32")));
+    }
+
+    #[test]
+    fn shifts_follow_the_type_of_their_left_operand() {
+        let lines = compile("int main() { return (8 >> __builtin_syscall(39)) + (8u >> 1); }");
+        assert!(lines.contains(&String::from("  %t1 = trunc i64 %t0 to i32")));
+        assert!(lines.contains(&String::from("  %t2 = ashr i32 8, %t1")));
+        assert!(lines.contains(&String::from("  %t3 = lshr i32 8, 1")));
+    }
+
+    #[test]
+    fn syscalls_are_inline_assembly() {
+        let lines = compile("int main() { return __builtin_syscall(60, 1u); }");
+        assert!(lines.contains(&String::from(
+            "  %t0 = call i64 asm sideeffect \"syscall\", \"={rax},{rax},{rdi},~{rcx},~{r11},~{memory}\"(i64 60, i64 1)"
+        )));
+    }
+}
@@ -1,49 +1,284 @@
+extern crate rcc;
+
+use assembler::{Echo, Toolchain};
+use driver::{Command, Input, Language, Options, Stage};
+use rcc::{Compilation, Emit, Format, Output, Session, Target};
 use std::env;
-use std::fs::File;
-use std::io::prelude::*;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
 
 mod assembler;
+mod driver;
 mod exporter;
-mod generator;
-mod lexer;
-mod parser;
-
-static DEBUG_FLAG: &str = "RCC_DEBUG";
 
 fn main() {
-    let path = env::args().nth(1).expect("expected a file to compile");
+    let options = match driver::parse(env::args().skip(1)) {
+        Ok(Command::Build(options)) => *options,
+        Ok(Command::Help) => {
+            print!("{}", driver::USAGE);
+            return;
+        }
+        Ok(Command::Version) => {
+            println!("rcc {}", env!("CARGO_PKG_VERSION"));
+            return;
+        }
+        Err(err) => fail(&format!("rcc: error: {}", err)),
+    };
+
+    if options.stage == Stage::Source {
+        if let Err(err) = preprocess(&options) {
+            fail(&format!("rcc: error: {}", err));
+        }
+        return;
+    }
+
+    let mut driver = Driver {
+        options: &options,
+        session: Session::new(options.target)
+            .syntax(options.syntax)
+            .optimizations(options.optimizations.clone())
+            .nostdlib(options.nostdlib)
+            .warnings(options.warnings.clone())
+            .sanitize(options.sanitize)
+            .format(options.format),
+        toolchain: Toolchain {
+            target: options.target,
+            assembler: options.assembler.clone(),
+            linker: options.linker.clone(),
+            echo: options.echo,
+        },
+        temporaries: Vec::new(),
+    };
+    let result = driver.run();
+    for temporary in &driver.temporaries {
+        let _res = fs::remove_file(temporary);
+    }
+    if let Err(err) = result {
+        fail(&err);
+    }
+}
+
+/// Reports what stopped the build and exits with a failing status
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+/// rcc has no preprocessing directives yet, so `-E` passes the C sources through unchanged
+fn preprocess(options: &Options) -> Result<(), String> {
+    let mut lines = Vec::new();
+    for input in options.inputs.iter().filter(|input| input.language == Language::C) {
+        lines.extend(input.read()?.lines().map(String::from));
+    }
+    exporter::to_file(Path::new(options.output.as_deref().unwrap_or("-")), &lines)
+}
 
-    let debug = env::var(DEBUG_FLAG).is_ok();
+/// What an input contributes to the executable
+enum Linkable {
+    /// An x86-64 object rcc encoded itself
+    Encoded(Vec<u8>),
+    File(PathBuf),
+}
 
-    let mut f = File::open(&path).expect("file not found");
+/// The command line over a compilation session: which files go in, where the outputs go, and
+/// which external tools finish the job
+struct Driver<'a> {
+    options: &'a Options,
+    session: Session,
+    toolchain: Toolchain,
+    /// Intermediate files, removed once the driver is done
+    temporaries: Vec<PathBuf>,
+}
 
-    let mut source = String::new();
-    f.read_to_string(&mut source).expect("cannot read file");
+impl<'a> Driver<'a> {
+    fn run(&mut self) -> Result<(), String> {
+        let options = self.options;
+        let mut linked = Vec::new();
+        for input in &options.inputs {
+            let linkable = match input.language {
+                Language::C => self.compile(input)?,
+                Language::Assembly => self.assemble(input, PathBuf::from(&input.path))?,
+                Language::Object => Some(Linkable::File(PathBuf::from(&input.path))),
+            };
+            linked.extend(linkable);
+        }
+        // `--emit` outputs and WebAssembly stop at their text, with nothing to link
+        let native = options.emits.is_empty() && options.target != Target::Wasm32;
+        if options.stage == Stage::Executable && native {
+            self.link(linked)?;
+        }
+        Ok(())
+    }
 
-    let tokens = lexer::lex(&source);
-    if debug {
-        lexer::debug(&tokens);
+    /// Where the output of the stage the driver stops at goes: `-o`, or the input's name with
+    /// the extension of the output in the working directory, as gcc does. Text from standard
+    /// input goes to standard output.
+    fn output(&self, input: &Input, extension: &str) -> PathBuf {
+        match (&self.options.output, input.stem()) {
+            (Some(output), _) => PathBuf::from(output),
+            (None, Some(stem)) => PathBuf::from(format!("{}.{}", stem, extension)),
+            (None, None) => PathBuf::from("-"),
+        }
     }
 
-    let prog = parser::parse(tokens);
-    if debug {
-        parser::debug(&prog);
+    /// Where an object goes, which for standard input has to be named with `-o`
+    fn object_output(&self, input: &Input) -> Result<PathBuf, String> {
+        if self.options.output.is_none() && input.stem().is_none() {
+            return Err(error(String::from("'-c' with standard input needs '-o'")));
+        }
+        Ok(self.output(input, "o"))
     }
 
-    // let syntax = generator::Syntax::ATT;
-    let assembly = generator::generate(&prog, &generator::Syntax::ATT);
-    if debug {
-        generator::debug(&prog);
+    fn temporary(&mut self, extension: &str) -> PathBuf {
+        let name = format!("rcc-{}-{}.{}", process::id(), self.temporaries.len(), extension);
+        let path = env::temp_dir().join(name);
+        self.temporaries.push(path.clone());
+        path
     }
 
-    let mut asm_path = PathBuf::from(&path);
-    asm_path.set_extension("s");
+    /// The extension of the file an output goes to when it is not named
+    fn extension(&self, emit: Emit) -> &'static str {
+        let json = self.options.format == Format::Json;
+        match emit {
+            Emit::Tokens if json => "tokens.json",
+            Emit::Tokens => "tokens",
+            Emit::Ast if json => "ast.json",
+            Emit::Ast => "ast",
+            Emit::Ir => "ir",
+            Emit::Assembly if self.options.target == Target::Wasm32 => "wat",
+            Emit::Assembly => "s",
+            Emit::Object => "o",
+            Emit::Llvm => "ll",
+        }
+    }
 
-    exporter::to_file(&asm_path, &assembly);
+    /// Runs the session over a C input, reporting its warnings, or its errors as the failure
+    fn translate(&self, input: &Input, source: &str, emits: &[Emit]) -> Result<Vec<Output>, String> {
+        match self.session.compile_file(&input.path, source, emits) {
+            Ok(Compilation { outputs, diagnostics }) => {
+                for diagnostic in &diagnostics {
+                    eprintln!("{}", diagnostic.located(&input.path, source));
+                }
+                Ok(outputs)
+            }
+            Err(diagnostics) => {
+                let lines: Vec<String> = diagnostics
+                    .iter()
+                    .map(|diagnostic| diagnostic.located(&input.path, source))
+                    .collect();
+                Err(lines.join("\n"))
+            }
+        }
+    }
+
+    /// Compiles a C input as far as the stage asks, returning what is left to link
+    fn compile(&mut self, input: &Input) -> Result<Option<Linkable>, String> {
+        let options = self.options;
+        let source = input.read().map_err(error)?;
+        if !options.emits.is_empty() {
+            let emits: Vec<Emit> = options.emits.iter().map(|(emit, _)| *emit).collect();
+            let outputs = self.translate(input, &source, &emits)?;
+            for ((emit, path), output) in options.emits.iter().zip(outputs) {
+                let path = match path {
+                    Some(path) => PathBuf::from(path),
+                    None => self.output(input, self.extension(*emit)),
+                };
+                match output {
+                    Output::Text(lines) => exporter::to_file(&path, &lines),
+                    Output::Binary(bytes) => exporter::to_binary_file(&path, &bytes),
+                }
+                .map_err(error)?;
+            }
+            return Ok(None);
+        }
 
-    let mut exe_path = PathBuf::from(&path);
-    exe_path.set_extension("");
+        let emit = if options.stage >= Stage::Object && options.assembles_in_process() {
+            Emit::Object
+        } else {
+            Emit::Assembly
+        };
+        let output = self.translate(input, &source, &[emit])?.remove(0);
+        let assembly = match output {
+            Output::Binary(object) => {
+                if options.stage == Stage::Object {
+                    exporter::to_binary_file(&self.object_output(input)?, &object).map_err(error)?;
+                    return Ok(None);
+                }
+                return Ok(Some(Linkable::Encoded(object)));
+            }
+            Output::Text(text) => text,
+        };
+        // WebAssembly modules stop at their text
+        if options.stage == Stage::Assembly || options.target == Target::Wasm32 {
+            exporter::to_file(&self.output(input, self.extension(Emit::Assembly)), &assembly).map_err(error)?;
+            return Ok(None);
+        }
+        let asm_path = self.temporary("s");
+        exporter::to_file(&asm_path, &assembly).map_err(error)?;
+        self.assemble(input, asm_path)
+    }
+
+    /// Assembles an assembly input, or the assembly generated for a C input, as far as the
+    /// stage asks. `-S` leaves assembly inputs alone.
+    fn assemble(&mut self, input: &Input, asm_path: PathBuf) -> Result<Option<Linkable>, String> {
+        let options = self.options;
+        match options.stage {
+            Stage::Object => {
+                self.toolchain
+                    .assemble(&asm_path, &self.object_output(input)?)
+                    .map_err(error)?;
+                Ok(None)
+            }
+            Stage::Executable if options.links_in_process() || options.linker.is_some() => {
+                // Only gcc links assembly files
+                let obj_path = self.temporary("o");
+                self.toolchain.assemble(&asm_path, &obj_path).map_err(error)?;
+                Ok(Some(Linkable::File(obj_path)))
+            }
+            Stage::Executable => Ok(Some(Linkable::File(asm_path))),
+            Stage::Source | Stage::Assembly => Ok(None),
+        }
+    }
+
+    fn link(&mut self, linked: Vec<Linkable>) -> Result<(), String> {
+        let options = self.options;
+        let exe_path = PathBuf::from(options.output.as_deref().unwrap_or("a.out"));
+        if options.links_in_process() {
+            if options.echo == Echo::DryRun && !self.temporaries.is_empty() {
+                // The objects to link come from commands that were not run
+                return Ok(());
+            }
+            let mut objects = Vec::new();
+            for linkable in linked {
+                objects.push(match linkable {
+                    Linkable::Encoded(object) => object,
+                    Linkable::File(path) => {
+                        fs::read(&path).map_err(|err| format!("rcc: error: {}: {}", path.display(), err))?
+                    }
+                });
+            }
+            let executable = self.session.link(&objects).map_err(|err| format!("rcc: {}", err))?;
+            return assembler::write_executable(&exe_path, &executable).map_err(error);
+        }
+
+        let mut inputs = Vec::new();
+        for linkable in linked {
+            inputs.push(match linkable {
+                Linkable::Encoded(object) => {
+                    let obj_path = self.temporary("o");
+                    exporter::to_binary_file(&obj_path, &object).map_err(error)?;
+                    obj_path
+                }
+                Linkable::File(path) => path,
+            });
+        }
+        self.toolchain
+            .link(&inputs, &exe_path, options.nostdlib, &options.linker_args)
+            .map_err(error)
+    }
+}
 
-    assembler::assemble(&asm_path, &exe_path);
+fn error(err: String) -> String {
+    format!("rcc: error: {}", err)
 }
@@ -0,0 +1,671 @@
+use fold;
+use ir::{BinaryOp, Block, BlockId, Function, Instruction, Module, Terminator, UnaryOp, VReg, Value};
+use lexer::Operator;
+use ssa::{self, DominatorTree};
+use std::collections::{HashMap, HashSet};
+use types::{Constant, Type};
+
+struct Pass {
+    name: &'static str,
+    /// Lowest `-O` level that enables the pass
+    level: u32,
+    run: fn(&mut Function) -> bool,
+}
+
+/// Passes in the order they run; the whole list repeats until none of them changes anything
+const PASSES: [Pass; 5] = [
+    Pass {
+        name: "const-prop",
+        level: 1,
+        run: constant_propagation,
+    },
+    Pass {
+        name: "copy-prop",
+        level: 1,
+        run: copy_propagation,
+    },
+    Pass {
+        name: "cse",
+        level: 2,
+        run: common_subexpression_elimination,
+    },
+    Pass {
+        name: "licm",
+        level: 2,
+        run: loop_invariant_code_motion,
+    },
+    Pass {
+        name: "dce",
+        level: 1,
+        run: dead_code_elimination,
+    },
+];
+
+const MAX_ROUNDS: usize = 8;
+
+/// Passes over the generated assembly rather than the IR, with the `-O` level enabling them
+const MACHINE_PASSES: [(&str, u32); 1] = [("peephole", 1)];
+
+/// Which passes run: an `-O` level, refined by `-f<pass>` and `-fno-<pass>` toggles
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    level: u32,
+    toggles: HashMap<&'static str, bool>,
+}
+
+impl Options {
+    pub fn set_level(&mut self, level: u32) {
+        self.level = level;
+    }
+
+    pub fn toggle(&mut self, name: &str, enabled: bool) -> Result<(), String> {
+        let names = PASSES.iter().map(|pass| pass.name);
+        match names
+            .chain(MACHINE_PASSES.iter().map(|(name, _)| *name))
+            .find(|pass| *pass == name)
+        {
+            Some(pass) => {
+                self.toggles.insert(pass, enabled);
+                Ok(())
+            }
+            None => Err(format!("unknown optimization pass '{}'", name)),
+        }
+    }
+
+    fn enabled(&self, pass: &Pass) -> bool {
+        self.enabled_by_name(pass.name, pass.level)
+    }
+
+    /// Whether the generator should run the named machine pass
+    pub fn machine_pass(&self, name: &str) -> bool {
+        match MACHINE_PASSES.iter().find(|(pass, _)| *pass == name) {
+            Some((pass, level)) => self.enabled_by_name(pass, *level),
+            None => panic!("unknown machine pass '{}'", name),
+        }
+    }
+
+    fn enabled_by_name(&self, name: &str, level: u32) -> bool {
+        match self.toggles.get(name) {
+            Some(enabled) => *enabled,
+            None => self.level >= level,
+        }
+    }
+}
+
+pub fn optimize(module: &mut Module, options: &Options) {
+    let passes: Vec<&Pass> = PASSES.iter().filter(|pass| options.enabled(pass)).collect();
+    if passes.is_empty() {
+        return;
+    }
+
+    for func in &mut module.functions {
+        ssa::construct(func);
+        for _ in 0..MAX_ROUNDS {
+            let mut changed = false;
+            for pass in &passes {
+                changed |= (pass.run)(func);
+            }
+            if !changed {
+                break;
+            }
+        }
+        ssa::destruct(func);
+    }
+}
+
+/// Replaces every use of the registers in `replacements` and drops their definitions
+fn replace_uses(func: &mut Function, replacements: &HashMap<VReg, Value>) {
+    let resolve = |mut value: Value| {
+        while let Value::Register(reg) = value {
+            match replacements.get(&reg) {
+                Some(replacement) => value = *replacement,
+                None => break,
+            }
+        }
+        value
+    };
+    for block in &mut func.blocks {
+        block
+            .instructions
+            .retain(|inst| !replacements.contains_key(&inst.dst()));
+        for inst in &mut block.instructions {
+            for operand in inst.operands_mut() {
+                *operand = resolve(*operand);
+            }
+        }
+        for operand in block.terminator.operands_mut() {
+            *operand = resolve(*operand);
+        }
+    }
+}
+
+/// The value of an instruction whose operands are all constant
+fn evaluate(func: &Function, inst: &Instruction, constants: &HashMap<VReg, Constant>) -> Option<Constant> {
+    let constant = |value: Value| match value {
+        Value::Const(int) => Some(int),
+        Value::Register(reg) => constants.get(&reg).cloned(),
+    };
+    // Diagnostics about constant operations were already reported on the source
+    let mut diagnostics = Vec::new();
+    match inst {
+        Instruction::Copy { src, .. } => constant(*src),
+        Instruction::Phi { dst, sources } => {
+            let mut values = sources
+                .iter()
+                .filter(|(_, value)| *value != Value::Register(*dst))
+                .map(|(_, value)| constant(*value));
+            let first = values.next()??;
+            if values.all(|value| value == Some(first)) {
+                Some(first)
+            } else {
+                None
+            }
+        }
+        Instruction::Convert { dst, src } => Some(constant(*src)?.cast(func.registers[dst.0], func.model)),
+        Instruction::Unary { op, src, .. } => {
+            let operator = match op {
+                UnaryOp::Neg => Operator::Negate,
+                UnaryOp::Not => Operator::Not,
+            };
+            fold::unary(operator, constant(*src)?, func.model, &mut diagnostics)
+        }
+        Instruction::Binary { op, left, right, .. } => {
+            let (left, right) = (constant(*left)?, constant(*right)?);
+            let operator = match op {
+                BinaryOp::Add => Operator::Plus,
+                BinaryOp::Sub => Operator::Negate,
+                BinaryOp::Mul => Operator::Star,
+                BinaryOp::Div => Operator::Divide,
+                BinaryOp::Shl => Operator::ShiftLeft,
+                BinaryOp::Shr => Operator::ShiftRight,
+                BinaryOp::Equal => return Some(Constant::wrap((left == right) as i128, Type::Int, func.model)),
+            };
+            fold::binary(operator, left, right, func.model, &mut diagnostics)
+        }
+        Instruction::Syscall { .. } => None,
+    }
+}
+
+/// Propagates constants through the function and resolves branches on constant conditions
+fn constant_propagation(func: &mut Function) -> bool {
+    let mut constants = HashMap::new();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in &func.blocks {
+            for inst in &block.instructions {
+                if constants.contains_key(&inst.dst()) {
+                    continue;
+                }
+                if let Some(int) = evaluate(func, inst, &constants) {
+                    constants.insert(inst.dst(), int);
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    let replacements: HashMap<VReg, Value> = constants.iter().map(|(reg, int)| (*reg, Value::Const(*int))).collect();
+    replace_uses(func, &replacements);
+
+    let mut folded_branch = false;
+    for i in 0..func.blocks.len() {
+        if let Terminator::Branch(Value::Const(int), then, otherwise) = func.blocks[i].terminator {
+            let (taken, skipped) = if int.is_zero() {
+                (otherwise, then)
+            } else {
+                (then, otherwise)
+            };
+            func.blocks[i].terminator = Terminator::Jump(taken);
+            if skipped != taken {
+                remove_phi_sources(&mut func.blocks[skipped.0], BlockId(i));
+            }
+            folded_branch = true;
+        }
+    }
+    if folded_branch {
+        ssa::remove_unreachable(func);
+    }
+
+    !replacements.is_empty() || folded_branch
+}
+
+fn remove_phi_sources(block: &mut Block, pred: BlockId) {
+    for inst in &mut block.instructions {
+        if let Instruction::Phi { sources, .. } = inst {
+            sources.retain(|(source, _)| *source != pred);
+        }
+    }
+}
+
+/// Replaces registers that are copies of another value, including phis of a single value
+fn copy_propagation(func: &mut Function) -> bool {
+    let mut copies = HashMap::new();
+    for block in &func.blocks {
+        for inst in &block.instructions {
+            match inst {
+                Instruction::Copy { dst, src } => {
+                    copies.insert(*dst, *src);
+                }
+                Instruction::Phi { dst, sources } => {
+                    let mut values = sources
+                        .iter()
+                        .map(|(_, value)| *value)
+                        .filter(|value| *value != Value::Register(*dst));
+                    if let Some(first) = values.next() {
+                        if values.all(|value| value == first) {
+                            copies.insert(*dst, first);
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+    replace_uses(func, &copies);
+    !copies.is_empty()
+}
+
+/// Removes instructions whose result is never used, unless they have side effects
+fn dead_code_elimination(func: &mut Function) -> bool {
+    let mut live = HashSet::new();
+    for block in &func.blocks {
+        for operand in block.terminator.operands() {
+            if let Value::Register(reg) = operand {
+                live.insert(reg);
+            }
+        }
+    }
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in &func.blocks {
+            for inst in &block.instructions {
+                if !live.contains(&inst.dst()) && !inst.has_side_effects() {
+                    continue;
+                }
+                for operand in inst.operands() {
+                    if let Value::Register(reg) = operand {
+                        changed |= live.insert(reg);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut removed = false;
+    for block in &mut func.blocks {
+        let before = block.instructions.len();
+        block
+            .instructions
+            .retain(|inst| live.contains(&inst.dst()) || inst.has_side_effects());
+        removed |= block.instructions.len() != before;
+    }
+    removed
+}
+
+#[derive(PartialEq, Eq, Hash, Clone)]
+enum Expression {
+    Convert(Value, Type),
+    Unary(UnaryOp, Value),
+    Binary(BinaryOp, Value, Value),
+}
+
+/// Reuses the result of an identical computation in a dominating block
+fn common_subexpression_elimination(func: &mut Function) -> bool {
+    let dom = DominatorTree::new(func);
+    let mut changed = false;
+    cse_block(func, &dom, BlockId(0), HashMap::new(), &mut changed);
+    changed
+}
+
+fn cse_block(
+    func: &mut Function,
+    dom: &DominatorTree,
+    block: BlockId,
+    mut available: HashMap<Expression, VReg>,
+    changed: &mut bool,
+) {
+    for i in 0..func.blocks[block.0].instructions.len() {
+        let inst = &func.blocks[block.0].instructions[i];
+        let expression = match *inst {
+            Instruction::Convert { dst, src } => Expression::Convert(src, func.registers[dst.0]),
+            Instruction::Unary { op, src, .. } => Expression::Unary(op, src),
+            Instruction::Binary { op, left, right, .. } => match op {
+                BinaryOp::Add | BinaryOp::Mul | BinaryOp::Equal if right < left => Expression::Binary(op, right, left),
+                _ => Expression::Binary(op, left, right),
+            },
+            Instruction::Copy { .. } | Instruction::Phi { .. } | Instruction::Syscall { .. } => continue,
+        };
+        let dst = inst.dst();
+        match available.get(&expression) {
+            Some(previous) => {
+                func.blocks[block.0].instructions[i] = Instruction::Copy {
+                    dst,
+                    src: Value::Register(*previous),
+                };
+                *changed = true;
+            }
+            None => {
+                available.insert(expression, dst);
+            }
+        }
+    }
+
+    for child in dom.children(block).to_vec() {
+        cse_block(func, dom, child, available.clone(), changed);
+    }
+}
+
+/// Hoists computations that do not change between iterations into the loop's preheader
+fn loop_invariant_code_motion(func: &mut Function) -> bool {
+    let dom = DominatorTree::new(func);
+    let preds = func.predecessors();
+
+    let mut headers: Vec<BlockId> = Vec::new();
+    for block in &dom.order {
+        for succ in func.blocks[block.0].terminator.successors() {
+            if dom.dominates(succ, *block) && !headers.contains(&succ) {
+                headers.push(succ);
+            }
+        }
+    }
+
+    for header in headers {
+        // The entry block has no edge to place a preheader on
+        if header == BlockId(0) {
+            continue;
+        }
+        let body = natural_loop(&dom, &preds, header);
+        let hoisted = invariants(func, &body);
+        if hoisted.is_empty() {
+            continue;
+        }
+
+        let preheader = preheader(func, &preds, &body, header);
+        let moved: HashSet<VReg> = hoisted.iter().map(Instruction::dst).collect();
+        for block in &body {
+            func.blocks[block.0]
+                .instructions
+                .retain(|inst| !moved.contains(&inst.dst()));
+        }
+        func.blocks[preheader.0].instructions.extend(hoisted);
+        // The control flow changed, so dominators are recomputed on the next round
+        return true;
+    }
+    false
+}
+
+/// Blocks of the loop whose back edges target `header`
+fn natural_loop(dom: &DominatorTree, preds: &[Vec<BlockId>], header: BlockId) -> Vec<BlockId> {
+    let mut body = vec![header];
+    let mut worklist: Vec<BlockId> = preds[header.0]
+        .iter()
+        .filter(|pred| dom.dominates(header, **pred))
+        .cloned()
+        .collect();
+    while let Some(block) = worklist.pop() {
+        if body.contains(&block) {
+            continue;
+        }
+        body.push(block);
+        worklist.extend(preds[block.0].iter().cloned());
+    }
+    body
+}
+
+/// Instructions of the loop whose operands are all defined outside of it, in dependency order
+fn invariants(func: &Function, body: &[BlockId]) -> Vec<Instruction> {
+    let defined: HashSet<VReg> = body
+        .iter()
+        .flat_map(|block| func.blocks[block.0].instructions.iter().map(Instruction::dst))
+        .collect();
+
+    let mut hoisted = Vec::new();
+    let mut invariant = HashSet::new();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in body {
+            for inst in &func.blocks[block.0].instructions {
+                if invariant.contains(&inst.dst()) || !hoistable(inst) {
+                    continue;
+                }
+                let operands_invariant = inst.operands().iter().all(|operand| match operand {
+                    Value::Const(_) => true,
+                    Value::Register(reg) => !defined.contains(reg) || invariant.contains(reg),
+                });
+                if operands_invariant {
+                    invariant.insert(inst.dst());
+                    hoisted.push(inst.clone());
+                    changed = true;
+                }
+            }
+        }
+    }
+    hoisted
+}
+
+/// Whether executing the instruction on every loop entry is safe even if the loop would not have
+fn hoistable(inst: &Instruction) -> bool {
+    match inst {
+        Instruction::Phi { .. } | Instruction::Syscall { .. } => false,
+        // Division may trap, so only a divisor known to be safe allows it
+        Instruction::Binary {
+            op: BinaryOp::Div,
+            right,
+            ..
+        } => match right {
+            Value::Const(int) => !int.is_zero() && int.mathematical() != -1,
+            Value::Register(_) => false,
+        },
+        _ => true,
+    }
+}
+
+/// The block control enters the loop from, created when the header has no suitable one
+fn preheader(func: &mut Function, preds: &[Vec<BlockId>], body: &[BlockId], header: BlockId) -> BlockId {
+    let outside: Vec<BlockId> = preds[header.0]
+        .iter()
+        .filter(|pred| !body.contains(pred))
+        .cloned()
+        .collect();
+    if outside.len() == 1 && func.blocks[outside[0].0].terminator.successors() == vec![header] {
+        return outside[0];
+    }
+
+    func.blocks.push(Block {
+        instructions: Vec::new(),
+        terminator: Terminator::Jump(header),
+    });
+    let preheader = BlockId(func.blocks.len() - 1);
+    for pred in &outside {
+        for succ in func.blocks[pred.0].terminator.successors_mut() {
+            if *succ == header {
+                *succ = preheader;
+            }
+        }
+    }
+
+    // Values entering from outside the loop now merge in the preheader
+    let mut merged = Vec::new();
+    for inst in &mut func.blocks[header.0].instructions {
+        if let Instruction::Phi { dst, sources } = inst {
+            let (entering, mut staying): (Vec<_>, Vec<_>) =
+                sources.drain(..).partition(|(pred, _)| outside.contains(pred));
+            let value = if entering.len() == 1 {
+                entering[0].1
+            } else {
+                let reg = VReg(func.registers.len() + merged.len());
+                merged.push((reg, func.registers[dst.0], entering));
+                Value::Register(reg)
+            };
+            staying.push((preheader, value));
+            *sources = staying;
+        }
+    }
+    for (reg, ctype, sources) in merged {
+        func.new_register(ctype);
+        func.blocks[preheader.0]
+            .instructions
+            .push(Instruction::Phi { dst: reg, sources });
+    }
+    preheader
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ir::tests::{binary, copy, function, int, reg, syscall};
+
+    fn text(func: &Function) -> Vec<String> {
+        func.to_string().lines().map(String::from).collect()
+    }
+
+    /// Runs passes over the function in SSA form, as `optimize` does
+    fn run(mut func: Function, passes: &[fn(&mut Function) -> bool]) -> Function {
+        ssa::construct(&mut func);
+        for pass in passes {
+            pass(&mut func);
+        }
+        func
+    }
+
+    #[test]
+    fn constant_branches_are_resolved_and_their_dead_side_dropped() {
+        let func = function(
+            2,
+            vec![
+                (
+                    vec![copy(0, int(1))],
+                    Terminator::Branch(reg(0), BlockId(1), BlockId(2)),
+                ),
+                (vec![copy(1, int(2))], Terminator::Jump(BlockId(3))),
+                (vec![copy(1, int(3))], Terminator::Jump(BlockId(3))),
+                (vec![], Terminator::Return(reg(1))),
+            ],
+        );
+        assert_eq!(
+            text(&run(func, &[constant_propagation, copy_propagation])),
+            [
+                "function f() -> int {",
+                "bb0:",
+                "\tjmp bb1",
+                "bb1:",
+                "\tjmp bb2",
+                "bb2:",
+                "\tret 2",
+                "}",
+            ]
+        );
+    }
+
+    #[test]
+    fn computations_are_reused_only_where_they_dominate() {
+        let func = function(
+            4,
+            vec![
+                (
+                    vec![syscall(0), binary(BinaryOp::Add, 1, reg(0), int(1))],
+                    Terminator::Branch(reg(0), BlockId(1), BlockId(2)),
+                ),
+                (
+                    vec![binary(BinaryOp::Add, 2, int(1), reg(0))],
+                    Terminator::Return(reg(2)),
+                ),
+                (
+                    vec![binary(BinaryOp::Sub, 3, int(1), reg(0))],
+                    Terminator::Return(reg(3)),
+                ),
+            ],
+        );
+        let lines = text(&run(func, &[common_subexpression_elimination]));
+        // Addition commutes, subtraction does not
+        assert_eq!(lines[6..8], ["\t%7: int = %5", "\tret %7"]);
+        assert_eq!(lines[9], "\t%6: int = sub 1, %4");
+    }
+
+    #[test]
+    fn invariants_move_to_a_new_preheader() {
+        let func = function(
+            4,
+            vec![
+                (vec![syscall(0)], Terminator::Branch(reg(0), BlockId(1), BlockId(2))),
+                (
+                    vec![
+                        binary(BinaryOp::Mul, 1, reg(0), int(3)),
+                        binary(BinaryOp::Div, 2, int(7), reg(0)),
+                        syscall(3),
+                    ],
+                    Terminator::Branch(reg(3), BlockId(1), BlockId(2)),
+                ),
+                (vec![], Terminator::Return(reg(1))),
+            ],
+        );
+        let mut func = run(func, &[]);
+        assert!(loop_invariant_code_motion(&mut func));
+        // The entry also branches past the loop, so the preheader is a block of its own. The
+        // division may trap on a zero divisor and the system call has side effects, so both stay.
+        assert_eq!(
+            text(&func)[2..],
+            [
+                "\t%4: int = syscall 39",
+                "\tbr %4, bb3, bb2",
+                "bb1:",
+                "\t%5: int = phi [bb1, %6], [bb3, 0]",
+                "\t%7: int = div 7, %4",
+                "\t%8: int = syscall 39",
+                "\tbr %8, bb1, bb2",
+                "bb2:",
+                "\t%9: int = phi [bb0, 0], [bb1, %6]",
+                "\tret %9",
+                "bb3:",
+                "\t%6: int = mul %4, 3",
+                "\tjmp bb1",
+                "}",
+            ]
+        );
+        assert!(!loop_invariant_code_motion(&mut func));
+    }
+
+    #[test]
+    fn unused_results_are_removed_but_side_effects_kept() {
+        let func = function(
+            3,
+            vec![(
+                vec![syscall(0), binary(BinaryOp::Mul, 1, reg(0), reg(0)), copy(2, int(4))],
+                Terminator::Return(reg(2)),
+            )],
+        );
+        assert_eq!(
+            text(&run(func, &[dead_code_elimination]))[2..4],
+            ["\t%3: int = syscall 39", "\t%5: int = 4"]
+        );
+    }
+
+    #[test]
+    fn optimized_loops_leave_ssa_form() {
+        let func = function(
+            2,
+            vec![
+                (vec![copy(0, int(0))], Terminator::Jump(BlockId(1))),
+                (
+                    vec![binary(BinaryOp::Add, 0, reg(0), int(1)), syscall(1)],
+                    Terminator::Branch(reg(1), BlockId(1), BlockId(2)),
+                ),
+                (vec![], Terminator::Return(reg(0))),
+            ],
+        );
+        let mut module = Module { functions: vec![func] };
+        let mut options = Options::default();
+        options.set_level(2);
+        optimize(&mut module, &options);
+        let func = &module.functions[0];
+        assert!(func
+            .blocks
+            .iter()
+            .all(|block| !block.instructions.iter().any(Instruction::is_phi)));
+        assert!(text(func).contains(&String::from("\tret %4")));
+    }
+}
@@ -1,273 +1,686 @@
-use lexer::{Integer, Keyword, Operator, Symbol, Token, TokenType};
+use diagnostic::Diagnostic;
+use json::{Format, Json};
+use lexer::{Integer, Keyword, Operator, Span, Symbol, Token, TokenType};
 use std::collections::VecDeque;
+use types::{Constant, DataModel, Type};
+use visit::{self, Visitor};
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Program {
     pub function: Function,
+    /// The data model the types of the integer constants were chosen under
+    pub model: DataModel,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Statement {
     Return(Expression),
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Function {
     pub name: String,
     pub statement: Statement,
 }
 
+/// An expression, as one tree whatever the precedence of its operators
 #[derive(Debug)]
-pub enum Expression {
-    Term(Term),
-    BinaryOperation {
+pub struct Expression {
+    pub kind: ExpressionKind,
+    /// The token the expression is reported at: its operator, its constant, its opening
+    /// parenthesis or the name it calls
+    pub span: Span,
+    /// The type semantic analysis works out, after the usual arithmetic conversions of the
+    /// operands. `None` until it runs.
+    pub ctype: Option<Type>,
+}
+
+/// Trees are the same whatever the source positions they were parsed from
+impl PartialEq for Expression {
+    fn eq(&self, other: &Expression) -> bool {
+        self.kind == other.kind && self.ctype == other.ctype
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ExpressionKind {
+    Binary {
         left: Box<Expression>,
         operator: Operator,
-        right: Term,
+        right: Box<Expression>,
     },
-}
-
-#[derive(Debug)]
-pub enum Term {
-    Factor(Factor),
-    BinaryOperation {
-        left: Box<Term>,
+    Unary {
         operator: Operator,
-        right: Factor,
+        operand: Box<Expression>,
+    },
+    /// Parentheses from the source, kept so that the tree reads as written
+    Parenthesized(Box<Expression>),
+    Const(Constant),
+    /// A call as written, before semantic analysis resolves its name
+    Call {
+        name: String,
+        args: Vec<Expression>,
+    },
+    /// `__builtin_syscall(number, args...)`, trapping into the kernel with up to six arguments.
+    /// Semantic analysis turns calls to the builtin into this node.
+    Syscall {
+        number: Box<Expression>,
+        args: Vec<Expression>,
     },
 }
 
-#[derive(Debug)]
-pub enum Factor {
-    Expression(Box<Expression>),
-    UnaryOperation { operator: Operator, factor: Box<Factor> },
-    Const(u32),
+/// The precedence levels of C, from the loosest to the tightest binding
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Precedence {
+    Comma,
+    Assignment,
+    Conditional,
+    LogicalOr,
+    LogicalAnd,
+    BitwiseOr,
+    BitwiseXor,
+    BitwiseAnd,
+    Equality,
+    Relational,
+    Shift,
+    Additive,
+    Multiplicative,
+    Unary,
+    Postfix,
 }
 
-macro_rules! simple_match {
-    ($tokens:expr, $type:pat) => {
-        match $tokens.pop_front() {
-            Some(Token { ttype: $type, .. }) => (),
-            Some(Token { ttype, .. }) => panic!(format!("Unexpected token '{:?}'.", ttype)),
-            _ => panic!("Unexpected Token while parsing"),
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Associativity {
+    Left,
+    Right,
+}
+
+impl Precedence {
+    /// Assignments, the conditional operator and prefix operators group from the right:
+    /// `a = b = c` is `a = (b = c)`
+    pub fn associativity(self) -> Associativity {
+        match self {
+            Precedence::Assignment | Precedence::Conditional | Precedence::Unary => Associativity::Right,
+            _ => Associativity::Left,
         }
-    };
+    }
+
+    /// The level binding just tighter than this one
+    pub fn tighter(self) -> Precedence {
+        match self {
+            Precedence::Comma => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Conditional,
+            Precedence::Conditional => Precedence::LogicalOr,
+            Precedence::LogicalOr => Precedence::LogicalAnd,
+            Precedence::LogicalAnd => Precedence::BitwiseOr,
+            Precedence::BitwiseOr => Precedence::BitwiseXor,
+            Precedence::BitwiseXor => Precedence::BitwiseAnd,
+            Precedence::BitwiseAnd => Precedence::Equality,
+            Precedence::Equality => Precedence::Relational,
+            Precedence::Relational => Precedence::Shift,
+            Precedence::Shift => Precedence::Additive,
+            Precedence::Additive => Precedence::Multiplicative,
+            Precedence::Multiplicative => Precedence::Unary,
+            Precedence::Unary | Precedence::Postfix => Precedence::Postfix,
+        }
+    }
 }
 
-pub fn parse(tokens: Vec<Token>) -> Program {
-    Program {
-        function: parse_fn(&mut VecDeque::from(tokens)),
+/// The binary operators and the level each one binds at. A new operator only needs its row
+/// here, whichever level it belongs to.
+const BINARY_OPERATORS: &[(Operator, Precedence)] = &[
+    (Operator::Star, Precedence::Multiplicative),
+    (Operator::Divide, Precedence::Multiplicative),
+    (Operator::Plus, Precedence::Additive),
+    (Operator::Negate, Precedence::Additive),
+    (Operator::ShiftLeft, Precedence::Shift),
+    (Operator::ShiftRight, Precedence::Shift),
+];
+
+const UNARY_OPERATORS: &[Operator] = &[Operator::Negate, Operator::Not, Operator::Bang];
+
+/// The precedence of an operator written between two operands, if it can be
+pub fn binary_precedence(operator: Operator) -> Option<Precedence> {
+    BINARY_OPERATORS
+        .iter()
+        .find(|(candidate, _)| *candidate == operator)
+        .map(|(_, precedence)| *precedence)
+}
+
+impl Expression {
+    pub fn new(kind: ExpressionKind, span: Span) -> Expression {
+        Expression {
+            kind,
+            span,
+            ctype: None,
+        }
+    }
+
+    /// The type semantic analysis resolved
+    pub fn ctype(&self) -> Type {
+        self.ctype.expect("Semantic analysis types every expression")
+    }
+
+    /// The level the expression binds at, which decides where it needs parentheses. The parser
+    /// only puts operators where C has them, but a tree built by hand may not.
+    pub fn precedence(&self) -> ParseResult<Precedence> {
+        match &self.kind {
+            ExpressionKind::Binary { operator, .. } => binary_precedence(*operator).ok_or_else(|| {
+                Diagnostic::error(format!("'{}' is not a binary operator", operator.spelling())).at(self.span)
+            }),
+            ExpressionKind::Unary { operator, .. } if !UNARY_OPERATORS.contains(operator) => {
+                Err(Diagnostic::error(format!("'{}' is not a unary operator", operator.spelling())).at(self.span))
+            }
+            ExpressionKind::Unary { .. } => Ok(Precedence::Unary),
+            ExpressionKind::Parenthesized(_)
+            | ExpressionKind::Const(_)
+            | ExpressionKind::Call { .. }
+            | ExpressionKind::Syscall { .. } => Ok(Precedence::Postfix),
+        }
     }
 }
 
-fn parse_fn(mut tokens: &mut VecDeque<Token>) -> Function {
-    let _return_type = match tokens.pop_front() {
+impl Program {
+    /// Whether the program makes system calls, which only targets with a kernel can
+    pub fn uses_syscalls(&self) -> bool {
+        struct Syscalls(bool);
+
+        impl Visitor for Syscalls {
+            fn visit_expression(&mut self, exp: &Expression) {
+                if let ExpressionKind::Syscall { .. } = exp.kind {
+                    self.0 = true;
+                }
+                visit::walk_expression(self, exp);
+            }
+        }
+
+        let mut syscalls = Syscalls(false);
+        syscalls.visit_program(self);
+        syscalls.0
+    }
+}
+
+type ParseResult<T> = Result<T, Diagnostic>;
+
+fn error<T>(message: String) -> ParseResult<T> {
+    Err(Diagnostic::error(message))
+}
+
+/// The error for a token that is not what the grammar needs, pointing at the token as gcc does
+fn expected<T>(what: &str, found: Option<&Token>) -> ParseResult<T> {
+    match found {
+        Some(token) => {
+            Err(Diagnostic::error(format!("expected {} before '{}' token", what, token.text())).at(token.span))
+        }
+        None => error(format!("expected {} at end of input", what)),
+    }
+}
+
+/// Takes the next token, which has to be the symbol
+fn expect_symbol(tokens: &mut VecDeque<Token>, symbol: Symbol) -> ParseResult<()> {
+    match tokens.front() {
+        Some(Token {
+            ttype: TokenType::Symbol { stype, .. },
+            ..
+        }) if *stype == symbol => {
+            tokens.pop_front();
+            Ok(())
+        }
+        found => expected(&format!("'{}'", symbol.spelling()), found),
+    }
+}
+
+/// Parses a translation unit, typing its integer constants under the target's data model
+pub fn parse(tokens: Vec<Token>, model: DataModel) -> ParseResult<Program> {
+    Ok(Program {
+        function: parse_fn(&mut VecDeque::from(tokens), model)?,
+        model,
+    })
+}
+
+fn parse_fn(tokens: &mut VecDeque<Token>, model: DataModel) -> ParseResult<Function> {
+    match tokens.front() {
         Some(Token {
             ttype: TokenType::Keyword {
                 ktype: Keyword::Int, ..
             },
             ..
-        }) => Keyword::Int,
-        _ => panic!("Unexpected Token"),
+        }) => tokens.pop_front(),
+        found => return expected("'int'", found),
     };
 
-    let name = match tokens.pop_front() {
+    let name = match tokens.front() {
         Some(Token {
             ttype: TokenType::Identifier { .. },
             value: Some(name),
-        }) => name,
-        _ => panic!("Unexpected Token"),
+            ..
+        }) => name.clone(),
+        found => return expected("identifier", found),
     };
+    tokens.pop_front();
 
-    simple_match!(&mut tokens, TokenType::Symbol { stype: Symbol::LeftParenthesis, .. });
+    expect_symbol(tokens, Symbol::LeftParenthesis)?;
     // Parse arguments
-    simple_match!(&mut tokens, TokenType::Symbol { stype: Symbol::RightParenthesis, .. });
-    simple_match!(&mut tokens, TokenType::Symbol { stype: Symbol::LeftBrace, .. });
-
-    let stmt = parse_statement(&mut tokens);
+    expect_symbol(tokens, Symbol::RightParenthesis)?;
+    expect_symbol(tokens, Symbol::LeftBrace)?;
+    let stmt = parse_statement(tokens, model)?;
+    expect_symbol(tokens, Symbol::RightBrace)?;
 
-    simple_match!(&mut tokens, TokenType::Symbol { stype: Symbol::RightBrace, .. });
-
-    Function { name, statement: stmt }
+    Ok(Function { name, statement: stmt })
 }
 
-fn parse_statement(mut tokens: &mut VecDeque<Token>) -> Statement {
-    match tokens.pop_front() {
+fn parse_statement(tokens: &mut VecDeque<Token>, model: DataModel) -> ParseResult<Statement> {
+    match tokens.front() {
         Some(Token {
             ttype: TokenType::Keyword {
                 ktype: Keyword::Return, ..
             },
             ..
         }) => {
-            let exp = parse_exp(&mut tokens);
-            simple_match!(&mut tokens, TokenType::Symbol { stype: Symbol::Semicolon, .. });
-            Statement::Return(exp)
+            tokens.pop_front();
+            let exp = parse_exp(tokens, model)?;
+            expect_symbol(tokens, Symbol::Semicolon)?;
+            Ok(Statement::Return(exp))
         }
-        _ => panic!("Unexpected token in stmt"),
+        found => expected("'return'", found),
     }
 }
 
-fn parse_exp(mut tokens: &mut VecDeque<Token>) -> Expression {
-    let mut exp = Expression::Term(parse_term(&mut tokens));
-
-    while let Some(Token {
-        ttype: TokenType::Operator { otype, .. },
-        ..
-    }) = tokens.get(0)
-    {
-        match otype {
-            Operator::Plus | Operator::Negate => {
-                tokens.pop_front();
-                let next_term = parse_term(&mut tokens);
-                exp = Expression::BinaryOperation {
-                    left: Box::new(exp),
-                    operator: *otype,
-                    right: next_term,
-                };
-            }
-            _ => {
-                break;
-            }
-        }
-    }
-
-    exp
+fn parse_exp(tokens: &mut VecDeque<Token>, model: DataModel) -> ParseResult<Expression> {
+    parse_binary(tokens, Precedence::Comma, model)
 }
 
-fn parse_term(mut tokens: &mut VecDeque<Token>) -> Term {
-    let mut term = Term::Factor(parse_factor(&mut tokens));
+/// Precedence climbing: parses an operand, then every binary operator binding at least as
+/// tightly as `min`, each with a right operand made only of operators binding tighter still,
+/// or as tightly for the levels that group from the right
+fn parse_binary(tokens: &mut VecDeque<Token>, min: Precedence, model: DataModel) -> ParseResult<Expression> {
+    let mut exp = parse_unary(tokens, model)?;
 
     while let Some(Token {
         ttype: TokenType::Operator { otype, .. },
+        span,
         ..
-    }) = tokens.get(0)
+    }) = tokens.front()
     {
-        match otype {
-            Operator::Star | Operator::Divide => {
-                tokens.pop_front();
-                let next_factor = parse_factor(&mut tokens);
-                term = Term::BinaryOperation {
-                    left: Box::new(term),
-                    operator: *otype,
-                    right: next_factor,
-                };
-            }
-            _ => {
-                break;
-            }
-        }
+        let (operator, span) = (*otype, *span);
+        let precedence = match binary_precedence(operator) {
+            Some(precedence) if precedence >= min => precedence,
+            _ => break,
+        };
+        tokens.pop_front();
+        let right = match precedence.associativity() {
+            Associativity::Left => parse_binary(tokens, precedence.tighter(), model)?,
+            Associativity::Right => parse_binary(tokens, precedence, model)?,
+        };
+        exp = Expression::new(
+            ExpressionKind::Binary {
+                left: Box::new(exp),
+                operator,
+                right: Box::new(right),
+            },
+            span,
+        );
     }
-    term
+
+    Ok(exp)
 }
 
-fn parse_factor(mut tokens: &mut VecDeque<Token>) -> Factor {
-    match tokens.pop_front() {
-        Some(Token {
+fn parse_unary(tokens: &mut VecDeque<Token>, model: DataModel) -> ParseResult<Expression> {
+    let token = match tokens.pop_front() {
+        Some(token) => token,
+        None => return expected("expression", None),
+    };
+    let span = token.span;
+    match token {
+        Token {
             ttype: TokenType::Symbol {
                 stype: Symbol::LeftParenthesis,
                 ..
             },
             ..
-        }) => {
-            let exp = parse_exp(&mut tokens);
-            if let Some(Token {
-                ttype:
-                    TokenType::Symbol {
-                        stype: Symbol::RightParenthesis,
-                        ..
-                    },
-                ..
-            }) = tokens.pop_front()
-            {
-                Factor::Expression(Box::new(exp))
-            } else {
-                panic!("Expected right parenthesis");
-            }
+        } => {
+            let exp = parse_exp(tokens, model)?;
+            expect_symbol(tokens, Symbol::RightParenthesis)?;
+            Ok(Expression::new(ExpressionKind::Parenthesized(Box::new(exp)), span))
         }
-        Some(Token {
+        Token {
             ttype: TokenType::Operator { otype, .. },
             ..
-        }) => match otype {
-            Operator::Negate | Operator::Bang | Operator::Not => {
-                let factor = parse_factor(&mut tokens);
-                Factor::UnaryOperation {
+        } if UNARY_OPERATORS.contains(otype) => {
+            let operand = parse_unary(tokens, model)?;
+            Ok(Expression::new(
+                ExpressionKind::Unary {
                     operator: *otype,
-                    factor: Box::new(factor),
-                }
-            }
-            _ => panic!("Unexpected operator"),
-        },
-        Some(Token {
+                    operand: Box::new(operand),
+                },
+                span,
+            ))
+        }
+        Token {
             ttype: TokenType::Integer { itype, .. },
             value: Some(ref num),
-        }) => {
-            let int = match itype {
-                Integer::Decimal => num.parse::<u32>().unwrap(),
-                Integer::Hexadecimal => u32::from_str_radix(num.trim_start_matches("0x"), 16).unwrap(),
-            };
-            Factor::Const(int)
-        }
-        _ => panic!("Unknown factor"),
+            ..
+        } => parse_integer(num, itype, model)
+            .map(|int| Expression::new(ExpressionKind::Const(int), span))
+            .map_err(|err| Diagnostic::error(err).at(span)),
+        Token {
+            ttype: TokenType::Identifier { .. },
+            value: Some(ref name),
+            ..
+        } => parse_call(name, span, tokens, model),
+        token => expected("expression", Some(&token)),
     }
 }
 
-pub fn debug(prog: &Program) {
-    println!("PARSING OUTPUT:");
-    print_func(&prog.function);
-    println!();
+/// A call to any name, which semantic analysis resolves
+fn parse_call(name: &str, span: Span, tokens: &mut VecDeque<Token>, model: DataModel) -> ParseResult<Expression> {
+    expect_symbol(tokens, Symbol::LeftParenthesis)?;
+    let name = String::from(name);
+    if let Some(Token {
+        ttype: TokenType::Symbol {
+            stype: Symbol::RightParenthesis,
+            ..
+        },
+        ..
+    }) = tokens.front()
+    {
+        tokens.pop_front();
+        return Ok(Expression::new(ExpressionKind::Call { name, args: Vec::new() }, span));
+    }
+    // Arguments are assignment expressions, so that commas separate them
+    let mut args = vec![parse_binary(tokens, Precedence::Assignment, model)?];
+    loop {
+        let symbol = match tokens.front() {
+            Some(Token {
+                ttype: TokenType::Symbol { stype, .. },
+                ..
+            }) if *stype == Symbol::Comma || *stype == Symbol::RightParenthesis => *stype,
+            found => return expected("',' or ')'", found),
+        };
+        tokens.pop_front();
+        if symbol == Symbol::RightParenthesis {
+            break;
+        }
+        args.push(parse_binary(tokens, Precedence::Assignment, model)?);
+    }
+    Ok(Expression::new(ExpressionKind::Call { name, args }, span))
 }
 
-fn print_func(func: &Function) {
-    println!("FUNCTION {}:", func.name);
-    print_stmt(&func.statement);
+fn parse_integer(num: &str, itype: &Integer, model: DataModel) -> Result<Constant, String> {
+    let digits_end = num.find(['u', 'U', 'l', 'L']).unwrap_or(num.len());
+    let (digits, suffix) = num.split_at(digits_end);
+    let digits = match itype {
+        Integer::Hexadecimal | Integer::Binary => &digits[2..],
+        Integer::Octal | Integer::Decimal => digits,
+    };
+
+    let value = match u64::from_str_radix(digits, *itype as u32) {
+        Ok(value) => value,
+        Err(_) => return Err(format!("integer constant '{}' is too large for its type", num)),
+    };
+
+    let unsigned = suffix.contains(['u', 'U']);
+    let long = suffix.chars().filter(|c| *c == 'l' || *c == 'L').count();
+    let decimal = matches!(itype, Integer::Decimal);
+
+    // Candidate types, in order, from the table in C11 6.4.4.1
+    let candidates: &[Type] = match (unsigned, long, decimal) {
+        (false, 0, true) => &[Type::Int, Type::Long, Type::LongLong],
+        (false, 0, false) => &[
+            Type::Int,
+            Type::UnsignedInt,
+            Type::Long,
+            Type::UnsignedLong,
+            Type::LongLong,
+            Type::UnsignedLongLong,
+        ],
+        (true, 0, _) => &[Type::UnsignedInt, Type::UnsignedLong, Type::UnsignedLongLong],
+        (false, 1, true) => &[Type::Long, Type::LongLong],
+        (false, 1, false) => &[Type::Long, Type::UnsignedLong, Type::LongLong, Type::UnsignedLongLong],
+        (true, 1, _) => &[Type::UnsignedLong, Type::UnsignedLongLong],
+        (false, _, true) => &[Type::LongLong],
+        (false, _, false) => &[Type::LongLong, Type::UnsignedLongLong],
+        (true, _, _) => &[Type::UnsignedLongLong],
+    };
+
+    match candidates.iter().find(|ctype| value <= ctype.max_value(model)) {
+        Some(ctype) => Ok(Constant { value, ctype: *ctype }),
+        None => Err(format!("integer constant '{}' is too large for its type", num)),
+    }
 }
 
-fn print_stmt(stmt: &Statement) {
-    match stmt {
-        Statement::Return(exp) => {
-            let res = print_exp(exp);
-            println!("\tRETURN {}", res);
+/// The tree of the program, indented one level per node as text, or as a JSON object with
+/// a `node` name on each node
+pub fn dump(prog: &Program, format: Format) -> Vec<String> {
+    match format {
+        Format::Text => {
+            let mut lines = vec![format!("Function {}", prog.function.name)];
+            match &prog.function.statement {
+                Statement::Return(exp) => {
+                    lines.push(String::from("  Return"));
+                    dump_exp(exp, 2, &mut lines);
+                }
+            }
+            lines
         }
-    };
+        Format::Json => {
+            let statement = match &prog.function.statement {
+                Statement::Return(exp) => {
+                    Json::Object(vec![("node", Json::string("Return")), ("expression", exp_json(exp))])
+                }
+            };
+            let function = Json::Object(vec![
+                ("node", Json::string("Function")),
+                ("name", Json::string(&prog.function.name)),
+                ("statement", statement),
+            ]);
+            vec![Json::Object(vec![("node", Json::string("Program")), ("function", function)]).to_string()]
+        }
+    }
 }
 
-fn print_exp(exp: &Expression) -> String {
-    match exp {
-        Expression::Term(term) => print_term(&term),
-        Expression::BinaryOperation { left, operator, right } => {
-            let l = print_exp(left);
-            let r = print_term(right);
-            format!("{} {} {}", l, get_op(operator), r)
+fn dump_exp(exp: &Expression, depth: usize, lines: &mut Vec<String>) {
+    let indent = "  ".repeat(depth);
+    match &exp.kind {
+        ExpressionKind::Binary { left, operator, right } => {
+            lines.push(format!("{}BinaryOperation {}", indent, operator.spelling()));
+            dump_exp(left, depth + 1, lines);
+            dump_exp(right, depth + 1, lines);
+        }
+        ExpressionKind::Unary { operator, operand } => {
+            lines.push(format!("{}UnaryOperation {}", indent, operator.spelling()));
+            dump_exp(operand, depth + 1, lines);
+        }
+        ExpressionKind::Parenthesized(exp) => {
+            lines.push(format!("{}Parenthesized", indent));
+            dump_exp(exp, depth + 1, lines);
+        }
+        ExpressionKind::Const(int) => lines.push(format!("{}Constant {} '{}'", indent, int, int.ctype)),
+        ExpressionKind::Call { name, args } => {
+            lines.push(format!("{}Call {}", indent, name));
+            for arg in args {
+                dump_exp(arg, depth + 1, lines);
+            }
+        }
+        ExpressionKind::Syscall { number, args } => {
+            lines.push(format!("{}Syscall", indent));
+            dump_exp(number, depth + 1, lines);
+            for arg in args {
+                dump_exp(arg, depth + 1, lines);
+            }
         }
     }
 }
 
-fn get_op(op: &Operator) -> &str {
-    match op {
-        Operator::Negate => "-",
-        Operator::Not => "~",
-        Operator::Bang => "!",
-        Operator::Plus => "+",
-        Operator::Star => "*",
-        Operator::Divide => "/",
+fn exp_json(exp: &Expression) -> Json {
+    match &exp.kind {
+        ExpressionKind::Binary { left, operator, right } => Json::Object(vec![
+            ("node", Json::string("BinaryOperation")),
+            ("operator", Json::string(operator.spelling())),
+            ("left", exp_json(left)),
+            ("right", exp_json(right)),
+        ]),
+        ExpressionKind::Unary { operator, operand } => Json::Object(vec![
+            ("node", Json::string("UnaryOperation")),
+            ("operator", Json::string(operator.spelling())),
+            ("operand", exp_json(operand)),
+        ]),
+        ExpressionKind::Parenthesized(exp) => Json::Object(vec![
+            ("node", Json::string("Parenthesized")),
+            ("expression", exp_json(exp)),
+        ]),
+        ExpressionKind::Const(int) => Json::Object(vec![
+            ("node", Json::string("Constant")),
+            ("value", Json::Number(int.mathematical())),
+            ("type", Json::String(int.ctype.to_string())),
+        ]),
+        ExpressionKind::Call { name, args } => Json::Object(vec![
+            ("node", Json::string("Call")),
+            ("name", Json::string(name)),
+            ("arguments", Json::Array(args.iter().map(exp_json).collect())),
+        ]),
+        ExpressionKind::Syscall { number, args } => Json::Object(vec![
+            ("node", Json::string("Syscall")),
+            ("number", exp_json(number)),
+            ("arguments", Json::Array(args.iter().map(exp_json).collect())),
+        ]),
     }
 }
 
-fn print_term(term: &Term) -> String {
-    match term {
-        Term::Factor(factor) => print_factor(&factor),
-        Term::BinaryOperation { left, operator, right } => {
-            let l = print_term(left);
-            let r = print_factor(right);
-            format!("{} {} {}", l, get_op(operator), r)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lexer;
+
+    fn parse_return(exp: &str) -> Expression {
+        let source = format!("int main() {{ return {}; }}", exp);
+        match parse(lexer::lex(&source).unwrap(), DataModel::LP64)
+            .unwrap()
+            .function
+            .statement
+        {
+            Statement::Return(exp) => exp,
         }
     }
-}
 
-fn print_factor(factor: &Factor) -> String {
-    match factor {
-        Factor::Expression(exp) => format!("({})", print_exp(exp)),
-        Factor::UnaryOperation { operator, factor } => format!("{}{}", get_op(operator), print_factor(factor)),
-        Factor::Const(int) => format!("{}", int),
+    #[test]
+    fn tighter_operators_group_first() {
+        assert_eq!(
+            parse_return("1 + 2 * 3"),
+            parse_return("1 + (2 * 3)").without_parentheses()
+        );
+        assert_eq!(parse_return("-1 * 2"), parse_return("(-1) * 2").without_parentheses());
+        assert_eq!(
+            parse_return("1 << 2 + 3"),
+            parse_return("1 << (2 + 3)").without_parentheses()
+        );
+    }
+
+    #[test]
+    fn operators_of_a_level_group_from_the_left() {
+        assert_eq!(
+            parse_return("1 - 2 - 3"),
+            parse_return("(1 - 2) - 3").without_parentheses()
+        );
+        assert_eq!(
+            parse_return("8 / 4 * 2"),
+            parse_return("(8 / 4) * 2").without_parentheses()
+        );
+        assert_eq!(
+            parse_return("1 << 2 >> 3"),
+            parse_return("(1 << 2) >> 3").without_parentheses()
+        );
+    }
+
+    #[test]
+    fn constants_too_large_for_every_type_are_errors() {
+        let source = "int main() { return 18446744073709551616; }";
+        match parse(lexer::lex(source).unwrap(), DataModel::LP64) {
+            Ok(_) => panic!("'{}' parsed", source),
+            Err(diagnostic) => assert_eq!(
+                diagnostic.message,
+                "integer constant '18446744073709551616' is too large for its type"
+            ),
+        }
+    }
+
+    fn parse_error(source: &str) -> String {
+        match parse(lexer::lex(source).unwrap(), DataModel::LP64) {
+            Ok(_) => panic!("'{}' parsed", source),
+            Err(diagnostic) => diagnostic.located("a.c", source),
+        }
+    }
+
+    #[test]
+    fn errors_show_the_tokens_as_written() {
+        assert_eq!(
+            parse_error("int main() { return 1 }"),
+            "a.c:1:23: error: expected ';' before '}' token"
+        );
+        assert_eq!(
+            parse_error("int main() {\n    return * 2;\n}"),
+            "a.c:2:12: error: expected expression before '*' token"
+        );
+        assert_eq!(
+            parse_error("int main() { return (1 + 2; }"),
+            "a.c:1:27: error: expected ')' before ';' token"
+        );
+        assert_eq!(
+            parse_error("int main() { return f(1 2); }"),
+            "a.c:1:25: error: expected ',' or ')' before '2' token"
+        );
+        assert_eq!(
+            parse_error("int 5() {}"),
+            "a.c:1:5: error: expected identifier before '5' token"
+        );
+        assert_eq!(
+            parse_error("int main() { return 1;"),
+            "a.c: error: expected '}' at end of input"
+        );
+        assert_eq!(
+            parse_error("int main() { return"),
+            "a.c: error: expected expression at end of input"
+        );
+    }
+
+    #[test]
+    fn misplaced_operators_are_errors() {
+        let constant = || Box::new(parse_return("1"));
+        let binary = Expression::new(
+            ExpressionKind::Binary {
+                left: constant(),
+                operator: Operator::Bang,
+                right: constant(),
+            },
+            Span::default(),
+        );
+        assert_eq!(binary.precedence().unwrap_err().message, "'!' is not a binary operator");
+        let unary = Expression::new(
+            ExpressionKind::Unary {
+                operator: Operator::Star,
+                operand: constant(),
+            },
+            Span::default(),
+        );
+        assert_eq!(unary.precedence().unwrap_err().message, "'*' is not a unary operator");
+    }
+
+    impl Expression {
+        fn without_parentheses(self) -> Expression {
+            let kind = match self.kind {
+                ExpressionKind::Binary { left, operator, right } => ExpressionKind::Binary {
+                    left: Box::new(left.without_parentheses()),
+                    operator,
+                    right: Box::new(right.without_parentheses()),
+                },
+                ExpressionKind::Unary { operator, operand } => ExpressionKind::Unary {
+                    operator,
+                    operand: Box::new(operand.without_parentheses()),
+                },
+                ExpressionKind::Parenthesized(exp) => return exp.without_parentheses(),
+                kind => kind,
+            };
+            Expression { kind, ..self }
+        }
     }
 }
@@ -0,0 +1,550 @@
+use generator::{DoubleArgInst, Line, NoArgInst, Param, Register, SingleArgInst, Width};
+
+/// Rewrites short instruction sequences into cheaper equivalents until none applies
+pub fn optimize(mut lines: Vec<Line>) -> Vec<Line> {
+    loop {
+        let mut changed = fold_push_pop(&mut lines);
+        changed |= remove_redundant_moves(&mut lines);
+        changed |= use_lea(&mut lines);
+        changed |= zero_with_xor(&mut lines);
+        if !changed {
+            return lines;
+        }
+    }
+}
+
+/// `push a; ...; pop b` becomes `mov a, b; ...` when nothing in between touches the stack or
+/// uses `b`, and disappears entirely when `a` is `b` and nothing in between overwrites it
+fn fold_push_pop(lines: &mut Vec<Line>) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i < lines.len() {
+        let pushed = match &lines[i] {
+            Line::SingleArgInst(SingleArgInst::Push, param @ Param::Register(..))
+            | Line::SingleArgInst(SingleArgInst::Push, param @ Param::Const(_)) => param.clone(),
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+
+        let mut j = i + 1;
+        while j < lines.len() && !is_barrier(&lines[j]) && !touches_stack(&lines[j]) {
+            j += 1;
+        }
+        let popped = match lines.get(j) {
+            Some(Line::SingleArgInst(SingleArgInst::Pop, Param::Register(reg, _))) => *reg,
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+
+        let source = register_of(&pushed);
+        let foldable = lines[i + 1..j].iter().all(|line| {
+            let (reads, writes) = effects(line);
+            !writes.contains(&popped) && (source == Some(popped) || !reads.contains(&popped))
+        });
+        if !foldable {
+            i += 1;
+            continue;
+        }
+
+        lines.remove(j);
+        if source == Some(popped) {
+            lines.remove(i);
+        } else {
+            let pushed = match pushed {
+                Param::Register(reg, _) => Param::Register(reg, Width::Quad),
+                other => other,
+            };
+            lines[i] = Line::DoubleArgInst(DoubleArgInst::Mov, pushed, Param::Register(popped, Width::Quad));
+        }
+        changed = true;
+    }
+    changed
+}
+
+/// Drops moves that copy a location onto itself, copy a value straight back to where it came
+/// from, or load a register that the next instruction overwrites without reading
+fn remove_redundant_moves(lines: &mut Vec<Line>) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i < lines.len() {
+        let redundant = match &lines[i] {
+            // A 32-bit move onto itself still clears the upper half
+            Line::DoubleArgInst(DoubleArgInst::Mov, src, dst) if src == dst => {
+                !matches!(dst, Param::Register(_, Width::Long))
+            }
+            Line::DoubleArgInst(DoubleArgInst::Mov, src, dst) => {
+                let returns =
+                    i > 0 && lines[i - 1] == Line::DoubleArgInst(DoubleArgInst::Mov, dst.clone(), src.clone());
+                let overwritten = match (dst, lines.get(i + 1)) {
+                    (Param::Register(reg, _), Some(next)) if !is_barrier(next) => {
+                        let (reads, writes) = effects(next);
+                        writes.contains(reg) && !reads.contains(reg)
+                    }
+                    _ => false,
+                };
+                returns && !matches!(dst, Param::Register(_, Width::Long)) || overwritten
+            }
+            _ => false,
+        };
+        if redundant {
+            lines.remove(i);
+            changed = true;
+        } else {
+            i += 1;
+        }
+    }
+    changed
+}
+
+/// Turns copy-then-add and multiply-by-small-constant sequences into a single `lea`
+fn use_lea(lines: &mut Vec<Line>) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i < lines.len() {
+        let (length, address, target) = match lea_pattern(&lines[i..]) {
+            Some(found) => found,
+            None => {
+                i += 1;
+                continue;
+            }
+        };
+        if flags_live(lines, i + length) {
+            i += 1;
+            continue;
+        }
+        lines.splice(
+            i..i + length,
+            vec![Line::DoubleArgInst(DoubleArgInst::Lea, address, target)],
+        );
+        changed = true;
+    }
+    changed
+}
+
+/// The number of lines a `lea` can replace at the start of `lines`, its address and destination
+fn lea_pattern(lines: &[Line]) -> Option<(usize, Param, Param)> {
+    use self::DoubleArgInst::{Add, Imul, Mov, Sub};
+
+    let inst = |i: usize| match lines.get(i) {
+        Some(Line::DoubleArgInst(inst, src, Param::Register(reg, width))) => Some((inst.clone(), src, *reg, *width)),
+        _ => None,
+    };
+    let (first, source, target, width) = inst(0)?;
+    let target_param = Param::Register(target, width);
+
+    // imul $3, t => lea (t,t,2), t
+    if let (Imul, Param::Const(factor @ 3)) | (Imul, Param::Const(factor @ 5)) | (Imul, Param::Const(factor @ 9)) =
+        (&first, source)
+    {
+        return Some((1, Param::Indexed(target, target, *factor as u8 - 1, 0), target_param));
+    }
+
+    let copied = match (&first, source) {
+        (Mov, Param::Register(reg, src_width)) if *reg != target && *src_width == width => *reg,
+        _ => return None,
+    };
+    let (second, operand, second_target, second_width) = inst(1)?;
+    if second_target != target || second_width != width {
+        return None;
+    }
+    match (&second, operand) {
+        // mov a, t; add b, t => lea (a,b,1), t
+        (Add, Param::Register(other, _)) if *other != target => {
+            let (base, index) = if *other == Register::RSP {
+                (*other, copied)
+            } else {
+                (copied, *other)
+            };
+            Some((2, Param::Indexed(base, index, 1, 0), target_param))
+        }
+        // mov a, t; add $c, t => lea c(a), t
+        (Add, Param::Const(offset)) => Some((2, Param::Memory(copied, *offset, width), target_param)),
+        (Sub, Param::Const(offset)) if *offset != i64::from(i32::MIN) => {
+            Some((2, Param::Memory(copied, -offset, width), target_param))
+        }
+        // mov a, t; imul $4, t; add b, t => lea (b,a,4), t
+        (Imul, Param::Const(scale @ 2)) | (Imul, Param::Const(scale @ 4)) | (Imul, Param::Const(scale @ 8))
+            if copied != Register::RSP =>
+        {
+            match inst(2)? {
+                (Add, Param::Register(other, _), third_target, third_width)
+                    if third_target == target && third_width == width && *other != target =>
+                {
+                    Some((3, Param::Indexed(*other, copied, *scale as u8, 0), target_param))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// `mov $0, r` becomes the shorter `xor r, r` wherever the flags it clobbers are dead
+fn zero_with_xor(lines: &mut [Line]) -> bool {
+    let mut changed = false;
+    for i in 0..lines.len() {
+        let reg = match &lines[i] {
+            Line::DoubleArgInst(DoubleArgInst::Mov, Param::Const(0), Param::Register(reg, _)) => *reg,
+            _ => continue,
+        };
+        if !flags_live(lines, i + 1) {
+            let reg = Param::Register(reg, Width::Long);
+            lines[i] = Line::DoubleArgInst(DoubleArgInst::Xor, reg.clone(), reg);
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Whether an instruction from `start` on may read the flags before something overwrites them
+fn flags_live(lines: &[Line], start: usize) -> bool {
+    for line in &lines[start..] {
+        match line {
+            Line::NoArgInst(NoArgInst::Ret) => return false,
+            Line::SingleArgInst(SingleArgInst::Sete, _)
+            | Line::SingleArgInst(SingleArgInst::Jne, _)
+            | Line::SingleArgInst(SingleArgInst::Je, _)
+            | Line::SingleArgInst(SingleArgInst::Jo, _)
+            | Line::SingleArgInst(SingleArgInst::Ja, _) => return true,
+            // The flags may be read wherever control goes next
+            _ if is_barrier(line) => return true,
+            _ if writes_flags(line) => return false,
+            _ => {}
+        }
+    }
+    false
+}
+
+fn writes_flags(line: &Line) -> bool {
+    match line {
+        Line::SingleArgInst(inst, _) => matches!(inst, SingleArgInst::Neg | SingleArgInst::Idiv | SingleArgInst::Div),
+        Line::DoubleArgInst(inst, _, _) => matches!(
+            inst,
+            DoubleArgInst::Cmp | DoubleArgInst::Add | DoubleArgInst::Sub | DoubleArgInst::Imul | DoubleArgInst::Xor
+        ),
+        _ => false,
+    }
+}
+
+/// Lines that begin or end a basic block, across which nothing is known
+fn is_barrier(line: &Line) -> bool {
+    matches!(
+        line,
+        Line::Directive(..)
+            | Line::Label(_)
+            | Line::NoArgInst(NoArgInst::Ret)
+            | Line::NoArgInst(NoArgInst::Syscall)
+            | Line::SingleArgInst(SingleArgInst::Jmp, _)
+            | Line::SingleArgInst(SingleArgInst::Jne, _)
+            | Line::SingleArgInst(SingleArgInst::Je, _)
+            | Line::SingleArgInst(SingleArgInst::Jo, _)
+            | Line::SingleArgInst(SingleArgInst::Ja, _)
+            | Line::SingleArgInst(SingleArgInst::Call, _)
+    )
+}
+
+fn touches_stack(line: &Line) -> bool {
+    let (reads, writes) = effects(line);
+    reads.contains(&Register::RSP) || writes.contains(&Register::RSP)
+}
+
+fn register_of(param: &Param) -> Option<Register> {
+    match param {
+        Param::Register(reg, _) => Some(*reg),
+        _ => None,
+    }
+}
+
+/// Registers used to address memory or held by a register operand
+fn param_registers(param: &Param) -> Vec<Register> {
+    match param {
+        Param::Register(reg, _) | Param::Memory(reg, _, _) => vec![*reg],
+        Param::Indexed(base, index, _, _) => vec![*base, *index],
+        Param::Const(_) | Param::RipRelative(_) | Param::Label(_) => vec![],
+    }
+}
+
+/// Registers an instruction reads and writes, implicit operands included
+fn effects(line: &Line) -> (Vec<Register>, Vec<Register>) {
+    match line {
+        Line::Directive(..) | Line::Label(_) => (vec![], vec![]),
+        Line::NoArgInst(NoArgInst::Ret) => (vec![Register::RAX, Register::RSP], vec![Register::RSP]),
+        Line::NoArgInst(NoArgInst::Cltd) | Line::NoArgInst(NoArgInst::Cqto) => {
+            (vec![Register::RAX], vec![Register::RDX])
+        }
+        // The system call number and its arguments, in the Linux x86-64 convention
+        Line::NoArgInst(NoArgInst::Syscall) => (
+            vec![
+                Register::RAX,
+                Register::RDI,
+                Register::RSI,
+                Register::RDX,
+                Register::R10,
+                Register::R8,
+                Register::R9,
+            ],
+            vec![Register::RAX, Register::RCX, Register::R11],
+        ),
+        Line::SingleArgInst(inst, param) => {
+            let mut reads = param_registers(param);
+            let mut writes = vec![];
+            match inst {
+                SingleArgInst::Push => {
+                    reads.push(Register::RSP);
+                    writes.push(Register::RSP);
+                }
+                SingleArgInst::Pop => {
+                    writes.extend(register_of(param));
+                    reads = vec![Register::RSP];
+                    writes.push(Register::RSP);
+                }
+                SingleArgInst::Idiv | SingleArgInst::Div => {
+                    reads.extend(&[Register::RAX, Register::RDX]);
+                    writes.extend(&[Register::RAX, Register::RDX]);
+                }
+                // `sete` only writes the low byte, so it keeps the rest of the register alive,
+                // which `param_registers` already counts as read
+                SingleArgInst::Sete | SingleArgInst::Not | SingleArgInst::Neg => writes.extend(register_of(param)),
+                SingleArgInst::Jmp | SingleArgInst::Jne | SingleArgInst::Je | SingleArgInst::Jo | SingleArgInst::Ja => {
+                }
+                // The argument registers and the stack go in, every caller-saved register may change
+                SingleArgInst::Call => {
+                    reads.extend(&[
+                        Register::RSP,
+                        Register::RDI,
+                        Register::RSI,
+                        Register::RDX,
+                        Register::RCX,
+                        Register::R8,
+                        Register::R9,
+                    ]);
+                    writes.extend(&[
+                        Register::RAX,
+                        Register::RCX,
+                        Register::RDX,
+                        Register::RSI,
+                        Register::RDI,
+                        Register::R8,
+                        Register::R9,
+                        Register::R10,
+                        Register::R11,
+                    ]);
+                }
+            }
+            (reads, writes)
+        }
+        Line::DoubleArgInst(inst, src, dst) => {
+            let mut reads = param_registers(src);
+            let mut writes = vec![];
+            match (inst, dst) {
+                (DoubleArgInst::Mov, Param::Register(reg, _))
+                | (DoubleArgInst::Movslq, Param::Register(reg, _))
+                | (DoubleArgInst::Lea, Param::Register(reg, _)) => writes.push(*reg),
+                (DoubleArgInst::Cmp, _) => reads.extend(param_registers(dst)),
+                (_, Param::Register(reg, _)) => {
+                    reads.push(*reg);
+                    writes.push(*reg);
+                }
+                _ => reads.extend(param_registers(dst)),
+            }
+            (reads, writes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generator::Register::*;
+
+    fn reg(reg: Register) -> Param {
+        Param::Register(reg, Width::Quad)
+    }
+
+    fn long(reg: Register) -> Param {
+        Param::Register(reg, Width::Long)
+    }
+
+    fn mov(src: Param, dst: Param) -> Line {
+        Line::DoubleArgInst(DoubleArgInst::Mov, src, dst)
+    }
+
+    fn double(inst: DoubleArgInst, src: Param, dst: Param) -> Line {
+        Line::DoubleArgInst(inst, src, dst)
+    }
+
+    fn single(inst: SingleArgInst, param: Param) -> Line {
+        Line::SingleArgInst(inst, param)
+    }
+
+    fn ret() -> Line {
+        Line::NoArgInst(NoArgInst::Ret)
+    }
+
+    #[test]
+    fn folds_push_pop_into_move() {
+        let before = vec![
+            single(SingleArgInst::Push, reg(RAX)),
+            mov(Param::Const(2), reg(RAX)),
+            single(SingleArgInst::Pop, reg(RCX)),
+            double(DoubleArgInst::Sub, reg(RAX), reg(RCX)),
+            mov(reg(RCX), reg(RAX)),
+            ret(),
+        ];
+        let after = vec![
+            mov(reg(RAX), reg(RCX)),
+            mov(Param::Const(2), reg(RAX)),
+            double(DoubleArgInst::Sub, reg(RAX), reg(RCX)),
+            mov(reg(RCX), reg(RAX)),
+            ret(),
+        ];
+        assert_eq!(optimize(before), after);
+    }
+
+    #[test]
+    fn keeps_push_pop_saving_a_clobbered_register() {
+        let before = vec![
+            single(SingleArgInst::Push, reg(RCX)),
+            mov(Param::Memory(RBP, -8, Width::Quad), reg(RCX)),
+            double(DoubleArgInst::Cmp, reg(RCX), Param::Memory(RBP, -16, Width::Quad)),
+            single(SingleArgInst::Pop, reg(RCX)),
+            single(SingleArgInst::Sete, Param::Register(RAX, Width::Byte)),
+            ret(),
+        ];
+        assert_eq!(optimize(before.clone()), before);
+    }
+
+    #[test]
+    fn removes_adjacent_push_pop_of_same_register() {
+        let before = vec![
+            single(SingleArgInst::Push, reg(RBX)),
+            single(SingleArgInst::Pop, reg(RBX)),
+            ret(),
+        ];
+        assert_eq!(optimize(before), vec![ret()]);
+    }
+
+    #[test]
+    fn removes_moves_back_and_forth() {
+        let before = vec![
+            mov(reg(RAX), reg(RBX)),
+            mov(reg(RBX), reg(RAX)),
+            double(DoubleArgInst::Add, reg(RBX), reg(RAX)),
+            ret(),
+        ];
+        let after = vec![
+            mov(reg(RAX), reg(RBX)),
+            double(DoubleArgInst::Add, reg(RBX), reg(RAX)),
+            ret(),
+        ];
+        assert_eq!(optimize(before), after);
+    }
+
+    #[test]
+    fn removes_self_moves_but_keeps_zero_extension() {
+        let before = vec![mov(reg(RCX), reg(RCX)), mov(long(RDX), long(RDX)), ret()];
+        let after = vec![mov(long(RDX), long(RDX)), ret()];
+        assert_eq!(optimize(before), after);
+    }
+
+    #[test]
+    fn removes_overwritten_load() {
+        let before = vec![
+            mov(Param::Const(7), reg(RCX)),
+            mov(Param::Memory(RBP, -8, Width::Quad), reg(RCX)),
+            mov(reg(RCX), reg(RAX)),
+            ret(),
+        ];
+        let after = vec![
+            mov(Param::Memory(RBP, -8, Width::Quad), reg(RCX)),
+            mov(reg(RCX), reg(RAX)),
+            ret(),
+        ];
+        assert_eq!(optimize(before), after);
+    }
+
+    #[test]
+    fn zeroes_with_xor_when_flags_are_dead() {
+        let before = vec![mov(Param::Const(0), long(RAX)), ret()];
+        let after = vec![double(DoubleArgInst::Xor, long(RAX), long(RAX)), ret()];
+        assert_eq!(optimize(before), after);
+    }
+
+    #[test]
+    fn keeps_zeroing_move_between_compare_and_set() {
+        let before = vec![
+            double(DoubleArgInst::Cmp, long(RCX), long(RDX)),
+            mov(Param::Const(0), long(RAX)),
+            single(SingleArgInst::Sete, Param::Register(RAX, Width::Byte)),
+            ret(),
+        ];
+        assert_eq!(optimize(before.clone()), before);
+    }
+
+    #[test]
+    fn adds_registers_with_lea() {
+        let before = vec![
+            mov(long(RCX), long(RAX)),
+            double(DoubleArgInst::Add, long(RDX), long(RAX)),
+            ret(),
+        ];
+        let after = vec![
+            double(DoubleArgInst::Lea, Param::Indexed(RCX, RDX, 1, 0), long(RAX)),
+            ret(),
+        ];
+        assert_eq!(optimize(before), after);
+    }
+
+    #[test]
+    fn adds_constants_with_lea() {
+        let before = vec![
+            mov(reg(RCX), reg(RAX)),
+            double(DoubleArgInst::Sub, Param::Const(5), reg(RAX)),
+            ret(),
+        ];
+        let after = vec![
+            double(DoubleArgInst::Lea, Param::Memory(RCX, -5, Width::Quad), reg(RAX)),
+            ret(),
+        ];
+        assert_eq!(optimize(before), after);
+    }
+
+    #[test]
+    fn scales_and_adds_with_lea() {
+        let before = vec![
+            mov(reg(RCX), reg(RAX)),
+            double(DoubleArgInst::Imul, Param::Const(8), reg(RAX)),
+            double(DoubleArgInst::Add, reg(RDX), reg(RAX)),
+            ret(),
+        ];
+        let after = vec![
+            double(DoubleArgInst::Lea, Param::Indexed(RDX, RCX, 8, 0), reg(RAX)),
+            ret(),
+        ];
+        assert_eq!(optimize(before), after);
+    }
+
+    #[test]
+    fn multiplies_by_three_with_lea() {
+        let before = vec![double(DoubleArgInst::Imul, Param::Const(3), long(RSI)), ret()];
+        let after = vec![
+            double(DoubleArgInst::Lea, Param::Indexed(RSI, RSI, 2, 0), long(RSI)),
+            ret(),
+        ];
+        assert_eq!(optimize(before), after);
+    }
+
+    #[test]
+    fn keeps_add_whose_flags_are_read() {
+        let before = vec![
+            mov(reg(RCX), reg(RAX)),
+            double(DoubleArgInst::Add, reg(RDX), reg(RAX)),
+            single(SingleArgInst::Jne, Param::Label(String::from(".L1"))),
+        ];
+        assert_eq!(optimize(before.clone()), before);
+    }
+}
@@ -0,0 +1,380 @@
+use ir::{BlockId, Function, Instruction, VReg, Value};
+use std::collections::HashSet;
+use types::Type;
+
+/// Where a virtual register lives for its whole lifetime
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Location<R> {
+    Register(R),
+    /// Index of a spill slot in the stack frame
+    Stack(usize),
+}
+
+/// The result of linear scan register allocation (Poletto and Sarkar) over one function
+pub struct Allocation<R> {
+    locations: Vec<Option<Location<R>>>,
+    /// Number of spill slots the frame needs
+    pub spill_slots: usize,
+    /// Every register assigned to at least one virtual register
+    pub used: Vec<R>,
+    /// Registers holding a live value at each instruction, indexed by block then instruction,
+    /// with the terminator last
+    busy: Vec<Vec<Vec<R>>>,
+    registers: Vec<R>,
+}
+
+impl<R: Copy + Eq> Allocation<R> {
+    pub fn location(&self, reg: VReg) -> Location<R> {
+        self.locations[reg.0].expect("virtual register was never allocated")
+    }
+
+    /// A register holding nothing live across the instruction, usable as a temporary
+    pub fn free_at(&self, block: BlockId, index: usize, excluding: &[R]) -> Option<R> {
+        let busy = &self.busy[block.0][index];
+        self.registers
+            .iter()
+            .find(|reg| !busy.contains(reg) && !excluding.contains(reg))
+            .cloned()
+    }
+}
+
+#[derive(Debug)]
+struct Interval {
+    reg: VReg,
+    start: usize,
+    end: usize,
+}
+
+/// Allocates `registers`, in order of preference, to the virtual registers of `func`.
+/// `clobbers` lists the registers an instruction destroys; values live across or used by
+/// that instruction never get one of them. Values of a type `fits` rejects always get a spill slot.
+pub fn allocate<R, F, G>(func: &Function, registers: &[R], clobbers: F, fits: G) -> Allocation<R>
+where
+    R: Copy + Eq,
+    F: Fn(&Instruction) -> Vec<R>,
+    G: Fn(Type) -> bool,
+{
+    let (live_in, live_out) = liveness(func);
+
+    // Every instruction reads its operands at an even position and writes its result just after
+    let mut intervals: Vec<Option<Interval>> = (0..func.registers.len()).map(|_| None).collect();
+    let mut extend = |reg: VReg, position: usize| {
+        let interval = intervals[reg.0].get_or_insert(Interval {
+            reg,
+            start: position,
+            end: position,
+        });
+        interval.start = interval.start.min(position);
+        interval.end = interval.end.max(position);
+    };
+
+    let mut positions = Vec::new();
+    let mut clobbered: Vec<(usize, Vec<R>)> = Vec::new();
+    let mut position = 0;
+    for (i, block) in func.blocks.iter().enumerate() {
+        let block_start = position;
+        let mut block_positions = Vec::new();
+        for inst in &block.instructions {
+            for operand in inst.operands() {
+                if let Value::Register(reg) = operand {
+                    extend(reg, position);
+                }
+            }
+            extend(inst.dst(), position + 1);
+            let destroyed = clobbers(inst);
+            if !destroyed.is_empty() {
+                clobbered.push((position, destroyed));
+            }
+            block_positions.push(position);
+            position += 2;
+        }
+        for operand in block.terminator.operands() {
+            if let Value::Register(reg) = operand {
+                extend(reg, position);
+            }
+        }
+        block_positions.push(position);
+        positions.push(block_positions);
+
+        for reg in &live_in[i] {
+            extend(*reg, block_start);
+        }
+        for reg in &live_out[i] {
+            extend(*reg, position + 1);
+        }
+        position += 2;
+    }
+
+    let mut intervals: Vec<Interval> = intervals.into_iter().flatten().collect();
+    intervals.sort_by_key(|interval| (interval.start, interval.reg));
+
+    let allowed = |interval: &Interval, reg: R| {
+        !clobbered.iter().any(|(position, destroyed)| {
+            interval.start <= *position && *position <= interval.end && destroyed.contains(&reg)
+        })
+    };
+
+    let mut locations: Vec<Option<Location<R>>> = vec![None; func.registers.len()];
+    let mut spill_slots = 0;
+    let mut active: Vec<(Interval, R)> = Vec::new();
+    let mut assigned: Vec<(VReg, usize, usize, R)> = Vec::new();
+    for interval in intervals {
+        active.retain(|(other, _)| other.end >= interval.start);
+        if !fits(func.registers[interval.reg.0]) {
+            locations[interval.reg.0] = Some(Location::Stack(spill_slots));
+            spill_slots += 1;
+            continue;
+        }
+
+        let free = registers
+            .iter()
+            .cloned()
+            .find(|reg| allowed(&interval, *reg) && !active.iter().any(|(_, taken)| taken == reg));
+        let reg = match free {
+            Some(reg) => Some(reg),
+            None => {
+                // Spill whichever interval that could make room ends last
+                let victim = active
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, (other, reg))| other.end > interval.end && allowed(&interval, *reg))
+                    .max_by_key(|(_, (other, _))| other.end)
+                    .map(|(i, _)| i);
+                match victim {
+                    Some(i) => {
+                        let (victim, reg) = active.remove(i);
+                        locations[victim.reg.0] = Some(Location::Stack(spill_slots));
+                        spill_slots += 1;
+                        assigned.retain(|(owner, _, _, _)| *owner != victim.reg);
+                        Some(reg)
+                    }
+                    None => None,
+                }
+            }
+        };
+
+        match reg {
+            Some(reg) => {
+                locations[interval.reg.0] = Some(Location::Register(reg));
+                assigned.push((interval.reg, interval.start, interval.end, reg));
+                active.push((interval, reg));
+            }
+            None => {
+                locations[interval.reg.0] = Some(Location::Stack(spill_slots));
+                spill_slots += 1;
+            }
+        }
+    }
+
+    let mut used = Vec::new();
+    for reg in registers {
+        if assigned.iter().any(|(_, _, _, taken)| taken == reg) {
+            used.push(*reg);
+        }
+    }
+
+    let busy = positions
+        .iter()
+        .map(|block| {
+            block
+                .iter()
+                .map(|position| {
+                    assigned
+                        .iter()
+                        .filter(|(_, start, end, _)| *start <= position + 1 && end >= position)
+                        .map(|(_, _, _, reg)| *reg)
+                        .collect()
+                })
+                .collect()
+        })
+        .collect();
+
+    Allocation {
+        locations,
+        spill_slots,
+        used,
+        busy,
+        registers: registers.to_vec(),
+    }
+}
+
+/// Registers live on entry to and exit from every block
+fn liveness(func: &Function) -> (Vec<HashSet<VReg>>, Vec<HashSet<VReg>>) {
+    let mut uses = Vec::new();
+    let mut defs = Vec::new();
+    for block in &func.blocks {
+        let mut used = HashSet::new();
+        let mut defined = HashSet::new();
+        for inst in &block.instructions {
+            for operand in inst.operands() {
+                if let Value::Register(reg) = operand {
+                    if !defined.contains(&reg) {
+                        used.insert(reg);
+                    }
+                }
+            }
+            defined.insert(inst.dst());
+        }
+        for operand in block.terminator.operands() {
+            if let Value::Register(reg) = operand {
+                if !defined.contains(&reg) {
+                    used.insert(reg);
+                }
+            }
+        }
+        uses.push(used);
+        defs.push(defined);
+    }
+
+    let mut live_in = vec![HashSet::new(); func.blocks.len()];
+    let mut live_out = vec![HashSet::new(); func.blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, block) in func.blocks.iter().enumerate().rev() {
+            let mut out = HashSet::new();
+            for succ in block.terminator.successors() {
+                out.extend(live_in[succ.0].iter().cloned());
+            }
+            let mut inside: HashSet<VReg> = out.difference(&defs[i]).cloned().collect();
+            inside.extend(uses[i].iter().cloned());
+            if inside != live_in[i] || out != live_out[i] {
+                live_in[i] = inside;
+                live_out[i] = out;
+                changed = true;
+            }
+        }
+    }
+    (live_in, live_out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ir::tests::{binary, copy, function, int, reg, syscall};
+    use ir::{BinaryOp, Terminator};
+
+    /// Allocates with no clobbers, where every type fits a register
+    fn allocate_plain(func: &Function, registers: &[char]) -> Allocation<char> {
+        allocate(func, registers, |_| Vec::new(), |_| true)
+    }
+
+    /// Four values all live at once, summed into one
+    fn four_live() -> Function {
+        function(
+            7,
+            vec![(
+                vec![
+                    syscall(0),
+                    syscall(1),
+                    syscall(2),
+                    syscall(3),
+                    binary(BinaryOp::Add, 4, reg(0), reg(1)),
+                    binary(BinaryOp::Add, 5, reg(4), reg(2)),
+                    binary(BinaryOp::Add, 6, reg(5), reg(3)),
+                ],
+                Terminator::Return(reg(6)),
+            )],
+        )
+    }
+
+    #[test]
+    fn values_get_registers_while_there_are_enough() {
+        let allocation = allocate_plain(&four_live(), &['a', 'b', 'c', 'd']);
+        assert_eq!(allocation.spill_slots, 0);
+        let taken: HashSet<char> = (0..4)
+            .map(|i| match allocation.location(VReg(i)) {
+                Location::Register(reg) => reg,
+                Location::Stack(_) => panic!("%{} spilled", i),
+            })
+            .collect();
+        assert_eq!(taken.len(), 4);
+    }
+
+    #[test]
+    fn values_beyond_the_registers_spill() {
+        let allocation = allocate_plain(&four_live(), &['a', 'b']);
+        let live: Vec<Location<char>> = (0..4).map(|i| allocation.location(VReg(i))).collect();
+        let registers: Vec<char> = live
+            .iter()
+            .filter_map(|location| match location {
+                Location::Register(reg) => Some(*reg),
+                Location::Stack(_) => None,
+            })
+            .collect();
+        // The values are live together, so the two that keep a register keep different ones
+        assert_eq!(registers.len(), 2);
+        assert_ne!(registers[0], registers[1]);
+        assert_eq!(allocation.spill_slots, 2);
+        assert!(live.contains(&Location::Stack(0)) && live.contains(&Location::Stack(1)));
+        assert_eq!(allocation.used, ['a', 'b']);
+    }
+
+    #[test]
+    fn the_interval_ending_last_is_spilled() {
+        // %0 lives to the return, so %1 and %2 keep the registers
+        let func = function(
+            5,
+            vec![(
+                vec![
+                    copy(0, int(1)),
+                    syscall(1),
+                    syscall(2),
+                    binary(BinaryOp::Add, 3, reg(1), reg(2)),
+                    binary(BinaryOp::Add, 4, reg(3), reg(0)),
+                ],
+                Terminator::Return(reg(4)),
+            )],
+        );
+        let allocation = allocate_plain(&func, &['a', 'b']);
+        assert_eq!(allocation.location(VReg(0)), Location::Stack(0));
+        assert!(matches!(allocation.location(VReg(1)), Location::Register(_)));
+        assert!(matches!(allocation.location(VReg(2)), Location::Register(_)));
+    }
+
+    #[test]
+    fn clobbered_registers_never_hold_operands_or_values_live_across() {
+        let func = function(
+            4,
+            vec![(
+                vec![
+                    copy(0, int(1)),
+                    syscall(1),
+                    Instruction::Syscall {
+                        dst: VReg(2),
+                        number: int(60),
+                        args: vec![reg(1)],
+                    },
+                    binary(BinaryOp::Add, 3, reg(0), reg(2)),
+                ],
+                Terminator::Return(reg(3)),
+            )],
+        );
+        let clobbers = |inst: &Instruction| match inst {
+            Instruction::Syscall { .. } => vec!['a', 'b'],
+            _ => Vec::new(),
+        };
+        let allocation = allocate(&func, &['a', 'b', 'c'], clobbers, |_| true);
+        // %0 lives across both system calls and %1 is the operand of the second, so neither
+        // may sit in a register they destroy, and %0 spills to leave `c` to %1. Results may.
+        assert_eq!(allocation.location(VReg(0)), Location::Stack(0));
+        assert_eq!(allocation.location(VReg(1)), Location::Register('c'));
+        assert_eq!(allocation.location(VReg(2)), Location::Register('a'));
+    }
+
+    #[test]
+    fn values_that_do_not_fit_always_spill() {
+        let func = function(1, vec![(vec![syscall(0)], Terminator::Return(reg(0)))]);
+        let allocation = allocate(&func, &['a'], |_| Vec::new(), |_| false);
+        assert_eq!(allocation.location(VReg(0)), Location::Stack(0));
+        assert!(allocation.used.is_empty());
+    }
+
+    #[test]
+    fn free_registers_exclude_live_ones() {
+        let allocation = allocate_plain(&four_live(), &['a', 'b', 'c', 'd', 'e']);
+        // While %0 to %3 are live at the first addition, only `e` is free
+        assert_eq!(allocation.free_at(BlockId(0), 4, &[]), Some('e'));
+        assert_eq!(allocation.free_at(BlockId(0), 4, &['e']), None);
+    }
+}
@@ -0,0 +1,546 @@
+use backend::{self, block_label, Backend};
+use ir::{BinaryOp, BlockId, Function, Instruction, Terminator, UnaryOp, Value};
+use regalloc::{Allocation, Location};
+use std::fmt;
+use types::{Constant, DataModel, Type};
+
+#[derive(Debug)]
+pub enum Line {
+    Directive(Directive, String),
+    Label(String),
+    Inst(Opcode, Vec<Operand>),
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Line::Directive(dir, other) => write!(f, ".{} {}", format!("{:?}", dir).to_lowercase(), other),
+            Line::Label(label) => write!(f, "{}:", label),
+            Line::Inst(opcode, operands) => {
+                write!(f, "\t{}", opcode.name())?;
+                for (i, operand) in operands.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { "\t" } else { ", " }, operand)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Directive {
+    Globl,
+}
+
+/// RV64IM instructions and the assembler pseudo-instructions rcc uses. The `w` forms operate on
+/// the low 32 bits and sign extend the result, which is how 32-bit values live in registers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Opcode {
+    Li,
+    Mv,
+    SextW,
+    Slli,
+    Slliw,
+    Srli,
+    Srliw,
+    Srai,
+    Sraiw,
+    Sll,
+    Sllw,
+    Srl,
+    Srlw,
+    Sra,
+    Sraw,
+    Add,
+    Addw,
+    Addi,
+    Addiw,
+    Sub,
+    Subw,
+    Mul,
+    Mulw,
+    Div,
+    Divw,
+    Divu,
+    Divuw,
+    Not,
+    Seqz,
+    Ld,
+    Sd,
+    J,
+    Bnez,
+    Call,
+    Ret,
+    Ecall,
+}
+
+impl Opcode {
+    fn name(self) -> &'static str {
+        match self {
+            Opcode::Li => "li",
+            Opcode::Mv => "mv",
+            Opcode::SextW => "sext.w",
+            Opcode::Slli => "slli",
+            Opcode::Slliw => "slliw",
+            Opcode::Srli => "srli",
+            Opcode::Srliw => "srliw",
+            Opcode::Srai => "srai",
+            Opcode::Sraiw => "sraiw",
+            Opcode::Sll => "sll",
+            Opcode::Sllw => "sllw",
+            Opcode::Srl => "srl",
+            Opcode::Srlw => "srlw",
+            Opcode::Sra => "sra",
+            Opcode::Sraw => "sraw",
+            Opcode::Add => "add",
+            Opcode::Addw => "addw",
+            Opcode::Addi => "addi",
+            Opcode::Addiw => "addiw",
+            Opcode::Sub => "sub",
+            Opcode::Subw => "subw",
+            Opcode::Mul => "mul",
+            Opcode::Mulw => "mulw",
+            Opcode::Div => "div",
+            Opcode::Divw => "divw",
+            Opcode::Divu => "divu",
+            Opcode::Divuw => "divuw",
+            Opcode::Not => "not",
+            Opcode::Seqz => "seqz",
+            Opcode::Ld => "ld",
+            Opcode::Sd => "sd",
+            Opcode::J => "j",
+            Opcode::Bnez => "bnez",
+            Opcode::Call => "call",
+            Opcode::Ret => "ret",
+            Opcode::Ecall => "ecall",
+        }
+    }
+}
+
+/// Integer registers by their LP64 ABI names
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Register {
+    Zero,
+    Ra,
+    Sp,
+    /// Temporaries t0-t6
+    T(u8),
+    /// Saved registers s0-s11, s0 being the frame pointer
+    S(u8),
+    /// Argument and return registers a0-a7
+    A(u8),
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Register::Zero => write!(f, "zero"),
+            Register::Ra => write!(f, "ra"),
+            Register::Sp => write!(f, "sp"),
+            Register::T(n) => write!(f, "t{}", n),
+            Register::S(n) => write!(f, "s{}", n),
+            Register::A(n) => write!(f, "a{}", n),
+        }
+    }
+}
+
+const FP: Register = Register::S(0);
+
+/// Holds the system call number, the arguments and result going in a0-a5 (Linux RISC-V)
+const SYSCALL_NUMBER: Register = Register::A(7);
+
+/// Never allocated: t0 and t1 reach spilled values and constants, t2 far stack slots
+const SCRATCH: [Register; 2] = [Register::T(0), Register::T(1)];
+const ADDRESS: Register = Register::T(2);
+
+/// The temporaries first, then the argument registers (rcc makes no calls yet so they are
+/// free), then the callee-saved s1-s11
+const ALLOCATABLE: [Register; 23] = [
+    Register::T(3),
+    Register::T(4),
+    Register::T(5),
+    Register::T(6),
+    Register::A(0),
+    Register::A(1),
+    Register::A(2),
+    Register::A(3),
+    Register::A(4),
+    Register::A(5),
+    Register::A(6),
+    Register::A(7),
+    Register::S(1),
+    Register::S(2),
+    Register::S(3),
+    Register::S(4),
+    Register::S(5),
+    Register::S(6),
+    Register::S(7),
+    Register::S(8),
+    Register::S(9),
+    Register::S(10),
+    Register::S(11),
+];
+
+#[derive(Debug, Clone)]
+pub enum Operand {
+    Register(Register),
+    Immediate(i64),
+    /// `offset(base)`
+    Memory(Register, i64),
+    Label(String),
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Register(reg) => write!(f, "{}", reg),
+            Operand::Immediate(int) => write!(f, "{}", int),
+            Operand::Memory(base, offset) => write!(f, "{}({})", offset, base),
+            Operand::Label(label) => write!(f, "{}", label),
+        }
+    }
+}
+
+/// The RV64GC backend, following the LP64 calling convention
+pub struct RiscV64;
+
+impl Backend for RiscV64 {
+    type Register = Register;
+    type Line = Line;
+
+    fn registers(&self) -> &'static [Register] {
+        &ALLOCATABLE
+    }
+
+    fn clobbers(&self, inst: &Instruction) -> Vec<Register> {
+        match inst {
+            // a0 carries the result even when there are no arguments
+            Instruction::Syscall { args, .. } => {
+                let mut clobbered: Vec<Register> = (0..args.len().max(1) as u8).map(Register::A).collect();
+                clobbered.push(SYSCALL_NUMBER);
+                clobbered
+            }
+            _ => vec![],
+        }
+    }
+
+    fn select(&self, func: &Function, allocation: &Allocation<Register>) -> Vec<Line> {
+        select_fn(func, allocation)
+    }
+
+    fn start(&self) -> Vec<Line> {
+        vec![
+            Line::Directive(Directive::Globl, String::from("_start")),
+            Line::Label(String::from("_start")),
+            // A zero frame pointer marks the outermost frame
+            Line::Inst(Opcode::Mv, vec![reg(FP), reg(Register::Zero)]),
+            Line::Inst(Opcode::Call, vec![Operand::Label(String::from("main"))]),
+            Line::Inst(Opcode::Li, vec![reg(SYSCALL_NUMBER), Operand::Immediate(93)]),
+            Line::Inst(Opcode::Ecall, vec![]),
+        ]
+    }
+
+    fn render(&self, lines: &[Line]) -> Vec<String> {
+        backend::prologue('@')
+            .into_iter()
+            .chain(lines.iter().map(|line| line.to_string()))
+            .collect()
+    }
+}
+
+fn reg(reg: Register) -> Operand {
+    Operand::Register(reg)
+}
+
+/// Whether an offset or operand fits the 12-bit signed immediate of I-type instructions
+fn fits_imm12(int: i64) -> bool {
+    (-2048..2048).contains(&int)
+}
+
+/// A constant as it sits in a register: 32-bit values are kept sign extended, even unsigned ones
+fn register_value(int: Constant) -> i64 {
+    match int.ctype.size(DataModel::LP64) {
+        4 => int.value as u32 as i32 as i64,
+        _ => int.value as i64,
+    }
+}
+
+fn is_word(ctype: Type) -> bool {
+    ctype.size(DataModel::LP64) == 4
+}
+
+fn select_fn(func: &Function, allocation: &Allocation<Register>) -> Vec<Line> {
+    let saved: Vec<Register> = allocation
+        .used
+        .iter()
+        .cloned()
+        .filter(|reg| matches!(reg, Register::S(_)))
+        .collect();
+    // Callee-saved registers and spill slots sit above the stack pointer, below ra and s0
+    let locals = ((saved.len() + allocation.spill_slots) as i64 * 8 + 15) / 16 * 16;
+
+    let sp = reg(Register::Sp);
+    let mut selector = Selector {
+        func,
+        allocation,
+        saved: &saved,
+        lines: vec![
+            Line::Directive(Directive::Globl, func.name.clone()),
+            Line::Label(func.name.clone()),
+            Line::Inst(Opcode::Addi, vec![sp.clone(), sp.clone(), Operand::Immediate(-16)]),
+            Line::Inst(Opcode::Sd, vec![reg(Register::Ra), Operand::Memory(Register::Sp, 8)]),
+            Line::Inst(Opcode::Sd, vec![reg(FP), Operand::Memory(Register::Sp, 0)]),
+            Line::Inst(Opcode::Addi, vec![reg(FP), sp.clone(), Operand::Immediate(16)]),
+        ],
+    };
+    if locals > 0 {
+        selector.add_immediate(Register::Sp, Register::Sp, -locals);
+    }
+    for (i, saved) in saved.iter().enumerate() {
+        let slot = selector.stack(8 * i as i64);
+        selector.emit(Opcode::Sd, vec![reg(*saved), slot]);
+    }
+
+    for (i, block) in func.blocks.iter().enumerate() {
+        selector.lines.push(Line::Label(block_label(func, BlockId(i))));
+        for inst in &block.instructions {
+            selector.instruction(inst);
+        }
+        selector.terminator(&block.terminator);
+    }
+    selector.lines
+}
+
+struct Selector<'a> {
+    func: &'a Function,
+    allocation: &'a Allocation<Register>,
+    /// Callee-saved registers the function uses, stored at the bottom of the frame
+    saved: &'a [Register],
+    lines: Vec<Line>,
+}
+
+impl<'a> Selector<'a> {
+    fn emit(&mut self, opcode: Opcode, operands: Vec<Operand>) {
+        self.lines.push(Line::Inst(opcode, operands));
+    }
+
+    /// `dst = src + int` for any 64-bit `int`
+    fn add_immediate(&mut self, dst: Register, src: Register, int: i64) {
+        if fits_imm12(int) {
+            self.emit(Opcode::Addi, vec![reg(dst), reg(src), Operand::Immediate(int)]);
+        } else {
+            self.emit(Opcode::Li, vec![reg(SCRATCH[0]), Operand::Immediate(int)]);
+            self.emit(Opcode::Add, vec![reg(dst), reg(src), reg(SCRATCH[0])]);
+        }
+    }
+
+    /// A memory operand `offset` bytes above the stack pointer, going through a scratch register
+    /// when the offset is out of reach of a load or store
+    fn stack(&mut self, offset: i64) -> Operand {
+        if fits_imm12(offset) {
+            return Operand::Memory(Register::Sp, offset);
+        }
+        self.emit(Opcode::Li, vec![reg(ADDRESS), Operand::Immediate(offset)]);
+        self.emit(Opcode::Add, vec![reg(ADDRESS), reg(ADDRESS), reg(Register::Sp)]);
+        Operand::Memory(ADDRESS, 0)
+    }
+
+    fn slot(&mut self, slot: usize) -> Operand {
+        self.stack(8 * (self.saved.len() + slot) as i64)
+    }
+
+    /// A register holding `value`, loaded into the given scratch register when it is a constant
+    /// or spilled
+    fn read(&mut self, value: Value, scratch: Register) -> Register {
+        match value {
+            Value::Const(int) if int.is_zero() => Register::Zero,
+            Value::Const(int) => {
+                self.emit(Opcode::Li, vec![reg(scratch), Operand::Immediate(register_value(int))]);
+                scratch
+            }
+            Value::Register(vreg) => match self.allocation.location(vreg) {
+                Location::Register(allocated) => allocated,
+                Location::Stack(slot) => {
+                    let slot = self.slot(slot);
+                    self.emit(Opcode::Ld, vec![reg(scratch), slot]);
+                    scratch
+                }
+            },
+        }
+    }
+
+    /// Puts `value` in the register `target`
+    fn load(&mut self, value: Value, target: Register) {
+        let source = self.read(value, target);
+        if source != target {
+            self.emit(Opcode::Mv, vec![reg(target), reg(source)]);
+        }
+    }
+
+    fn instruction(&mut self, inst: &Instruction) {
+        let dst_type = self.func.registers[inst.dst().0];
+        let (dst, slot) = match self.allocation.location(inst.dst()) {
+            Location::Register(allocated) => (allocated, None),
+            Location::Stack(slot) => (SCRATCH[0], Some(slot)),
+        };
+
+        match inst {
+            Instruction::Copy {
+                src: Value::Const(int), ..
+            } => {
+                self.emit(Opcode::Li, vec![reg(dst), Operand::Immediate(register_value(*int))]);
+            }
+            Instruction::Copy { src, .. } => {
+                let src = self.read(*src, SCRATCH[1]);
+                self.emit(Opcode::Mv, vec![reg(dst), reg(src)]);
+            }
+            Instruction::Phi { .. } => panic!("phi instructions must be removed before code generation"),
+            Instruction::Convert { src, .. } => {
+                let src_type = self.func.type_of(*src);
+                let src = self.read(*src, SCRATCH[1]);
+                if is_word(dst_type) && !is_word(src_type) {
+                    self.emit(Opcode::SextW, vec![reg(dst), reg(src)]);
+                } else if !is_word(dst_type) && is_word(src_type) && !src_type.is_signed() {
+                    self.emit(Opcode::Slli, vec![reg(dst), reg(src), Operand::Immediate(32)]);
+                    self.emit(Opcode::Srli, vec![reg(dst), reg(dst), Operand::Immediate(32)]);
+                } else {
+                    // Sign extended words already are valid 64-bit values of the same sign
+                    self.emit(Opcode::Mv, vec![reg(dst), reg(src)]);
+                }
+            }
+            Instruction::Unary { op, src, .. } => {
+                let src = self.read(*src, SCRATCH[1]);
+                match op {
+                    UnaryOp::Neg => {
+                        let opcode = if is_word(dst_type) { Opcode::Subw } else { Opcode::Sub };
+                        self.emit(opcode, vec![reg(dst), reg(Register::Zero), reg(src)]);
+                    }
+                    UnaryOp::Not => self.emit(Opcode::Not, vec![reg(dst), reg(src)]),
+                }
+            }
+            Instruction::Binary { op, left, right, .. } => {
+                let op_type = self.func.type_of(*left);
+                let word = is_word(op_type);
+                let signed = op_type.is_signed();
+                let left = self.read(*left, SCRATCH[0]);
+                // Adding or subtracting a small constant takes an immediate, and so does shifting
+                // by a constant, which like a shift by a register takes the count modulo the width
+                let bits = if word { 32 } else { 64 };
+                let immediate = match (op, right, word) {
+                    (BinaryOp::Add, Value::Const(int), true) => Some((Opcode::Addiw, register_value(*int))),
+                    (BinaryOp::Add, Value::Const(int), false) => Some((Opcode::Addi, register_value(*int))),
+                    (BinaryOp::Sub, Value::Const(int), true) => {
+                        Some((Opcode::Addiw, register_value(*int).wrapping_neg()))
+                    }
+                    (BinaryOp::Sub, Value::Const(int), false) => {
+                        Some((Opcode::Addi, register_value(*int).wrapping_neg()))
+                    }
+                    (BinaryOp::Shl, Value::Const(int), _) => {
+                        let opcode = if word { Opcode::Slliw } else { Opcode::Slli };
+                        Some((opcode, (int.value % bits) as i64))
+                    }
+                    (BinaryOp::Shr, Value::Const(int), _) => {
+                        let opcode = match (word, signed) {
+                            (true, true) => Opcode::Sraiw,
+                            (true, false) => Opcode::Srliw,
+                            (false, true) => Opcode::Srai,
+                            (false, false) => Opcode::Srli,
+                        };
+                        Some((opcode, (int.value % bits) as i64))
+                    }
+                    _ => None,
+                };
+                if let Some((opcode, int)) = immediate.filter(|(_, int)| fits_imm12(*int)) {
+                    self.emit(opcode, vec![reg(dst), reg(left), Operand::Immediate(int)]);
+                } else {
+                    let right = self.read(*right, SCRATCH[1]);
+                    let (left, right) = (reg(left), reg(right));
+                    match op {
+                        BinaryOp::Equal => {
+                            // Both sides are sign extended the same way, so comparing all 64 bits works for words
+                            self.emit(Opcode::Sub, vec![reg(dst), left, right]);
+                            self.emit(Opcode::Seqz, vec![reg(dst), reg(dst)]);
+                        }
+                        _ => {
+                            let opcode = match (op, word, signed) {
+                                (BinaryOp::Add, true, _) => Opcode::Addw,
+                                (BinaryOp::Add, false, _) => Opcode::Add,
+                                (BinaryOp::Sub, true, _) => Opcode::Subw,
+                                (BinaryOp::Sub, false, _) => Opcode::Sub,
+                                (BinaryOp::Mul, true, _) => Opcode::Mulw,
+                                (BinaryOp::Mul, false, _) => Opcode::Mul,
+                                (BinaryOp::Shl, true, _) => Opcode::Sllw,
+                                (BinaryOp::Shl, false, _) => Opcode::Sll,
+                                (BinaryOp::Shr, true, true) => Opcode::Sraw,
+                                (BinaryOp::Shr, true, false) => Opcode::Srlw,
+                                (BinaryOp::Shr, false, true) => Opcode::Sra,
+                                (BinaryOp::Shr, false, false) => Opcode::Srl,
+                                (_, true, true) => Opcode::Divw,
+                                (_, true, false) => Opcode::Divuw,
+                                (_, false, true) => Opcode::Div,
+                                (_, false, false) => Opcode::Divu,
+                            };
+                            self.emit(opcode, vec![reg(dst), left, right]);
+                        }
+                    }
+                }
+            }
+            Instruction::Syscall { number, args, .. } => {
+                // No operand lives in a register the call clobbers, so each loads straight into place
+                for (i, arg) in args.iter().enumerate() {
+                    self.load(*arg, Register::A(i as u8));
+                }
+                self.load(*number, SYSCALL_NUMBER);
+                self.emit(Opcode::Ecall, vec![]);
+                self.emit(Opcode::Mv, vec![reg(dst), reg(Register::A(0))]);
+            }
+        }
+
+        if let Some(slot) = slot {
+            let slot = self.slot(slot);
+            self.emit(Opcode::Sd, vec![reg(SCRATCH[0]), slot]);
+        }
+    }
+
+    fn terminator(&mut self, terminator: &Terminator) {
+        match terminator {
+            Terminator::Return(value) => {
+                match *value {
+                    Value::Const(int) => self.emit(
+                        Opcode::Li,
+                        vec![reg(Register::A(0)), Operand::Immediate(register_value(int))],
+                    ),
+                    value => {
+                        let value = self.read(value, SCRATCH[0]);
+                        self.emit(Opcode::Mv, vec![reg(Register::A(0)), reg(value)]);
+                    }
+                }
+                for (i, saved) in self.saved.iter().enumerate() {
+                    let slot = self.stack(8 * i as i64);
+                    self.emit(Opcode::Ld, vec![reg(*saved), slot]);
+                }
+                let sp = reg(Register::Sp);
+                self.emit(Opcode::Addi, vec![sp.clone(), reg(FP), Operand::Immediate(-16)]);
+                self.emit(Opcode::Ld, vec![reg(Register::Ra), Operand::Memory(Register::Sp, 8)]);
+                self.emit(Opcode::Ld, vec![reg(FP), Operand::Memory(Register::Sp, 0)]);
+                self.emit(Opcode::Addi, vec![sp.clone(), sp, Operand::Immediate(16)]);
+                self.emit(Opcode::Ret, vec![]);
+            }
+            Terminator::Jump(target) => {
+                let label = block_label(self.func, *target);
+                self.emit(Opcode::J, vec![Operand::Label(label)]);
+            }
+            Terminator::Branch(value, then, otherwise) => {
+                let (then, otherwise) = (block_label(self.func, *then), block_label(self.func, *otherwise));
+                if let Value::Const(int) = value {
+                    let target = if int.is_zero() { otherwise } else { then };
+                    self.emit(Opcode::J, vec![Operand::Label(target)]);
+                    return;
+                }
+                let value = self.read(*value, SCRATCH[0]);
+                self.emit(Opcode::Bnez, vec![reg(value), Operand::Label(then)]);
+                self.emit(Opcode::J, vec![Operand::Label(otherwise)]);
+            }
+        }
+    }
+}
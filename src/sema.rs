@@ -0,0 +1,535 @@
+//! Semantic analysis, between parsing and lowering: resolves every name against the scopes it
+//! can be declared in, checks calls against the prototypes they resolve to, and works out the
+//! type of every expression. Calls to builtins become the nodes the backends lower.
+
+use diagnostic::{Diagnostic, Warning};
+use fold;
+use lexer::{Operator, Span};
+use parser::{Expression, ExpressionKind, Function, Program, Statement};
+use std::collections::HashMap;
+use std::mem;
+use types::{DataModel, Type};
+
+/// The functions the compiler provides, declared in a scope outside the file's
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Builtin {
+    Syscall,
+}
+
+impl Builtin {
+    const ALL: [(&'static str, Builtin); 1] = [("__builtin_syscall", Builtin::Syscall)];
+
+    fn prototype(self) -> Prototype {
+        match self {
+            // The system call number, then up to six arguments, all as wide as a register
+            Builtin::Syscall => Prototype {
+                return_type: Type::Long,
+                parameters: vec![Type::Long; 7],
+                optional: 6,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prototype {
+    pub return_type: Type,
+    pub parameters: Vec<Type>,
+    /// How many of the parameters, from the last, can be left out
+    pub optional: usize,
+}
+
+impl Prototype {
+    fn check_arguments(&self, name: &str, count: usize) -> Result<(), Diagnostic> {
+        let required = self.parameters.len() - self.optional;
+        if count < required {
+            return Err(Diagnostic::error(format!(
+                "too few arguments to function '{}': expected at least {}, have {}",
+                name, required, count
+            )));
+        }
+        if count > self.parameters.len() {
+            return Err(Diagnostic::error(format!(
+                "too many arguments to function '{}': expected at most {}, have {}",
+                name,
+                self.parameters.len(),
+                count
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// What a name refers to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Symbol {
+    /// A function defined in the file. `int f()` declares no prototype, so calls to it have
+    /// nothing to be checked against.
+    Function {
+        return_type: Type,
+    },
+    Builtin(Builtin),
+}
+
+/// Nested scopes, from the builtins' outward one in. A name resolves to its innermost
+/// declaration.
+pub struct SymbolTable {
+    scopes: Vec<HashMap<String, Symbol>>,
+}
+
+impl SymbolTable {
+    /// A table with the builtins' scope, and the file's scope inside it
+    pub fn new() -> SymbolTable {
+        let builtins = Builtin::ALL
+            .iter()
+            .map(|(name, builtin)| (String::from(*name), Symbol::Builtin(*builtin)))
+            .collect();
+        SymbolTable {
+            scopes: vec![builtins, HashMap::new()],
+        }
+    }
+
+    pub fn enter(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    pub fn leave(&mut self) {
+        self.scopes.pop();
+    }
+
+    /// Declares a name in the innermost scope, which must not already declare it
+    pub fn declare(&mut self, name: &str, symbol: Symbol) -> Result<(), Diagnostic> {
+        if let Some(Symbol::Builtin(_)) = self.resolve(name) {
+            return Err(Diagnostic::error(format!(
+                "'{}' is a builtin and cannot be redefined",
+                name
+            )));
+        }
+        let scope = self.scopes.last_mut().expect("The builtins' scope is never left");
+        if scope.contains_key(name) {
+            return Err(Diagnostic::error(format!("redefinition of '{}'", name)));
+        }
+        scope.insert(String::from(name), symbol);
+        Ok(())
+    }
+
+    pub fn resolve(&self, name: &str) -> Option<&Symbol> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+}
+
+impl Default for SymbolTable {
+    fn default() -> SymbolTable {
+        SymbolTable::new()
+    }
+}
+
+/// Checks the program, resolving its calls in place and adding warnings about conversions
+/// that may change values
+pub fn analyze(prog: &mut Program, diagnostics: &mut Vec<Diagnostic>) -> Result<(), Diagnostic> {
+    Analyzer {
+        symbols: SymbolTable::new(),
+        diagnostics,
+        model: prog.model,
+    }
+    .function(&mut prog.function)
+}
+
+struct Analyzer<'a> {
+    symbols: SymbolTable,
+    diagnostics: &'a mut Vec<Diagnostic>,
+    model: DataModel,
+}
+
+impl<'a> Analyzer<'a> {
+    fn function(&mut self, func: &mut Function) -> Result<(), Diagnostic> {
+        // Declared before its body, so that the function is in scope within it
+        self.symbols
+            .declare(&func.name, Symbol::Function { return_type: Type::Int })?;
+        self.symbols.enter();
+        self.statement(&mut func.statement)?;
+        self.symbols.leave();
+        Ok(())
+    }
+
+    fn statement(&mut self, stmt: &mut Statement) -> Result<(), Diagnostic> {
+        match stmt {
+            Statement::Return(exp) => {
+                self.expression(exp)?;
+                self.convert(exp, Type::Int);
+                Ok(())
+            }
+        }
+    }
+
+    /// Works out the type of an expression, after the conversions C applies to its operands,
+    /// and records it on the expression and each of its subexpressions
+    fn expression(&mut self, exp: &mut Expression) -> Result<Type, Diagnostic> {
+        // A tree built by hand may have an operator where C cannot, which no later stage handles
+        exp.precedence()?;
+        let span = exp.span;
+        let ctype = match &mut exp.kind {
+            ExpressionKind::Binary { left, operator, right } => {
+                let left_type = self.expression(left)?;
+                let right_type = self.expression(right)?;
+                // The operands of a shift are promoted on their own, and the result has the
+                // type of the left one
+                let (ctype, right_type) = if operator.is_shift() {
+                    (left_type.promote(), right_type.promote())
+                } else {
+                    let ctype = left_type.common(right_type, self.model);
+                    (ctype, ctype)
+                };
+                self.convert(left, ctype);
+                self.convert(right, right_type);
+                ctype
+            }
+            ExpressionKind::Unary { operator, operand } => {
+                let operand_type = self.expression(operand)?;
+                self.truth_value(*operator, operand, span);
+                match operator {
+                    Operator::Bang => Type::Int,
+                    _ => operand_type.promote(),
+                }
+            }
+            ExpressionKind::Parenthesized(exp) => self.expression(exp)?,
+            ExpressionKind::Const(int) => int.ctype,
+            ExpressionKind::Call { name, args } => {
+                for arg in args.iter_mut() {
+                    self.expression(arg)?;
+                }
+                let builtin = match self.symbols.resolve(name) {
+                    Some(Symbol::Builtin(builtin)) => *builtin,
+                    Some(Symbol::Function { .. }) => {
+                        return Err(Diagnostic::error(format!(
+                            "cannot call '{}': rcc only calls builtins so far",
+                            name
+                        ))
+                        .at(span))
+                    }
+                    None => {
+                        return Err(Diagnostic::error(format!("implicit declaration of function '{}'", name)).at(span))
+                    }
+                };
+                let prototype = builtin.prototype();
+                prototype
+                    .check_arguments(name, args.len())
+                    .map_err(|err| err.at(span))?;
+                for (arg, parameter) in args.iter().zip(&prototype.parameters) {
+                    self.convert(arg, *parameter);
+                }
+                let mut args = mem::take(args);
+                exp.kind = match builtin {
+                    Builtin::Syscall => ExpressionKind::Syscall {
+                        number: Box::new(args.remove(0)),
+                        args,
+                    },
+                };
+                prototype.return_type
+            }
+            ExpressionKind::Syscall { number, args } => {
+                for arg in std::iter::once(&mut **number).chain(args) {
+                    self.expression(arg)?;
+                    self.convert(arg, Type::Long);
+                }
+                Type::Long
+            }
+        };
+        exp.ctype = Some(ctype);
+        Ok(ctype)
+    }
+
+    /// Warns, as gcc does under `-Wall`, about an operator that treats a truth value as a number
+    /// or a number as a truth value: `~!x` is never zero, and `!(x * y)` likely meant `!(x && y)`.
+    /// Constant operands are left alone, as gcc folds them first.
+    fn truth_value(&mut self, operator: Operator, operand: &Expression, span: Span) {
+        if fold::evaluate(operand, self.model, &mut Vec::new()).is_some() {
+            return;
+        }
+        // Negation does not change whether a value is zero, so `!-(x * y)` is tested the same way
+        let tested = unwrapped(operand, operator == Operator::Bang);
+        let diagnostic = match (operator, &tested.kind) {
+            (
+                Operator::Not,
+                ExpressionKind::Unary {
+                    operator: Operator::Bang,
+                    ..
+                },
+            ) => Diagnostic::warning(Warning::BoolOperation, String::from("'~' on a boolean expression")).at(span),
+            (
+                Operator::Bang,
+                ExpressionKind::Binary {
+                    operator: Operator::Star,
+                    ..
+                },
+            ) => Diagnostic::warning(
+                Warning::IntInBoolContext,
+                String::from("'*' in boolean context, suggest '&&' instead"),
+            )
+            .at(tested.span),
+            _ => return,
+        };
+        self.diagnostics.push(diagnostic);
+    }
+
+    /// Warns about an implicit conversion that may change a value, as gcc does: a constant when
+    /// it does change, and anything else when the type it converts to cannot represent every
+    /// value of its own
+    fn convert(&mut self, exp: &Expression, to: Type) {
+        let from = exp.ctype();
+        if from == to {
+            return;
+        }
+        let signedness = if to.is_signed() { "signed" } else { "unsigned" };
+        let diagnostic = match fold::evaluate(exp, self.model, &mut Vec::new()) {
+            Some(int) => {
+                let converted = int.cast(to, self.model);
+                if converted.mathematical() == int.mathematical() {
+                    return;
+                }
+                let changes = format!(
+                    "conversion from '{}' to '{}' changes value from '{}' to '{}'",
+                    from,
+                    to,
+                    int.mathematical(),
+                    converted.mathematical()
+                );
+                if to.size(self.model) < from.size(self.model) {
+                    let prefix = if to.is_signed() { "overflow in" } else { "unsigned" };
+                    Diagnostic::warning(Warning::Overflow, format!("{} {}", prefix, changes))
+                } else {
+                    Diagnostic::warning(Warning::SignConversion, format!("{} {}", signedness, changes))
+                }
+            }
+            None if to.size(self.model) < from.size(self.model) => Diagnostic::warning(
+                Warning::Conversion,
+                format!("conversion from '{}' to '{}' may change value", from, to),
+            ),
+            // Unsigned values fit in any wider signed type
+            None if from.is_signed() != to.is_signed()
+                && (from.is_signed() || to.size(self.model) == from.size(self.model)) =>
+            {
+                Diagnostic::warning(
+                    Warning::SignConversion,
+                    format!(
+                        "conversion to '{}' from '{}' may change the sign of the result",
+                        to, from
+                    ),
+                )
+            }
+            None => return,
+        };
+        self.diagnostics.push(diagnostic.at(exp.span));
+    }
+}
+
+/// The expression inside any parentheses, and inside any negations too if `negations`
+fn unwrapped(mut exp: &Expression, negations: bool) -> &Expression {
+    loop {
+        exp = match &exp.kind {
+            ExpressionKind::Parenthesized(inner) => inner,
+            ExpressionKind::Unary {
+                operator: Operator::Negate,
+                operand,
+            } if negations => operand,
+            _ => return exp,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lexer;
+    use parser;
+
+    fn analyzed(source: &str) -> Result<Program, Diagnostic> {
+        let mut prog = parser::parse(lexer::lex(source).unwrap(), DataModel::LP64).unwrap();
+        analyze(&mut prog, &mut Vec::new()).map(|_| prog)
+    }
+
+    fn message(source: &str) -> String {
+        analyzed(source).unwrap_err().message
+    }
+
+    #[test]
+    fn builtin_calls_are_resolved() {
+        let prog = analyzed("int main() { return __builtin_syscall(60, 1 + 2); }").unwrap();
+        match prog.function.statement {
+            Statement::Return(Expression {
+                kind: ExpressionKind::Syscall { ref args, .. },
+                ..
+            }) => assert_eq!(args.len(), 1),
+            ref stmt => panic!("Unexpected statement {:?}", stmt),
+        }
+    }
+
+    #[test]
+    fn undeclared_functions_are_errors() {
+        assert_eq!(
+            message("int main() { return foo(1); }"),
+            "implicit declaration of function 'foo'"
+        );
+        assert_eq!(
+            message("int main() { return main(); }"),
+            "cannot call 'main': rcc only calls builtins so far"
+        );
+    }
+
+    #[test]
+    fn calls_are_checked_against_prototypes() {
+        assert_eq!(
+            message("int main() { return __builtin_syscall(); }"),
+            "too few arguments to function '__builtin_syscall': expected at least 1, have 0"
+        );
+        assert_eq!(
+            message("int main() { return __builtin_syscall(1, 2, 3, 4, 5, 6, 7, 8); }"),
+            "too many arguments to function '__builtin_syscall': expected at most 7, have 8"
+        );
+    }
+
+    #[test]
+    fn builtins_cannot_be_redefined() {
+        assert_eq!(
+            message("int __builtin_syscall() { return 0; }"),
+            "'__builtin_syscall' is a builtin and cannot be redefined"
+        );
+    }
+
+    #[test]
+    fn inner_scopes_shadow_outer_ones() {
+        let mut symbols = SymbolTable::new();
+        symbols
+            .declare("f", Symbol::Function { return_type: Type::Int })
+            .unwrap();
+        assert!(symbols
+            .declare(
+                "f",
+                Symbol::Function {
+                    return_type: Type::Long
+                }
+            )
+            .is_err());
+        symbols.enter();
+        symbols
+            .declare(
+                "f",
+                Symbol::Function {
+                    return_type: Type::Long,
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            symbols.resolve("f"),
+            Some(&Symbol::Function {
+                return_type: Type::Long
+            })
+        );
+        symbols.leave();
+        assert_eq!(symbols.resolve("f"), Some(&Symbol::Function { return_type: Type::Int }));
+    }
+
+    fn warnings(source: &str) -> Vec<String> {
+        let mut prog = parser::parse(lexer::lex(source).unwrap(), DataModel::LP64).unwrap();
+        let mut diagnostics = Vec::new();
+        analyze(&mut prog, &mut diagnostics).unwrap();
+        diagnostics.iter().map(Diagnostic::to_string).collect()
+    }
+
+    #[test]
+    fn constants_are_warned_about_when_conversion_changes_them() {
+        assert_eq!(
+            warnings("int main() { return 5000000000l; }"),
+            ["warning: overflow in conversion from 'long' to 'int' changes value from '5000000000' to '705032704' [-Woverflow]"]
+        );
+        assert_eq!(
+            warnings("int main() { return -1 + 2000000000u; }"),
+            ["warning: unsigned conversion from 'int' to 'unsigned int' changes value from '-1' to '4294967295' [-Wsign-conversion]"]
+        );
+        assert!(warnings("int main() { return 1l + 2u; }").is_empty());
+    }
+
+    #[test]
+    fn truth_values_used_as_numbers_are_warned_about() {
+        assert_eq!(
+            warnings("int main() { return ~(!__builtin_syscall(39)) + !-(__builtin_syscall(39) * 2); }"),
+            [
+                "warning: '~' on a boolean expression [-Wbool-operation]",
+                "warning: '*' in boolean context, suggest '&&' instead [-Wint-in-bool-context]"
+            ]
+        );
+        // Constants are folded first, and `~` is not looked through
+        assert!(
+            warnings("int main() { return ~!5 + !(2 * 3) + !~(__builtin_syscall(39) * 2); }")
+                .iter()
+                .all(|warning| warning.ends_with("[-Wconversion]"))
+        );
+    }
+
+    #[test]
+    fn other_conversions_are_warned_about_when_they_may_change_values() {
+        assert_eq!(
+            warnings("int main() { return __builtin_syscall(39); }"),
+            ["warning: conversion from 'long' to 'int' may change value [-Wconversion]"]
+        );
+        assert_eq!(
+            warnings("int main() { return __builtin_syscall(39, 1ul * __builtin_syscall(39)); }"),
+            [
+                "warning: conversion to 'unsigned long' from 'long' may change the sign of the result [-Wsign-conversion]",
+                "warning: conversion to 'long' from 'unsigned long' may change the sign of the result [-Wsign-conversion]",
+                "warning: conversion from 'long' to 'int' may change value [-Wconversion]"
+            ]
+        );
+        // A shift count is not converted to the type of the value it shifts
+        assert!(warnings("int main() { return 1 << __builtin_syscall(39); }").is_empty());
+    }
+
+    fn returned(source: &str) -> Expression {
+        let Statement::Return(exp) = analyzed(&format!("int main() {{ return {}; }}", source))
+            .unwrap()
+            .function
+            .statement;
+        exp
+    }
+
+    #[test]
+    fn expressions_are_annotated_with_the_types_of_their_conversions() {
+        assert_eq!(returned("1u + 2").ctype, Some(Type::UnsignedInt));
+        assert_eq!(returned("1 * (2l - 3)").ctype, Some(Type::Long));
+        assert_eq!(returned("!5ul").ctype, Some(Type::Int));
+        assert_eq!(returned("~5ul").ctype, Some(Type::UnsignedLong));
+        assert_eq!(returned("__builtin_syscall(39)").ctype, Some(Type::Long));
+        assert_eq!(returned("1 << 2ul").ctype, Some(Type::Int));
+        assert_eq!(returned("1ul >> 2").ctype, Some(Type::UnsignedLong));
+    }
+
+    #[test]
+    fn operands_keep_their_own_types() {
+        match returned("1u + -2l").kind {
+            ExpressionKind::Binary { left, right, .. } => {
+                assert_eq!(left.ctype, Some(Type::UnsignedInt));
+                assert_eq!(right.ctype, Some(Type::Long));
+                match right.kind {
+                    ExpressionKind::Unary { operand, .. } => assert_eq!(operand.ctype, Some(Type::Long)),
+                    kind => panic!("Unexpected expression {:?}", kind),
+                }
+            }
+            kind => panic!("Unexpected expression {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn diagnostics_point_at_their_expression() {
+        let source = "int main() {\n    return 1 + foo(2);\n}";
+        let error = analyzed(source).unwrap_err();
+        assert_eq!(
+            error.located("a.c", source),
+            "a.c:2:16: error: implicit declaration of function 'foo'"
+        );
+        let source = "int main() {\n    return 5000000000l;\n}";
+        let mut prog = parser::parse(lexer::lex(source).unwrap(), DataModel::LP64).unwrap();
+        let mut diagnostics = Vec::new();
+        analyze(&mut prog, &mut diagnostics).unwrap();
+        assert_eq!(diagnostics[0].span.unwrap().line_column(source), (2, 12));
+    }
+}
@@ -0,0 +1,582 @@
+use aarch64;
+use backend::{self, Backend};
+use diagnostic::{Diagnostic, Level, Warnings};
+use elf;
+use encoder;
+use fold;
+use generator::{self, SourceFile, Syntax};
+use i386;
+use ir;
+use json::Format;
+use lexer;
+use linker;
+use llvm;
+use opt;
+use parser::{self, Program};
+use riscv;
+use sema;
+use target::Target;
+use wasm;
+
+/// What a session compiles its source to
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Emit {
+    /// The tokens with their positions
+    Tokens,
+    /// The tree the parser built, before constant folding
+    Ast,
+    /// The intermediate representation the backends select from, after optimization
+    Ir,
+    /// Assembly for the native targets, a WebAssembly text module for wasm32
+    Assembly,
+    /// A relocatable ELF object, which only x86-64 is encoded to without an assembler
+    Object,
+    /// LLVM IR, straight from the AST
+    Llvm,
+}
+
+#[derive(Debug)]
+pub enum Output {
+    /// Lines of assembly, IR or a dump
+    Text(Vec<String>),
+    Binary(Vec<u8>),
+}
+
+/// A successful compilation, with the warnings it raised
+#[derive(Debug)]
+pub struct Compilation {
+    /// One output for each of the kinds asked for, in the same order
+    pub outputs: Vec<Output>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// The settings a program is compiled with, built up from `Session::new`. Compiling neither
+/// reads nor writes files, prints nothing and runs no other programs, so embedders decide
+/// what to do with the output.
+pub struct Session {
+    target: Target,
+    syntax: Syntax,
+    optimizations: opt::Options,
+    nostdlib: bool,
+    format: Format,
+    warnings: Warnings,
+    sanitize: bool,
+}
+
+impl Session {
+    pub fn new(target: Target) -> Session {
+        Session {
+            target,
+            syntax: Syntax::ATT,
+            optimizations: opt::Options::default(),
+            nostdlib: false,
+            format: Format::Text,
+            warnings: Warnings::default(),
+            sanitize: false,
+        }
+    }
+
+    /// The x86 assembler dialect
+    pub fn syntax(mut self, syntax: Syntax) -> Session {
+        self.syntax = syntax;
+        self
+    }
+
+    pub fn optimizations(mut self, optimizations: opt::Options) -> Session {
+        self.optimizations = optimizations;
+        self
+    }
+
+    /// Compiles for a program linked without the C library: `main` comes with rcc's own
+    /// `_start`, and `link` leaves the stub out
+    pub fn nostdlib(mut self, nostdlib: bool) -> Session {
+        self.nostdlib = nostdlib;
+        self
+    }
+
+    /// How tokens and the AST are written
+    pub fn format(mut self, format: Format) -> Session {
+        self.format = format;
+        self
+    }
+
+    /// Which warnings are reported, and whether they are errors
+    pub fn warnings(mut self, warnings: Warnings) -> Session {
+        self.warnings = warnings;
+        self
+    }
+
+    /// Checks signed overflow and division by zero at run time: a failed check prints the
+    /// error and where in the source it happened, then aborts. Only x86-64 code is checked.
+    pub fn sanitize(mut self, sanitize: bool) -> Session {
+        self.sanitize = sanitize;
+        self
+    }
+
+    /// Compiles one translation unit to each of the kinds of output, going only as far as
+    /// they need. The errors come after any warnings raised before them. Runtime checks
+    /// report their locations in `<source>`.
+    pub fn compile(&self, source: &str, emits: &[Emit]) -> Result<Compilation, Vec<Diagnostic>> {
+        self.compile_file("<source>", source, emits)
+    }
+
+    /// Compiles a translation unit read from `path`, as `compile` does, naming the file in the
+    /// locations runtime checks report
+    pub fn compile_file(&self, path: &str, source: &str, emits: &[Emit]) -> Result<Compilation, Vec<Diagnostic>> {
+        let tokens = lexer::lex(source).map_err(|err| vec![err])?;
+        let outputs: Vec<Option<Output>> = emits
+            .iter()
+            .map(|emit| match emit {
+                Emit::Tokens => Some(Output::Text(lexer::dump(source, &tokens, self.format))),
+                _ => None,
+            })
+            .collect();
+        if outputs.iter().all(Option::is_some) {
+            return Ok(Compilation {
+                outputs: outputs.into_iter().flatten().collect(),
+                diagnostics: Vec::new(),
+            });
+        }
+
+        let prog = parser::parse(tokens, self.target.data_model()).map_err(|err| vec![err])?;
+        self.finish(prog, emits, outputs, Some(SourceFile { path, text: source }))
+    }
+
+    /// Compiles a parsed program, which has no tokens left to emit. The types of its constants
+    /// were chosen under a data model, which has to be the target's. With no source to point
+    /// into, runtime checks report the function they are in.
+    pub fn generate(&self, prog: Program, emits: &[Emit]) -> Result<Compilation, Vec<Diagnostic>> {
+        let model = self.target.data_model();
+        if prog.model != model {
+            return Err(vec![Diagnostic::error(format!(
+                "program was parsed for the {:?} data model, but {} uses {:?}",
+                prog.model,
+                self.target.triple(),
+                model
+            ))]);
+        }
+        self.finish(prog, emits, emits.iter().map(|_| None).collect(), None)
+    }
+
+    /// Fills in the outputs from the AST on
+    fn finish(
+        &self,
+        mut prog: Program,
+        emits: &[Emit],
+        mut outputs: Vec<Option<Output>>,
+        source: Option<SourceFile>,
+    ) -> Result<Compilation, Vec<Diagnostic>> {
+        for (emit, output) in emits.iter().zip(&mut outputs) {
+            if *emit == Emit::Ast {
+                *output = Some(Output::Text(parser::dump(&prog, self.format)));
+            }
+        }
+
+        let mut diagnostics = Vec::new();
+        if let Err(err) = sema::analyze(&mut prog, &mut diagnostics) {
+            diagnostics.push(err);
+            return Err(self.warnings.apply(diagnostics));
+        }
+        if outputs.iter().any(Option::is_none) {
+            let prog = fold::fold(prog, &mut diagnostics);
+            for (emit, output) in emits.iter().zip(&mut outputs) {
+                if output.is_some() {
+                    continue;
+                }
+                match self.lower(&prog, *emit, source) {
+                    Ok(lowered) => *output = Some(lowered),
+                    Err(err) => {
+                        diagnostics.push(err);
+                        return Err(self.warnings.apply(diagnostics));
+                    }
+                }
+            }
+        }
+        // Under `-Werror`, any warning fails the compilation
+        let diagnostics = self.warnings.apply(diagnostics);
+        if diagnostics.iter().any(|diagnostic| diagnostic.level == Level::Error) {
+            return Err(diagnostics);
+        }
+        Ok(Compilation {
+            outputs: outputs.into_iter().flatten().collect(),
+            diagnostics,
+        })
+    }
+
+    fn lower(&self, prog: &Program, emit: Emit, source: Option<SourceFile>) -> Result<Output, Diagnostic> {
+        if emit == Emit::Tokens {
+            return Err(Diagnostic::error(String::from(
+                "tokens can only be emitted when compiling source",
+            )));
+        }
+        if self.target == Target::Wasm32 && prog.uses_syscalls() {
+            // A wasm32-unknown-unknown module has no kernel to call, only what its host imports
+            return Err(Diagnostic::error(String::from(
+                "__builtin_syscall is not available on wasm32",
+            )));
+        }
+
+        // The checks are made as instructions are selected, so the IR has none either way
+        if self.sanitize && emit != Emit::Ir && (self.target != Target::X86_64 || emit == Emit::Llvm) {
+            return Err(Diagnostic::error(String::from(
+                "runtime checks are only generated for x86-64 assembly and objects",
+            )));
+        }
+
+        if emit == Emit::Llvm {
+            // LLVM IR comes straight from the AST, leaving optimization and code generation to llc
+            return Ok(Output::Text(llvm::generate(prog, self.target)));
+        }
+
+        if self.target == Target::Wasm32 {
+            if emit != Emit::Assembly {
+                return Err(Diagnostic::error(String::from(
+                    "wasm32 modules are only written as text",
+                )));
+            }
+            // WebAssembly comes straight from the AST and needs no assembler
+            let module = wasm::lower(prog);
+            wasm::validate(&module).map_err(|err| {
+                Diagnostic::error(format!(
+                    "internal compiler error: generated invalid WebAssembly {}",
+                    err
+                ))
+            })?;
+            return Ok(Output::Text(wasm::generate(&module)));
+        }
+
+        let mut module = ir::lower(prog);
+        opt::optimize(&mut module, &self.optimizations);
+        if emit == Emit::Ir {
+            return Ok(Output::Text(module.to_string().lines().map(String::from).collect()));
+        }
+
+        // `_start` goes with whichever translation unit defines `main`
+        let start = self.nostdlib && module.functions.iter().any(|func| func.name == "main");
+        let syntax = self.syntax;
+        if self.target == Target::X86_64 {
+            let backend = generator::X86_64 {
+                syntax,
+                peephole: self.optimizations.machine_pass("peephole"),
+                sanitize: self.sanitize,
+                source,
+            };
+            let mut lines = if start { backend.start() } else { Vec::new() };
+            lines.extend(backend::generate_module(&backend, &module));
+            return Ok(match emit {
+                // x86-64 is encoded in-process, with no assembler involved
+                Emit::Object => Output::Binary(elf::write(&encoder::assemble(&lines))),
+                _ => Output::Text(backend.render(&lines)),
+            });
+        }
+
+        if emit == Emit::Object {
+            return Err(Diagnostic::error(format!(
+                "rcc has no built-in assembler for {}",
+                self.target.triple()
+            )));
+        }
+        Ok(Output::Text(match self.target {
+            Target::I386 => backend::generate(&i386::I386 { syntax }, &module, start),
+            Target::AArch64 => backend::generate(&aarch64::AArch64, &module, start),
+            Target::RiscV64 => backend::generate(&riscv::RiscV64, &module, start),
+            Target::X86_64 | Target::Wasm32 => unreachable!(),
+        }))
+    }
+
+    /// Links x86-64 objects into a static executable, without any external tools. rcc's
+    /// `_start` stub is added unless the objects bring their own, under `nostdlib`.
+    pub fn link(&self, objects: &[Vec<u8>]) -> Result<Vec<u8>, Diagnostic> {
+        if self.target != Target::X86_64 {
+            return Err(Diagnostic::error(format!(
+                "rcc has no built-in linker for {}",
+                self.target.triple()
+            )));
+        }
+        let mut objects = objects.to_vec();
+        if !self.nostdlib {
+            objects.insert(0, elf::write(&linker::start_stub()));
+        }
+        linker::link(&objects).map_err(Diagnostic::error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diagnostic::Level;
+    use types::DataModel;
+
+    fn text(session: &Session, source: &str, emit: Emit) -> Vec<String> {
+        match session.compile(source, &[emit]).unwrap().outputs.pop() {
+            Some(Output::Text(lines)) => lines,
+            _ => panic!("expected text"),
+        }
+    }
+
+    #[test]
+    fn compiles_source_to_assembly() {
+        let session = Session::new(Target::X86_64);
+        let assembly = text(&session, "int main() { return 2 + 3; }", Emit::Assembly);
+        assert!(assembly.iter().any(|line| line.contains("main:")));
+    }
+
+    #[test]
+    fn x86_64_objects_are_encoded_in_process() {
+        let session = Session::new(Target::X86_64);
+        match session
+            .compile("int main() { return 0; }", &[Emit::Object])
+            .unwrap()
+            .outputs
+            .pop()
+        {
+            Some(Output::Binary(object)) => assert_eq!(&object[..4], b"\x7fELF"),
+            _ => panic!("expected an object"),
+        }
+        assert!(Session::new(Target::AArch64)
+            .compile("int main() { return 0; }", &[Emit::Object])
+            .is_err());
+    }
+
+    #[test]
+    fn i386_shifts_long_long_in_libgcc() {
+        let session = Session::new(Target::I386);
+        let source = "int main() { return (__builtin_syscall(39) + 1ll << 40) + (-1ll >> __builtin_syscall(39)); }";
+        let assembly = text(&session, source, Emit::Assembly);
+        assert!(assembly.iter().any(|line| line == "\tcall\t__ashldi3"));
+        assert!(assembly.iter().any(|line| line == "\tcall\t__ashrdi3"));
+        let assembly = text(
+            &session,
+            "int main() { return 1 << __builtin_syscall(39); }",
+            Emit::Assembly,
+        );
+        assert!(assembly.iter().any(|line| line == "\tshll\t%cl, %eax"));
+    }
+
+    #[test]
+    fn assembly_marks_the_stack_non_executable() {
+        for (target, note) in [
+            (Target::X86_64, ".section .note.GNU-stack,\"\",@progbits"),
+            (Target::I386, ".section .note.GNU-stack,\"\",@progbits"),
+            (Target::AArch64, ".section .note.GNU-stack,\"\",%progbits"),
+            (Target::RiscV64, ".section .note.GNU-stack,\"\",@progbits"),
+        ] {
+            let assembly = text(&Session::new(target), "int main() { return 0; }", Emit::Assembly);
+            assert_eq!(assembly[..2], [note, ".text"], "{:?}", target);
+        }
+        let intel = Session::new(Target::X86_64).syntax(Syntax::Intel);
+        let assembly = text(&intel, "int main() { return 0; }", Emit::Assembly);
+        assert_eq!(
+            assembly[..3],
+            [
+                ".intel_syntax noprefix",
+                ".section .note.GNU-stack,\"\",@progbits",
+                ".text"
+            ]
+        );
+    }
+
+    #[test]
+    fn every_kind_comes_out_in_order() {
+        let session = Session::new(Target::X86_64);
+        let emits = [Emit::Ir, Emit::Tokens, Emit::Assembly, Emit::Ast];
+        let outputs = session.compile("int main() { return 1; }", &emits).unwrap().outputs;
+        assert_eq!(outputs.len(), 4);
+        match &outputs[1] {
+            Output::Text(lines) => assert_eq!(lines[0], "1:1: keyword 'int'"),
+            Output::Binary(_) => panic!("expected text"),
+        }
+    }
+
+    #[test]
+    fn trees_with_misplaced_operators_are_rejected() {
+        use lexer::Operator;
+        use parser::{ExpressionKind, Statement};
+
+        let mut prog = parser::parse(lexer::lex("int main() { return 1 + 2; }").unwrap(), DataModel::LP64).unwrap();
+        let Statement::Return(ref mut exp) = prog.function.statement;
+        if let ExpressionKind::Binary { ref mut operator, .. } = exp.kind {
+            *operator = Operator::Not;
+        }
+        let errors = Session::new(Target::X86_64)
+            .generate(prog, &[Emit::Assembly])
+            .unwrap_err();
+        assert_eq!(errors[0].message, "'~' is not a binary operator");
+    }
+
+    #[test]
+    fn tokens_need_only_lexing() {
+        let session = Session::new(Target::X86_64).format(Format::Json);
+        let tokens = text(&session, "int main() {\n  return 1 +", Emit::Tokens);
+        assert_eq!(tokens.len(), 1);
+        assert!(tokens[0].ends_with(r#"{"kind":"operator","text":"+","line":2,"column":12,"start":24,"end":25}]"#));
+    }
+
+    #[test]
+    fn the_ast_is_dumped_before_folding() {
+        let session = Session::new(Target::X86_64);
+        let ast = text(&session, "int main() { return -(2 * 3ul); }", Emit::Ast);
+        assert_eq!(
+            ast,
+            [
+                "Function main",
+                "  Return",
+                "    UnaryOperation -",
+                "      Parenthesized",
+                "        BinaryOperation *",
+                "          Constant 2 'int'",
+                "          Constant 3ul 'unsigned long'",
+            ]
+        );
+        let session = session.format(Format::Json);
+        assert_eq!(
+            text(&session, "int f() { return !7; }", Emit::Ast),
+            [concat!(
+                r#"{"node":"Program","function":{"node":"Function","name":"f","statement":"#,
+                r#"{"node":"Return","expression":{"node":"UnaryOperation","operator":"!","#,
+                r#""operand":{"node":"Constant","value":7,"type":"int"}}}}}"#
+            )]
+        );
+    }
+
+    #[test]
+    fn constants_take_their_types_from_the_data_model() {
+        let source = "int main() { return 3000000000; }";
+        // A `long` holds the constant under LP64, but only a `long long` does under ILP32
+        let ast = |target| text(&Session::new(target), source, Emit::Ast).pop().unwrap();
+        assert_eq!(ast(Target::X86_64), "    Constant 3000000000l 'long'");
+        assert_eq!(ast(Target::I386), "    Constant 3000000000ll 'long long'");
+        assert_eq!(ast(Target::Wasm32), "    Constant 3000000000ll 'long long'");
+
+        let prog = parser::parse(lexer::lex(source).unwrap(), DataModel::LP64).unwrap();
+        let diagnostics = Session::new(Target::I386)
+            .generate(prog, &[Emit::Assembly])
+            .unwrap_err();
+        assert_eq!(
+            diagnostics[0].message,
+            "program was parsed for the LP64 data model, but i386-pc-linux-gnu uses ILP32"
+        );
+    }
+
+    #[test]
+    fn errors_are_diagnostics() {
+        let session = Session::new(Target::X86_64);
+        for source in &[
+            "int main() { return 1 $ 2; }",
+            "int main() { return (1; }",
+            "int main() { return",
+        ] {
+            let diagnostics = session.compile(source, &[Emit::Assembly]).unwrap_err();
+            assert_eq!(diagnostics.len(), 1);
+            assert_eq!(diagnostics[0].level, Level::Error);
+        }
+    }
+
+    #[test]
+    fn werror_fails_on_warnings() {
+        let mut warnings = Warnings::default();
+        let source = "int main() { return 2147483647 + 1; }";
+        assert!(Session::new(Target::X86_64).compile(source, &[Emit::Assembly]).is_ok());
+        warnings.set_errors(true);
+        let session = Session::new(Target::X86_64).warnings(warnings.clone());
+        let diagnostics = session.compile(source, &[Emit::Assembly]).unwrap_err();
+        assert_eq!(diagnostics[0].level, Level::Error);
+        warnings.toggle("overflow", false).unwrap();
+        let session = Session::new(Target::X86_64).warnings(warnings);
+        assert!(session
+            .compile(source, &[Emit::Assembly])
+            .unwrap()
+            .diagnostics
+            .is_empty());
+    }
+
+    #[test]
+    fn sanitized_code_checks_signed_arithmetic() {
+        let session = Session::new(Target::X86_64).sanitize(true);
+        let source = "int main() { return -__builtin_syscall(39) / (__builtin_syscall(39) + 1u); }";
+        let assembly = text(&session, source, Emit::Assembly);
+        assert!(assembly.iter().any(|line| line.starts_with("\tjo")));
+        assert!(assembly.iter().any(|line| line.starts_with("\tje")));
+        assert!(assembly
+            .iter()
+            .any(|line| line.contains("signed integer overflow in 'long' negation")));
+        // The quotient is unsigned long, which only division by zero makes undefined
+        assert!(!assembly.iter().any(|line| line.contains("in 'unsigned long' division")));
+        assert!(assembly.iter().any(|line| line == "__rcc_ubsan_abort:"));
+        assert!(session.compile(source, &[Emit::Object]).is_ok());
+        assert!(!text(&Session::new(Target::X86_64), source, Emit::Assembly)
+            .iter()
+            .any(|line| line.contains("ubsan")));
+        assert!(Session::new(Target::AArch64)
+            .sanitize(true)
+            .compile(source, &[Emit::Assembly])
+            .is_err());
+    }
+
+    #[test]
+    fn sanitized_code_checks_shift_counts() {
+        let session = Session::new(Target::X86_64).sanitize(true);
+        let assembly = text(
+            &session,
+            "int main() { return 1 << __builtin_syscall(39); }",
+            Emit::Assembly,
+        );
+        let check = assembly.iter().position(|line| line == "\tcmp\t$31, %rax").unwrap();
+        assert!(assembly[check + 1].starts_with("\tja"));
+        assert!(assembly.iter().any(|line| line.ends_with("\tshl\t%cl, %eax")));
+        assert!(assembly
+            .iter()
+            .any(|line| line.contains("shift exponent is negative or too large for 32-bit type 'int'")));
+        // A constant count out of range always fails
+        let assembly = text(&session, "int main() { return 1l >> 64; }", Emit::Assembly);
+        assert!(assembly.iter().any(|line| line.starts_with("\tjmp\t.Lmain_ubsan")));
+        assert!(assembly
+            .iter()
+            .any(|line| line.contains("shift exponent is negative or too large for 64-bit type 'long'")));
+    }
+
+    #[test]
+    fn runtime_errors_point_at_the_operator() {
+        let session = Session::new(Target::X86_64).sanitize(true);
+        let source = "int main() {\n    return 1 / __builtin_syscall(39);\n}";
+        let messages = |assembly: Vec<String>| -> Vec<String> {
+            assembly
+                .into_iter()
+                .filter(|line| line.contains("runtime error"))
+                .map(|line| String::from(line.trim()))
+                .collect()
+        };
+        let compiled = match session
+            .compile_file("a.c", source, &[Emit::Assembly])
+            .unwrap()
+            .outputs
+            .pop()
+        {
+            Some(Output::Text(lines)) => lines,
+            _ => panic!("expected text"),
+        };
+        assert_eq!(
+            messages(compiled),
+            [
+                ".ascii \"a.c:2:14: runtime error: division by zero\\n\"",
+                ".ascii \"a.c:2:14: runtime error: signed integer overflow in 'long' division\\n\""
+            ]
+        );
+        let prog = parser::parse(lexer::lex(source).unwrap(), DataModel::LP64).unwrap();
+        match session.generate(prog, &[Emit::Assembly]).unwrap().outputs.pop() {
+            Some(Output::Text(lines)) => {
+                assert!(messages(lines)[0].contains("\"main: runtime error: division by zero"))
+            }
+            _ => panic!("expected text"),
+        }
+    }
+
+    #[test]
+    fn warnings_come_before_errors() {
+        let session = Session::new(Target::Wasm32);
+        let source = "int main() { return 2147483647 + 1 + __builtin_syscall(60, 0); }";
+        let diagnostics = session.compile(source, &[Emit::Assembly]).unwrap_err();
+        let levels: Vec<Level> = diagnostics.iter().map(|diagnostic| diagnostic.level).collect();
+        assert_eq!(levels, [Level::Warning, Level::Error]);
+    }
+}
@@ -0,0 +1,512 @@
+use ir::{Block, BlockId, Function, Instruction, Terminator, VReg, Value};
+use std::collections::{HashMap, HashSet};
+use types::Constant;
+
+/// Immediate dominators, computed with the iterative algorithm of Cooper, Harvey and Kennedy
+pub struct DominatorTree {
+    idom: Vec<Option<BlockId>>,
+    children: Vec<Vec<BlockId>>,
+    /// Reachable blocks in reverse postorder
+    pub order: Vec<BlockId>,
+}
+
+impl DominatorTree {
+    pub fn new(func: &Function) -> DominatorTree {
+        let order = reverse_postorder(func);
+        let mut position = vec![usize::MAX; func.blocks.len()];
+        for (i, block) in order.iter().enumerate() {
+            position[block.0] = i;
+        }
+        let preds = func.predecessors();
+
+        let mut idom: Vec<Option<BlockId>> = vec![None; func.blocks.len()];
+        idom[0] = Some(BlockId(0));
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in order.iter().skip(1) {
+                let mut new_idom = None;
+                for pred in &preds[block.0] {
+                    if idom[pred.0].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => *pred,
+                        Some(other) => intersect(&idom, &position, *pred, other),
+                    });
+                }
+                if new_idom != idom[block.0] {
+                    idom[block.0] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut children = vec![Vec::new(); func.blocks.len()];
+        for block in order.iter().skip(1) {
+            if let Some(parent) = idom[block.0] {
+                children[parent.0].push(*block);
+            }
+        }
+
+        DominatorTree { idom, children, order }
+    }
+
+    /// The immediate dominator of a block, `None` for the entry and unreachable blocks
+    pub fn idom(&self, block: BlockId) -> Option<BlockId> {
+        match self.idom[block.0] {
+            Some(parent) if parent != block => Some(parent),
+            _ => None,
+        }
+    }
+
+    pub fn children(&self, block: BlockId) -> &[BlockId] {
+        &self.children[block.0]
+    }
+
+    pub fn dominates(&self, a: BlockId, b: BlockId) -> bool {
+        let mut runner = Some(b);
+        while let Some(block) = runner {
+            if block == a {
+                return true;
+            }
+            runner = self.idom(block);
+        }
+        false
+    }
+
+    /// Dominance frontier of every block, indexed by block number
+    pub fn frontiers(&self, func: &Function) -> Vec<Vec<BlockId>> {
+        let mut frontiers = vec![Vec::new(); func.blocks.len()];
+        for (block, preds) in func.predecessors().iter().enumerate() {
+            if preds.len() < 2 || self.idom[block].is_none() {
+                continue;
+            }
+            for pred in preds {
+                let mut runner = *pred;
+                while self.idom[runner.0].is_some() && Some(runner) != self.idom(BlockId(block)) {
+                    if !frontiers[runner.0].contains(&BlockId(block)) {
+                        frontiers[runner.0].push(BlockId(block));
+                    }
+                    match self.idom(runner) {
+                        Some(parent) => runner = parent,
+                        None => break,
+                    }
+                }
+            }
+        }
+        frontiers
+    }
+}
+
+fn intersect(idom: &[Option<BlockId>], position: &[usize], mut a: BlockId, mut b: BlockId) -> BlockId {
+    while a != b {
+        while position[a.0] > position[b.0] {
+            a = idom[a.0].unwrap();
+        }
+        while position[b.0] > position[a.0] {
+            b = idom[b.0].unwrap();
+        }
+    }
+    a
+}
+
+fn reverse_postorder(func: &Function) -> Vec<BlockId> {
+    let mut visited = vec![false; func.blocks.len()];
+    let mut postorder = Vec::new();
+    let mut stack = vec![(BlockId(0), 0)];
+    visited[0] = true;
+    while let Some((block, next)) = stack.pop() {
+        let succs = func.blocks[block.0].terminator.successors();
+        if next < succs.len() {
+            stack.push((block, next + 1));
+            let succ = succs[next];
+            if !visited[succ.0] {
+                visited[succ.0] = true;
+                stack.push((succ, 0));
+            }
+        } else {
+            postorder.push(block);
+        }
+    }
+    postorder.reverse();
+    postorder
+}
+
+/// Drops blocks that cannot be reached from the entry, renumbering the rest
+pub fn remove_unreachable(func: &mut Function) {
+    let mut reachable = reverse_postorder(func);
+    if reachable.len() == func.blocks.len() {
+        return;
+    }
+    reachable.sort();
+
+    let mut renumber = vec![None; func.blocks.len()];
+    for (i, block) in reachable.iter().enumerate() {
+        renumber[block.0] = Some(BlockId(i));
+    }
+
+    let blocks: Vec<Block> = func.blocks.drain(..).collect();
+    for (i, mut block) in blocks.into_iter().enumerate() {
+        if renumber[i].is_none() {
+            continue;
+        }
+        for succ in block.terminator.successors_mut() {
+            *succ = renumber[succ.0].unwrap();
+        }
+        for inst in &mut block.instructions {
+            if let Instruction::Phi { sources, .. } = inst {
+                sources.retain(|(pred, _)| renumber[pred.0].is_some());
+                for (pred, _) in sources.iter_mut() {
+                    *pred = renumber[pred.0].unwrap();
+                }
+            }
+        }
+        func.blocks.push(block);
+    }
+}
+
+/// Rewrites the function into SSA form: every virtual register gets exactly one definition,
+/// with phi instructions placed on the dominance frontiers of the original definitions.
+pub fn construct(func: &mut Function) {
+    remove_unreachable(func);
+    let dom = DominatorTree::new(func);
+    let frontiers = dom.frontiers(func);
+    let preds = func.predecessors();
+
+    // Only registers used in a block other than the one defining them can need a phi
+    let mut definitions: HashMap<VReg, Vec<BlockId>> = HashMap::new();
+    let mut non_local = HashSet::new();
+    for (i, block) in func.blocks.iter().enumerate() {
+        let mut killed = HashSet::new();
+        for inst in &block.instructions {
+            for operand in inst.operands() {
+                if let Value::Register(reg) = operand {
+                    if !killed.contains(&reg) {
+                        non_local.insert(reg);
+                    }
+                }
+            }
+            killed.insert(inst.dst());
+            definitions.entry(inst.dst()).or_default().push(BlockId(i));
+        }
+        for operand in block.terminator.operands() {
+            if let Value::Register(reg) = operand {
+                if !killed.contains(&reg) {
+                    non_local.insert(reg);
+                }
+            }
+        }
+    }
+
+    let mut non_local: Vec<VReg> = non_local.into_iter().collect();
+    non_local.sort();
+    for reg in non_local {
+        let mut worklist = definitions.get(&reg).cloned().unwrap_or_default();
+        let mut has_phi = HashSet::new();
+        while let Some(block) = worklist.pop() {
+            for frontier in &frontiers[block.0] {
+                if !has_phi.insert(*frontier) {
+                    continue;
+                }
+                let sources = preds[frontier.0]
+                    .iter()
+                    .map(|pred| (*pred, Value::Register(reg)))
+                    .collect();
+                func.blocks[frontier.0]
+                    .instructions
+                    .insert(0, Instruction::Phi { dst: reg, sources });
+                if !definitions[&reg].contains(frontier) {
+                    worklist.push(*frontier);
+                }
+            }
+        }
+    }
+
+    let mut stacks = HashMap::new();
+    rename(func, &dom, BlockId(0), &mut stacks);
+}
+
+fn rename(func: &mut Function, dom: &DominatorTree, block: BlockId, stacks: &mut HashMap<VReg, Vec<VReg>>) {
+    let mut defined = Vec::new();
+
+    let mut instructions = std::mem::take(&mut func.blocks[block.0].instructions);
+    for inst in &mut instructions {
+        if !inst.is_phi() {
+            for operand in inst.operands_mut() {
+                *operand = current(func, stacks, *operand);
+            }
+        }
+        let original = inst.dst();
+        let renamed = func.new_register(func.registers[original.0]);
+        stacks.entry(original).or_default().push(renamed);
+        defined.push(original);
+        *inst.dst_mut() = renamed;
+    }
+    func.blocks[block.0].instructions = instructions;
+
+    let mut terminator = func.blocks[block.0].terminator.clone();
+    for operand in terminator.operands_mut() {
+        *operand = current(func, stacks, *operand);
+    }
+    func.blocks[block.0].terminator = terminator;
+
+    let mut succs = func.blocks[block.0].terminator.successors();
+    succs.dedup();
+    for succ in succs {
+        let mut instructions = std::mem::take(&mut func.blocks[succ.0].instructions);
+        for inst in &mut instructions {
+            if let Instruction::Phi { sources, .. } = inst {
+                for (pred, value) in sources.iter_mut() {
+                    if *pred == block {
+                        *value = current(func, stacks, *value);
+                    }
+                }
+            }
+        }
+        func.blocks[succ.0].instructions = instructions;
+    }
+
+    for child in dom.children(block).to_vec() {
+        rename(func, dom, child, stacks);
+    }
+
+    for original in defined {
+        stacks.get_mut(&original).unwrap().pop();
+    }
+}
+
+/// The reaching definition of a register, or zero when it has none on this path
+fn current(func: &Function, stacks: &HashMap<VReg, Vec<VReg>>, value: Value) -> Value {
+    match value {
+        Value::Const(_) => value,
+        Value::Register(reg) => match stacks.get(&reg).and_then(|stack| stack.last()) {
+            Some(renamed) => Value::Register(*renamed),
+            None => Value::Const(Constant {
+                value: 0,
+                ctype: func.registers[reg.0],
+            }),
+        },
+    }
+}
+
+/// Replaces phi instructions with copies at the end of each predecessor
+pub fn destruct(func: &mut Function) {
+    // A copy cannot be placed on an edge leaving a block with several successors, so split it
+    let preds = func.predecessors();
+    for (block, preds) in preds.iter().enumerate() {
+        if !func.blocks[block].instructions.iter().any(Instruction::is_phi) {
+            continue;
+        }
+        for pred in preds {
+            let mut succs = func.blocks[pred.0].terminator.successors();
+            succs.dedup();
+            if succs.len() < 2 {
+                continue;
+            }
+            func.blocks.push(Block {
+                instructions: Vec::new(),
+                terminator: Terminator::Jump(BlockId(block)),
+            });
+            let split = BlockId(func.blocks.len() - 1);
+            for succ in func.blocks[pred.0].terminator.successors_mut() {
+                if *succ == BlockId(block) {
+                    *succ = split;
+                }
+            }
+            for inst in &mut func.blocks[block].instructions {
+                if let Instruction::Phi { sources, .. } = inst {
+                    for (source, _) in sources.iter_mut() {
+                        if source == pred {
+                            *source = split;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    for block in 0..func.blocks.len() {
+        let (phis, rest): (Vec<Instruction>, Vec<Instruction>) =
+            func.blocks[block].instructions.drain(..).partition(Instruction::is_phi);
+        func.blocks[block].instructions = rest;
+
+        // Phis execute in parallel, so read every source before writing any destination
+        let mut copies: HashMap<BlockId, (Vec<Instruction>, Vec<Instruction>)> = HashMap::new();
+        for phi in phis {
+            if let Instruction::Phi { dst, sources } = phi {
+                for (pred, value) in sources {
+                    let temp = func.new_register(func.registers[dst.0]);
+                    let (reads, writes) = copies.entry(pred).or_default();
+                    reads.push(Instruction::Copy { dst: temp, src: value });
+                    writes.push(Instruction::Copy {
+                        dst,
+                        src: Value::Register(temp),
+                    });
+                }
+            }
+        }
+        for (pred, (mut reads, mut writes)) in copies {
+            let instructions = &mut func.blocks[pred.0].instructions;
+            instructions.append(&mut reads);
+            instructions.append(&mut writes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ir::tests::{binary, copy, function, int, reg, syscall};
+    use ir::BinaryOp;
+
+    /// `x` is 2 or 3 depending on the value of a system call, then returned
+    fn diamond() -> Function {
+        function(
+            2,
+            vec![
+                (vec![syscall(0)], Terminator::Branch(reg(0), BlockId(1), BlockId(2))),
+                (vec![copy(1, int(2))], Terminator::Jump(BlockId(3))),
+                (vec![copy(1, int(3))], Terminator::Jump(BlockId(3))),
+                (vec![], Terminator::Return(reg(1))),
+            ],
+        )
+    }
+
+    /// `x` counts up from 0 while a system call returns non-zero
+    fn counting_loop() -> Function {
+        function(
+            2,
+            vec![
+                (vec![copy(0, int(0))], Terminator::Jump(BlockId(1))),
+                (
+                    vec![binary(BinaryOp::Add, 0, reg(0), int(1)), syscall(1)],
+                    Terminator::Branch(reg(1), BlockId(1), BlockId(2)),
+                ),
+                (vec![], Terminator::Return(reg(0))),
+            ],
+        )
+    }
+
+    fn text(func: &Function) -> Vec<String> {
+        func.to_string().lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn branches_dominate_neither_side_of_a_diamond() {
+        let func = diamond();
+        let dom = DominatorTree::new(&func);
+        assert_eq!(dom.idom(BlockId(0)), None);
+        assert_eq!(dom.idom(BlockId(1)), Some(BlockId(0)));
+        assert_eq!(dom.idom(BlockId(3)), Some(BlockId(0)));
+        assert!(dom.dominates(BlockId(0), BlockId(3)));
+        assert!(!dom.dominates(BlockId(1), BlockId(3)));
+        assert_eq!(
+            dom.frontiers(&func),
+            [vec![], vec![BlockId(3)], vec![BlockId(3)], vec![]]
+        );
+    }
+
+    #[test]
+    fn a_loop_header_is_in_its_own_frontier() {
+        let func = counting_loop();
+        let dom = DominatorTree::new(&func);
+        assert!(dom.dominates(BlockId(1), BlockId(2)));
+        assert_eq!(dom.frontiers(&func)[1], [BlockId(1)]);
+        assert_eq!(dom.order, [BlockId(0), BlockId(1), BlockId(2)]);
+    }
+
+    #[test]
+    fn definitions_merge_in_phis_at_joins() {
+        let mut func = diamond();
+        construct(&mut func);
+        assert_eq!(
+            text(&func)[11..13],
+            ["\t%5: int = phi [bb1, %4], [bb2, %3]", "\tret %5"]
+        );
+    }
+
+    #[test]
+    fn loop_carried_values_get_a_phi_in_the_header() {
+        let mut func = counting_loop();
+        construct(&mut func);
+        assert_eq!(
+            text(&func),
+            [
+                "function f() -> int {",
+                "bb0:",
+                "\t%2: int = 0",
+                "\tjmp bb1",
+                "bb1:",
+                "\t%3: int = phi [bb0, %2], [bb1, %4]",
+                "\t%4: int = add %3, 1",
+                "\t%5: int = syscall 39",
+                "\tbr %5, bb1, bb2",
+                "bb2:",
+                "\tret %4",
+                "}",
+            ]
+        );
+        let mut defined = HashSet::new();
+        for block in &func.blocks {
+            for inst in &block.instructions {
+                assert!(defined.insert(inst.dst()), "{} is defined twice", inst.dst());
+            }
+        }
+    }
+
+    #[test]
+    fn paths_without_a_definition_read_zero() {
+        let mut func = diamond();
+        func.blocks[2].instructions.clear();
+        construct(&mut func);
+        assert!(text(&func).contains(&String::from("\t%4: int = phi [bb1, %3], [bb2, 0]")));
+    }
+
+    #[test]
+    fn destruction_splits_critical_edges_for_the_copies() {
+        let mut func = counting_loop();
+        construct(&mut func);
+        destruct(&mut func);
+        assert!(func
+            .blocks
+            .iter()
+            .all(|block| !block.instructions.iter().any(Instruction::is_phi)));
+        // The back edge leaves a block with two successors, so its copies get a block of their own
+        assert_eq!(
+            text(&func)[9..17],
+            [
+                "\tbr %5, bb3, bb2",
+                "bb2:",
+                "\tret %4",
+                "bb3:",
+                "\t%7: int = %4",
+                "\t%3: int = %7",
+                "\tjmp bb1",
+                "}",
+            ]
+        );
+        assert_eq!(text(&func)[2..5], ["\t%2: int = 0", "\t%6: int = %2", "\t%3: int = %6"]);
+    }
+
+    #[test]
+    fn unreachable_blocks_are_dropped_and_the_rest_renumbered() {
+        let mut func = diamond();
+        func.blocks[0].terminator = Terminator::Jump(BlockId(2));
+        func.blocks[3].instructions.push(Instruction::Phi {
+            dst: VReg(1),
+            sources: vec![(BlockId(1), int(2)), (BlockId(2), int(3))],
+        });
+        remove_unreachable(&mut func);
+        assert_eq!(func.blocks.len(), 3);
+        assert_eq!(func.blocks[0].terminator, Terminator::Jump(BlockId(1)));
+        assert_eq!(func.blocks[1].terminator, Terminator::Jump(BlockId(2)));
+        assert_eq!(
+            func.blocks[2].instructions,
+            [Instruction::Phi {
+                dst: VReg(1),
+                sources: vec![(BlockId(1), int(3))],
+            }]
+        );
+    }
+}
@@ -0,0 +1,64 @@
+use types::DataModel;
+
+/// The architectures rcc generates code for
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub enum Target {
+    X86_64,
+    /// 32-bit x86, selected with `-m32`
+    I386,
+    AArch64,
+    RiscV64,
+    /// WebAssembly text modules, which rcc writes without assembling or linking
+    Wasm32,
+}
+
+impl Target {
+    /// Reads the architecture out of a target triple such as `aarch64-linux-gnu`
+    pub fn from_triple(triple: &str) -> Result<Target, String> {
+        match triple.split('-').next() {
+            Some("x86_64") => Ok(Target::X86_64),
+            Some("i386") | Some("i486") | Some("i586") | Some("i686") => Ok(Target::I386),
+            Some("aarch64") | Some("arm64") => Ok(Target::AArch64),
+            Some("riscv64") => Ok(Target::RiscV64),
+            Some("wasm32") => Ok(Target::Wasm32),
+            _ => Err(format!("unsupported target '{}'", triple)),
+        }
+    }
+
+    /// The full triple LLVM expects in a module's `target triple`
+    pub fn triple(self) -> &'static str {
+        match self {
+            Target::X86_64 => "x86_64-pc-linux-gnu",
+            Target::I386 => "i386-pc-linux-gnu",
+            Target::AArch64 => "aarch64-unknown-linux-gnu",
+            Target::RiscV64 => "riscv64-unknown-linux-gnu",
+            Target::Wasm32 => "wasm32-unknown-unknown",
+        }
+    }
+
+    /// The compiler driver that assembles and links for this target
+    pub fn gcc(self) -> &'static str {
+        match self {
+            Target::X86_64 | Target::I386 => "gcc",
+            Target::AArch64 => "aarch64-linux-gnu-gcc",
+            Target::RiscV64 => "riscv64-linux-gnu-gcc",
+            Target::Wasm32 => unreachable!("WebAssembly modules are not assembled"),
+        }
+    }
+
+    /// Flags selecting this target when its driver also serves others
+    pub fn gcc_flags(self) -> &'static [&'static str] {
+        match self {
+            Target::I386 => &["-m32"],
+            _ => &[],
+        }
+    }
+
+    pub fn data_model(self) -> DataModel {
+        match self {
+            Target::X86_64 | Target::AArch64 | Target::RiscV64 => DataModel::LP64,
+            Target::I386 | Target::Wasm32 => DataModel::ILP32,
+        }
+    }
+}
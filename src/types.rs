@@ -0,0 +1,225 @@
+use std::fmt;
+
+/// How wide `long` is. Every other type has the same size under both models.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum DataModel {
+    /// 64-bit `long` and pointers, as on 64-bit Linux
+    LP64,
+    /// 32-bit `int`, `long` and pointers, as on i386
+    ILP32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Type {
+    Int,
+    UnsignedInt,
+    Long,
+    UnsignedLong,
+    LongLong,
+    UnsignedLongLong,
+}
+
+impl Type {
+    /// Size in bytes under the data model
+    pub fn size(self, model: DataModel) -> u32 {
+        match self {
+            Type::Int | Type::UnsignedInt => 4,
+            Type::Long | Type::UnsignedLong => match model {
+                DataModel::LP64 => 8,
+                DataModel::ILP32 => 4,
+            },
+            Type::LongLong | Type::UnsignedLongLong => 8,
+        }
+    }
+
+    pub fn is_signed(self) -> bool {
+        match self {
+            Type::Int | Type::Long | Type::LongLong => true,
+            Type::UnsignedInt | Type::UnsignedLong | Type::UnsignedLongLong => false,
+        }
+    }
+
+    pub fn bits(self, model: DataModel) -> u32 {
+        self.size(model) * 8
+    }
+
+    /// Largest representable value, as an unsigned quantity
+    pub fn max_value(self, model: DataModel) -> u64 {
+        let bits = if self.is_signed() {
+            self.bits(model) - 1
+        } else {
+            self.bits(model)
+        };
+        if bits == 64 {
+            u64::MAX
+        } else {
+            (1 << bits) - 1
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Type::Int => "int",
+            Type::UnsignedInt => "unsigned int",
+            Type::Long => "long",
+            Type::UnsignedLong => "unsigned long",
+            Type::LongLong => "long long",
+            Type::UnsignedLongLong => "unsigned long long",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// An integer constant, stored as its value extended to 64 bits: sign-extended for signed types,
+/// zero-extended for unsigned ones. The low bits of the type's size are its bit pattern.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Constant {
+    pub value: u64,
+    pub ctype: Type,
+}
+
+impl Type {
+    /// Integer conversion rank (C11 6.3.1.1)
+    pub fn rank(self) -> u32 {
+        match self {
+            Type::Int | Type::UnsignedInt => 1,
+            Type::Long | Type::UnsignedLong => 2,
+            Type::LongLong | Type::UnsignedLongLong => 3,
+        }
+    }
+
+    pub fn to_unsigned(self) -> Type {
+        match self {
+            Type::Int | Type::UnsignedInt => Type::UnsignedInt,
+            Type::Long | Type::UnsignedLong => Type::UnsignedLong,
+            Type::LongLong | Type::UnsignedLongLong => Type::UnsignedLongLong,
+        }
+    }
+
+    /// Smallest representable value, as a mathematical integer
+    pub fn min_value(self, model: DataModel) -> i128 {
+        if self.is_signed() {
+            -(self.max_value(model) as i128) - 1
+        } else {
+            0
+        }
+    }
+
+    /// Integer promotions (C11 6.3.1.1p2). Every type rcc knows is at least as wide as `int`.
+    pub fn promote(self) -> Type {
+        self
+    }
+
+    /// Usual arithmetic conversions (C11 6.3.1.8), which depend on the data model when a
+    /// signed type meets an unsigned one of lower rank
+    pub fn common(self, other: Type, model: DataModel) -> Type {
+        let (a, b) = (self.promote(), other.promote());
+        if a == b {
+            a
+        } else if a.is_signed() == b.is_signed() {
+            if a.rank() >= b.rank() {
+                a
+            } else {
+                b
+            }
+        } else {
+            let (signed, unsigned) = if a.is_signed() { (a, b) } else { (b, a) };
+            if unsigned.rank() >= signed.rank() {
+                unsigned
+            } else if signed.max_value(model) >= unsigned.max_value(model) {
+                signed
+            } else {
+                signed.to_unsigned()
+            }
+        }
+    }
+}
+
+impl Constant {
+    /// Converts a mathematical value into `ctype`, wrapping modulo 2^N
+    pub fn wrap(value: i128, ctype: Type, model: DataModel) -> Constant {
+        let bits = ctype.bits(model);
+        let truncated = value as u64 & (u64::MAX >> (64 - bits));
+        let negative = ctype.is_signed() && truncated >> (bits - 1) & 1 == 1;
+        Constant {
+            value: if negative {
+                truncated | !(u64::MAX >> (64 - bits))
+            } else {
+                truncated
+            },
+            ctype,
+        }
+    }
+
+    /// The mathematical value this constant represents
+    pub fn mathematical(self) -> i128 {
+        if self.ctype.is_signed() {
+            i128::from(self.value as i64)
+        } else {
+            i128::from(self.value)
+        }
+    }
+
+    /// The value as it sits in a 64-bit register: sign-extended for signed types
+    pub fn as_i64(self) -> i64 {
+        self.value as i64
+    }
+
+    pub fn cast(self, ctype: Type, model: DataModel) -> Constant {
+        Constant::wrap(self.mathematical(), ctype, model)
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.value == 0
+    }
+}
+
+impl fmt::Display for Constant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let suffix = match self.ctype {
+            Type::Int => "",
+            Type::UnsignedInt => "u",
+            Type::Long => "l",
+            Type::UnsignedLong => "ul",
+            Type::LongLong => "ll",
+            Type::UnsignedLongLong => "ull",
+        };
+        write!(f, "{}{}", self.mathematical(), suffix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_takes_its_size_from_the_data_model() {
+        assert_eq!(Type::Long.size(DataModel::LP64), 8);
+        assert_eq!(Type::Long.size(DataModel::ILP32), 4);
+        assert_eq!(Type::UnsignedLong.max_value(DataModel::ILP32), u64::from(u32::MAX));
+        // A `long` holds every `unsigned int` only when it is wider
+        assert_eq!(Type::Long.common(Type::UnsignedInt, DataModel::LP64), Type::Long);
+        assert_eq!(
+            Type::Long.common(Type::UnsignedInt, DataModel::ILP32),
+            Type::UnsignedLong
+        );
+    }
+
+    #[test]
+    fn constants_wrap_into_their_type() {
+        let int = Constant::wrap(1 << 31, Type::Int, DataModel::LP64);
+        assert_eq!(int.mathematical(), -(1 << 31));
+        assert_eq!(int.as_i64(), -(1 << 31));
+        let long = Constant::wrap(-1, Type::Long, DataModel::ILP32);
+        assert_eq!(
+            long.cast(Type::UnsignedLong, DataModel::ILP32).mathematical(),
+            0xffff_ffff
+        );
+        assert_eq!(long.cast(Type::UnsignedLong, DataModel::LP64).as_i64(), -1);
+        // Equal values are equal constants, whichever model made them
+        assert_eq!(long, Constant::wrap(-1, Type::Long, DataModel::LP64));
+    }
+}
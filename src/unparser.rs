@@ -0,0 +1,276 @@
+use diagnostic::Diagnostic;
+use lexer::Operator;
+use parser::{Associativity, Expression, ExpressionKind, Function, Precedence, Program, Statement};
+use types::{Constant, DataModel};
+
+const INDENT: &str = "    ";
+
+/// Prints the program back as C that compiles, and parses back to the same tree. Parentheses
+/// in the tree are printed as they are, and where a tree built by hand nests an operator
+/// inside a tighter one, the parentheses its precedence calls for are added. A tree built by
+/// hand with an operator where C cannot have it has no source, and is an error.
+pub fn unparse(prog: &Program) -> Result<String, Diagnostic> {
+    let mut source = String::new();
+    function(&prog.function, prog.model, &mut source)?;
+    Ok(source)
+}
+
+fn function(func: &Function, model: DataModel, source: &mut String) -> Result<(), Diagnostic> {
+    source.push_str(&format!("int {}() {{\n", func.name));
+    statement(&func.statement, 1, model, source)?;
+    source.push_str("}\n");
+    Ok(())
+}
+
+fn statement(stmt: &Statement, depth: usize, model: DataModel, source: &mut String) -> Result<(), Diagnostic> {
+    match stmt {
+        Statement::Return(exp) => source.push_str(&format!(
+            "{}return {};\n",
+            INDENT.repeat(depth),
+            expression(exp, Precedence::Comma, model)?
+        )),
+    }
+    Ok(())
+}
+
+/// Prints an expression where only operators binding at least as tightly as `min` can stand
+/// without parentheses
+fn expression(exp: &Expression, min: Precedence, model: DataModel) -> Result<String, Diagnostic> {
+    let precedence = exp.precedence()?;
+    let printed = match &exp.kind {
+        ExpressionKind::Binary { left, operator, right } => {
+            let (left_min, right_min) = match precedence.associativity() {
+                Associativity::Left => (precedence, precedence.tighter()),
+                Associativity::Right => (precedence.tighter(), precedence),
+            };
+            format!(
+                "{} {} {}",
+                expression(left, left_min, model)?,
+                operator.spelling(),
+                expression(right, right_min, model)?
+            )
+        }
+        ExpressionKind::Unary { operator, operand } => {
+            let operand = expression(operand, Precedence::Unary, model)?;
+            // `- -x` rather than `--x`, which C reads as a decrement
+            let space = if *operator == Operator::Negate && operand.starts_with('-') {
+                " "
+            } else {
+                ""
+            };
+            format!("{}{}{}", operator.spelling(), space, operand)
+        }
+        ExpressionKind::Parenthesized(exp) => format!("({})", expression(exp, Precedence::Comma, model)?),
+        ExpressionKind::Const(int) => constant(*int, model),
+        ExpressionKind::Call { name, args } => format!("{}({})", name, arguments(args.iter(), model)?),
+        ExpressionKind::Syscall { number, args } => format!(
+            "__builtin_syscall({})",
+            arguments(std::iter::once(&**number).chain(args), model)?
+        ),
+    };
+    if precedence < min {
+        Ok(format!("({})", printed))
+    } else {
+        Ok(printed)
+    }
+}
+
+fn arguments<'a, I: Iterator<Item = &'a Expression>>(args: I, model: DataModel) -> Result<String, Diagnostic> {
+    let printed = args
+        .map(|arg| expression(arg, Precedence::Assignment, model))
+        .collect::<Result<Vec<String>, Diagnostic>>()?;
+    Ok(printed.join(", "))
+}
+
+/// The parser only makes non-negative constants, which print as literals with the suffix of
+/// their type. C has no negative literals, so any other constant prints as a negation, and
+/// the most negative value of its type as one less than the negated maximum.
+fn constant(int: Constant, model: DataModel) -> String {
+    let value = int.mathematical();
+    if value >= 0 {
+        int.to_string()
+    } else if -value > int.ctype.max_value(model) as i128 {
+        format!("(-{} - 1)", Constant::wrap(-value - 1, int.ctype, model))
+    } else {
+        format!("(-{})", Constant::wrap(-value, int.ctype, model))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lexer::{self, Span};
+    use parser::{self, binary_precedence};
+    use types::Type;
+
+    /// Deterministic pseudo-random numbers, so that a failing case can be replayed by its seed
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self) -> u64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            self.0 ^ (self.0 >> 29)
+        }
+
+        fn below(&mut self, bound: u64) -> u64 {
+            (self.next() >> 32) % bound
+        }
+
+        fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+            items[self.below(items.len() as u64) as usize]
+        }
+    }
+
+    const TYPES: [Type; 6] = [
+        Type::Int,
+        Type::UnsignedInt,
+        Type::Long,
+        Type::UnsignedLong,
+        Type::LongLong,
+        Type::UnsignedLongLong,
+    ];
+
+    fn random_constant(rng: &mut Lcg) -> Constant {
+        let ctype = rng.pick(&TYPES);
+        let shift = rng.below(64);
+        let value = (rng.next() >> shift) & ctype.max_value(DataModel::LP64);
+        Constant { value, ctype }
+    }
+
+    /// A node built by hand, which has no position in any source
+    fn node(kind: ExpressionKind) -> Expression {
+        Expression::new(kind, Span::default())
+    }
+
+    /// Parenthesizes what the parser could only have read with parentheses
+    fn grouped(exp: Expression, min: Precedence) -> Box<Expression> {
+        if exp.precedence().unwrap() < min {
+            Box::new(node(ExpressionKind::Parenthesized(Box::new(exp))))
+        } else {
+            Box::new(exp)
+        }
+    }
+
+    fn random_expression(rng: &mut Lcg, depth: u32) -> Expression {
+        if depth == 0 {
+            return node(ExpressionKind::Const(random_constant(rng)));
+        }
+        match rng.below(10) {
+            0..=2 => {
+                let operator = rng.pick(&[Operator::Plus, Operator::Negate, Operator::Star, Operator::Divide]);
+                let precedence = binary_precedence(operator).unwrap();
+                node(ExpressionKind::Binary {
+                    left: grouped(random_expression(rng, depth - 1), precedence),
+                    operator,
+                    right: grouped(random_expression(rng, depth - 1), precedence.tighter()),
+                })
+            }
+            3 => node(ExpressionKind::Parenthesized(Box::new(random_expression(
+                rng,
+                depth - 1,
+            )))),
+            4..=6 => node(ExpressionKind::Unary {
+                operator: rng.pick(&[Operator::Negate, Operator::Not, Operator::Bang]),
+                operand: grouped(random_expression(rng, depth - 1), Precedence::Unary),
+            }),
+            7 => node(ExpressionKind::Call {
+                name: String::from("__builtin_syscall"),
+                args: (0..rng.below(8))
+                    .map(|_| *grouped(random_expression(rng, depth - 1), Precedence::Assignment))
+                    .collect(),
+            }),
+            _ => node(ExpressionKind::Const(random_constant(rng))),
+        }
+    }
+
+    fn random_program(rng: &mut Lcg) -> Program {
+        // Names that start like keywords, to check the lexer keeps them whole
+        let name = rng.pick(&["main", "f", "_start", "int_value", "returned", "x9"]);
+        Program {
+            function: Function {
+                name: String::from(name),
+                statement: Statement::Return(random_expression(rng, 6)),
+            },
+            model: DataModel::LP64,
+        }
+    }
+
+    fn reparse(source: &str) -> Program {
+        parser::parse(lexer::lex(source).unwrap(), DataModel::LP64).unwrap_or_else(|err| panic!("{}\n{}", err, source))
+    }
+
+    #[test]
+    fn printed_programs_parse_back_to_the_same_tree() {
+        for seed in 0..500 {
+            let mut rng = Lcg(seed);
+            let prog = random_program(&mut rng);
+            let source = unparse(&prog).unwrap();
+            let parsed = reparse(&source);
+            assert_eq!(parsed, prog, "seed {}:\n{}", seed, source);
+            assert_eq!(unparse(&parsed).unwrap(), source, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn source_round_trips_through_the_tree() {
+        let source = "int main() {\n    return -(2 + ~3) * - -4ul / __builtin_syscall(60, 1, !0) - 0x10;\n}\n";
+        let printed = unparse(&reparse(source)).unwrap();
+        assert_eq!(printed, source.replace("0x10", "16"));
+        assert_eq!(reparse(&printed), reparse(source));
+    }
+
+    #[test]
+    fn negative_constants_print_as_negations() {
+        let constant = |value, ctype| node(ExpressionKind::Const(Constant::wrap(value, ctype, DataModel::LP64)));
+        assert_eq!(
+            expression(&constant(-5, Type::Long), Precedence::Comma, DataModel::LP64).unwrap(),
+            "(-5l)"
+        );
+        assert_eq!(
+            expression(&constant(-2147483648, Type::Int), Precedence::Comma, DataModel::LP64).unwrap(),
+            "(-2147483647 - 1)"
+        );
+        let negated = node(ExpressionKind::Unary {
+            operator: Operator::Negate,
+            operand: Box::new(constant(-1, Type::Int)),
+        });
+        assert_eq!(
+            expression(&negated, Precedence::Comma, DataModel::LP64).unwrap(),
+            "-(-1)"
+        );
+    }
+
+    #[test]
+    fn precedence_adds_the_parentheses_a_tree_needs() {
+        let int = |value| {
+            Box::new(node(ExpressionKind::Const(Constant::wrap(
+                value,
+                Type::Int,
+                DataModel::LP64,
+            ))))
+        };
+        let binary = |left, operator, right| Box::new(node(ExpressionKind::Binary { left, operator, right }));
+        let sum = binary(int(1), Operator::Plus, int(2));
+        let product = binary(sum, Operator::Star, int(3));
+        assert_eq!(
+            expression(&product, Precedence::Comma, DataModel::LP64).unwrap(),
+            "(1 + 2) * 3"
+        );
+        let difference = binary(int(1), Operator::Negate, binary(int(2), Operator::Negate, int(3)));
+        assert_eq!(
+            expression(&difference, Precedence::Comma, DataModel::LP64).unwrap(),
+            "1 - (2 - 3)"
+        );
+        let negated = node(ExpressionKind::Unary {
+            operator: Operator::Negate,
+            operand: binary(int(1), Operator::Star, int(2)),
+        });
+        assert_eq!(
+            expression(&negated, Precedence::Comma, DataModel::LP64).unwrap(),
+            "-(1 * 2)"
+        );
+    }
+}
@@ -0,0 +1,179 @@
+//! Traversals of the AST. A `Visitor` reads the tree and a `VisitorMut` rewrites it in
+//! place; each `visit_` method walks into the node's children by default, so an
+//! implementation only overrides the nodes it cares about and calls the matching `walk_`
+//! function wherever it still wants the children visited.
+
+use parser::{Expression, ExpressionKind, Function, Program, Statement};
+use types::Constant;
+
+pub trait Visitor {
+    fn visit_program(&mut self, prog: &Program) {
+        walk_program(self, prog);
+    }
+
+    fn visit_function(&mut self, func: &Function) {
+        walk_function(self, func);
+    }
+
+    fn visit_statement(&mut self, stmt: &Statement) {
+        walk_statement(self, stmt);
+    }
+
+    fn visit_expression(&mut self, exp: &Expression) {
+        walk_expression(self, exp);
+    }
+
+    fn visit_constant(&mut self, _int: &Constant) {}
+}
+
+pub fn walk_program<V: Visitor + ?Sized>(visitor: &mut V, prog: &Program) {
+    visitor.visit_function(&prog.function);
+}
+
+pub fn walk_function<V: Visitor + ?Sized>(visitor: &mut V, func: &Function) {
+    visitor.visit_statement(&func.statement);
+}
+
+pub fn walk_statement<V: Visitor + ?Sized>(visitor: &mut V, stmt: &Statement) {
+    match stmt {
+        Statement::Return(exp) => visitor.visit_expression(exp),
+    }
+}
+
+pub fn walk_expression<V: Visitor + ?Sized>(visitor: &mut V, exp: &Expression) {
+    match &exp.kind {
+        ExpressionKind::Binary { left, right, .. } => {
+            visitor.visit_expression(left);
+            visitor.visit_expression(right);
+        }
+        ExpressionKind::Unary { operand, .. } => visitor.visit_expression(operand),
+        ExpressionKind::Parenthesized(exp) => visitor.visit_expression(exp),
+        ExpressionKind::Const(int) => visitor.visit_constant(int),
+        ExpressionKind::Call { args, .. } => {
+            for arg in args {
+                visitor.visit_expression(arg);
+            }
+        }
+        ExpressionKind::Syscall { number, args } => {
+            visitor.visit_expression(number);
+            for arg in args {
+                visitor.visit_expression(arg);
+            }
+        }
+    }
+}
+
+pub trait VisitorMut {
+    fn visit_program_mut(&mut self, prog: &mut Program) {
+        walk_program_mut(self, prog);
+    }
+
+    fn visit_function_mut(&mut self, func: &mut Function) {
+        walk_function_mut(self, func);
+    }
+
+    fn visit_statement_mut(&mut self, stmt: &mut Statement) {
+        walk_statement_mut(self, stmt);
+    }
+
+    fn visit_expression_mut(&mut self, exp: &mut Expression) {
+        walk_expression_mut(self, exp);
+    }
+
+    fn visit_constant_mut(&mut self, _int: &mut Constant) {}
+}
+
+pub fn walk_program_mut<V: VisitorMut + ?Sized>(visitor: &mut V, prog: &mut Program) {
+    visitor.visit_function_mut(&mut prog.function);
+}
+
+pub fn walk_function_mut<V: VisitorMut + ?Sized>(visitor: &mut V, func: &mut Function) {
+    visitor.visit_statement_mut(&mut func.statement);
+}
+
+pub fn walk_statement_mut<V: VisitorMut + ?Sized>(visitor: &mut V, stmt: &mut Statement) {
+    match stmt {
+        Statement::Return(exp) => visitor.visit_expression_mut(exp),
+    }
+}
+
+pub fn walk_expression_mut<V: VisitorMut + ?Sized>(visitor: &mut V, exp: &mut Expression) {
+    match &mut exp.kind {
+        ExpressionKind::Binary { left, right, .. } => {
+            visitor.visit_expression_mut(left);
+            visitor.visit_expression_mut(right);
+        }
+        ExpressionKind::Unary { operand, .. } => visitor.visit_expression_mut(operand),
+        ExpressionKind::Parenthesized(exp) => visitor.visit_expression_mut(exp),
+        ExpressionKind::Const(int) => visitor.visit_constant_mut(int),
+        ExpressionKind::Call { args, .. } => {
+            for arg in args {
+                visitor.visit_expression_mut(arg);
+            }
+        }
+        ExpressionKind::Syscall { number, args } => {
+            visitor.visit_expression_mut(number);
+            for arg in args {
+                visitor.visit_expression_mut(arg);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lexer;
+    use parser;
+    use types::{DataModel, Type};
+
+    fn parse(source: &str) -> Program {
+        parser::parse(lexer::lex(source).unwrap(), DataModel::LP64).unwrap()
+    }
+
+    /// Counts the constants and the parentheses, leaving everything else to the walks
+    #[derive(Default)]
+    struct Counter {
+        constants: usize,
+        parentheses: usize,
+    }
+
+    impl Visitor for Counter {
+        fn visit_expression(&mut self, exp: &Expression) {
+            if let ExpressionKind::Parenthesized(_) = exp.kind {
+                self.parentheses += 1;
+            }
+            walk_expression(self, exp);
+        }
+
+        fn visit_constant(&mut self, _int: &Constant) {
+            self.constants += 1;
+        }
+    }
+
+    #[test]
+    fn visitors_reach_every_node() {
+        let mut counter = Counter::default();
+        counter.visit_program(&parse(
+            "int main() { return (1 + -(2)) * __builtin_syscall(3, 4 / 5); }",
+        ));
+        assert_eq!(counter.constants, 5);
+        assert_eq!(counter.parentheses, 2);
+    }
+
+    /// Widens every constant to `long`
+    struct Widen;
+
+    impl VisitorMut for Widen {
+        fn visit_constant_mut(&mut self, int: &mut Constant) {
+            *int = int.cast(Type::Long, DataModel::LP64);
+        }
+    }
+
+    #[test]
+    fn mutable_visitors_rewrite_in_place() {
+        let mut prog = parse("int main() { return 1 + ~(2 * 3u); }");
+        Widen.visit_program_mut(&mut prog);
+        assert_eq!(prog, parse("int main() { return 1l + ~(2l * 3l); }"));
+    }
+}
@@ -0,0 +1,362 @@
+use lexer::Operator;
+use parser::{Expression, ExpressionKind, Program, Statement};
+use std::fmt;
+use types::{Constant, DataModel, Type};
+
+/// A WebAssembly module, generated straight from the AST
+#[derive(Debug)]
+pub struct Module {
+    pub functions: Vec<Function>,
+}
+
+#[derive(Debug)]
+pub struct Function {
+    pub name: String,
+    pub result: ValType,
+    pub body: Vec<Instr>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ValType {
+    I32,
+    I64,
+}
+
+impl ValType {
+    fn of(ctype: Type, model: DataModel) -> ValType {
+        match ctype.size(model) {
+            4 => ValType::I32,
+            _ => ValType::I64,
+        }
+    }
+}
+
+impl fmt::Display for ValType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValType::I32 => write!(f, "i32"),
+            ValType::I64 => write!(f, "i64"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Instr {
+    Const(ValType, i64),
+    Add(ValType),
+    Sub(ValType),
+    Mul(ValType),
+    DivS(ValType),
+    DivU(ValType),
+    Shl(ValType),
+    ShrS(ValType),
+    ShrU(ValType),
+    Xor(ValType),
+    /// Compares with zero, always yielding an i32
+    Eqz(ValType),
+    /// `i64.extend_i32_s`
+    ExtendS,
+    /// `i64.extend_i32_u`
+    ExtendU,
+    /// `i32.wrap_i64`
+    Wrap,
+    Return,
+}
+
+impl Instr {
+    /// The operand types an instruction pops and the result types it pushes
+    fn signature(self) -> (Vec<ValType>, Vec<ValType>) {
+        match self {
+            Instr::Const(vt, _) => (vec![], vec![vt]),
+            Instr::Add(vt)
+            | Instr::Sub(vt)
+            | Instr::Mul(vt)
+            | Instr::DivS(vt)
+            | Instr::DivU(vt)
+            | Instr::Shl(vt)
+            | Instr::ShrS(vt)
+            | Instr::ShrU(vt)
+            | Instr::Xor(vt) => (vec![vt, vt], vec![vt]),
+            Instr::Eqz(vt) => (vec![vt], vec![ValType::I32]),
+            Instr::ExtendS | Instr::ExtendU => (vec![ValType::I32], vec![ValType::I64]),
+            Instr::Wrap => (vec![ValType::I64], vec![ValType::I32]),
+            Instr::Return => (vec![], vec![]),
+        }
+    }
+}
+
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instr::Const(vt, int) => write!(f, "{}.const {}", vt, int),
+            Instr::Add(vt) => write!(f, "{}.add", vt),
+            Instr::Sub(vt) => write!(f, "{}.sub", vt),
+            Instr::Mul(vt) => write!(f, "{}.mul", vt),
+            Instr::DivS(vt) => write!(f, "{}.div_s", vt),
+            Instr::DivU(vt) => write!(f, "{}.div_u", vt),
+            Instr::Shl(vt) => write!(f, "{}.shl", vt),
+            Instr::ShrS(vt) => write!(f, "{}.shr_s", vt),
+            Instr::ShrU(vt) => write!(f, "{}.shr_u", vt),
+            Instr::Xor(vt) => write!(f, "{}.xor", vt),
+            Instr::Eqz(vt) => write!(f, "{}.eqz", vt),
+            Instr::ExtendS => write!(f, "i64.extend_i32_s"),
+            Instr::ExtendU => write!(f, "i64.extend_i32_u"),
+            Instr::Wrap => write!(f, "i32.wrap_i64"),
+            Instr::Return => write!(f, "return"),
+        }
+    }
+}
+
+pub fn lower(prog: &Program) -> Module {
+    let func = &prog.function;
+    let mut builder = Builder {
+        body: Vec::new(),
+        model: prog.model,
+    };
+    match &func.statement {
+        Statement::Return(exp) => {
+            builder.exp(exp);
+            builder.convert(exp.ctype(), Type::Int);
+            builder.body.push(Instr::Return);
+        }
+    }
+
+    Module {
+        functions: vec![Function {
+            name: func.name.clone(),
+            result: ValType::of(Type::Int, prog.model),
+            body: builder.body,
+        }],
+    }
+}
+
+/// The module in the WebAssembly text format, every function exported under its C name
+pub fn generate(module: &Module) -> Vec<String> {
+    let mut lines = vec![String::from("(module")];
+    for func in &module.functions {
+        lines.push(format!(
+            "  (func ${} (export \"{}\") (result {})",
+            func.name, func.name, func.result
+        ));
+        lines.extend(func.body.iter().map(|instr| format!("    {}", instr)));
+        lines.push(String::from("  )"));
+    }
+    lines.push(String::from(")"));
+    lines
+}
+
+/// Type checks every function body against the WebAssembly validation rules for the
+/// instructions rcc emits
+pub fn validate(module: &Module) -> Result<(), String> {
+    for func in &module.functions {
+        let mut stack: Vec<ValType> = Vec::new();
+        // After a `return` the rest of the body is unreachable and its stack polymorphic
+        let mut unreachable = false;
+        for instr in &func.body {
+            let (params, results) = instr.signature();
+            let params = if *instr == Instr::Return {
+                vec![func.result]
+            } else {
+                params
+            };
+            for expected in params.iter().rev() {
+                match stack.pop() {
+                    Some(found) if found == *expected => {}
+                    Some(found) => {
+                        return Err(format!(
+                            "in ${}: '{}' expects {} but found {}",
+                            func.name, instr, expected, found
+                        ))
+                    }
+                    None if unreachable => {}
+                    None => return Err(format!("in ${}: '{}' pops from an empty stack", func.name, instr)),
+                }
+            }
+            stack.extend(results);
+            if *instr == Instr::Return {
+                stack.clear();
+                unreachable = true;
+            }
+        }
+        if !unreachable && stack != [func.result] {
+            return Err(format!(
+                "in ${}: the body leaves {:?} on the stack instead of a {}",
+                func.name, stack, func.result
+            ));
+        }
+    }
+    Ok(())
+}
+
+struct Builder {
+    body: Vec<Instr>,
+    model: DataModel,
+}
+
+impl Builder {
+    /// Converts the value on top of the stack from one C type to another
+    fn convert(&mut self, from: Type, to: Type) {
+        match (ValType::of(from, self.model), ValType::of(to, self.model)) {
+            (ValType::I32, ValType::I64) if from.is_signed() => self.body.push(Instr::ExtendS),
+            (ValType::I32, ValType::I64) => self.body.push(Instr::ExtendU),
+            (ValType::I64, ValType::I32) => self.body.push(Instr::Wrap),
+            _ => {}
+        }
+    }
+
+    fn constant(&mut self, int: Constant) {
+        let vt = ValType::of(int.ctype, self.model);
+        let value = match vt {
+            ValType::I32 => int.value as u32 as i32 as i64,
+            ValType::I64 => int.value as i64,
+        };
+        self.body.push(Instr::Const(vt, value));
+    }
+
+    /// Emits an operand, converted to the type of the operation on it
+    fn operand(&mut self, operand: &Expression, ctype: Type) {
+        self.exp(operand);
+        self.convert(operand.ctype(), ctype);
+    }
+
+    fn exp(&mut self, exp: &Expression) {
+        let ctype = exp.ctype();
+        let vt = ValType::of(ctype, self.model);
+        match &exp.kind {
+            ExpressionKind::Binary { left, operator, right } => {
+                // A shift count has a type of its own, while WebAssembly shifts take both
+                // operands in one
+                self.operand(left, ctype);
+                self.operand(right, ctype);
+                self.body.push(match operator {
+                    Operator::Plus => Instr::Add(vt),
+                    Operator::Negate => Instr::Sub(vt),
+                    Operator::Star => Instr::Mul(vt),
+                    Operator::Divide if ctype.is_signed() => Instr::DivS(vt),
+                    Operator::Divide => Instr::DivU(vt),
+                    Operator::ShiftLeft => Instr::Shl(vt),
+                    Operator::ShiftRight if ctype.is_signed() => Instr::ShrS(vt),
+                    Operator::ShiftRight => Instr::ShrU(vt),
+                    _ => panic!("Unexpected operator '{:?}'", operator),
+                });
+            }
+            ExpressionKind::Parenthesized(exp) => self.exp(exp),
+            ExpressionKind::Const(int) => self.constant(*int),
+            ExpressionKind::Unary { operator, operand } => match operator {
+                // WebAssembly has no negation, so subtract from a zero pushed below the operand
+                Operator::Negate => {
+                    self.body.push(Instr::Const(vt, 0));
+                    self.operand(operand, ctype);
+                    self.body.push(Instr::Sub(vt));
+                }
+                Operator::Not => {
+                    self.operand(operand, ctype);
+                    self.body.push(Instr::Const(vt, -1));
+                    self.body.push(Instr::Xor(vt));
+                }
+                // Tested in the operand's own type, while the result is an `int`
+                Operator::Bang => {
+                    self.exp(operand);
+                    self.body.push(Instr::Eqz(ValType::of(operand.ctype(), self.model)));
+                }
+                _ => panic!("Unexpected operator '{:?}'", operator),
+            },
+            // A wasm32-unknown-unknown module has no kernel to call, only what its host imports
+            ExpressionKind::Call { name, .. } => panic!("Unresolved call to '{}'", name),
+            ExpressionKind::Syscall { .. } => panic!("__builtin_syscall is not available on wasm32"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lexer;
+    use parser;
+    use sema;
+
+    fn compile(source: &str) -> Module {
+        let mut prog = parser::parse(lexer::lex(source).unwrap(), DataModel::LP64).unwrap();
+        sema::analyze(&mut prog, &mut Vec::new()).unwrap();
+        lower(&prog)
+    }
+
+    #[test]
+    fn generated_modules_validate() {
+        for source in &[
+            "int main() { return 2; }",
+            "int main() { return -(3 + 4) * ~5 / !0; }",
+            "int main() { return 5000000000 / 2u + -1; }",
+            "int main() { return 4000000000u / 3ll - 1ul; }",
+            "int main() { return (-8 >> 1) + (1l << 2u) + (8u >> 1ll); }",
+        ] {
+            assert_eq!(validate(&compile(source)), Ok(()), "{}", source);
+        }
+    }
+
+    #[test]
+    fn mixed_width_operands_are_converted() {
+        let module = compile("int main() { return 1 + 2l; }");
+        assert_eq!(
+            module.functions[0].body,
+            vec![
+                Instr::Const(ValType::I32, 1),
+                Instr::ExtendS,
+                Instr::Const(ValType::I64, 2),
+                Instr::Add(ValType::I64),
+                Instr::Wrap,
+                Instr::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn shift_counts_are_converted_to_the_shifted_type() {
+        let module = compile("int main() { return 1l << 2; }");
+        assert_eq!(
+            module.functions[0].body,
+            vec![
+                Instr::Const(ValType::I64, 1),
+                Instr::Const(ValType::I32, 2),
+                Instr::ExtendS,
+                Instr::Shl(ValType::I64),
+                Instr::Wrap,
+                Instr::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn ill_typed_bodies_are_rejected() {
+        let module = Module {
+            functions: vec![Function {
+                name: String::from("main"),
+                result: ValType::I32,
+                body: vec![
+                    Instr::Const(ValType::I64, 1),
+                    Instr::Const(ValType::I32, 2),
+                    Instr::Add(ValType::I32),
+                ],
+            }],
+        };
+        assert!(validate(&module).is_err());
+    }
+
+    #[test]
+    fn text_format() {
+        let module = compile("int main() { return -2; }");
+        assert_eq!(
+            generate(&module),
+            vec![
+                "(module",
+                "  (func $main (export \"main\") (result i32)",
+                "    i32.const 0",
+                "    i32.const 2",
+                "    i32.sub",
+                "    return",
+                "  )",
+                ")",
+            ]
+        );
+    }
+}